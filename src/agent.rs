@@ -0,0 +1,267 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, warn};
+use super::metrics::{self, Stage};
+use super::{config, Listener, Result};
+
+/// Header carrying the SHA-256 of the snippets, so a truncated upload is never loaded
+const CHECKSUM_HEADER: &str = "x-snippet-sha256";
+/// Largest snippet file accepted - far beyond any real set of apps
+const MAX_BODY: usize = 16 * 1024 * 1024;
+/// How long a watcher has to send its request, so a stalled one can't block the next
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the watcher waits for an agent to reload, so an unreachable one can't stall the rest
+const PUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Send the local snippets to every agent in --agent-urls, each of which writes them and reloads
+/// its Caddy. All of them are tried, so one unreachable agent doesn't leave the rest on the old
+/// snippets.
+pub(crate) async fn push_to_agents() -> Result<()> {
+    let agents = &config().agents;
+    if agents.urls.is_empty() {
+        return Ok(());
+    }
+
+    let snippet = std::fs::read(config().local_caddy.snippets_dir.join(&config().snippet_file_name))?;
+    let checksum = format!("{:x}", Sha256::digest(&snippet));
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(PUSH_TIMEOUT)
+        .build()?;
+    let mut failed = Vec::new();
+    for url in &agents.urls {
+        info!(%url, "sending snippets to agent...");
+        let started = std::time::Instant::now();
+        let result = client.put(url.join("snippets")?)
+            .bearer_auth(agents.token.as_deref().unwrap_or_default())
+            .header(CHECKSUM_HEADER, &checksum)
+            .body(snippet.clone())
+            .send()
+            .await;
+        match result {
+            Ok(response) if response.status().is_success() => metrics::observe(Stage::AgentReload, started.elapsed()),
+            Ok(response) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                error!(%url, %status, body, "agent was unable to reload Caddy");
+                failed.push(format!("{url}: {status} {}", body.trim()));
+            },
+            Err(e) => {
+                error!(%url, error=%e, "unable to send snippets to agent");
+                failed.push(format!("{url}: {e}"));
+            },
+        }
+    }
+
+    match failed.is_empty() {
+        true => Ok(()),
+        false => Err(format!("{} of {} agent(s) failed to reload - {}", failed.len(), agents.urls.len(), failed.join("; ")).into()),
+    }
+}
+
+/// Run as an agent: accept snippets PUT to /snippets by a watcher, write them to the local
+/// snippets dir and reload the local Caddy. Requests are handled one at a time, so reloads never
+/// overlap.
+pub(crate) async fn serve(address: SocketAddr) -> Result<()> {
+    let Some(ref token) = config().agents.token else {
+        return Err("--agent-token must be set to run an agent".into());
+    };
+    let listener = Listener::new()?;
+    let server = TcpListener::bind(address).await
+        .map_err(|e| format!("unable to listen for snippets on {address}: {e}"))?;
+    info!(%address, "agent waiting for snippets");
+    loop {
+        let (mut stream, peer) = server.accept().await?;
+        let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(request)) => handle(&listener, token, request).await,
+            Ok(Err(e)) => ("400 Bad Request", e.to_string()),
+            Err(_) => ("408 Request Timeout", "timed out reading the request".to_string()),
+        };
+        debug!(%peer, status=response.0, "responding to watcher");
+        if let Err(e) = respond(&mut stream, response).await {
+            warn!(%peer, error=%e, "unable to respond to watcher");
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    checksum: Option<String>,
+    body: Vec<u8>,
+}
+
+async fn handle(listener: &Listener, token: &str, request: Request) -> (&'static str, String) {
+    if (request.method.as_str(), request.path.as_str()) != ("PUT", "/snippets") {
+        return ("404 Not Found", "not found".to_string());
+    }
+    let presented = request.authorization.as_deref().and_then(|auth| auth.strip_prefix("Bearer "));
+    if !presented.is_some_and(|presented| tokens_match(presented, token)) {
+        return ("401 Unauthorized", "missing or incorrect token".to_string());
+    }
+    let checksum = format!("{:x}", Sha256::digest(&request.body));
+    if request.checksum.as_deref() != Some(checksum.as_str()) {
+        return ("400 Bad Request", format!("snippets have checksum {checksum}, expected {}", request.checksum.unwrap_or_default()));
+    }
+
+    match write_and_reload(listener, &request.body).await {
+        Ok(()) => {
+            info!(checksum, "reloaded Caddy with snippets from the watcher");
+            ("200 OK", "reloaded".to_string())
+        },
+        Err(e) => {
+            error!(error=%e, "unable to reload Caddy with snippets from the watcher");
+            ("500 Internal Server Error", e.to_string())
+        },
+    }
+}
+
+async fn write_and_reload(listener: &Listener, snippet: &[u8]) -> Result<()> {
+    if config().dry_run {
+        info!(snippets=%String::from_utf8_lossy(snippet), "dry run - not writing the snippets or reloading Caddy");
+        return Ok(());
+    }
+
+    let path = config().local_caddy.snippets_dir.join(&config().snippet_file_name);
+    // renamed into place, so Caddy never imports a partly written file
+    let temp = path.with_extension("agent-tmp");
+    std::fs::write(&temp, snippet)?;
+    std::fs::rename(&temp, &path)?;
+    listener.reload_instance(&config().local_caddy).await
+}
+
+/// Compare without stopping at the first difference, so the token can't be guessed by timing
+fn tokens_match(presented: &str, token: &str) -> bool {
+    presented.len() == token.len() && presented.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Read a request with a Content-Length body - all the watcher sends
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buffer = Vec::new();
+    let header_end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buffer.len() > 64 * 1024 {
+            return Err("request headers too long".into());
+        }
+        let mut chunk = [0; 8192];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err("connection closed before the end of the headers".into());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let mut request = Request { method, path, authorization: None, checksum: None, body: Vec::new() };
+    let mut content_length = 0;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => content_length = value.parse().map_err(|_| format!("invalid Content-Length {value}"))?,
+            "authorization" => request.authorization = Some(value.to_string()),
+            CHECKSUM_HEADER => request.checksum = Some(value.to_ascii_lowercase()),
+            _ => {},
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(format!("snippets of {content_length} bytes are too large").into());
+    }
+
+    request.body = buffer.split_off(header_end + 4);
+    while request.body.len() < content_length {
+        let mut chunk = vec![0; (content_length - request.body.len()).min(64 * 1024)];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err("connection closed before the end of the body".into());
+        }
+        request.body.extend_from_slice(&chunk[..read]);
+    }
+    request.body.truncate(content_length);
+
+    Ok(request)
+}
+
+async fn respond(stream: &mut TcpStream, (status, body): (&str, String)) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}\n",
+        body.len() + 1,
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use super::{handle, read_request, Request};
+    use crate::{Listener, Result};
+
+    /// Read a request sent as the given bytes. The client closes the connection after sending them
+    /// unless `keep_open` is set.
+    async fn read_sent(raw: &'static [u8], keep_open: bool) -> Result<Request> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(raw).await.unwrap();
+            keep_open.then_some(stream)
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let request = read_request(&mut stream).await;
+        drop(client.await.unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn read_request_parses_the_headers_and_body() {
+        let raw = b"PUT /snippets HTTP/1.1\r\nAuthorization: Bearer token\r\nX-Snippet-SHA256: ABC123\r\nContent-Length: 5\r\n\r\nhello";
+        let request = read_sent(raw, true).await.unwrap();
+
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("PUT", "/snippets"));
+        assert_eq!(request.authorization.as_deref(), Some("Bearer token"));
+        assert_eq!(request.checksum.as_deref(), Some("abc123"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn read_request_rejects_oversized_and_truncated_bodies() {
+        let oversized = b"PUT /snippets HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n";
+        assert!(read_sent(oversized, true).await.is_err());
+
+        let truncated = b"PUT /snippets HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello";
+        assert!(read_sent(truncated, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn handle_checks_the_route_token_and_checksum_before_reloading() {
+        let listener = Listener::new().unwrap();
+        let request = |path: &str, authorization: &str, checksum: &str| Request {
+            method: "PUT".to_string(),
+            path: path.to_string(),
+            authorization: Some(authorization.to_string()),
+            checksum: Some(checksum.to_string()),
+            body: b"hello".to_vec(),
+        };
+        // the SHA-256 of "hello"
+        let checksum = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        assert_eq!(handle(&listener, "token", request("/other", "Bearer token", checksum)).await.0, "404 Not Found");
+        assert_eq!(handle(&listener, "token", request("/snippets", "Bearer wrong", checksum)).await.0, "401 Unauthorized");
+        assert_eq!(handle(&listener, "token", request("/snippets", "token", checksum)).await.0, "401 Unauthorized");
+        assert_eq!(handle(&listener, "token", request("/snippets", "Bearer token", "0000")).await.0, "400 Bad Request");
+    }
+}
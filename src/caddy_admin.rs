@@ -0,0 +1,125 @@
+use std::time::Duration;
+use reqwest::{StatusCode, Url};
+use super::Result;
+
+/// How long a request to a Caddy admin API may take, including Caddy loading the config
+const ADMIN_API_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// POST a Caddyfile to an admin API endpoint (e.g., load), over HTTP or - for a unix:///path URL -
+/// the admin API's unix socket. Returns the response's status and body.
+pub(crate) async fn post_caddyfile(url: &Url, endpoint: &str, caddyfile: String) -> Result<(StatusCode, String)> {
+    if url.scheme() == "unix" {
+        return tokio::time::timeout(ADMIN_API_TIMEOUT, post_to_socket(url.path(), endpoint, caddyfile)).await
+            .map_err(|_| format!("Caddy admin socket {} didn't respond within {ADMIN_API_TIMEOUT:?}", url.path()))?;
+    }
+
+    let response = reqwest::Client::builder()
+        .timeout(ADMIN_API_TIMEOUT)
+        .build()?
+        .post(url.join(endpoint)?)
+        .header(reqwest::header::CONTENT_TYPE, "text/caddyfile")
+        .body(caddyfile)
+        .send()
+        .await?;
+    let status = response.status();
+    Ok((status, response.text().await.unwrap_or_default()))
+}
+
+#[cfg(unix)]
+async fn post_to_socket(path: &str, endpoint: &str, caddyfile: String) -> Result<(StatusCode, String)> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::UnixStream::connect(path).await
+        .map_err(|e| format!("unable to connect to Caddy admin socket {path}: {e}"))?;
+    // Caddy only accepts an empty Host on its admin socket, as HTTP/1.1 asks for without a host name
+    let request = format!(
+        "POST /{endpoint} HTTP/1.1\r\nHost:\r\nContent-Type: text/caddyfile\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{caddyfile}",
+        caddyfile.len(),
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    parse_response(&response).ok_or_else(|| format!("malformed response from Caddy admin socket {path}").into())
+}
+
+#[cfg(not(unix))]
+async fn post_to_socket(path: &str, _endpoint: &str, _caddyfile: String) -> Result<(StatusCode, String)> {
+    Err(format!("unable to use Caddy admin socket {path}: unix sockets are only supported on unix").into())
+}
+
+/// The status and body of an HTTP/1.1 response read to the end of the connection
+fn parse_response(response: &[u8]) -> Option<(StatusCode, String)> {
+    let response = String::from_utf8_lossy(response);
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    let status = head.split(' ').nth(1)?.parse::<u16>().ok()?;
+    let chunked = head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .any(|(name, value)| name.eq_ignore_ascii_case("transfer-encoding") && value.trim().eq_ignore_ascii_case("chunked"));
+
+    let body = match chunked {
+        true => {
+            let mut decoded = String::new();
+            let mut rest = body;
+            while let Some((size, after)) = rest.split_once("\r\n") {
+                let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+                if size == 0 {
+                    break;
+                }
+                decoded.push_str(after.get(..size)?);
+                rest = after.get(size..)?.strip_prefix("\r\n")?;
+            }
+            decoded
+        },
+        false => body.to_string(),
+    };
+
+    Some((StatusCode::from_u16(status).ok()?, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+    use super::parse_response;
+
+    #[test]
+    fn parse_response_reads_plain_and_chunked_bodies() {
+        let plain = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(parse_response(plain), Some((StatusCode::OK, String::new())));
+
+        let chunked = b"HTTP/1.1 400 Bad Request\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nbad Ca\r\n7\r\nddyfile\r\n0\r\n\r\n";
+        assert_eq!(parse_response(chunked), Some((StatusCode::BAD_REQUEST, "bad Caddyfile".to_string())));
+
+        assert_eq!(parse_response(b"garbage"), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn post_caddyfile_loads_over_a_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("docker-caddy-rs-admin-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n:80") {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+
+        let url = reqwest::Url::parse(&format!("unix://{}", path.display())).unwrap();
+        let (status, _) = super::post_caddyfile(&url, "load", ":80".to_string()).await.unwrap();
+        let request = server.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(request.starts_with("POST /load HTTP/1.1\r\nHost:\r\n"), "{request}");
+        assert!(request.ends_with("\r\n\r\n:80"), "{request}");
+    }
+}
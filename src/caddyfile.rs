@@ -0,0 +1,97 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use super::{config, Result};
+
+/// Re-indent Caddyfile content the way `caddy fmt` would: one tab per level of nesting, with
+/// trailing whitespace and runs of blank lines removed, as well as blank lines just inside a block.
+pub(crate) fn format(content: &str) -> String {
+    let mut formatted = Vec::new();
+    let mut depth: usize = 0;
+    let mut previous_blank = true;
+
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() {
+            if !previous_blank && !formatted.last().is_some_and(|l: &String| l.ends_with('{')) {
+                formatted.push(String::new());
+            }
+            previous_blank = true;
+            continue;
+        }
+
+        if line.starts_with('}') && formatted.last().is_some_and(|l| l.is_empty()) {
+            formatted.pop();
+        }
+
+        // a closing brace at the start of the line belongs to the enclosing level
+        let indent = if line.starts_with('}') { depth.saturating_sub(1) } else { depth };
+        formatted.push(format!("{}{}", "\t".repeat(indent), line));
+        previous_blank = false;
+
+        // placeholders open and close on the same line, so only the net change matters
+        let (opens, closes) = count_braces(line);
+        depth = (depth + opens).saturating_sub(closes);
+    }
+
+    while formatted.last().is_some_and(|l| l.is_empty()) {
+        formatted.pop();
+    }
+
+    formatted.join("\n") + "\n"
+}
+
+/// Format the content by piping it through `caddy fmt` using the local Caddy binary
+pub(crate) fn caddy_fmt(content: &str) -> Result<String> {
+    let mut child = Command::new(&config().local_caddy.bin_path)
+        .args(["fmt", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    child.stdin.take().ok_or("unable to open stdin for caddy fmt")?.write_all(content.as_bytes())?;
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "caddy fmt exited with status {}: {}",
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr),
+        ).into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Count the braces in a line, ignoring any inside quoted strings
+fn count_braces(line: &str) -> (usize, usize) {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let (mut opens, mut closes) = (0, 0);
+
+    for c in line.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '{' if !in_quotes => opens += 1,
+            '}' if !in_quotes => closes += 1,
+            _ => {},
+        }
+    }
+
+    (opens, closes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+
+    #[test]
+    fn format_indents_with_tabs_and_drops_extra_blank_lines() {
+        let content = "\n@web host web.example.com  \nhandle @web {\n\n  header X-Braces \"{not a block\"\n      reverse_proxy {http.request.host} {\n lb_policy first\n}\n\n\n}\n\n\nhandle {\n  abort\n\n}\n\n";
+        assert_eq!(
+            format(content),
+            "@web host web.example.com\nhandle @web {\n\theader X-Braces \"{not a block\"\n\treverse_proxy {http.request.host} {\n\t\tlb_policy first\n\t}\n}\n\nhandle {\n\tabort\n}\n",
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use reqwest::Url;
+use serde::Serialize;
+use tracing::{debug, warn};
+use super::{config, template, AppData, ApplicationData};
+
+/// A state transition, written as a JSON line for external automation. The fields are part of
+/// the output format, so only add to them.
+#[derive(Serialize)]
+#[serde(tag="event", rename_all="snake_case")]
+pub(crate) enum Transition<'a> {
+    AppAdded { app: &'a str, containers: &'a [String] },
+    AppRemoved { app: &'a str },
+    UpstreamsChanged { app: &'a str, containers: &'a [String] },
+    /// Caddy was reloaded with new snippets, after the given change
+    Reloaded { action: &'a str, app: Option<&'a str> },
+    ReloadFailed { action: &'a str, app: Option<&'a str>, error: String },
+    /// An app changed by a reload failed --verify-after-reload
+    VerificationFailed { app: &'a str, host: &'a str, error: String },
+    /// A container was taken out of the app's upstreams after being OOM-killed repeatedly
+    ContainerDrained { app: &'a str, container: &'a str, oom_kills: usize, until: String },
+    /// A drained container was put back in the app's upstreams
+    ContainerRestored { app: &'a str, container: &'a str },
+    /// An app's host was left out of the snippets, e.g., for an unknown auth type
+    AppRejected { app: &'a str, host: &'a str, reason: &'a str },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    #[serde(flatten)]
+    transition: &'a Transition<'a>,
+}
+
+fn writer() -> Option<&'static Mutex<Box<dyn Write + Send>>> {
+    static WRITER: OnceLock<Option<Mutex<Box<dyn Write + Send>>>> = OnceLock::new();
+    WRITER.get_or_init(|| {
+        let path = config().emit_events.as_ref()?;
+        let writer: Box<dyn Write + Send> = if path.as_os_str() == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            match File::options().create(true).append(true).open(path) {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    warn!(path=%path.display(), error=%e, "unable to open --emit-events file, not emitting events");
+                    return None;
+                },
+            }
+        };
+        Some(Mutex::new(writer))
+    }).as_ref()
+}
+
+/// Whether events go to stdout, so logs must go elsewhere
+pub(crate) fn to_stdout() -> bool {
+    config().emit_events.as_ref().is_some_and(|path| path.as_os_str() == "-")
+}
+
+/// Whether the transitions go anywhere, so they're worth working out
+fn enabled() -> bool {
+    config().emit_events.is_some() || !config().notify.urls.is_empty()
+}
+
+pub(crate) fn emit(transition: Transition) {
+    let line = Line { time: chrono::Utc::now().to_rfc3339(), transition: &transition };
+    notify(&line);
+
+    let Some(writer) = writer() else {
+        return;
+    };
+
+    let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+    let result = serde_json::to_writer(&mut *writer, &line)
+        .map_err(std::io::Error::from)
+        .and_then(|()| writer.write_all(b"\n"))
+        .and_then(|()| writer.flush());
+    if let Err(e) = result {
+        warn!(error=%e, "unable to emit event");
+    }
+}
+
+/// POST the transition to each of the --notify-urls in the background, so a slow receiver can't
+/// hold up reloads
+fn notify(line: &Line) {
+    if config().notify.urls.is_empty() {
+        return;
+    }
+
+    let body = match template::render_notification(line) {
+        Some(Ok(body)) => body,
+        Some(Err(e)) => {
+            warn!(error=%e, "unable to notify of event");
+            return;
+        },
+        None => match serde_json::to_string(line) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!(error=%e, "unable to notify of event");
+                return;
+            },
+        },
+    };
+    for url in &config().notify.urls {
+        tokio::spawn(post(url.clone(), body.clone()));
+    }
+}
+
+async fn post(url: Url, body: String) {
+    let result = reqwest::Client::new()
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, &config().notify.content_type)
+        .body(body)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status);
+    match result {
+        Ok(response) => debug!(%url, status=%response.status(), "notified of event"),
+        Err(e) => warn!(%url, error=%e, "unable to notify of event"),
+    }
+}
+
+/// The containers of each app, by name, as of the last emitted transitions
+pub(crate) type EmittedApps = BTreeMap<String, Vec<String>>;
+
+/// Emit the apps added or removed, and those whose containers changed, since `emitted`, updating it
+pub(crate) fn emit_app_changes(emitted: &mut EmittedApps, app_data: &ApplicationData) {
+    if !enabled() {
+        return;
+    }
+
+    app_changes(emitted, app_data, emit);
+}
+
+/// Pass each app added, removed or with changed containers since `emitted` to `emit`, updating it
+fn app_changes(emitted: &mut EmittedApps, app_data: &ApplicationData, mut emit: impl FnMut(Transition)) {
+    let current = app_data.iter()
+        .filter(|(_, ad)| !ad.containers.is_empty())
+        .map(|(app, ad)| {
+            let mut containers = ad.containers.iter().map(|adc| adc.container_name.clone()).collect::<Vec<_>>();
+            containers.sort();
+            (app.clone(), containers)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    emitted.retain(|app, _| {
+        let kept = current.contains_key(app);
+        if !kept {
+            emit(Transition::AppRemoved { app });
+        }
+        kept
+    });
+    for (app, containers) in current {
+        match emitted.get(&app) {
+            None => emit(Transition::AppAdded { app: &app, containers: &containers }),
+            Some(previous) if *previous != containers => emit(Transition::UpstreamsChanged { app: &app, containers: &containers }),
+            Some(_) => continue,
+        }
+        emitted.insert(app, containers);
+    }
+}
+
+/// Why each rejected host was left out, as of the last emitted transitions
+pub(crate) type EmittedRejections = BTreeMap<String, String>;
+
+/// Emit the hosts newly left out of the snippets (or for a new reason) since `emitted`, updating it
+pub(crate) fn emit_rejections(emitted: &mut EmittedRejections, app_data: &ApplicationData) {
+    if !enabled() {
+        return;
+    }
+
+    for (app, host, reason) in new_rejections(emitted, app_data) {
+        emit(Transition::AppRejected { app: &app, host: &host, reason: &reason });
+    }
+}
+
+/// The app, host and reason of each host rejected since `emitted`, updating it. A host that's
+/// served again is forgotten, so it's included again if it's rejected again.
+fn new_rejections(emitted: &mut EmittedRejections, app_data: &ApplicationData) -> Vec<(String, String, String)> {
+    let current = app_data.values()
+        .filter(|ad| !ad.containers.is_empty())
+        .flat_map(AppData::with_port_variants)
+        .filter_map(|ad| Some((format!("{}.{}", ad.app_name, ad.domain()), (ad.rejection()?, ad.app_name.clone()))))
+        .collect::<BTreeMap<_, _>>();
+
+    emitted.retain(|host, _| current.contains_key(host));
+    let mut rejections = Vec::new();
+    for (host, (reason, app)) in current {
+        if emitted.get(&host) != Some(&reason) {
+            emitted.insert(host.clone(), reason.clone());
+            rejections.push((app, host, reason));
+        }
+    }
+    rejections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{app_changes, new_rejections, post, EmittedApps, EmittedRejections, Line, Transition};
+    use crate::tests::{app, app_data};
+
+    #[test]
+    fn app_changes_are_emitted_as_json_lines() {
+        let changes = |emitted: &mut EmittedApps, apps| {
+            let mut lines = Vec::new();
+            app_changes(emitted, &apps, |transition| lines.push(serde_json::to_string(&Line { time: "now".to_string(), transition: &transition }).unwrap()));
+            lines
+        };
+        let mut emitted = EmittedApps::new();
+
+        assert_eq!(changes(&mut emitted, app_data(vec![app("web", &[("port", "80")])])), [
+            r#"{"time":"now","event":"app_added","app":"web","containers":["web-1"]}"#,
+        ]);
+        assert!(changes(&mut emitted, app_data(vec![app("web", &[("port", "8080")])])).is_empty());
+
+        let mut scaled = app("web", &[("port", "80")]);
+        scaled.containers.push(app("web", &[("port", "80")]).containers[0].clone());
+        scaled.containers[1].container_name = "web-2".to_string();
+        assert_eq!(changes(&mut emitted, app_data(vec![scaled])), [
+            r#"{"time":"now","event":"upstreams_changed","app":"web","containers":["web-1","web-2"]}"#,
+        ]);
+        assert_eq!(changes(&mut emitted, app_data(vec![])), [r#"{"time":"now","event":"app_removed","app":"web"}"#]);
+    }
+
+    #[test]
+    fn new_rejections_are_reported_once_until_served_again() {
+        let mut emitted = EmittedRejections::new();
+        let typo = app_data(vec![app("typo", &[("port", "80"), ("auth", "oicd")]), app("fine", &[("port", "80")])]);
+        let rejections = new_rejections(&mut emitted, &typo);
+        assert_eq!(rejections.len(), 1);
+        assert_eq!((rejections[0].0.as_str(), rejections[0].1.as_str()), ("typo", "typo.local.example.com"));
+        assert!(new_rejections(&mut emitted, &typo).is_empty());
+
+        let fixed = app_data(vec![app("typo", &[("port", "80"), ("auth", "oidc")])]);
+        assert!(new_rejections(&mut emitted, &fixed).is_empty());
+        assert_eq!(new_rejections(&mut emitted, &typo).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn notifications_post_the_transition() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = reqwest::Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        let receiver = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"}") {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let transition = Transition::AppRemoved { app: "web" };
+        let body = serde_json::to_string(&Line { time: "now".to_string(), transition: &transition }).unwrap();
+        post(url, body).await;
+        let request = receiver.await.unwrap().to_lowercase();
+
+        assert!(request.starts_with("post /hook http/1.1\r\n"), "{request}");
+        assert!(request.contains("content-type: application/json\r\n"), "{request}");
+        assert!(request.ends_with(r#"{"time":"now","event":"app_removed","app":"web"}"#), "{request}");
+
+        // a --notify-template sees the same fields
+        let template = minijinja::Environment::new().render_str("{{ event }}: {{ app }}", Line { time: "now".to_string(), transition: &transition });
+        assert_eq!(template.unwrap(), "app_removed: web");
+    }
+}
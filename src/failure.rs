@@ -0,0 +1,76 @@
+use std::error::Error;
+use std::fmt;
+use std::process::ExitCode;
+
+/// The class of a fatal error, which determines the exit code. Config errors are reported through
+/// clap, so exit with its code for invalid arguments (2).
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum FailureKind {
+    Docker,
+    Reload,
+    Dns,
+}
+
+impl FailureKind {
+    fn exit_code(self) -> u8 {
+        match self {
+            FailureKind::Docker => 3,
+            FailureKind::Reload => 4,
+            FailureKind::Dns => 5,
+        }
+    }
+}
+
+/// An error tagged with its failure class
+#[derive(Debug)]
+pub(crate) struct Failure {
+    kind: FailureKind,
+    source: Box<dyn Error>,
+}
+
+impl Failure {
+    pub(crate) fn wrap(kind: FailureKind, source: Box<dyn Error>) -> Box<dyn Error> {
+        Box::new(Failure { kind, source })
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl Error for Failure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// The exit code for a fatal error. Errors that weren't tagged with a class are still treated as
+/// Docker errors if they came from the Docker API, and otherwise exit with 1.
+pub(crate) fn exit_code(error: &(dyn Error + 'static)) -> ExitCode {
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        ExitCode::from(failure.kind.exit_code())
+    } else if error.is::<docker_api::Error>() {
+        ExitCode::from(FailureKind::Docker.exit_code())
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::ExitCode;
+    use super::{exit_code, Failure, FailureKind};
+
+    #[test]
+    fn exit_codes_follow_the_failure_class() {
+        let reload = Failure::wrap(FailureKind::Reload, "caddy exited with status 1".into());
+        assert_eq!(exit_code(reload.as_ref()), ExitCode::from(4));
+        assert_eq!(reload.to_string(), "caddy exited with status 1");
+        assert_eq!(exit_code(Failure::wrap(FailureKind::Dns, "zone not found".into()).as_ref()), ExitCode::from(5));
+
+        let untagged: Box<dyn std::error::Error> = "something else".into();
+        assert_eq!(exit_code(untagged.as_ref()), ExitCode::FAILURE);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+use super::{config, RenderedSnippets, Result};
+
+/// Directory (inside each snippets dir) that previous generations of the snippets are kept in.
+/// Caddy skips dotfiles when importing a glob, so these are never imported.
+pub(crate) const GENERATIONS_DIR: &str = ".generations";
+/// File within the generations dir holding the number of the last generation that reloaded
+const LAST_GOOD_FILE: &str = "last-good";
+
+/// The previous N generations of the snippet files, numbered sequentially, along with which one
+/// last reloaded successfully.
+pub(crate) struct SnippetGenerations {
+    keep: usize,
+    latest: u64,
+    last_good: Option<u64>,
+}
+
+impl SnippetGenerations {
+    /// Pick up where any existing generations (e.g., from before a restart) left off
+    pub(crate) fn load(keep: usize) -> Result<Self> {
+        let local_dir = generations_dir(&config().local_caddy.snippets_dir);
+        let latest = list_generations(&local_dir)?.into_iter().max().unwrap_or(0);
+        let last_good = match fs::read_to_string(local_dir.join(LAST_GOOD_FILE)) {
+            Ok(generation) => Some(generation.trim().parse()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        info!(keep, latest, last_good, "loaded snippet generations");
+
+        Ok(SnippetGenerations {
+            keep,
+            latest,
+            last_good,
+        })
+    }
+
+    pub(crate) fn last_good(&self) -> Option<u64> {
+        self.last_good
+    }
+
+    /// Whether the most recently written generation reloaded successfully
+    pub(crate) fn latest_is_good(&self) -> bool {
+        self.last_good == Some(self.latest)
+    }
+
+    /// Keep a copy of the snippets as a new generation, pruning the oldest generations (other
+    /// than the last good one) beyond the number to keep
+    pub(crate) fn record(&mut self, rendered: &RenderedSnippets) -> Result<u64> {
+        let generation = self.latest + 1;
+
+        for (snippets_dir, content) in instances(rendered) {
+            let dir = generations_dir(snippets_dir);
+            fs::create_dir_all(&dir)?;
+            fs::write(dir.join(generation.to_string()), content)?;
+
+            let mut generations = list_generations(&dir)?;
+            generations.sort_unstable_by(|a, b| b.cmp(a));
+            for old in generations.into_iter().skip(self.keep).filter(|g| Some(*g) != self.last_good) {
+                debug!(generation=old, dir=%dir.display(), "pruning snippet generation");
+                fs::remove_file(dir.join(old.to_string()))?;
+            }
+        }
+
+        self.latest = generation;
+        Ok(generation)
+    }
+
+    pub(crate) fn mark_good(&mut self, generation: u64) -> Result<()> {
+        for snippets_dir in [&config().local_caddy.snippets_dir, &config().docker_caddy.snippets_dir] {
+            fs::write(generations_dir(snippets_dir).join(LAST_GOOD_FILE), generation.to_string())?;
+        }
+
+        self.last_good = Some(generation);
+        Ok(())
+    }
+
+    pub(crate) fn restore(&self, generation: u64) -> Result<RenderedSnippets> {
+        let read = |snippets_dir: &Path| fs::read_to_string(generations_dir(snippets_dir).join(generation.to_string()));
+
+        Ok(RenderedSnippets {
+            local: read(&config().local_caddy.snippets_dir)?,
+            docker: read(&config().docker_caddy.snippets_dir)?,
+        })
+    }
+}
+
+fn instances(rendered: &RenderedSnippets) -> [(&PathBuf, &str); 2] {
+    [
+        (&config().local_caddy.snippets_dir, &rendered.local),
+        (&config().docker_caddy.snippets_dir, &rendered.docker),
+    ]
+}
+
+fn generations_dir(snippets_dir: &Path) -> PathBuf {
+    snippets_dir.join(GENERATIONS_DIR)
+}
+
+fn list_generations(dir: &Path) -> Result<Vec<u64>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        if let Some(generation) = entry?.file_name().to_str().and_then(|name| name.parse().ok()) {
+            generations.push(generation);
+        }
+    }
+
+    Ok(generations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generations_dir, list_generations, SnippetGenerations};
+    use crate::config;
+    use crate::tests::{app, app_data, SNIPPETS_DIRS};
+
+    #[test]
+    fn prunes_old_generations_but_keeps_the_last_good_one() {
+        let _dirs = SNIPPETS_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+        let local_dir = generations_dir(&config().local_caddy.snippets_dir);
+        for snippets_dir in [&config().local_caddy.snippets_dir, &config().docker_caddy.snippets_dir] {
+            let _ = std::fs::remove_dir_all(generations_dir(snippets_dir));
+        }
+
+        let mut generations = SnippetGenerations::load(2).unwrap();
+        let rendered = |port: &str| crate::render_snippets(&app_data(vec![app("web", &[("port", port)])])).unwrap();
+        let good = generations.record(&rendered("80")).unwrap();
+        generations.mark_good(good).unwrap();
+        generations.record(&rendered("81")).unwrap();
+        generations.record(&rendered("82")).unwrap();
+        assert!(!generations.latest_is_good());
+
+        let mut kept = list_generations(&local_dir).unwrap();
+        kept.sort_unstable();
+        assert_eq!(kept, [1, 2, 3]);
+        generations.record(&rendered("83")).unwrap();
+        let mut kept = list_generations(&local_dir).unwrap();
+        kept.sort_unstable();
+        assert_eq!(kept, [1, 3, 4]);
+
+        // picks up where it left off after a restart
+        let reloaded = SnippetGenerations::load(2).unwrap();
+        assert_eq!(reloaded.last_good(), Some(1));
+        assert!(reloaded.restore(1).unwrap() == rendered("80"));
+    }
+}
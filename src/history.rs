@@ -0,0 +1,89 @@
+use std::path::Path;
+use std::process::Command;
+use tracing::{debug, error, info};
+use super::{Result, SnippetChange};
+use crate::generations::GENERATIONS_DIR;
+
+/// Commit the current contents of a snippets directory to a git repository in that directory,
+/// creating the repository if needed. Failures are logged rather than returned, as the history is
+/// only a record of what was generated.
+pub(crate) fn commit_snippets(dir: &Path, change: &SnippetChange) {
+    if let Err(e) = try_commit_snippets(dir, change) {
+        error!(dir=%dir.display(), error=%e, "unable to commit snippets to git history");
+    }
+}
+
+fn try_commit_snippets(dir: &Path, change: &SnippetChange) -> Result<()> {
+    if !dir.join(".git").exists() {
+        info!(dir=%dir.display(), "initialising git repository for snippet history");
+        git(dir, &["init", "--quiet"])?;
+    }
+
+    // previous generations are already history of their own
+    git(dir, &["add", "--all", "--", ".", &format!(":!{}", GENERATIONS_DIR)])?;
+
+    // exits with 0 if nothing is staged
+    let unchanged = Command::new("git")
+        .current_dir(dir)
+        .args(["diff", "--cached", "--quiet"])
+        .status()?
+        .success();
+    if unchanged {
+        debug!(dir=%dir.display(), "snippets unchanged, nothing to commit");
+        return Ok(());
+    }
+
+    let message = match change.app_name {
+        Some(app_name) => format!("{} event for app {}", change.action, app_name),
+        None => format!("{} event", change.action),
+    };
+    git(dir, &[
+        "-c", "user.name=docker-caddy-rs",
+        "-c", "user.email=docker-caddy-rs@localhost",
+        "commit", "--quiet", "--message", &message,
+    ])?;
+
+    Ok(())
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<()> {
+    let status = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .status()?;
+
+    if !status.success() {
+        return Err(format!("git {} exited with status {}", args.join(" "), status.code().unwrap_or(-1)).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use super::try_commit_snippets;
+    use crate::generations::GENERATIONS_DIR;
+    use crate::SnippetChange;
+
+    fn log(dir: &std::path::Path) -> String {
+        let output = Command::new("git").current_dir(dir).args(["log", "--format=%s", "--name-only"]).output().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn commits_changed_snippets_without_the_generations() {
+        let dir = std::env::temp_dir().join(format!("docker-caddy-rs-history-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(GENERATIONS_DIR)).unwrap();
+        std::fs::write(dir.join("docker-hosts.caddy"), "web.example.com {\n}\n").unwrap();
+        std::fs::write(dir.join(GENERATIONS_DIR).join("1.caddy"), "").unwrap();
+
+        try_commit_snippets(&dir, &SnippetChange { action: "start", app_name: Some("web") }).unwrap();
+        try_commit_snippets(&dir, &SnippetChange { action: "rebuild", app_name: None }).unwrap();
+        let log = log(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(log, "start event for app web\n\ndocker-hosts.caddy\n");
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::{HashMap, HashSet};
+use docker_api::Docker;
+use docker_api::models::{ContainerInspect200Response, ContainerSummary, EndpointSettings};
+use tracing::debug;
+use super::Result;
+use crate::metrics::{self, Stage};
+
+/// The parts of a container list entry that change when anything we use from the inspect response
+/// does, so a matching entry means the cached response is still current
+#[derive(PartialEq)]
+struct Fingerprint {
+    names: Option<Vec<String>>,
+    labels: Option<HashMap<String, String>>,
+    state: Option<String>,
+    health: Option<String>,
+    networks: Option<HashMap<String, EndpointSettings>>,
+}
+
+impl Fingerprint {
+    fn new(summary: &ContainerSummary) -> Self {
+        Fingerprint {
+            names: summary.names.clone(),
+            labels: summary.labels.clone(),
+            state: summary.state.clone(),
+            // the status includes the uptime, so only the health part (e.g., "(health: starting)")
+            // is compared
+            health: summary.status.as_deref()
+                .and_then(|status| status.rsplit_once('('))
+                .map(|(_, health)| health.to_string()),
+            networks: summary.network_settings.as_ref().and_then(|ns| ns.networks.clone()),
+        }
+    }
+}
+
+async fn inspect(docker: &Docker, id: &str) -> Result<ContainerInspect200Response> {
+    let started = std::time::Instant::now();
+    let container = docker.containers().get(id).inspect().await;
+    metrics::observe(Stage::Inspect, started.elapsed());
+    Ok(container?)
+}
+
+/// Inspect responses by container id, so sweeps over every container only inspect the ones that
+/// have changed since they were last seen
+#[derive(Default)]
+pub(crate) struct InspectCache {
+    /// The fingerprint is unknown for responses cached after an event, until the next sweep
+    entries: HashMap<String, (Option<Fingerprint>, ContainerInspect200Response)>,
+}
+
+impl InspectCache {
+    /// Inspect a container found by listing, reusing the cached response if the list entry is
+    /// unchanged since it was cached
+    pub(crate) async fn inspect_listed(&mut self, docker: &Docker, summary: &ContainerSummary) -> Result<ContainerInspect200Response> {
+        let id = summary.id.as_deref().ok_or("listed container has no ID")?;
+        let fingerprint = Fingerprint::new(summary);
+        if let Some((Some(cached_fingerprint), cached)) = self.entries.get(id) {
+            if *cached_fingerprint == fingerprint {
+                debug!(container_id=id, "container unchanged, using cached inspect response");
+                return Ok(cached.clone());
+            }
+        }
+
+        let container = inspect(docker, id).await?;
+        self.entries.insert(id.to_string(), (Some(fingerprint), container.clone()));
+        Ok(container)
+    }
+
+    /// Inspect a container after an event that may have changed it
+    pub(crate) async fn refresh(&mut self, docker: &Docker, id: &str) -> Result<ContainerInspect200Response> {
+        let container = inspect(docker, id).await?;
+        self.entries.insert(id.to_string(), (None, container.clone()));
+        Ok(container)
+    }
+
+    pub(crate) fn remove(&mut self, id: &str) {
+        self.entries.remove(id);
+    }
+
+    /// Forget containers that no longer exist
+    pub(crate) fn retain_ids(&mut self, ids: &HashSet<&str>) {
+        self.entries.retain(|id, _| ids.contains(id.as_str()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use docker_api::models::ContainerSummary;
+    use serde_json::json;
+    use super::{Fingerprint, InspectCache};
+
+    fn summary(status: &str, labels: serde_json::Value) -> ContainerSummary {
+        serde_json::from_value(json!({ "Id": "web-1-id", "Names": ["/web-1"], "State": "running", "Status": status, "Labels": labels })).unwrap()
+    }
+
+    #[test]
+    fn fingerprints_ignore_the_uptime_but_not_the_health_or_labels() {
+        let labels = json!({ "test.app": "web" });
+        let fingerprint = Fingerprint::new(&summary("Up 2 minutes (healthy)", labels.clone()));
+        assert!(fingerprint == Fingerprint::new(&summary("Up 3 minutes (healthy)", labels.clone())));
+        assert!(fingerprint != Fingerprint::new(&summary("Up 3 minutes (unhealthy)", labels)));
+        assert!(fingerprint != Fingerprint::new(&summary("Up 2 minutes (healthy)", json!({ "test.app": "api" }))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unchanged_containers_are_not_inspected_again() {
+        // never connected to, so any inspect fails
+        let docker = docker_api::Docker::unix("/nonexistent/docker.sock");
+        let listed = summary("Up 2 minutes", json!({ "test.app": "web" }));
+        let inspected = serde_json::from_value(json!({ "Id": "web-1-id", "Name": "/web-1" })).unwrap();
+        let mut cache = InspectCache::default();
+        cache.entries.insert("web-1-id".to_string(), (Some(Fingerprint::new(&listed)), inspected));
+
+        assert!(cache.inspect_listed(&docker, &listed).await.is_ok());
+        let relabelled = summary("Up 2 minutes", json!({ "test.app": "api" }));
+        assert!(cache.inspect_listed(&docker, &relabelled).await.is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use tracing::debug;
+
+/// Swarm template placeholders, and the container labels Swarm records their values in
+const SWARM_PLACEHOLDERS: [(&str, &str); 5] = [
+    ("{{.Service.Name}}", "com.docker.swarm.service.name"),
+    ("{{.Service.ID}}", "com.docker.swarm.service.id"),
+    ("{{.Task.Name}}", "com.docker.swarm.task.name"),
+    ("{{.Task.ID}}", "com.docker.swarm.task.id"),
+    ("{{.Node.ID}}", "com.docker.swarm.node.id"),
+];
+
+/// Expand our labels' values, for labels written with Swarm template placeholders (e.g.,
+/// `{{.Service.Name}}`) or `${VAR}` references to the container's environment. Only labels
+/// starting with the prefix are expanded, and anything that can't be resolved is left as it is.
+pub(crate) fn expand_labels(labels: &mut HashMap<String, String>, prefix: &str, env: &[String]) {
+    let env = env.iter()
+        .filter_map(|var| var.split_once('='))
+        .collect::<HashMap<_, _>>();
+    let swarm = SWARM_PLACEHOLDERS.iter()
+        .filter_map(|(placeholder, label)| Some((*placeholder, labels.get(*label)?.clone())))
+        .collect::<Vec<_>>();
+
+    for (key, value) in labels.iter_mut() {
+        if !key.starts_with(prefix) {
+            continue;
+        }
+        if let Cow::Owned(expanded) = expand(value, &swarm, &env) {
+            debug!(label=key, value, expanded, "expanded label");
+            *value = expanded;
+        }
+    }
+}
+
+fn expand<'a>(value: &'a str, swarm: &[(&str, String)], env: &HashMap<&str, &str>) -> Cow<'a, str> {
+    if !value.contains("{{") && !value.contains("${") {
+        return Cow::Borrowed(value);
+    }
+
+    let mut expanded = value.to_string();
+    for (placeholder, replacement) in swarm {
+        expanded = expanded.replace(placeholder, replacement);
+    }
+
+    let mut result = String::with_capacity(expanded.len());
+    let mut rest = expanded.as_str();
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let reference = &rest[start..];
+        match reference[2..].find('}').map(|end| &reference[2..end + 2]) {
+            Some(name) if env.contains_key(name) => {
+                result.push_str(env[name]);
+                rest = &reference[name.len() + 3..];
+            },
+            // unknown variables are kept, so a typo shows up in the generated snippets
+            _ => {
+                result.push_str("${");
+                rest = &reference[2..];
+            },
+        }
+    }
+    result.push_str(rest);
+
+    match result == value {
+        true => Cow::Borrowed(value),
+        false => Cow::Owned(result),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::expand_labels;
+
+    #[test]
+    fn only_our_labels_are_expanded() {
+        let mut labels = HashMap::from([
+            ("com.docker.swarm.service.name".to_string(), "stack_web".to_string()),
+            ("test.app".to_string(), "{{.Service.Name}}".to_string()),
+            ("test.port".to_string(), "${PORT}".to_string()),
+            ("test.socket".to_string(), "/run/${MISSING}/web.sock".to_string()),
+            ("other.app".to_string(), "${PORT}".to_string()),
+        ]);
+        expand_labels(&mut labels, "test.", &["PORT=8080".to_string()]);
+
+        assert_eq!(labels["test.app"], "stack_web");
+        assert_eq!(labels["test.port"], "8080");
+        // unknown variables are kept, so a typo shows up in the snippets
+        assert_eq!(labels["test.socket"], "/run/${MISSING}/web.sock");
+        assert_eq!(labels["other.app"], "${PORT}");
+    }
+}
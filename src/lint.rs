@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, HashMap};
+use docker_api::opts::ContainerListOpts;
+use docker_api::Docker;
+use super::{claimed_by_other_instance, config, project_selected, schema, AppData, CaddyAuthType, ContainerSummaryInternal, InspectCache, Result};
+
+/// Something wrong with a container's labels
+struct Problem {
+    container: String,
+    app: Option<String>,
+    message: String,
+}
+
+/// Check the labels of every running container, printing each problem found. Fails if there were
+/// any, so it can gate CI.
+pub(crate) async fn lint(docker: &Docker) -> Result<()> {
+    let containers = docker.containers().list(&ContainerListOpts::builder().build()).await?;
+    let mut inspect_cache = InspectCache::default();
+    let mut summaries = Vec::new();
+    for container in containers {
+        let container = inspect_cache.inspect_listed(docker, &container).await?;
+        summaries.push(ContainerSummaryInternal::new_from_inspect(&container)?);
+    }
+
+    let (problems, app_count) = find_problems(&summaries);
+    for problem in &problems {
+        match problem.app {
+            Some(ref app) => println!("{} (app {app}): {}", problem.container, problem.message),
+            None => println!("{}: {}", problem.container, problem.message),
+        }
+    }
+
+    match problems.len() {
+        0 => {
+            println!("no label problems in {app_count} app(s)");
+            Ok(())
+        },
+        count => Err(format!("found {count} label problem(s)").into()),
+    }
+}
+
+/// The problems with the containers' labels, in container order, and how many apps they define
+fn find_problems(containers: &[ContainerSummaryInternal]) -> (Vec<Problem>, usize) {
+    let mut problems = Vec::new();
+    // each app's config, with the container it was first found on
+    let mut apps: BTreeMap<String, (String, AppData)> = BTreeMap::new();
+    let known_labels = schema::label_schema()["properties"].as_object()
+        .map(|properties| properties.keys().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    let prefix = format!("{}.", config().label_prefix);
+
+    for summary in containers {
+        if summary.labels.as_ref().is_some_and(claimed_by_other_instance) || !project_selected(summary.labels.as_ref()) {
+            continue;
+        }
+        let container_name = summary.container_name.clone();
+        let mut problem = |app: Option<&str>, message: String| problems.push(Problem {
+            container: container_name.clone(),
+            app: app.map(str::to_string),
+            message,
+        });
+
+        let views = summary.app_views();
+        let labels = summary.labels.clone().unwrap_or_default();
+        let ours = labels.keys().filter(|label| label.starts_with(&prefix)).collect::<Vec<_>>();
+        if views.is_empty() {
+            if !ours.is_empty() {
+                problem(None, format!("has {prefix}* labels but no {} label, so is ignored", config().app_name_label));
+            }
+            continue;
+        }
+
+        for view in views {
+            let view_labels = view.labels.as_ref().map(HashMap::keys).into_iter().flatten();
+            for label in view_labels.filter(|label| label.starts_with(&prefix)) {
+                let numbered_extra = label.strip_prefix(&format!("{}.", config().extra_label))
+                    .is_some_and(|n| n.bytes().all(|b| b.is_ascii_digit()));
+                if !known_labels.contains(label) && !numbered_extra {
+                    problem(None, format!("unknown label {label}"));
+                }
+            }
+
+            let ad = match AppData::new_from_container(&view) {
+                Ok(Some(ad)) => ad,
+                Ok(None) => continue,
+                Err(e) => {
+                    problem(None, e.to_string());
+                    continue;
+                },
+            };
+            if let CaddyAuthType::Unknown(ref auth) = ad.auth_type {
+                problem(Some(&ad.app_name), format!("unknown auth value {auth} (expected oidc, headers or none)"));
+            }
+            match apps.get(&ad.app_name) {
+                Some((other, existing)) if !existing.same_config(&ad) => {
+                    problem(Some(&ad.app_name), format!("app is also defined by container {other} with different settings"));
+                },
+                Some(_) => {},
+                None => {
+                    apps.insert(ad.app_name.clone(), (container_name.clone(), ad));
+                },
+            }
+        }
+    }
+
+    for (container, ad) in apps.values() {
+        if let Some(ref dependency) = ad.depends_on {
+            if !apps.contains_key(dependency) {
+                problems.push(Problem {
+                    container: container.clone(),
+                    app: Some(ad.app_name.clone()),
+                    message: format!("depends_on names app {dependency}, which no running container defines"),
+                });
+            }
+        }
+    }
+
+    problems.sort_by(|a, b| (&a.container, &a.app).cmp(&(&b.container, &b.app)));
+    (problems, apps.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find_problems;
+    use crate::tests::container;
+
+    #[test]
+    fn lint_finds_label_problems_across_containers() {
+        let (problems, app_count) = find_problems(&[
+            container("web-1", &[("app", "web"), ("port", "80"), ("prot", "8080")]),
+            container("web-2", &[("app", "web"), ("port", "8080")]),
+            container("api-1", &[("app", "api"), ("port", "80"), ("auth", "oicd"), ("depends_on", "db")]),
+            container("orphan-1", &[("port", "80")]),
+            container("fine-1", &[("app", "fine"), ("port", "80"), ("caddy.extra.0", "encode gzip")]),
+        ]);
+        let problems = problems.iter()
+            .map(|problem| format!("{} {:?} {}", problem.container, problem.app, problem.message))
+            .collect::<Vec<_>>();
+
+        assert_eq!(app_count, 3);
+        assert_eq!(problems, [
+            "api-1 Some(\"api\") unknown auth value oicd (expected oidc, headers or none)",
+            "api-1 Some(\"api\") depends_on names app db, which no running container defines",
+            "orphan-1 None has test.* labels but no test.app label, so is ignored",
+            "web-1 None unknown label test.prot",
+            "web-2 Some(\"web\") app is also defined by container web-1 with different settings",
+        ]);
+    }
+}
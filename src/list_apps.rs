@@ -0,0 +1,153 @@
+use serde::Serialize;
+use super::{recurring, AppData, AppUpstream, ApplicationData, CaddyAuthType, Result};
+
+/// A host an app is served on, and where it's proxied to
+#[derive(Serialize)]
+struct Listing {
+    app: String,
+    host: String,
+    upstreams: Vec<String>,
+    /// None if the app listens on a socket
+    port: Option<u16>,
+    external: bool,
+    auth: String,
+    /// Why the app is left out of the snippets, if it is
+    waiting: Option<String>,
+    /// The recurring conditions found for the app or its containers, e.g., no_upstreams
+    conditions: Vec<String>,
+    /// The containers' images, without duplicates
+    images: Vec<String>,
+    /// When the most recently started container started
+    started_at: Option<String>,
+}
+
+impl Listing {
+    fn new(ad: &AppData, app_data: &ApplicationData, active: &[recurring::Active]) -> Self {
+        Listing {
+            app: ad.app_name.clone(),
+            host: format!("{}.{}", ad.app_name, ad.domain()),
+            upstreams: ad.upstream_targets(),
+            port: match ad.upstream {
+                AppUpstream::Port(port) => Some(port),
+                AppUpstream::Socket(_) => None,
+            },
+            external: ad.external,
+            auth: match ad.auth_type {
+                CaddyAuthType::Oidc => "oidc".to_string(),
+                CaddyAuthType::TrustedHeaders => "headers".to_string(),
+                CaddyAuthType::Unknown(ref auth) => format!("unknown ({auth})"),
+                CaddyAuthType::None => "none".to_string(),
+            },
+            waiting: ad.waiting_for(app_data).map(|wait| wait.to_string()),
+            conditions: active.iter()
+                .filter(|a| a.subject == ad.app_name || ad.containers.iter().any(|adc| a.subject == adc.container_name))
+                .map(|a| a.to_string())
+                .collect(),
+            images: ad.containers.iter()
+                .filter_map(|adc| adc.image.clone())
+                .fold(Vec::new(), |mut images, image| {
+                    if !images.contains(&image) {
+                        images.push(image);
+                    }
+                    images
+                }),
+            started_at: ad.containers.iter()
+                .filter_map(|adc| adc.started_at.clone())
+                .max_by_key(|started_at| chrono::DateTime::parse_from_rfc3339(started_at).ok()),
+        }
+    }
+}
+
+/// Print every host the apps are served on (including named & split ports) as a table, or as JSON
+pub(crate) fn print(app_data: &ApplicationData, json: bool) -> Result<()> {
+    // rendering finds the conditions that leave apps out of the snippets
+    super::render_snippets(app_data)?;
+    let active = recurring::active();
+    let mut listings = app_data.values()
+        .flat_map(AppData::with_port_variants)
+        .map(|ad| Listing::new(&ad, app_data, &active))
+        .collect::<Vec<_>>();
+    listings.sort_by(|a, b| a.host.cmp(&b.host));
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&listings)?);
+        return Ok(());
+    }
+
+    let rows = listings.iter()
+        .map(|listing| [
+            listing.app.clone(),
+            listing.host.clone(),
+            listing.port.map(|port| port.to_string()).unwrap_or_else(|| "socket".to_string()),
+            listing.external.to_string(),
+            listing.auth.clone(),
+            match (&listing.waiting, listing.conditions.is_empty()) {
+                (Some(waiting), _) => waiting.clone(),
+                (None, true) => "serving".to_string(),
+                (None, false) => listing.conditions.join(","),
+            },
+            match listing.images.is_empty() {
+                true => "unknown".to_string(),
+                false => listing.images.join(","),
+            },
+            listing.started_at.clone().unwrap_or_else(|| "unknown".to_string()),
+            match listing.upstreams.is_empty() {
+                true => "(none)".to_string(),
+                false => listing.upstreams.join(" "),
+            },
+        ])
+        .collect::<Vec<_>>();
+    let header = ["APP", "HOST", "PORT", "EXTERNAL", "AUTH", "STATUS", "IMAGE", "STARTED", "UPSTREAMS"].map(str::to_string);
+    let widths = (0..header.len() - 1)
+        .map(|column| rows.iter().chain([&header]).map(|row| row[column].len()).max().unwrap_or_default())
+        .collect::<Vec<_>>();
+    for row in [&header].into_iter().chain(&rows) {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(&widths) {
+            line.push_str(&format!("{cell:width$}  "));
+        }
+        line.push_str(&row[row.len() - 1]);
+        println!("{line}");
+    }
+    println!("\n{} host(s) for {} app(s)", listings.len(), app_data.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Listing;
+    use crate::recurring;
+    use crate::tests::{app, app_data};
+
+    #[test]
+    fn listing_shows_distinct_images_and_the_latest_start() {
+        let mut web = app("web", &[("port", "80")]);
+        let mut second = web.containers[0].clone();
+        second.container_name = "web-2".to_string();
+        web.containers.push(second);
+        web.containers[0].image = Some("web:1".to_string());
+        web.containers[0].started_at = Some("2026-10-16T09:00:00.5Z".to_string());
+        web.containers[1].image = Some("web:1".to_string());
+        web.containers[1].started_at = Some("2026-10-16T10:00:00Z".to_string());
+        let app_data = app_data(vec![web]);
+
+        let listing = Listing::new(&app_data["web"], &app_data, &[]);
+        assert_eq!(listing.images, vec!["web:1"]);
+        assert_eq!(listing.started_at.as_deref(), Some("2026-10-16T10:00:00Z"));
+    }
+
+    #[test]
+    fn listing_shows_the_conditions_of_the_app_and_its_containers() {
+        let mut web = app("listed-web", &[("port", "80")]);
+        web.containers[0].container_name = "listed-web-1".to_string();
+        let app_data = app_data(vec![web, app("listed-other", &[("port", "80")])]);
+        recurring::check("no_upstreams", "listed-web");
+        recurring::check("no_upstream_ip", "listed-web-1");
+        recurring::check("no_upstreams", "listed-other");
+
+        let active = recurring::active();
+        let listing = Listing::new(&app_data["listed-web"], &app_data, &active);
+        assert_eq!(listing.conditions, vec!["no_upstreams for 0ns", "no_upstream_ip for 0ns"]);
+    }
+}
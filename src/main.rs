@@ -1,25 +1,70 @@
+mod agent;
+mod caddy_admin;
+mod caddyfile;
+mod emit;
+mod failure;
+mod generations;
+mod history;
+mod inspect_cache;
+mod label_vars;
+mod lint;
+mod list_apps;
+mod managed_region;
+mod metrics;
+mod plan;
+mod recurring;
+mod remote_docker;
 mod powerdns;
+mod report;
+mod schema;
+mod secrets;
+mod service;
+mod snippet_lock;
+mod ssh_caddy;
+mod state;
+mod systemd;
+mod template;
+mod update_check;
+mod verify;
 
 use docker_api::models::{ContainerInspect200Response, EventMessage};
 use docker_api::opts::{ContainerListOpts, ContainerFilter, ExecCreateOpts, ExecStartOpts};
-use docker_api::{conn::TtyChunk, Docker, opts::EventsOpts};
+use docker_api::{conn::TtyChunk, Docker, Exec, opts::EventsOpts};
 use tokio_stream::StreamExt;
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
 use std::io::Write;
-use std::net::IpAddr;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::str;
-use std::sync::OnceLock;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use chrono::Datelike;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::Instant;
 use indoc::indoc;
-use tracing_subscriber;
-use tracing::{info, warn, debug, error};
-use clap::{Parser, ValueEnum};
+use tracing::{info, warn, debug, error, info_span, Instrument};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::util::SubscriberInitExt;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use local_ip_address::{local_ip, local_ipv6};
 use local_ip_address::Error::LocalIpAddressNotFound;
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
 
+const EXIT_CODES_HELP: &str = indoc! {"
+    Exit codes:
+      1  Any other error
+      2  Invalid arguments or configuration
+      3  Unable to talk to Docker
+      4  Unable to reload Caddy
+      5  Unable to update DNS
+"};
+
 /// Watch docker for Container events, write those out to a set of Caddy snippets, then
 /// trigger a reload of both Caddy instances.
 ///
@@ -34,16 +79,26 @@ use serde::{Deserialize, Serialize};
 /// On Linux, there are other workarounds, such as modifying the network rules, or running Docker
 /// in "Host" networking mode, etc.
 #[derive(Debug, Parser)]
-#[command(name="docker-caddyfile-updater", bin_name="docker-caddyfile-updater")]
+#[command(name="docker-caddyfile-updater", bin_name="docker-caddyfile-updater", after_long_help=EXIT_CODES_HELP)]
 struct Cli {
     /// Path to the "local" Caddy binary, which handles SSL termination and proxies to the Docker
-    /// Caddy instance.
-    #[arg(long, visible_alias="lcbp", env, default_value = "/usr/local/bin/caddy")]
-    local_caddy_bin_path: PathBuf,
+    /// Caddy instance. If not set, /usr/local/bin/caddy is used, or Homebrew's
+    /// /opt/homebrew/bin/caddy on Apple Silicon if only that exists.
+    #[arg(long, visible_alias="lcbp", env)]
+    local_caddy_bin_path: Option<PathBuf>,
     /// Path to the "local" Caddy configuration directory, used to set the working directory when
-    /// reloading Caddy
-    #[arg(long, visible_alias="lccd", env, default_value = "/usr/local/etc")]
-    local_caddy_config_dir: PathBuf,
+    /// reloading Caddy. If not set, /usr/local/etc is used, or /opt/homebrew/etc alongside a
+    /// Homebrew Caddy binary
+    #[arg(long, visible_alias="lccd", env)]
+    local_caddy_config_dir: Option<PathBuf>,
+    /// Working directory for reloading the "local" Caddy, if it shouldn't be
+    /// --local-caddy-config-dir. The Caddyfile is still read from the config dir
+    #[arg(long, visible_alias="lcrd", env)]
+    local_caddy_reload_dir: Option<PathBuf>,
+    /// Comma-separated KEY=VALUE environment variables for reloading the "local" Caddy, on top of
+    /// our own environment - e.g., HOME=/var/lib/caddy so a service account uses Caddy's data dir
+    #[arg(long, visible_alias="lcre", env, value_delimiter=',', value_parser=parse_env_var)]
+    local_caddy_reload_env: Vec<(String, String)>,
     /// Directory to write the "local" snippets out to (Caddy will then import these)
     #[arg(long, visible_alias="lcsd", env)]
     local_caddy_snippets_dir: PathBuf,
@@ -62,21 +117,145 @@ struct Cli {
     /// directory when reloading Caddy
     #[arg(long, visible_alias="dccd", env, default_value = "/etc/caddy")]
     docker_caddy_config_dir: PathBuf,
+    /// Comma-separated ENV_NAME=/path/inside/container secrets for reloading Caddy in a container:
+    /// each file is read and exported as the variable, for DNS plugins configured from the
+    /// environment. Paths may refer to the container's environment (e.g., $DO_API_KEY_FILE). An
+    /// empty path (e.g., "DO_API_KEY=") exports nothing, to turn the default off
+    #[arg(long, visible_alias="dcse", env, value_delimiter=',', value_parser=parse_secret_env, default_value="DO_API_KEY=$DO_API_KEY_FILE")]
+    docker_caddy_secret_env: Vec<(String, String)>,
     /// Directory to write the snippets for the second Caddy instance. This should be a directory
     /// that is on the host machine and is mounted into Docker.
     #[arg(long, visible_alias="dcsd", env)]
     docker_caddy_snippets_dir: PathBuf,
+    /// Create the snippets dirs (with --dir-mode permissions) if they don't exist, rather than
+    /// refusing to start
+    #[arg(long, visible_alias="cd", env)]
+    create_dirs: bool,
+    /// Permissions (in octal) for snippets dirs created by --create-dirs. Ignored on Windows
+    #[arg(long, visible_alias="dm", env, default_value="755", value_parser=parse_dir_mode)]
+    dir_mode: u32,
+    /// URL of the "local" Caddy's admin API (e.g., http://localhost:2019, or
+    /// unix:///run/caddy/admin.sock for a unix socket). If set, Caddy is reloaded by loading the
+    /// Caddyfile in --local-caddy-config-dir through the API, rather than by running the Caddy
+    /// binary. Imports in the Caddyfile are resolved by the running Caddy, so should be absolute
+    /// paths
+    #[arg(long, visible_alias="lcau", env)]
+    local_caddy_admin_url: Option<Url>,
+    /// Comma-separated admin API URLs of further "local" Caddy servers sharing the local snippets
+    /// dir and storage (e.g., the other half of an HA pair). After the local Caddy, each is sent
+    /// the Caddyfile in --local-caddy-config-dir, which should import the snippets by absolute
+    /// path. Reloading only succeeds if every member reloads
+    #[arg(long, visible_alias="lcpau", env, value_delimiter=',')]
+    local_caddy_peer_admin_urls: Vec<Url>,
+    /// Comma-separated ssh destinations (e.g., caddy@edge.example.com, or a ~/.ssh/config alias)
+    /// of Caddy servers on other hosts to keep in sync with the local snippets. After the local
+    /// Caddy, the snippet file is uploaded to each over SFTP and `caddy reload` run there. Uses
+    /// the ssh & sftp commands, with key authentication only. A server that fails is logged, and
+    /// doesn't roll back the local Caddy
+    #[arg(long, visible_alias="scd", env, value_delimiter=',')]
+    ssh_caddy_destinations: Vec<String>,
+    /// How to copy the snippet file to the --ssh-caddy-destinations servers. Either way, its
+    /// SHA-256 checksum is checked (with sha256sum over ssh) before Caddy is reloaded
+    #[arg(value_enum, long, visible_alias="sct", env, default_value_t=SnippetTransfer::Sftp)]
+    ssh_caddy_transfer: SnippetTransfer,
+    /// Comma-separated base URLs (e.g., http://edge.example.com:9181/) of agents (see the agent
+    /// command) on other hosts to send the local snippets to after the local Caddy is reloaded,
+    /// each of which reloads its own Caddy. An agent that fails is logged, and doesn't roll back
+    /// the local Caddy
+    #[arg(long, visible_alias="agu", env, value_delimiter=',')]
+    agent_urls: Vec<Url>,
+    /// Shared secret the watcher and its agents authenticate with. It's sent as a bearer token,
+    /// so use https agent URLs (e.g., through a TLS-terminating proxy) or a private network
+    #[arg(long, visible_alias="agt", env, hide_env_values=true)]
+    agent_token: Option<String>,
+    /// Private key for --ssh-caddy-destinations, if not the ssh defaults
+    #[arg(long, visible_alias="scif", env)]
+    ssh_caddy_identity_file: Option<PathBuf>,
+    /// Directory on the --ssh-caddy-destinations servers to upload the local snippets to
+    #[arg(long, visible_alias="scsd", env)]
+    ssh_caddy_snippets_dir: Option<String>,
+    /// Directory on the --ssh-caddy-destinations servers holding the Caddyfile, which `caddy
+    /// reload` is run from
+    #[arg(long, visible_alias="sccd", env, default_value="/etc/caddy")]
+    ssh_caddy_config_dir: String,
+    /// Path to the Caddy binary on the --ssh-caddy-destinations servers
+    #[arg(long, visible_alias="scbp", env, default_value="caddy")]
+    ssh_caddy_bin_path: String,
+    /// URL of the Docker Caddy's admin API (e.g., http://localhost:2020, or unix:///path for a
+    /// socket mounted out of the container), which must be published by the container. If set, Caddy is reloaded through the API rather than by exec'ing into
+    /// the container, and --docker-caddy-caddyfile must also be set
+    #[arg(long, visible_alias="dcau", env)]
+    docker_caddy_admin_url: Option<Url>,
+    /// Path on this machine to the Caddyfile of the Docker Caddy, to load through
+    /// --docker-caddy-admin-url
+    #[arg(long, visible_alias="dccf", env)]
+    docker_caddy_caddyfile: Option<PathBuf>,
     /// The prefix for the labels used to determine what should and should not be exposed via
     /// Caddy. e.g., "my.name"
     /// Available labels are:
     /// * app - the name of the application, prepended to the domain or local domain
-    /// * port - the port the app runs on (mandatory, no default)
+    /// * port - the port the app runs on (mandatory unless socket is set, no default). Further
+    ///   comma-separated ports (e.g., 8080,api:9090) are each served on a host of their own from
+    ///   --named-port-host-template, named by the port number if no name is given
+    /// * socket - path (inside the docker Caddy container) to a unix socket the app listens on,
+    ///   used instead of the port
     /// * external - if the app will be exposed via the domain_name (true), or the local domain
-    /// (otherwise)
+    ///   (otherwise)
     /// * auth (oidc, headers, none) - if headers, include the "auth-headers" snippet, otherwise do
-    /// nothing.
+    ///   nothing.
+    /// * blocked_path_action (abort, forbidden, not-found) - overrides --blocked-path-action for
+    ///   the app
+    /// * internal_paths - comma-separated paths (e.g., /admin/*,/debug/*) of an external app that
+    ///   are only reachable from --internal-cidrs, and blocked for everyone else
+    /// * allow_countries / deny_countries - space or comma-separated ISO country codes, checked
+    ///   using the caddy-maxmind-geolocation module. Clients in --internal-cidrs are never blocked
+    /// * client_auth (require, verify-if-given) - require TLS client certificates, verified
+    ///   against the CA file in client_auth_ca (a path as seen by the local Caddy). These apps get
+    ///   their own site block in the "docker_sites" snippet, which must be imported at the top
+    ///   level of the local Caddyfile
+    /// * insecure_http - if true, an internal app is also served over plain HTTP (no TLS), via a
+    ///   site block in the "docker_sites" snippet. Ignored for external apps
+    /// * depends_on - name of another app that must have a running, healthy container before this
+    ///   app is included in the snippets (e.g., don't expose a UI before its API)
+    /// * dns_provider (none, power-dns) - overrides --local-dns-provider or
+    ///   --external-dns-provider for the app
+    /// * lb_retries / lb_try_duration / lb_try_interval - Caddy's reverse_proxy retry options
+    ///   (e.g., 3 / 5s / 250ms), so requests are retried on another container during rolling
+    ///   restarts
+    /// * fail_duration / max_fails / unhealthy_status - Caddy's passive health check options
+    ///   (e.g., 30s / 3 / 5xx,429), so a failing container is left out for the fail duration.
+    ///   Apps with several containers default to 30s / 3. A fail_duration of 0 turns them off
+    /// * port.external / port.internal - ports to serve on the external and internal domains,
+    ///   each in a block of its own. They replace the app's own block on their domain
+    /// * <index>.<label> - e.g., 0.app / 0.port and 1.app / 1.port, to define several apps on one
+    ///   container. Each app uses its indexed labels, falling back to the container's unindexed
+    ///   ones
+    /// * caddy.extra (and caddy.extra.0, caddy.extra.1, ...) - Caddy directives inserted verbatim
+    ///   into the app's handle block in the Docker Caddy, before reverse_proxy
+    /// * priority - an integer (default 0). Apps with a higher priority are written first within
+    ///   a snippet, so their handles win when matchers overlap
+    /// * network - the Docker network to reach the app's containers on, overriding --proxy-network
     #[arg(long, visible_alias="lp", env)]
     label_prefix: String,
+    /// File of NAME=value lines setting options by their environment variable names (e.g.,
+    /// DOMAIN_NAME=example.com), below the command line and the environment in precedence. It's
+    /// re-read on SIGHUP, so e.g. the domain names, snippets dirs and label prefix can be changed
+    /// without a restart. Lines naming no option are ignored
+    #[arg(long, visible_alias="ef", env)]
+    env_file: Option<PathBuf>,
+    /// Expand Swarm template placeholders ({{.Service.Name}}, {{.Service.ID}}, {{.Task.Name}},
+    /// {{.Task.ID}} and {{.Node.ID}}) and ${VAR} references to the container's environment in the
+    /// values of the --label-prefix labels, for labels templated by an orchestrator
+    #[arg(long, visible_alias="elv", env)]
+    expand_label_variables: bool,
+    /// Directory that secret://<path> references in label values are read from (e.g.,
+    /// secret://htpasswd/app1 reads htpasswd/app1 in it), so credentials given to labels don't
+    /// appear in docker inspect. Defaults to where Docker mounts secrets. References are kept as
+    /// they are in the app data, and only replaced when the snippets are rendered, so the
+    /// secrets aren't saved to --state-file, logged or dumped. An app with a secret that can't be
+    /// read is left out
+    #[arg(long, visible_alias="secd", env, default_value="/run/secrets")]
+    secrets_dir: PathBuf,
     /// Prefix for the local domain, used by the generated Caddy snippets for anything where
     /// "external" is false or absent.
     #[arg(long, visible_alias="ldp", env)]
@@ -84,130 +263,1232 @@ struct Cli {
     /// The general domain name, e.g., example.com
     #[arg(long, visible_alias="dn", env)]
     domain_name: String,
-    /// Path to the docker.sock file, used to communicate with the Docker API
-    #[arg(long, visible_alias="dsp", env, default_value="/var/run/docker.sock")]
-    docker_socket_path: PathBuf,
+    /// Domain the "local" Caddy serves external apps on, instead of --domain-name. This is the
+    /// domain clients use, so is also the one DNS records are created in
+    #[arg(long, visible_alias="lced", env)]
+    local_caddy_external_domain: Option<String>,
+    /// Domain the "local" Caddy serves internal apps on, instead of
+    /// <--local-domain-prefix>.<--domain-name>
+    #[arg(long, visible_alias="lcid", env)]
+    local_caddy_internal_domain: Option<String>,
+    /// Domain the Docker Caddy serves external apps on, instead of --domain-name. If it differs
+    /// from the "local" Caddy's, the "local" Caddy rewrites the Host header when proxying
+    #[arg(long, visible_alias="dced", env)]
+    docker_caddy_external_domain: Option<String>,
+    /// Domain the Docker Caddy serves internal apps on, instead of
+    /// <--local-domain-prefix>.<--domain-name>
+    #[arg(long, visible_alias="dcid", env)]
+    docker_caddy_internal_domain: Option<String>,
+    /// Container runtime serving the Docker API. Podman's socket is found in its own default
+    /// locations, and its events are translated to Docker's
+    #[arg(value_enum, long, visible_alias="crt", env, default_value_t=ContainerRuntime::Docker)]
+    container_runtime: ContainerRuntime,
+    /// Path to the docker.sock file, used to communicate with the Docker API. If not set,
+    /// /var/run/docker.sock is used, or the rootless Docker socket ($XDG_RUNTIME_DIR/docker.sock)
+    /// if the system socket doesn't exist. With --container-runtime=podman, the rootless Podman
+    /// socket ($XDG_RUNTIME_DIR/podman/podman.sock) is used, or /run/podman/podman.sock
+    #[arg(long, visible_alias="dsp", env)]
+    docker_socket_path: Option<PathBuf>,
+    /// Docker daemon to connect to instead of the local socket - tcp://host:2375, or
+    /// https://host:2376 (or tcp:// with the --docker-tls-* options) for a daemon started with
+    /// --tlsverify. unix:///path/to/docker.sock is the same as --docker-socket-path
+    #[arg(long, visible_alias="dh", env)]
+    docker_host: Option<String>,
+    /// CA certificate to verify the Docker daemon with. If not set, the system CAs are used
+    #[arg(long, visible_alias="dtca", env)]
+    docker_tls_ca: Option<PathBuf>,
+    /// Client certificate to connect to the Docker daemon with, for --docker-host
+    #[arg(long, visible_alias="dtc", env)]
+    docker_tls_cert: Option<PathBuf>,
+    /// Key for --docker-tls-cert
+    #[arg(long, visible_alias="dtk", env)]
+    docker_tls_key: Option<PathBuf>,
+    /// How the docker Caddy instance addresses upstream containers
+    #[arg(value_enum, long, visible_alias="uam", env, default_value_t=UpstreamAddressMode::Name)]
+    upstream_address_mode: UpstreamAddressMode,
+    /// Which IP family to use for upstream addresses when --upstream-address-mode is "ip". IPv6
+    /// addresses are written in their bracketed form, e.g., http://[fd00::2]:8080
+    #[arg(value_enum, long, visible_alias="uipf", env, default_value_t=IpFamilyPreference::PreferV4)]
+    upstream_ip_family: IpFamilyPreference,
+    /// Docker network (shared with the docker Caddy instance) that upstream containers are reached
+    /// on, for containers attached to several networks. Containers not attached to it are left out
+    /// of the upstreams. Can be overridden per app with the network label. If not set, the
+    /// container's first network (by name) is used
+    #[arg(long, visible_alias="pn", env)]
+    proxy_network: Option<String>,
+    /// Format of each upstream URL in the docker Caddy snippets. {host} is the address from
+    /// --upstream-address-mode, {name} the container name, {network} the container's network
+    /// (see --proxy-network), {app} the app name and {port} the app's port - e.g., "http://{name}.{network}.local:{port}"
+    #[arg(long, visible_alias="uut", env, default_value="http://{host}:{port}")]
+    upstream_url_template: String,
+    /// What Caddy should do with requests for blocked paths (e.g., /metrics). Can be overridden
+    /// per app with the blocked_path_action label
+    #[arg(value_enum, long, visible_alias="bpa", env, default_value_t=BlockedPathAction::Abort)]
+    blocked_path_action: BlockedPathAction,
+    /// Security headers (HSTS, etc.) the local Caddy adds to responses from external apps. Can be
+    /// overridden per app with the security_headers label
+    #[arg(value_enum, long, visible_alias="sh", env, default_value_t=SecurityHeaders::Off)]
+    security_headers: SecurityHeaders,
+    /// What the local Caddy does with requests for an internal app's host on the external domain
+    /// (e.g., app.example.com for app.local.example.com), so probing it from outside doesn't fall
+    /// through to the default site. The sites go in the "docker_sites" snippet
+    #[arg(value_enum, long, visible_alias="ihr", env, default_value_t=InternalHostResponse::None)]
+    internal_host_response: InternalHostResponse,
+    /// What to do with an app whose auth label isn't a known type (e.g., a typo like "oicd"):
+    /// "reject" leaves it out of the snippets and emits an app_rejected event (see --emit-events
+    /// and --notify-urls), "none" serves it without auth, and "fallback:<snippet>" imports the
+    /// named snippet as its auth
+    #[arg(long, visible_alias="uap", env, default_value="none", value_parser=parse_unknown_auth_policy)]
+    unknown_auth_policy: UnknownAuthPolicy,
+    /// Leave external apps that nothing authenticates (see the report command) out of the
+    /// snippets, rather than serving them to the internet unprotected. Each is emitted as an
+    /// app_rejected event (see --emit-events and --notify-urls) when it's first left out
+    #[arg(long, visible_alias="rafe", env)]
+    require_auth_for_external: bool,
+    /// Name of this instance, for running several against one Docker daemon & DNS zone. Apps
+    /// claimed by another instance through the instance label are ignored, and the snippets and
+    /// DNS records (as TXT records) are marked with the name. DNS names marked by another instance,
+    /// or with records that aren't marked at all, are never replaced or deleted
+    #[arg(long, visible_alias="in", env)]
+    instance_name: Option<String>,
+    /// Host (before the domain) for each of an app's named ports, from the ports label. {app} is
+    /// replaced with the app name and {name} with the port name
+    #[arg(long, visible_alias="npht", env, default_value="{app}-{name}")]
+    named_port_host_template: String,
+    /// Comma-separated names of extra snippet groups (e.g., "dmz,lan,vpn"). Apps with a zone label
+    /// naming a group go in its "(<group>_docker_hosts)" snippet, instead of the external or
+    /// internal one
+    #[arg(long, visible_alias="sgr", env, value_delimiter=',')]
+    snippet_groups: Vec<String>,
+    /// Comma-separated addresses the local Caddy binds internal apps' sites to (e.g., the LAN
+    /// interface's address), emitted as a `bind` directive. Defaults to all interfaces
+    #[arg(long, visible_alias="iba", env, value_delimiter=',')]
+    internal_bind_addresses: Vec<String>,
+    /// Comma-separated addresses the local Caddy binds external apps' sites to (e.g., "[::]" for
+    /// IPv6 only, or "0.0.0.0,[::]" for both). Defaults to all interfaces
+    #[arg(long, visible_alias="eba", env, value_delimiter=',')]
+    external_bind_addresses: Vec<String>,
+    /// Comma-separated app name globs (e.g., "media-*") - only matching apps are managed. Lets
+    /// several instances share a Docker daemon, each managing a subset of apps
+    #[arg(long, visible_alias="oa", env, value_delimiter=',')]
+    only_apps: Vec<String>,
+    /// Comma-separated app name globs of apps to leave alone, even if they match --only-apps
+    #[arg(long, visible_alias="ea", env, value_delimiter=',')]
+    exclude_apps: Vec<String>,
+    /// Comma-separated compose project names - only containers from these projects are managed
+    #[arg(long, visible_alias="pf", env, value_delimiter=',')]
+    project_filter: Vec<String>,
+    /// Comma-separated CIDRs considered "internal" when restricting an external app's
+    /// internal_paths. Also accepts Caddy's "private_ranges" shorthand
+    #[arg(long, visible_alias="ic", env, value_delimiter=',', default_value="private_ranges")]
+    internal_cidrs: Vec<String>,
+    /// Path (as seen by the local Caddy) to the MaxMind country database, used by the
+    /// allow_countries and deny_countries labels
+    #[arg(long, visible_alias="gdp", env, default_value="/usr/share/GeoIP/GeoLite2-Country.mmdb")]
+    geoip_db_path: PathBuf,
+    /// Shell command run before each Caddy reload. The DOCKER_CADDY_ACTION, DOCKER_CADDY_APP,
+    /// DOCKER_CADDY_LOCAL_SNIPPETS_DIR and DOCKER_CADDY_DOCKER_SNIPPETS_DIR environment variables
+    /// describe the change
+    #[arg(long, visible_alias="prh", env)]
+    pre_reload_hook: Option<String>,
+    /// Shell command run after each Caddy reload, with the same environment variables as
+    /// --pre-reload-hook plus DOCKER_CADDY_RELOAD_STATUS (success or failure)
+    #[arg(long, visible_alias="porh", env)]
+    post_reload_hook: Option<String>,
+    /// Commit the snippet directories to a local git repository (created if needed) after each
+    /// change, giving a history of the generated config
+    #[arg(long, visible_alias="gh", env)]
+    git_history: bool,
+    /// Number of previous generations of the snippet files to keep (in a ".generations" directory
+    /// inside each snippets dir). If a reload fails, the last generation that reloaded
+    /// successfully is restored and Caddy reloaded again, even across restarts. 0 disables this,
+    /// leaving only the snippets last reloaded by this process (kept in memory) to restore
+    #[arg(long, visible_alias="sg", env, default_value_t=0)]
+    snippet_generations: usize,
+    /// File to save the discovered apps to after each change. On startup they're used until the
+    /// running containers have been checked - and to write the snippets from if Docker can't be
+    /// reached yet, rather than failing
+    #[arg(long, visible_alias="stf", env)]
+    state_file: Option<PathBuf>,
+    /// Ignore a saved state older than this. 0 for no limit
+    #[arg(long, visible_alias="stma", env, default_value_t=86400)]
+    state_max_age_secs: u64,
+    /// Hold back a write that would remove more than this many of the apps being served (and
+    /// their DNS records) at once, e.g., "5", or a percentage like "50%", in case Docker briefly
+    /// listed no containers. Held removals are written once confirmed with SIGUSR1, or dropped if
+    /// the apps come back. On startup, the apps served before are only known from --state-file
+    #[arg(long, visible_alias="mxd", env, value_parser=parse_deletion_limit)]
+    max_deletions: Option<DeletionLimit>,
+    /// Confirm removals over --max-deletions in the first write on startup
+    #[arg(long, visible_alias="fd", env)]
+    force_deletions: bool,
+    /// After each reload, request the host of every app that was added or changed through the
+    /// local Caddy, expecting a response other than a 5xx within --verify-timeout-secs
+    #[arg(long, visible_alias="var", env)]
+    verify_after_reload: bool,
+    /// Seconds to wait for each app's response when verifying
+    #[arg(long, visible_alias="vts", env, default_value_t=10)]
+    verify_timeout_secs: u64,
+    /// Address of the local Caddy to send the verification requests to, instead of resolving
+    /// each app's host (e.g., when DNS isn't updated until after the reload). Port 443 is used
+    #[arg(long, visible_alias="vad", env)]
+    verify_address: Option<IpAddr>,
+    /// What to do when an app fails verification. Without --snippet-generations, rolling back
+    /// restores the snippets last reloaded by this process
+    #[arg(value_enum, long, visible_alias="vfa", env, default_value_t=VerifyFailureAction::Log)]
+    verify_failure_action: VerifyFailureAction,
+    /// Name of the snippet file written to each snippets dir. With --instance-name, the names
+    /// written are listed in a ".docker-caddy-rs-<instance>.written" file in each dir, and a file
+    /// written under a previous name is removed on startup and resync - as long as it still
+    /// starts with the instance's header
+    #[arg(long, visible_alias="sfn", env, default_value="docker-hosts")]
+    snippet_file_name: String,
+    /// Keep hand-written content (e.g., a few static hosts) in the snippet files, only rewriting
+    /// what's between the "# BEGIN docker-caddy-rs" and "# END docker-caddy-rs" lines. Files
+    /// without them have the snippets added at the end
+    #[arg(long, visible_alias="pum", env)]
+    preserve_unmanaged: bool,
+    /// Validate each Caddy's config with the new snippets (with caddy validate, or the admin API's
+    /// adapt) before reloading. If it's invalid, the previous snippet files are put back and the
+    /// error logged, leaving Caddy serving the previous snippets
+    #[arg(long, visible_alias="vbr", env)]
+    validate_before_reload: bool,
+    /// How to format the generated snippets before writing them
+    #[arg(value_enum, long, visible_alias="sf", env, default_value_t=SnippetFormat::None)]
+    snippet_format: SnippetFormat,
+    /// Template, in minijinja (Jinja2) syntax, for each app's block in the "local" snippets,
+    /// instead of the built-in one printed by `template-context defaults`. Templates can use the
+    /// app data and generated pieces of Caddyfile shown by `template-context dump`, minijinja's
+    /// built-in filters (e.g., default) and the slugify, to_upper and b64 filters and
+    /// cidr_contains(cidr, ip) function
+    #[arg(long, visible_alias="lt", env)]
+    local_template: Option<PathBuf>,
+    /// Template for each app's block in the Docker snippets, like --local-template
+    #[arg(long, visible_alias="dt", env)]
+    docker_template: Option<PathBuf>,
+    /// Add a comment for each upstream container to the docker Caddy snippets, with the
+    /// container's image, start time and whether it is unhealthy
+    #[arg(long, visible_alias="sc", env)]
+    snippet_comments: bool,
+    /// Generate the snippets as the given version did, so upgrading can't change the generated
+    /// config until you opt in
+    #[arg(value_enum, long, visible_alias="cl", env, default_value_t=CompatLevel::Latest)]
+    compat_level: CompatLevel,
+    /// Address (e.g., 0.0.0.0:9180) to serve Prometheus metrics on, at /metrics - histograms of
+    /// how long inspecting containers, rendering & writing the snippets, reloading Caddy and
+    /// updating DNS take. Only served by the run command
+    #[arg(long, visible_alias="ma", env)]
+    metrics_address: Option<SocketAddr>,
+    /// Check GitHub on startup for a newer release, and log if there is one
+    #[arg(long, visible_alias="cfu", env)]
+    check_for_updates: bool,
+    /// Discover the apps and render the snippets as usual, but print them to stdout (moving the
+    /// logs to stderr) instead of writing them. Nothing is written to the snippets dirs, Caddy is
+    /// never reloaded and DNS is never updated
+    #[arg(long, visible_alias="dr", env)]
+    dry_run: bool,
+    /// How long to keep an app after its last container is destroyed, waiting for a replacement
+    /// (e.g., when Watchtower redeploys it), before removing it from the snippets & DNS. A
+    /// replacement with the same labels then leaves the snippets, DNS and Caddy untouched. 0 removes
+    /// the app at once, so a redeploy removes it and adds it back with a reload each
+    #[arg(long, visible_alias="rgm", env, default_value_t=0)]
+    redeploy_grace_ms: u64,
+    /// How often to list the running containers, to catch any that were removed without a destroy
+    /// event being seen (e.g., while the event stream was reconnecting). 0 disables the sweeps
+    #[arg(long, visible_alias="ris", env, default_value_t=0)]
+    reconcile_interval_secs: u64,
+    /// How long a tracked container can be missing from the sweeps before it's expired from its
+    /// app (and the app removed, if it was the last container)
+    #[arg(long, visible_alias="mcgs", env, default_value_t=60)]
+    missing_container_grace_secs: u64,
+    /// What to leave in the snippets on SIGTERM or SIGINT, once any write in progress (and any
+    /// debounced changes) have been written and Caddy reloaded
+    #[arg(value_enum, long, visible_alias="osd", env, default_value_t=ShutdownAction::Keep)]
+    on_shutdown: ShutdownAction,
+    /// How often to log which external apps have no authentication (as `report` shows). 0 only
+    /// logs it on startup
+    #[arg(long, visible_alias="eris", env, default_value_t=0)]
+    exposure_report_interval_secs: u64,
+    /// Longest wait between attempts to reconnect to Docker after its event stream fails (e.g.,
+    /// the daemon restarted). The wait starts at a second and doubles up to this
+    #[arg(long, visible_alias="rmbs", env, default_value_t=60)]
+    reconnect_max_backoff_secs: u64,
+    /// Warnings found again on every snippet write (e.g., an app with no running containers) are
+    /// logged when first found, then at most this often, with a count of the repeats in between.
+    /// 0 logs every occurrence
+    #[arg(long, visible_alias="wris", env, default_value_t=300)]
+    warning_repeat_interval_secs: u64,
+    /// Append a JSON line to this file (or "-" for stdout, moving the logs to stderr) for every
+    /// state change - apps added, removed & rejected, their containers changing, and Caddy
+    /// reloads - for automation to follow
+    #[arg(long, visible_alias="ee", env)]
+    emit_events: Option<PathBuf>,
+    /// Comma-separated URLs (e.g., a webhook, or an ntfy topic) to POST each of the --emit-events
+    /// state changes to, whether or not --emit-events is set
+    #[arg(long, visible_alias="nu", env, value_delimiter=',')]
+    notify_urls: Vec<Url>,
+    /// Template, in minijinja (Jinja2) syntax like --local-template, for the --notify-urls request
+    /// bodies, instead of the JSON line. It's given the JSON line's fields - event, time, and the
+    /// event's own fields (e.g., app, containers or error), which can be checked with `is defined`
+    #[arg(long, visible_alias="nt", env)]
+    notify_template: Option<PathBuf>,
+    /// Content-Type of the --notify-urls request bodies
+    #[arg(long, visible_alias="nct", env, default_value="application/json")]
+    notify_content_type: String,
+    /// Format of the logs. With json, each line is an object with the event's fields (e.g.,
+    /// app_name and container_id) as top-level attributes, for shipping to Loki, ELK, etc.
+    #[arg(value_enum, long, visible_alias="lf", env, default_value_t=LogFormat::Pretty)]
+    log_format: LogFormat,
+    /// Wait this long after a container event for further events, so e.g. a compose project
+    /// starting up results in a single snippet write & Caddy reload. 0 writes for every event
+    #[arg(long, visible_alias="rdm", env, default_value_t=0)]
+    reload_debounce_ms: u64,
+    /// Take a container out of its app's upstreams once it's been OOM-killed this many times
+    /// within --oom-drain-window-secs, so Caddy stops routing to a replica stuck in an OOM loop.
+    /// 0 disables this
+    #[arg(long, visible_alias="odt", env, default_value_t=0)]
+    oom_drain_threshold: usize,
+    /// The window, in seconds, in which a container's OOM kills are counted towards
+    /// --oom-drain-threshold
+    #[arg(long, visible_alias="odw", env, default_value_t=300)]
+    oom_drain_window_secs: u64,
+    /// How long a drained container stays out of the upstreams
+    #[arg(long, visible_alias="odd", env, default_value_t=300)]
+    oom_drain_secs: u64,
+    /// Maximum number of Docker events waiting to be processed. If more arrive, they are dropped
+    /// and the app data is rebuilt from the running containers instead
+    #[arg(long, visible_alias="eqd", env, default_value_t=1024, value_parser=clap::value_parser!(u64).range(1..))]
+    event_queue_depth: u64,
     /// DNS provider to use to automatically update local DNS records
     #[arg(value_enum, long, visible_alias="ldnsp", env, default_value_t=DnsProviderCli::None)]
     local_dns_provider: DnsProviderCli,
+    /// DNS provider to use for the records of external apps. Defaults to --local-dns-provider
+    #[arg(value_enum, long, visible_alias="ednsp", env)]
+    external_dns_provider: Option<DnsProviderCli>,
     /// PowerDNS configuration options
     #[command(flatten)]
     power_dns_cli_opts: Option<PowerDnsCliOpts>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug, Copy, Clone, ValueEnum)]
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Watch Docker for container events, keeping the Caddy snippets and DNS up to date (default)
+    Run,
+    /// Build the Caddy snippets for the currently running containers once, then exit. DNS is not
+    /// updated, and Caddy is only reloaded with --reload
+    Generate {
+        /// Write the snippets for both Caddy instances to this file (or "-" for stdout), each
+        /// preceded by an instance marker, instead of to the snippet directories
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Reload Caddy after writing the snippet files, e.g., for running from cron
+        #[arg(long, conflicts_with="output")]
+        reload: bool,
+    },
+    /// Print a JSON Schema describing the supported container labels (using --label-prefix), for
+    /// validating compose files
+    Schema,
+    /// List every app served on the external domain with its authentication, flagging any
+    /// without authentication in the generated snippets
+    Report,
+    /// Run on another proxy host as an agent of a watcher (see --agent-urls): accept snippets
+    /// from it over HTTP, authenticated with --agent-token, write them to
+    /// --local-caddy-snippets-dir and reload the local Caddy
+    Agent {
+        /// Address to accept snippets on
+        #[arg(long, default_value="0.0.0.0:9181")]
+        listen: SocketAddr,
+    },
+    /// List every app with its host, upstream targets, port, external flag and auth type
+    ListApps {
+        /// Print the apps as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the labels of every running container - unparsable values, unknown labels or auth
+    /// types, apps defined differently by several containers, etc. - exiting with an error if
+    /// there are any problems
+    LintLabels,
+    /// Show what running would change for the currently running containers - the snippet diffs,
+    /// which Caddy instances would be reloaded and the DNS record changes - without changing
+    /// anything
+    Plan {
+        /// Apply the changes after showing them
+        #[arg(long)]
+        auto_approve: bool,
+    },
+    /// Inspect the data made available to snippet templates
+    TemplateContext {
+        #[command(subcommand)]
+        command: TemplateContextCommand,
+    },
+    /// Manage a service running this program with the options given before `service`: a launchd
+    /// daemon on macOS, or a WinSW service on Windows (winsw must be on the PATH)
+    Service {
+        /// Label of the service
+        #[arg(long, default_value="docker-caddyfile-updater")]
+        label: String,
+        #[command(subcommand)]
+        command: ServiceCommand,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum ServiceCommand {
+    /// Write the service definition and start the service
+    Install,
+    /// Stop the service and remove its definition
+    Uninstall,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum TemplateContextCommand {
+    /// Print the context each app's blocks are rendered from for the currently running
+    /// containers, as JSON
+    Dump,
+    /// Print the built-in snippet templates, as a starting point for --local-template and
+    /// --docker-template
+    Defaults,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum, Serialize, Deserialize)]
 enum DnsProviderCli {
     /// Do not update DNS
+    #[serde(rename="none")]
     None,
     /// Update PowerDNS using its HTTP API. Must set all --power-dns-* options
+    #[serde(rename="power-dns")]
     PowerDNS,
 }
 
-enum DnsProvider {
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
+enum ContainerRuntime {
+    Docker,
+    Podman,
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum UpstreamAddressMode {
+    /// Use the container name - requires the docker Caddy instance to share a Docker network (with
+    /// DNS resolution) with the container
+    Name,
+    /// Use the container's IP address, taken from its network settings
+    Ip,
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum IpFamilyPreference {
+    /// Only use IPv4 addresses
+    V4Only,
+    /// Only use IPv6 addresses
+    V6Only,
+    /// Use the IPv4 address if the container has one, otherwise the IPv6 address
+    PreferV4,
+    /// Use the IPv6 address if the container has one, otherwise the IPv4 address
+    PreferV6,
+}
+
+impl IpFamilyPreference {
+    /// The preferred of a container's addresses, if it has one of an allowed family
+    fn pick(self, ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) -> Option<IpAddr> {
+        match self {
+            IpFamilyPreference::V4Only => ipv4.map(IpAddr::V4),
+            IpFamilyPreference::V6Only => ipv6.map(IpAddr::V6),
+            IpFamilyPreference::PreferV4 => ipv4.map(IpAddr::V4).or(ipv6.map(IpAddr::V6)),
+            IpFamilyPreference::PreferV6 => ipv6.map(IpAddr::V6).or(ipv4.map(IpAddr::V4)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+enum BlockedPathAction {
+    /// Abort the connection without sending a response
+    Abort,
+    /// Respond with an empty 403
+    Forbidden,
+    /// Respond with a 404 and a "Not Found" body
+    NotFound,
+}
+
+impl BlockedPathAction {
+    fn directive(&self) -> &'static str {
+        match self {
+            BlockedPathAction::Abort => "abort",
+            BlockedPathAction::Forbidden => "respond 403",
+            BlockedPathAction::NotFound => "respond \"Not Found\" 404",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
+enum InternalHostResponse {
+    /// Leave the hosts to the rest of the Caddyfile
+    None,
+    /// Respond with a 404 and a "Not Found" body
+    NotFound,
+    /// Redirect to the app on the local domain
+    Redirect,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
+enum ShutdownAction {
+    /// Leave the apps in the snippets, so Caddy keeps serving them while this is stopped
+    Keep,
+    /// Write the snippets with no apps and reload Caddy, so nothing is served that isn't watched
+    Clear,
+}
+
+/// How apps with an unrecognised auth label are handled
+#[derive(Debug, Clone)]
+enum UnknownAuthPolicy {
+    Reject,
     None,
-    PowerDNS(PowerDnsCliOpts)
+    /// Import the named snippet, as with auth=headers
+    Fallback(String),
+}
+
+fn parse_unknown_auth_policy(policy: &str) -> std::result::Result<UnknownAuthPolicy, String> {
+    match policy.split_once(':') {
+        None if policy == "reject" => Ok(UnknownAuthPolicy::Reject),
+        None if policy == "none" => Ok(UnknownAuthPolicy::None),
+        Some(("fallback", snippet)) if !snippet.is_empty() => Ok(UnknownAuthPolicy::Fallback(snippet.to_string())),
+        _ => Err(format!("{policy} is not reject, none or fallback:<snippet>")),
+    }
+}
+
+/// The most apps that can be removed by one write, from --max-deletions
+#[derive(Debug, Copy, Clone)]
+enum DeletionLimit {
+    Count(usize),
+    Percent(f64),
+}
+
+impl DeletionLimit {
+    fn exceeded(&self, deleted: usize, served: usize) -> bool {
+        match *self {
+            DeletionLimit::Count(count) => deleted > count,
+            DeletionLimit::Percent(percent) => deleted as f64 > served as f64 * percent / 100.0,
+        }
+    }
+}
+
+fn parse_deletion_limit(limit: &str) -> std::result::Result<DeletionLimit, String> {
+    match limit.strip_suffix('%') {
+        Some(percent) => match percent.parse::<f64>() {
+            Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(DeletionLimit::Percent(percent)),
+            _ => Err(format!("{limit} is not a percentage from 0% to 100%")),
+        },
+        None => limit.parse().map(DeletionLimit::Count).map_err(|_| format!("{limit} is not a number or a percentage")),
+    }
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+enum SecurityHeaders {
+    /// HSTS with subdomains & preload, no referrer, and no framing
+    Strict,
+    /// HSTS, same-origin referrers only, and framing by the same origin only
+    Basic,
+    /// Leave the app's headers alone
+    Off,
+}
+
+impl SecurityHeaders {
+    fn headers(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            SecurityHeaders::Strict => &[
+                ("Strict-Transport-Security", "max-age=63072000; includeSubDomains; preload"),
+                ("X-Content-Type-Options", "nosniff"),
+                ("Referrer-Policy", "no-referrer"),
+                ("Content-Security-Policy", "frame-ancestors 'none'"),
+            ],
+            SecurityHeaders::Basic => &[
+                ("Strict-Transport-Security", "max-age=31536000"),
+                ("X-Content-Type-Options", "nosniff"),
+                ("Referrer-Policy", "strict-origin-when-cross-origin"),
+                ("Content-Security-Policy", "frame-ancestors 'self'"),
+            ],
+            SecurityHeaders::Off => &[],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum LogFormat {
+    /// Multi-line, human-readable logs
+    Pretty,
+    /// One line per event, human-readable
+    Compact,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum SnippetFormat {
+    /// Write the snippets as generated
+    None,
+    /// Re-indent the snippets with the built-in formatter, matching the output of `caddy fmt`
+    Internal,
+    /// Run the snippets through `caddy fmt`, using --local-caddy-bin-path
+    Caddy,
+}
+
+/// Versions whose snippet output can be reproduced. Ordered, so behaviour can be gated with
+/// comparisons
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, ValueEnum)]
+enum CompatLevel {
+    /// Upstreams in container order, regardless of health checks
+    #[value(name="0.1")]
+    V0_1,
+    /// This build
+    Latest,
 }
 
 struct Config {
+    label_prefix: String,
+    expand_label_variables: bool,
+    secrets_dir: PathBuf,
     app_name_label: String,
     port_label: String,
+    socket_label: String,
+    ports_label: String,
     external_label: String,
     auth_label: String,
-    external_domain: String,
-    local_domain: String,
+    blocked_path_action_label: String,
+    security_headers_label: String,
+    internal_paths_label: String,
+    public_paths_label: String,
+    allow_countries_label: String,
+    deny_countries_label: String,
+    client_auth_label: String,
+    client_auth_ca_label: String,
+    insecure_http_label: String,
+    depends_on_label: String,
+    auth_headers_label: String,
+    robots_label: String,
+    priority_label: String,
+    network_label: String,
+    lb_retries_label: String,
+    extra_label: String,
+    external_port_label: String,
+    internal_port_label: String,
+    lb_try_duration_label: String,
+    lb_try_interval_label: String,
+    fail_duration_label: String,
+    max_fails_label: String,
+    unhealthy_status_label: String,
+    security_contact_label: String,
+    dns_provider_label: String,
+    instance_label: String,
+    zone_label: String,
     local_caddy: CaddyConfig,
     docker_caddy: CaddyConfig,
+    local_reload_dir: Option<PathBuf>,
+    local_reload_env: Vec<(String, String)>,
+    /// Secrets to read into the environment when reloading Caddy in a container
+    docker_secret_env: Vec<(String, String)>,
+    /// Further members of the local Caddy's reload group
+    local_caddy_peers: Vec<Url>,
+    ssh_caddy: Option<SshCaddy>,
+    agents: AgentConfig,
+    /// Whether the "local" Caddy paths were configured, detected or defaulted
+    local_caddy_source: &'static str,
     docker_config: DockerConfig,
-    dns_provider: DnsProvider,
+    upstream: UpstreamConfig,
+    blocked_path_action: BlockedPathAction,
+    security_headers: SecurityHeaders,
+    internal_host_response: InternalHostResponse,
+    unknown_auth_policy: UnknownAuthPolicy,
+    require_auth_for_external: bool,
+    instance_name: Option<String>,
+    snippet_groups: Vec<String>,
+    internal_bind_addresses: Vec<String>,
+    external_bind_addresses: Vec<String>,
+    named_port_host_template: String,
+    app_filter: AppFilter,
+    project_filter: Vec<String>,
+    internal_cidrs: Vec<String>,
+    geoip_db_path: PathBuf,
+    reload_hooks: ReloadHooks,
+    git_history: bool,
+    snippet_generations: usize,
+    verify: Option<VerifyConfig>,
+    state_file: Option<PathBuf>,
+    state_max_age: Duration,
+    max_deletions: Option<DeletionLimit>,
+    force_deletions: bool,
+    create_dirs: bool,
+    dir_mode: u32,
+    snippet_file_name: String,
+    preserve_unmanaged: bool,
+    validate_before_reload: bool,
+    snippet_format: SnippetFormat,
+    local_template: Option<PathBuf>,
+    docker_template: Option<PathBuf>,
+    snippet_comments: bool,
+    compat_level: CompatLevel,
+    metrics_address: Option<SocketAddr>,
+    check_for_updates: bool,
+    dry_run: bool,
+    redeploy_grace: Duration,
+    reconcile_interval: Duration,
+    missing_container_grace: Duration,
+    on_shutdown: ShutdownAction,
+    exposure_report_interval: Duration,
+    reconnect_max_backoff: Duration,
+    warning_repeat_interval: Duration,
+    emit_events: Option<PathBuf>,
+    notify: NotifyConfig,
+    log_format: LogFormat,
+    reload_debounce: Duration,
+    oom_drain_threshold: usize,
+    oom_drain_window: Duration,
+    oom_drain_duration: Duration,
+    event_queue_depth: usize,
+    dns: DnsConfig,
+    command: Command,
 }
 
 struct CaddyConfig {
+    /// Domain external apps are served on
+    external_domain: String,
+    /// Domain internal apps are served on
+    local_domain: String,
     bin_path: PathBuf,
     config_dir: PathBuf,
     snippets_dir: PathBuf,
     location: CaddyLocation,
 }
 
+/// Where state changes are POSTed, and how
+struct NotifyConfig {
+    urls: Vec<Url>,
+    template: Option<PathBuf>,
+    content_type: String,
+}
+
+/// Agents on other hosts to send the snippets to, or the token to accept them with as an agent
+struct AgentConfig {
+    urls: Vec<Url>,
+    token: Option<String>,
+}
+
 enum CaddyLocation {
     Local,
     Docker(String),
+    /// Reachable through its admin API, which loads the given Caddyfile
+    AdminApi { url: Url, caddyfile: PathBuf },
 }
 
 struct DockerConfig {
+    runtime: ContainerRuntime,
     docker_socket_path: PathBuf,
+    /// How the socket path was chosen, for logging on startup
+    docker_socket_source: &'static str,
+    /// Used instead of the socket, if --docker-host is a TCP address
+    remote: Option<RemoteDocker>,
+}
+
+struct DnsConfig {
+    local_provider: DnsProviderCli,
+    external_provider: DnsProviderCli,
+    power_dns: Option<PowerDnsCliOpts>,
+}
+
+struct ReloadHooks {
+    pre_reload: Option<String>,
+    post_reload: Option<String>,
+}
+
+const SYSTEM_DOCKER_SOCKET: &str = "/var/run/docker.sock";
+const SYSTEM_PODMAN_SOCKET: &str = "/run/podman/podman.sock";
+
+/// Where the "local" Caddy is looked for when its paths aren't set, in order - Homebrew installs
+/// to /usr/local on Intel Macs and /opt/homebrew on Apple Silicon
+const LOCAL_CADDY_PREFIXES: [&str; 2] = ["/usr/local", "/opt/homebrew"];
+
+fn parse_env_var(var: &str) -> std::result::Result<(String, String), String> {
+    match var.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("{var} is not KEY=VALUE")),
+    }
+}
+
+fn parse_secret_env(var: &str) -> std::result::Result<(String, String), String> {
+    let (name, path) = parse_env_var(var)?;
+    match name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        true => Ok((name, path)),
+        false => Err(format!("{name} is not a valid environment variable name")),
+    }
+}
+
+fn parse_dir_mode(mode: &str) -> std::result::Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{mode} is not an octal permission mode (e.g., 755)")),
+    }
+}
+
+/// Fill in any unset "local" Caddy paths from the first prefix with a Caddy binary, returning the
+/// binary path, config dir and where they came from
+fn local_caddy_paths(bin_path: Option<PathBuf>, config_dir: Option<PathBuf>) -> (PathBuf, PathBuf, &'static str) {
+    if let (Some(bin_path), Some(config_dir)) = (&bin_path, &config_dir) {
+        return (bin_path.clone(), config_dir.clone(), "configured");
+    }
+
+    let (prefix, source) = LOCAL_CADDY_PREFIXES.iter()
+        .find(|prefix| PathBuf::from(prefix).join("bin/caddy").exists())
+        .map(|prefix| (PathBuf::from(prefix), "detected"))
+        .unwrap_or_else(|| (PathBuf::from(LOCAL_CADDY_PREFIXES[0]), "default"));
+    (
+        bin_path.unwrap_or_else(|| prefix.join("bin/caddy")),
+        config_dir.unwrap_or_else(|| prefix.join("etc")),
+        source,
+    )
+}
+
+impl DockerConfig {
+    fn new(args: &Cli) -> Result<Self> {
+        let mut docker_socket_path = args.docker_socket_path.clone();
+        let mut remote = None;
+        if let Some(ref docker_host) = args.docker_host {
+            let tls_requested = args.docker_tls_ca.is_some() || args.docker_tls_cert.is_some() || args.docker_tls_key.is_some();
+            match docker_host.split_once("://") {
+                Some(("unix", path)) if docker_socket_path.is_none() => docker_socket_path = Some(PathBuf::from(path)),
+                Some(("unix", _)) => return Err("only one of --docker-host and --docker-socket-path can be set".into()),
+                Some((scheme @ ("tcp" | "http" | "https"), host)) => {
+                    let tls = match (&args.docker_tls_cert, &args.docker_tls_key) {
+                        (Some(cert), Some(key)) if scheme != "http" => Some(DockerTls {
+                            ca: args.docker_tls_ca.clone(),
+                            cert: cert.clone(),
+                            key: key.clone(),
+                        }),
+                        _ if scheme == "https" || tls_requested => return Err("--docker-tls-cert and --docker-tls-key must be provided to connect to Docker with TLS".into()),
+                        _ => None,
+                    };
+                    remote = Some(RemoteDocker { host: host.to_string(), tls });
+                },
+                _ => return Err("--docker-host must start with unix://, tcp:// or https://".into()),
+            }
+        }
+
+        let (docker_socket_path, docker_socket_source) = match docker_socket_path {
+            Some(path) => (path, "configured"),
+            None => {
+                let (system, rootless) = match args.container_runtime {
+                    ContainerRuntime::Docker => (SYSTEM_DOCKER_SOCKET, "docker.sock"),
+                    ContainerRuntime::Podman => (SYSTEM_PODMAN_SOCKET, "podman/podman.sock"),
+                };
+                let rootless = std::env::var_os("XDG_RUNTIME_DIR")
+                    .map(|dir| PathBuf::from(dir).join(rootless))
+                    .filter(|path| path.exists());
+                pick_socket(args.container_runtime, PathBuf::from(system), rootless)
+            },
+        };
+
+        Ok(DockerConfig {
+            runtime: args.container_runtime,
+            docker_socket_path,
+            docker_socket_source,
+            remote,
+        })
+    }
+}
+
+/// The socket to use of the system one and the rootless one, if that exists, with where it came
+/// from
+fn pick_socket(runtime: ContainerRuntime, system: PathBuf, rootless: Option<PathBuf>) -> (PathBuf, &'static str) {
+    match (rootless, runtime) {
+        // Podman is usually run rootless, so its socket wins over the system one
+        (Some(path), ContainerRuntime::Podman) => (path, "rootless"),
+        (Some(path), ContainerRuntime::Docker) if !system.exists() => (path, "rootless"),
+        _ => (system, "system"),
+    }
+}
+
+/// Which apps this instance manages, by name
+struct AppFilter {
+    only: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl AppFilter {
+    fn selects(&self, app_name: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|glob| glob_matches(glob, app_name)))
+            && !self.exclude.iter().any(|glob| glob_matches(glob, app_name))
+    }
+}
+
+/// Whether another instance has claimed the app (or this one is unnamed and the app is claimed at
+/// all), going by the instance label
+fn claimed_by_other_instance(labels: &HashMap<String, String>) -> bool {
+    labels.get(&config().instance_label)
+        .is_some_and(|instance| Some(instance) != config().instance_name.as_ref())
+}
+
+/// Whether the container is from a compose project --project-filter selects, going by the
+/// compose project label
+fn project_selected(labels: Option<&HashMap<String, String>>) -> bool {
+    config().project_filter.is_empty()
+        || labels.and_then(|labels| labels.get(COMPOSE_PROJECT_LABEL)).is_some_and(|project| config().project_filter.contains(project))
+}
+
+/// Match a glob supporting "*" (any run of characters) and "?" (any single character)
+fn glob_matches(glob: &str, s: &str) -> bool {
+    let glob = glob.chars().collect::<Vec<_>>();
+    let s = s.chars().collect::<Vec<_>>();
+    let (mut g, mut i) = (0, 0);
+    // where to resume if the current attempt fails: just after the last "*", and the position in s
+    // that "*" would then extend to
+    let mut backtrack = None;
+    while i < s.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g + 1, i + 1));
+                g += 1;
+            },
+            Some(&c) if c == '?' || c == s[i] => {
+                g += 1;
+                i += 1;
+            },
+            _ => match backtrack {
+                Some((bg, bi)) => {
+                    g = bg;
+                    i = bi;
+                    backtrack = Some((bg, bi + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+struct UpstreamConfig {
+    address_mode: UpstreamAddressMode,
+    ip_family: IpFamilyPreference,
+    network: Option<String>,
+    url_template: String,
 }
 
 impl Config {
-    fn new(args: Cli) -> Self {
-        let local_caddy_location = if args.local_caddy_on_docker {
+    fn new(args: Cli) -> Result<Self> {
+        let docker_config = DockerConfig::new(&args)?;
+        let (local_caddy_bin_path, local_caddy_config_dir, local_caddy_source) =
+            local_caddy_paths(args.local_caddy_bin_path, args.local_caddy_config_dir);
+        let local_domain = format!("{}.{}", &args.local_domain_prefix, &args.domain_name);
+        let local_caddy_location = if let Some(url) = args.local_caddy_admin_url {
+            CaddyLocation::AdminApi { url, caddyfile: local_caddy_config_dir.join("Caddyfile") }
+        } else if args.local_caddy_on_docker {
             if let Some(container_name) = args.local_caddy_docker_container_name {
                 CaddyLocation::Docker(container_name)
             } else {
-                panic!("container name was not specified for local caddy")
+                return Err("--local-caddy-docker-container-name must be provided if --local-caddy-on-docker is set".into());
             }
         } else {
             CaddyLocation::Local
         };
-        Self {
+        let docker_caddy_location = match (args.docker_caddy_admin_url, args.docker_caddy_caddyfile) {
+            (Some(url), Some(caddyfile)) => CaddyLocation::AdminApi { url, caddyfile },
+            (Some(_), None) => return Err("--docker-caddy-caddyfile must be provided if --docker-caddy-admin-url is set".into()),
+            (None, _) => CaddyLocation::Docker("caddy".to_string()),
+        };
+        Ok(Self {
             app_name_label: format!("{}.app", &args.label_prefix),
+            label_prefix: args.label_prefix.clone(),
+            expand_label_variables: args.expand_label_variables,
+            secrets_dir: args.secrets_dir,
             port_label: format!("{}.port", &args.label_prefix),
+            socket_label: format!("{}.socket", &args.label_prefix),
+            ports_label: format!("{}.ports", &args.label_prefix),
             external_label: format!("{}.external", &args.label_prefix),
             auth_label: format!("{}.auth", &args.label_prefix),
-            local_domain: format!("{}.{}", &args.local_domain_prefix, &args.domain_name),
-            external_domain: args.domain_name,
+            blocked_path_action_label: format!("{}.blocked_path_action", &args.label_prefix),
+            security_headers_label: format!("{}.security_headers", &args.label_prefix),
+            internal_paths_label: format!("{}.internal_paths", &args.label_prefix),
+            public_paths_label: format!("{}.public_paths", &args.label_prefix),
+            allow_countries_label: format!("{}.allow_countries", &args.label_prefix),
+            deny_countries_label: format!("{}.deny_countries", &args.label_prefix),
+            client_auth_label: format!("{}.client_auth", &args.label_prefix),
+            client_auth_ca_label: format!("{}.client_auth_ca", &args.label_prefix),
+            insecure_http_label: format!("{}.insecure_http", &args.label_prefix),
+            depends_on_label: format!("{}.depends_on", &args.label_prefix),
+            auth_headers_label: format!("{}.auth_headers", &args.label_prefix),
+            robots_label: format!("{}.robots", &args.label_prefix),
+            priority_label: format!("{}.priority", &args.label_prefix),
+            network_label: format!("{}.network", &args.label_prefix),
+            lb_retries_label: format!("{}.lb_retries", &args.label_prefix),
+            extra_label: format!("{}.caddy.extra", &args.label_prefix),
+            external_port_label: format!("{}.port.external", &args.label_prefix),
+            internal_port_label: format!("{}.port.internal", &args.label_prefix),
+            lb_try_duration_label: format!("{}.lb_try_duration", &args.label_prefix),
+            lb_try_interval_label: format!("{}.lb_try_interval", &args.label_prefix),
+            fail_duration_label: format!("{}.fail_duration", &args.label_prefix),
+            max_fails_label: format!("{}.max_fails", &args.label_prefix),
+            unhealthy_status_label: format!("{}.unhealthy_status", &args.label_prefix),
+            security_contact_label: format!("{}.security_contact", &args.label_prefix),
+            dns_provider_label: format!("{}.dns_provider", &args.label_prefix),
+            instance_label: format!("{}.instance", &args.label_prefix),
+            zone_label: format!("{}.zone", &args.label_prefix),
             local_caddy: CaddyConfig {
-                bin_path: args.local_caddy_bin_path,
-                config_dir: args.local_caddy_config_dir,
+                external_domain: args.local_caddy_external_domain.unwrap_or_else(|| args.domain_name.clone()),
+                local_domain: args.local_caddy_internal_domain.unwrap_or_else(|| local_domain.clone()),
+                bin_path: local_caddy_bin_path,
+                config_dir: local_caddy_config_dir,
                 snippets_dir: args.local_caddy_snippets_dir,
                 location: local_caddy_location,
             },
             docker_caddy: CaddyConfig {
+                external_domain: args.docker_caddy_external_domain.unwrap_or_else(|| args.domain_name.clone()),
+                local_domain: args.docker_caddy_internal_domain.unwrap_or(local_domain),
                 bin_path: args.docker_caddy_bin_path,
                 config_dir: args.docker_caddy_config_dir,
                 snippets_dir: args.docker_caddy_snippets_dir,
-                location: CaddyLocation::Docker("caddy".to_string()),
+                location: docker_caddy_location,
             },
-            docker_config: DockerConfig {
-                docker_socket_path: args.docker_socket_path
+            local_reload_dir: args.local_caddy_reload_dir,
+            local_reload_env: args.local_caddy_reload_env,
+            docker_secret_env: args.docker_caddy_secret_env.into_iter().filter(|(_, path)| !path.is_empty()).collect(),
+            local_caddy_peers: args.local_caddy_peer_admin_urls,
+            agents: match args.agent_token {
+                None if !args.agent_urls.is_empty() => return Err("--agent-token must be provided if --agent-urls is set".into()),
+                token => AgentConfig { urls: args.agent_urls, token },
             },
-            dns_provider: match args.local_dns_provider {
-                DnsProviderCli::None => DnsProvider::None,
-                DnsProviderCli::PowerDNS => DnsProvider::PowerDNS(args.power_dns_cli_opts.expect("power-dns config must be provided if DNS Provider is set to PowerDNS"))
+            ssh_caddy: match (args.ssh_caddy_destinations.is_empty(), args.ssh_caddy_snippets_dir) {
+                (true, _) => None,
+                (false, None) => return Err("--ssh-caddy-snippets-dir must be provided if --ssh-caddy-destinations is set".into()),
+                (false, Some(snippets_dir)) => Some(SshCaddy {
+                    destinations: args.ssh_caddy_destinations,
+                    identity_file: args.ssh_caddy_identity_file,
+                    transfer: args.ssh_caddy_transfer,
+                    snippets_dir,
+                    config_dir: args.ssh_caddy_config_dir,
+                    bin_path: args.ssh_caddy_bin_path,
+                }),
+            },
+            local_caddy_source,
+            docker_config,
+            upstream: UpstreamConfig {
+                address_mode: args.upstream_address_mode,
+                network: args.proxy_network,
+                ip_family: args.upstream_ip_family,
+                url_template: args.upstream_url_template,
+            },
+            blocked_path_action: args.blocked_path_action,
+            security_headers: args.security_headers,
+            internal_host_response: args.internal_host_response,
+            unknown_auth_policy: args.unknown_auth_policy,
+            require_auth_for_external: args.require_auth_for_external,
+            instance_name: args.instance_name,
+            snippet_groups: args.snippet_groups,
+            internal_bind_addresses: args.internal_bind_addresses,
+            external_bind_addresses: args.external_bind_addresses,
+            named_port_host_template: args.named_port_host_template,
+            app_filter: AppFilter {
+                only: args.only_apps,
+                exclude: args.exclude_apps,
+            },
+            project_filter: args.project_filter,
+            internal_cidrs: args.internal_cidrs,
+            geoip_db_path: args.geoip_db_path,
+            reload_hooks: ReloadHooks {
+                pre_reload: args.pre_reload_hook,
+                post_reload: args.post_reload_hook,
+            },
+            git_history: args.git_history,
+            snippet_generations: args.snippet_generations,
+            state_file: args.state_file,
+            state_max_age: Duration::from_secs(args.state_max_age_secs),
+            max_deletions: args.max_deletions,
+            force_deletions: args.force_deletions,
+            verify: match args.verify_after_reload {
+                true => Some(VerifyConfig {
+                    timeout: Duration::from_secs(args.verify_timeout_secs),
+                    address: args.verify_address,
+                    on_failure: args.verify_failure_action,
+                }),
+                false => None,
+            },
+            snippet_file_name: args.snippet_file_name,
+            preserve_unmanaged: args.preserve_unmanaged,
+            validate_before_reload: args.validate_before_reload,
+            snippet_format: args.snippet_format,
+            local_template: args.local_template,
+            docker_template: args.docker_template,
+            create_dirs: args.create_dirs,
+            dir_mode: args.dir_mode,
+            snippet_comments: args.snippet_comments,
+            compat_level: args.compat_level,
+            metrics_address: args.metrics_address,
+            check_for_updates: args.check_for_updates,
+            dry_run: args.dry_run,
+            redeploy_grace: Duration::from_millis(args.redeploy_grace_ms),
+            reconcile_interval: Duration::from_secs(args.reconcile_interval_secs),
+            on_shutdown: args.on_shutdown,
+            missing_container_grace: Duration::from_secs(args.missing_container_grace_secs),
+            exposure_report_interval: Duration::from_secs(args.exposure_report_interval_secs),
+            reconnect_max_backoff: Duration::from_secs(args.reconnect_max_backoff_secs.max(1)),
+            warning_repeat_interval: Duration::from_secs(args.warning_repeat_interval_secs),
+            emit_events: args.emit_events,
+            notify: NotifyConfig {
+                urls: args.notify_urls,
+                template: args.notify_template,
+                content_type: args.notify_content_type,
+            },
+            log_format: args.log_format,
+            reload_debounce: Duration::from_millis(args.reload_debounce_ms),
+            oom_drain_threshold: args.oom_drain_threshold,
+            oom_drain_window: Duration::from_secs(args.oom_drain_window_secs),
+            oom_drain_duration: Duration::from_secs(args.oom_drain_secs),
+            event_queue_depth: args.event_queue_depth as usize,
+            dns: DnsConfig {
+                local_provider: args.local_dns_provider,
+                external_provider: args.external_dns_provider.unwrap_or(args.local_dns_provider),
+                power_dns: match (args.local_dns_provider, args.external_dns_provider) {
+                    (DnsProviderCli::PowerDNS, _) | (_, Some(DnsProviderCli::PowerDNS)) => Some(args.power_dns_cli_opts.ok_or("power-dns config must be provided if DNS Provider is set to PowerDNS")?),
+                    _ => args.power_dns_cli_opts,
+                },
+            },
+            command: args.command.unwrap_or(Command::Run),
+        })
+    }
+}
+
+/// The running config, replaced when the options are re-read on SIGHUP. Each config is leaked,
+/// so references to an earlier one stay valid - they're only re-read on request, so it's little.
+static CONFIG: RwLock<Option<&'static Config>> = RwLock::new(None);
+
+fn config() -> &'static Config {
+    if let Some(config) = *CONFIG.read().unwrap_or_else(|e| e.into_inner()) {
+        return config;
+    }
+    let mut running = CONFIG.write().unwrap_or_else(|e| e.into_inner());
+    if let Some(config) = *running {
+        return config;
+    }
+    let args = parse_cli(&command_line()).unwrap_or_else(|e| match e.downcast::<clap::Error>() {
+        Ok(e) => e.exit(),
+        Err(e) => config_error(&e.to_string()),
+    });
+    let config = Box::leak(Box::new(Config::new(args).unwrap_or_else(|e| config_error(&e.to_string()))));
+    *running = Some(config);
+    config
+}
+
+/// Re-read the options from the command line, the environment and the --env-file, and replace the
+/// running config. If they're invalid, the running config is kept.
+fn reload_config() -> Result<()> {
+    let config = Config::new(parse_cli(&command_line())?)?;
+    *CONFIG.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::leak(Box::new(config)));
+    Ok(())
+}
+
+fn command_line() -> Vec<std::ffi::OsString> {
+    #[cfg(not(test))]
+    return std::env::args_os().collect();
+    // tests don't get our command line, so use a fixed one
+    #[cfg(test)]
+    return tests::args().into_iter().map(Into::into).collect();
+}
+
+/// Parse the options, with the --env-file's variables as the defaults of the options they name
+fn parse_cli(command_line: &[std::ffi::OsString]) -> Result<Cli> {
+    let mut command = Cli::command();
+    // options set only in the env file are missing here, so errors wait for the second pass
+    let matches = command.clone().ignore_errors(true).try_get_matches_from(command_line)?;
+    if let Some(env_file) = matches.get_one::<PathBuf>("env_file") {
+        let content = std::fs::read_to_string(env_file)
+            .map_err(|e| format!("unable to read --env-file {}: {e}", env_file.display()))?;
+        for (name, value) in parse_env_file(&content) {
+            // the environment takes precedence, as it does over defaults
+            if std::env::var_os(name).is_some() {
+                continue;
+            }
+            let id = command.get_arguments()
+                .find(|arg| arg.get_env().is_some_and(|env| env == name))
+                .map(|arg| arg.get_id().clone());
+            if let Some(id) = id {
+                let value: &'static str = value.to_string().leak();
+                command = command.mut_arg(id, |arg| arg.default_value(value).required(false));
             }
         }
     }
+
+    Ok(Cli::from_arg_matches(&command.try_get_matches_from(command_line)?)?)
 }
 
-fn config() -> &'static Config {
-    static CONFIG: OnceLock<Config> = OnceLock::new();
-    CONFIG.get_or_init(|| { Config::new(Cli::parse()) })
+/// The NAME=value pairs of an env file, skipping blank lines and # comments. Values may be quoted,
+/// and names may be preceded by export.
+fn parse_env_file(content: &str) -> Vec<(&str, &str)> {
+    content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| {
+            let name = name.trim();
+            let value = value.trim();
+            let value = ['"', '\'']
+                .into_iter()
+                .find_map(|quote| value.strip_prefix(quote).and_then(|value| value.strip_suffix(quote)))
+                .unwrap_or(value);
+            (name.strip_prefix("export ").map(str::trim).unwrap_or(name), value)
+        })
+        .collect()
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-struct StaticHost {
-    host: String,
-    a: Option<String>,
-    aaaa: Option<String>,
-    cname: Option<String>,
-    #[serde(rename="useLocal")]
-    use_local: Option<bool>,
+/// Exit with clap's usage error for a config problem clap can't check itself
+fn config_error(message: &str) -> ! {
+    Cli::command().error(clap::error::ErrorKind::MissingRequiredArgument, message).exit()
 }
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 type ApplicationData = HashMap<String, AppData>;
 
-#[cfg(unix)]
 pub fn new_docker() -> Result<Docker> {
+    match config().docker_config.remote {
+        Some(ref remote) => remote.connect(),
+        None => local_docker(),
+    }
+}
+
+#[cfg(unix)]
+fn local_docker() -> Result<Docker> {
     Ok(Docker::unix(&config().docker_config.docker_socket_path))
 }
 
 #[cfg(not(unix))]
-use Result as DockerResult;
-use crate::powerdns::{PowerDnsApiRecord, PowerDnsApiRRSet, PowerDnsApiRRSets, PowerDnsClient, PowerDnsCliOpts, RRSetChangeType, RRSetType};
+fn local_docker() -> Result<Docker> {
+    Ok(Docker::new("tcp://127.0.0.1:8080")?)
+}
+use crate::failure::{Failure, FailureKind};
+use crate::generations::SnippetGenerations;
+use crate::inspect_cache::InspectCache;
+use crate::metrics::Stage;
+use crate::snippet_lock::SnippetLocks;
+use crate::ssh_caddy::{SnippetTransfer, SshCaddy};
+use crate::template::AppTemplateContext;
+use crate::verify::{VerifiedApps, VerifyConfig, VerifyFailureAction};
+use crate::powerdns::{check_owner, zone_for_name, PowerDnsApiNewZone, PowerDnsApiRRSet, PowerDnsApiRRSets, PowerDnsClient, PowerDnsCliOpts};
+use crate::remote_docker::{DockerTls, RemoteDocker};
 
-#[cfg(not(unix))]
-pub fn new_docker() -> DockerResult<Docker> {
-    Docker::new("tcp://127.0.0.1:8080")
+/// The command running a caddy subcommand for a local Caddy, from --local-caddy-reload-dir and
+/// --local-caddy-reload-env
+fn local_caddy_command(config: &CaddyConfig, subcommand: &str, reload_dir: Option<&Path>, env: &[(String, String)]) -> Result<std::process::Command> {
+    let mut command = std::process::Command::new(&config.bin_path);
+    command.envs(env.iter().map(|(key, value)| (key, value)));
+    match reload_dir {
+        // Caddy only looks for the Caddyfile in the working directory
+        Some(reload_dir) => command.current_dir(reload_dir)
+            .args([subcommand, "--config"])
+            .arg(config.config_dir.join("Caddyfile")),
+        None => command.current_dir(config.config_dir.to_str().ok_or("unable to get local caddy config dir as string")?)
+            .args([subcommand]),
+    };
+    Ok(command)
 }
 
 pub fn print_chunk(chunk: TtyChunk) {
@@ -222,17 +1503,122 @@ pub fn print_chunk(chunk: TtyChunk) {
     }
 }
 
-#[derive(Debug)]
+/// Most of the exec output to keep in an error - Caddy puts the reason for a failure at the end
+const MAX_EXEC_OUTPUT_LEN: usize = 2000;
+
+/// Keep only the end of the output, so a long log doesn't swamp the error it's attached to
+fn truncate_exec_output(output: &str) -> String {
+    let output = output.trim();
+    if output.len() <= MAX_EXEC_OUTPUT_LEN {
+        return output.to_string();
+    }
+
+    let mut start = output.len() - MAX_EXEC_OUTPUT_LEN;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[truncated] ...{}", &output[start..])
+}
+
+#[derive(Debug, Clone)]
 struct ContainerSummaryInternal {
     id: String,
     container_name: String,
     labels: Option<HashMap<String, String>>,
     network_mode_host: bool,
+    /// The networks the container is attached to, in name order
+    networks: Vec<ContainerNetwork>,
+    image: Option<String>,
+    started_at: Option<String>,
+    paused: bool,
+    health: Health,
+}
+
+/// Where a container is with its Docker health check
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+enum Health {
+    /// It has no health check
+    #[default]
+    None,
+    /// It hasn't passed its health check yet
+    Starting,
+    Healthy,
+    /// It's failing its health check
+    Unhealthy,
+}
+
+impl Health {
+    fn of(container: &ContainerInspect200Response) -> Self {
+        // containers without a health check have a status of "none", or no health at all
+        match container.state.as_ref().and_then(|s| s.health.as_ref()).and_then(|h| h.status.as_deref()) {
+            Some("starting") => Health::Starting,
+            Some("healthy") => Health::Healthy,
+            Some("unhealthy") => Health::Unhealthy,
+            _ => Health::None,
+        }
+    }
+}
+
+/// A network a container is attached to, with the container's addresses on it
+#[derive(Debug, Clone)]
+struct ContainerNetwork {
+    name: String,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
 }
 
 impl ContainerSummaryInternal {
+    /// The container as seen by each app it serves. Indexed labels (e.g., "<prefix>.0.app" and
+    /// "<prefix>.0.port") define several apps on one container, each seeing its own labels
+    /// without the index, on top of the container's unindexed labels. Otherwise the container
+    /// serves a single app if it has an app label.
+    fn app_views(&self) -> Vec<Cow<'_, ContainerSummaryInternal>> {
+        let Some(ref labels) = self.labels else {
+            return Vec::new();
+        };
+        let prefix = format!("{}.", config().label_prefix);
+        let index_of = |label: &str| label.strip_prefix(&prefix)
+            .and_then(|rest| rest.split_once('.'))
+            .filter(|(index, _)| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+            .map(|(index, name)| (index.to_string(), name.to_string()));
+
+        let mut indexes = labels.keys()
+            .filter_map(|label| index_of(label))
+            .filter(|(_, name)| name == "app")
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        if indexes.is_empty() {
+            return match labels.contains_key(&config().app_name_label) {
+                true => vec![Cow::Borrowed(self)],
+                false => Vec::new(),
+            };
+        }
+        indexes.sort_by_key(|index| index.parse::<u32>().unwrap_or(u32::MAX));
+
+        indexes.into_iter()
+            .map(|app_index| {
+                let mut app_labels = labels.iter()
+                    .filter(|(label, _)| index_of(label).is_none())
+                    .map(|(label, value)| (label.clone(), value.clone()))
+                    .collect::<HashMap<_, _>>();
+                for (label, value) in labels {
+                    if let Some((index, name)) = index_of(label) {
+                        if index == app_index {
+                            app_labels.insert(format!("{prefix}{name}"), value.clone());
+                        }
+                    }
+                }
+                Cow::Owned(ContainerSummaryInternal {
+                    labels: Some(app_labels),
+                    ..self.clone()
+                })
+            })
+            .collect()
+    }
+
     fn new_from_inspect(container: &ContainerInspect200Response) -> Result<Self> {
-        let container_name = container.name.as_ref().map(|s| s.as_str()).map(|s| s.strip_prefix("/").unwrap_or(s).to_string()).unwrap();
+        let container_name = container.name.as_deref().map(|s| s.strip_prefix("/").unwrap_or(s).to_string()).unwrap();
         let network_mode_host = if let Some(ref network_settings) = container.network_settings {
             if let Some(ref networks) = network_settings.networks {
                 networks.contains_key("host")
@@ -243,11 +1629,35 @@ impl ContainerSummaryInternal {
             false
         };
 
+        // in name order, so the network picked for containers attached to more than one is stable
+        let mut networks = container.network_settings.as_ref()
+            .and_then(|ns| ns.networks.as_ref())
+            .map(|networks| networks.iter()
+                .map(|(name, endpoint)| ContainerNetwork {
+                    name: name.clone(),
+                    ipv4: endpoint.ip_address.as_deref().and_then(|ip| ip.parse().ok()),
+                    ipv6: endpoint.global_i_pv_6_address.as_deref().and_then(|ip| ip.parse().ok()),
+                })
+                .collect::<Vec<_>>())
+            .unwrap_or_default();
+        networks.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut labels = container.config.as_ref().unwrap().labels.clone();
+        if let (Some(labels), true) = (&mut labels, config().expand_label_variables) {
+            let env = container.config.as_ref().and_then(|c| c.env.as_deref()).unwrap_or_default();
+            label_vars::expand_labels(labels, &format!("{}.", config().label_prefix), env);
+        }
+
         Ok(ContainerSummaryInternal {
             id: container.id.clone().unwrap(),
             container_name,
-            labels: container.config.as_ref().unwrap().labels.clone(),
+            labels,
             network_mode_host,
+            networks,
+            image: container.config.as_ref().and_then(|c| c.image.clone()),
+            started_at: container.state.as_ref().and_then(|s| s.started_at.clone()),
+            paused: container.state.as_ref().and_then(|s| s.paused).unwrap_or_default(),
+            health: Health::of(container),
         })
     }
 }
@@ -262,16 +1672,41 @@ struct EventSummaryInternal {
 
 impl EventSummaryInternal {
     fn new_from_event(event: &EventMessage) -> Result<Self> {
+        let actor = event.actor.as_ref().ok_or("container event has no actor")?;
+        let id = actor.id.clone().ok_or("container event has no container ID")?;
+        // Podman leaves out attributes Docker always sends, so the ID stands in for a missing name
+        let attribute = |name: &str| actor.attributes.as_ref()
+            .and_then(|attributes| attributes.get(name))
+            .map(|s| s.strip_prefix("/").unwrap_or(s).to_string());
         Ok(EventSummaryInternal {
-            id: event.actor.as_ref().unwrap().id.clone().unwrap(),
-            app_name: event.actor.as_ref().unwrap().attributes.as_ref().unwrap().get(&config().app_name_label).map(|s| s.to_string()).clone(),
-            container_name: event.actor.as_ref().unwrap().attributes.as_ref().unwrap().get("name").map(|s| s.strip_prefix("/").unwrap_or(s).to_string()).unwrap(),
-            old_name: event.actor.as_ref().unwrap().attributes.as_ref().unwrap().get("oldName").map(|s| s.strip_prefix("/").unwrap_or(s).to_string()),
+            app_name: attribute(&config().app_name_label),
+            container_name: attribute("name").unwrap_or_else(|| id.clone()),
+            old_name: attribute("oldName"),
+            id,
         })
     }
 }
 
-#[derive(Debug)]
+/// The Docker name for a container event's action. Podman reports removal as "remove", deaths as
+/// "died", and health changes as "health_status" with the status in an attribute
+fn normalize_action<'a>(runtime: ContainerRuntime, action: &'a str, attributes: Option<&'a HashMap<String, String>>) -> Cow<'a, str> {
+    if runtime != ContainerRuntime::Podman {
+        return Cow::Borrowed(action);
+    }
+
+    match action {
+        "remove" => Cow::Borrowed("destroy"),
+        "died" => Cow::Borrowed("die"),
+        "health_status" => match attributes.and_then(|attributes| attributes.get("health_status")) {
+            Some(status) => Cow::Owned(format!("health_status: {status}")),
+            None => Cow::Borrowed(action),
+        },
+        _ => Cow::Borrowed(action),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
 enum CaddyAuthType {
     Oidc,
     TrustedHeaders,
@@ -279,13 +1714,191 @@ enum CaddyAuthType {
     None,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+enum ClientAuthMode {
+    Require,
+    VerifyIfGiven,
+}
+
+impl ClientAuthMode {
+    fn caddy_mode(&self) -> &'static str {
+        match self {
+            ClientAuthMode::Require => "require_and_verify",
+            ClientAuthMode::VerifyIfGiven => "verify_if_given",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClientAuth {
+    mode: ClientAuthMode,
+    ca_file: String,
+}
+
+/// Caddy's options for retrying a request on another upstream
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RetryPolicy {
+    retries: Option<u32>,
+    try_duration: Option<String>,
+    try_interval: Option<String>,
+}
+
+impl RetryPolicy {
+    fn is_empty(&self) -> bool {
+        self.retries.is_none() && self.try_duration.is_none() && self.try_interval.is_none()
+    }
+}
+
+/// Caddy's passive health check options, which stop requests going to a container that keeps
+/// failing them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct CircuitBreaker {
+    fail_duration: Option<String>,
+    max_fails: Option<u32>,
+    /// Status codes (e.g., 503) or classes (e.g., 5xx) counted as failures
+    unhealthy_status: Vec<String>,
+}
+
+impl CircuitBreaker {
+    /// For apps with several containers, so one failing container doesn't fail a share of the
+    /// requests while the others are fine
+    const DEFAULT_FAIL_DURATION: &'static str = "30s";
+    const DEFAULT_MAX_FAILS: u32 = 3;
+
+    /// The options to set, with the defaults filled in if the app has several upstreams
+    fn options(&self, upstreams: usize) -> Vec<(&'static str, String)> {
+        let defaults = upstreams > 1 && config().compat_level > CompatLevel::V0_1;
+        let fail_duration = match self.fail_duration {
+            Some(ref fail_duration) => Some(fail_duration.clone()),
+            None if defaults => Some(Self::DEFAULT_FAIL_DURATION.to_string()),
+            None => None,
+        };
+        // Caddy ignores the rest without a fail duration
+        match fail_duration {
+            None => Vec::new(),
+            Some(fail_duration) if fail_duration == "0" => Vec::new(),
+            Some(fail_duration) => {
+                let max_fails = self.max_fails.or(defaults.then_some(Self::DEFAULT_MAX_FAILS));
+                [
+                    ("fail_duration", Some(fail_duration)),
+                    ("max_fails", max_fails.map(|m| m.to_string())),
+                    ("unhealthy_status", (!self.unhealthy_status.is_empty()).then(|| self.unhealthy_status.join(" "))),
+                ].into_iter()
+                    .filter_map(|(name, value)| Some((name, value?)))
+                    .collect()
+            },
+        }
+    }
+}
+
+/// Why an app's depends_on is holding it back
+enum DependencyWait<'a> {
+    /// The dependency has no running, healthy container
+    NotReady(&'a str),
+    /// The chain of dependencies comes back around to this one
+    Circular(&'a str),
+}
+
+impl std::fmt::Display for DependencyWait<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DependencyWait::NotReady(dependency) => write!(f, "waiting for {dependency}"),
+            DependencyWait::Circular(dependency) => write!(f, "circular depends_on ({dependency})"),
+        }
+    }
+}
+
+/// Whether this is a valid HTTP header name - an RFC 9110 token, e.g., "Remote-User"
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Whether this is a status Caddy accepts for unhealthy_status, e.g., "503" or "5xx"
+fn is_status_match(status: &str) -> bool {
+    let bytes = status.as_bytes();
+    bytes.len() == 3 && (b'1'..=b'5').contains(&bytes[0]) && (
+        bytes[1..].iter().all(u8::is_ascii_digit) || &bytes[1..] == b"xx"
+    )
+}
+
+/// Whether this is a duration Caddy accepts, e.g., "250ms" or "1m30s"
+fn is_caddy_duration(duration: &str) -> bool {
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let number_len = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        if number_len == 0 || rest[..number_len].parse::<f64>().is_err() {
+            return false;
+        }
+        rest = &rest[number_len..];
+        let Some(unit) = ["ns", "us", "µs", "ms", "s", "m", "h", "d"].into_iter()
+            .filter(|unit| rest.starts_with(unit))
+            .max_by_key(|unit| unit.len()) else {
+            return false;
+        };
+        rest = &rest[unit.len()..];
+    }
+    !duration.is_empty()
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SplitPorts {
+    external: Option<u16>,
+    internal: Option<u16>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+enum AppUpstream {
+    Port(u16),
+    Socket(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NamedPort {
+    name: String,
+    port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct AppData {
     app_name: String,
     containers: Vec<AppContainerData>,
-    port: u16,
+    upstream: AppUpstream,
+    /// Extra ports, each served on a host from --named-port-host-template
+    named_ports: Vec<NamedPort>,
     external: bool,
     auth_type: CaddyAuthType,
+    /// Identity headers a request must have after the auth-headers snippet runs
+    auth_headers: Vec<String>,
+    blocked_path_action: BlockedPathAction,
+    security_headers: SecurityHeaders,
+    internal_paths: Vec<String>,
+    /// Paths served to anyone, ahead of the blocked paths, internal paths, geo policy and auth
+    public_paths: Vec<String>,
+    allow_countries: Vec<String>,
+    deny_countries: Vec<String>,
+    client_auth: Option<ClientAuth>,
+    insecure_http: bool,
+    depends_on: Option<String>,
+    dns_provider: DnsProviderCli,
+    /// Serve a robots.txt disallowing all crawlers, instead of the app's own
+    robots_deny: bool,
+    /// Contact (e.g., a mailto: URI) for a generated security.txt
+    security_contact: Option<String>,
+    /// Snippet group (from --snippet-groups) the app goes in, instead of the external/internal split
+    zone: Option<String>,
+    /// Apps with a higher priority are written first, so win when matchers overlap
+    priority: i32,
+    retry_policy: RetryPolicy,
+    circuit_breaker: CircuitBreaker,
+    /// Ports to serve on the external and internal domains instead of the app's own port
+    split_ports: SplitPorts,
+    /// Directives from the caddy.extra labels, inserted verbatim before reverse_proxy
+    extra_directives: Vec<String>,
+    /// Distinguishes the matchers of blocks for a split port, which share the app's name
+    #[serde(skip)]
+    matcher_suffix: Option<&'static str>,
     network_mode_host: bool,
 }
 
@@ -294,102 +1907,845 @@ impl AppData {
         summary
             .labels
             .as_ref()
-            .map(|labels| { labels.get(&config().app_name_label).map(|s| s.clone()) })
+            .map(|labels| { labels.get(&config().app_name_label).cloned() })
             .unwrap_or(None)
     }
 
+    /// [`AppData::new_from_container`], skipping a container with invalid labels so it can't stop
+    /// the other apps being served
+    fn new_from_container_or_warn(container: &ContainerSummaryInternal) -> Option<Self> {
+        match Self::new_from_container(container) {
+            Ok(ad) => ad,
+            Err(e) => {
+                if let Some(r) = recurring::check("invalid_labels", &container.container_name) {
+                    warn!(container_name=container.container_name, error=%e, r.suppressed, persisting=?r.persisting, "invalid labels, skipping container");
+                }
+                None
+            },
+        }
+    }
+
     fn new_from_container(container: &ContainerSummaryInternal) -> Result<Option<Self>> {
         if let Some(labels) = &container.labels {
             if !labels.contains_key(&config().app_name_label) {
                 return Ok(None);
             }
-
             let app_name = labels[&config().app_name_label].clone();
-            let port: u16 = labels[&config().port_label].parse()?;
-            let external: bool = labels.get(&config().external_label).map(|b| b.parse()).unwrap_or(Ok(false))?;
+            let mut named_ports = match labels.get(&config().ports_label) {
+                Some(ports) => ports.split(',')
+                    .filter(|p| !p.trim().is_empty())
+                    .map(|p| match p.trim().split_once(':') {
+                        Some((name, port)) => Ok(NamedPort {
+                            name: name.to_string(),
+                            port: port.parse().map_err(|e| format!("invalid port {port} in ports for app {app_name}: {e}"))?,
+                        }),
+                        None => Err(format!("invalid ports for app {app_name}: {p} should be <name>:<port>")),
+                    })
+                    .collect::<std::result::Result<Vec<_>, _>>()?,
+                None => Vec::new(),
+            };
+            // the first port serves the app's own host, and any others get hosts of their own
+            let mut ports = labels.get(&config().port_label)
+                .map(|ports| ports.split(',').map(str::trim).filter(|p| !p.is_empty()).collect::<Vec<_>>())
+                .unwrap_or_default()
+                .into_iter();
+            let port = ports.next();
+            for p in ports {
+                let (name, port) = p.split_once(':').unwrap_or((p, p));
+                named_ports.push(NamedPort {
+                    name: name.to_string(),
+                    port: port.parse().map_err(|e| format!("invalid port {port} in port for app {app_name}: {e}"))?,
+                });
+            }
+            let split_port = |label: &String| match labels.get(label) {
+                Some(port) => port.parse()
+                    .map(Some)
+                    .map_err(|e| format!("invalid port {port} in {label} for app {app_name}: {e}")),
+                None => Ok(None),
+            };
+            let split_ports = SplitPorts {
+                external: split_port(&config().external_port_label)?,
+                internal: split_port(&config().internal_port_label)?,
+            };
+            let mut external: bool = labels.get(&config().external_label).map(|b| b.parse()).unwrap_or(Ok(false))?;
+            let upstream = match (labels.get(&config().socket_label), port, named_ports.first(), &split_ports) {
+                (Some(socket), _, _, _) => AppUpstream::Socket(socket.clone()),
+                (None, Some(port), _, _) => AppUpstream::Port(port.parse().map_err(|e| format!("invalid port {port} for app {app_name}: {e}"))?),
+                // the first named port also serves the app's own host
+                (None, None, Some(named_port), _) => AppUpstream::Port(named_port.port),
+                // the app only has split ports, so put its own block on a domain that one of them
+                // covers, where it's replaced
+                (None, None, None, SplitPorts { external: Some(port), .. }) => {
+                    external = true;
+                    AppUpstream::Port(*port)
+                },
+                (None, None, None, SplitPorts { internal: Some(port), .. }) => {
+                    external = false;
+                    AppUpstream::Port(*port)
+                },
+                (None, None, None, _) => return Err(format!("app {app_name} must have either a port, ports or a socket label").into()),
+            };
             let network_mode_host: bool = container.network_mode_host;
             let auth_type = labels.get(&config().auth_label).map(|s| match s.as_str() {
                 "oidc" => CaddyAuthType::Oidc,
                 "headers" => CaddyAuthType::TrustedHeaders, 
                 "none" => CaddyAuthType::None, 
-                v @ _ => CaddyAuthType::Unknown(v.to_string())
+                v => CaddyAuthType::Unknown(v.to_string())
             }).unwrap_or(CaddyAuthType::None);
-
-            Ok(Some(AppData {
-                app_name,
-                containers: Vec::new(),
-                port,
-                external,
-                auth_type,
-                network_mode_host,
+            if let CaddyAuthType::Unknown(ref auth) = auth_type {
+                warn!(app_name, auth, policy=?config().unknown_auth_policy, "unknown auth type, applying --unknown-auth-policy");
+            }
+            let blocked_path_action = match labels.get(&config().blocked_path_action_label) {
+                Some(action) => BlockedPathAction::from_str(action, true)
+                    .map_err(|e| format!("invalid blocked_path_action for app {app_name}: {e}"))?,
+                None => config().blocked_path_action,
+            };
+            let security_headers = match labels.get(&config().security_headers_label) {
+                Some(preset) => SecurityHeaders::from_str(preset, true)
+                    .map_err(|e| format!("invalid security_headers for app {app_name}: {e}"))?,
+                None => config().security_headers,
+            };
+            let paths = |label: &String| labels.get(label)
+                .map(|paths| paths.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect())
+                .unwrap_or_default();
+            let internal_paths = paths(&config().internal_paths_label);
+            let public_paths = paths(&config().public_paths_label);
+            let country_codes = |label: &String| labels.get(label)
+                .map(|codes| codes.split([',', ' ']).filter(|c| !c.is_empty()).map(|c| c.to_uppercase()).collect())
+                .unwrap_or_default();
+            let allow_countries = country_codes(&config().allow_countries_label);
+            let deny_countries = country_codes(&config().deny_countries_label);
+            let client_auth = match labels.get(&config().client_auth_label) {
+                Some(mode) => Some(ClientAuth {
+                    mode: ClientAuthMode::from_str(mode, true)
+                        .map_err(|e| format!("invalid client_auth for app {app_name}: {e}"))?,
+                    ca_file: labels.get(&config().client_auth_ca_label)
+                        .ok_or(format!("app {app_name} requires client auth but has no client_auth_ca label"))?
+                        .clone(),
+                }),
+                None => None,
+            };
+            let insecure_http: bool = labels.get(&config().insecure_http_label).map(|b| b.parse()).unwrap_or(Ok(false))?;
+            let insecure_http = match insecure_http {
+                true if external => {
+                    warn!(app_name, "insecure_http is only supported for internal apps, ignoring");
+                    false
+                },
+                true if client_auth.is_some() => {
+                    warn!(app_name, "insecure_http would bypass client_auth, ignoring");
+                    false
+                },
+                insecure_http => insecure_http,
+            };
+            let dns_provider = match labels.get(&config().dns_provider_label) {
+                Some(provider) => DnsProviderCli::from_str(provider, true)
+                    .map_err(|e| format!("invalid dns_provider for app {app_name}: {e}"))?,
+                None if external => config().dns.external_provider,
+                None => config().dns.local_provider,
+            };
+            let auth_headers: Vec<String> = labels.get(&config().auth_headers_label)
+                .map(|headers| headers.split(',').map(|h| h.trim().to_string()).filter(|h| !h.is_empty()).collect())
+                .unwrap_or_default();
+            // they're written into the snippets as they are
+            if let Some(header) = auth_headers.iter().find(|header| !is_header_name(header)) {
+                return Err(format!("invalid auth_headers for app {app_name}: {header} (expected header names, e.g., Remote-User)").into());
+            }
+            let auth_headers = match auth_type {
+                CaddyAuthType::TrustedHeaders => auth_headers,
+                _ if !auth_headers.is_empty() => {
+                    warn!(app_name, "auth_headers is only used with auth=headers, ignoring");
+                    Vec::new()
+                },
+                _ => auth_headers,
+            };
+            let robots_deny = match labels.get(&config().robots_label).map(String::as_str) {
+                Some("deny") => true,
+                Some("allow") | None => false,
+                Some(robots) => return Err(format!("invalid robots for app {app_name}: {robots} (expected deny or allow)").into()),
+            };
+            let zone = match labels.get(&config().zone_label) {
+                Some(zone) if config().snippet_groups.contains(zone) => Some(zone.clone()),
+                Some(zone) => return Err(format!("invalid zone for app {app_name}: {zone} is not one of --snippet-groups").into()),
+                None => None,
+            };
+            let priority = match labels.get(&config().priority_label) {
+                Some(priority) => priority.parse()
+                    .map_err(|_| format!("invalid priority for app {app_name}: {priority} (expected an integer)"))?,
+                None => 0,
+            };
+            let duration = |label: &String, name: &str| match labels.get(label) {
+                Some(duration) if is_caddy_duration(duration) => Ok(Some(duration.clone())),
+                Some(duration) => Err(format!("invalid {name} for app {app_name}: {duration} (expected a duration, e.g., 5s)")),
+                None => Ok(None),
+            };
+            // the unnumbered label goes first, then the numbered ones in order
+            let mut extra_directives = labels.iter()
+                .filter_map(|(label, directive)| {
+                    let rest = label.strip_prefix(&config().extra_label)?;
+                    let index = match rest.strip_prefix('.') {
+                        Some(index) => Some(index.parse::<u32>().ok()?),
+                        None if rest.is_empty() => None,
+                        None => return None,
+                    };
+                    Some((index, directive.trim().to_string()))
+                })
+                .filter(|(_, directive)| !directive.is_empty())
+                .collect::<Vec<_>>();
+            extra_directives.sort_unstable_by_key(|(index, _)| *index);
+            let extra_directives = extra_directives.into_iter().map(|(_, directive)| directive).collect();
+            let retry_policy = RetryPolicy {
+                retries: match labels.get(&config().lb_retries_label) {
+                    Some(retries) => Some(retries.parse()
+                        .map_err(|_| format!("invalid lb_retries for app {app_name}: {retries} (expected a number)"))?),
+                    None => None,
+                },
+                try_duration: duration(&config().lb_try_duration_label, "lb_try_duration")?,
+                try_interval: duration(&config().lb_try_interval_label, "lb_try_interval")?,
+            };
+            let circuit_breaker = CircuitBreaker {
+                fail_duration: match labels.get(&config().fail_duration_label) {
+                    Some(fail_duration) if fail_duration == "0" => Some(fail_duration.clone()),
+                    _ => duration(&config().fail_duration_label, "fail_duration")?,
+                },
+                max_fails: match labels.get(&config().max_fails_label) {
+                    Some(max_fails) => Some(max_fails.parse()
+                        .map_err(|_| format!("invalid max_fails for app {app_name}: {max_fails} (expected a number)"))?),
+                    None => None,
+                },
+                unhealthy_status: match labels.get(&config().unhealthy_status_label) {
+                    Some(statuses) => statuses.split(',')
+                        .map(str::trim)
+                        .filter(|status| !status.is_empty())
+                        .map(|status| match is_status_match(status) {
+                            true => Ok(status.to_string()),
+                            false => Err(format!("invalid unhealthy_status for app {app_name}: {status} (expected a status, e.g., 503 or 5xx)")),
+                        })
+                        .collect::<std::result::Result<_, _>>()?,
+                    None => Vec::new(),
+                },
+            };
+
+            // it's written into a backtick-quoted response, which a backtick would end
+            let security_contact = match labels.get(&config().security_contact_label) {
+                Some(contact) if contact.contains('`') => return Err(format!("invalid security_contact for app {app_name}: {contact} (backticks aren't allowed)").into()),
+                contact => contact.cloned(),
+            };
+
+            Ok(Some(AppData {
+                app_name,
+                containers: Vec::new(),
+                upstream,
+                named_ports,
+                external,
+                auth_type,
+                auth_headers,
+                blocked_path_action,
+                security_headers,
+                internal_paths,
+                public_paths,
+                allow_countries,
+                deny_countries,
+                client_auth,
+                insecure_http,
+                depends_on: labels.get(&config().depends_on_label).cloned(),
+                dns_provider,
+                robots_deny,
+                security_contact,
+                zone,
+                priority,
+                retry_policy,
+                circuit_breaker,
+                split_ports,
+                extra_directives,
+                matcher_suffix: None,
+                network_mode_host,
             }))
         } else {
-            return Ok(None)
+            Ok(None)
+        }
+    }
+
+    /// Whether the app this depends on (and anything that depends on in turn) is up. Apps
+    /// without a dependency are always ready.
+    fn dependencies_ready(&self, app_data: &ApplicationData) -> bool {
+        match self.waiting_for(app_data) {
+            None => true,
+            Some(DependencyWait::Circular(dependency)) => {
+                if let Some(r) = recurring::check("circular_depends_on", &self.app_name) {
+                    warn!(app_name=self.app_name, dependency, r.suppressed, persisting=?r.persisting, "circular depends_on, treating as not ready");
+                }
+                false
+            },
+            Some(DependencyWait::NotReady(dependency)) => {
+                info!(app_name=self.app_name, dependency, "dependency has no running, healthy containers, leaving app out of snippets");
+                false
+            },
         }
     }
 
+    /// The dependency holding the app back, if any. A dependency is up once it has a running
+    /// container that isn't paused, and has passed its health check if it has one.
+    fn waiting_for<'a>(&'a self, app_data: &'a ApplicationData) -> Option<DependencyWait<'a>> {
+        let mut seen = vec![self.app_name.as_str()];
+        let mut current = self;
+
+        while let Some(ref dependency) = current.depends_on {
+            if seen.contains(&dependency.as_str()) {
+                return Some(DependencyWait::Circular(dependency));
+            }
+
+            match app_data.get(dependency) {
+                Some(ad) if ad.containers.iter().any(|adc| !adc.paused && matches!(adc.health, Health::None | Health::Healthy)) => {
+                    seen.push(dependency);
+                    current = ad;
+                },
+                _ => return Some(DependencyWait::NotReady(dependency)),
+            }
+        }
+
+        None
+    }
+
+    /// The app itself, followed by a copy for each named port with the port's host as its name
+    fn with_port_variants(&self) -> Vec<Cow<'_, AppData>> {
+        // a split port for the app's own domain replaces its own block
+        let replaced = match self.external {
+            true => self.split_ports.external.is_some(),
+            false => self.split_ports.internal.is_some(),
+        };
+        let split_ports = [("external", true, self.split_ports.external), ("internal", false, self.split_ports.internal)]
+            .into_iter()
+            .filter_map(|(suffix, external, port)| Some(Cow::Owned(AppData {
+                upstream: AppUpstream::Port(port?),
+                external,
+                insecure_http: self.insecure_http && !external,
+                named_ports: Vec::new(),
+                split_ports: SplitPorts::default(),
+                matcher_suffix: Some(suffix),
+                ..self.clone()
+            })));
+
+        (!replaced).then_some(Cow::Borrowed(self))
+            .into_iter()
+            .chain(split_ports)
+            .chain(self.named_ports.iter().map(|named_port| Cow::Owned(AppData {
+                app_name: config().named_port_host_template
+                    .replace("{app}", &self.app_name)
+                    .replace("{name}", &named_port.name),
+                upstream: AppUpstream::Port(named_port.port),
+                named_ports: Vec::new(),
+                split_ports: SplitPorts::default(),
+                ..self.clone()
+            })))
+            .collect()
+    }
+
+    /// Name for the app's Caddy matchers
+    fn matcher(&self) -> String {
+        match self.matcher_suffix {
+            Some(suffix) => format!("{}_{suffix}", self.app_name),
+            None => self.app_name.clone(),
+        }
+    }
+
+    /// Whether the label-derived configuration of both is the same, ignoring their containers
+    fn same_config(&self, other: &AppData) -> bool {
+        let without_containers = |ad: &AppData| {
+            let mut value = serde_json::to_value(ad).ok();
+            if let Some(serde_json::Value::Object(ref mut fields)) = value {
+                fields.remove("containers");
+            }
+            value
+        };
+
+        without_containers(self) == without_containers(other)
+    }
+
+    /// The domain clients use for the app, which the "local" Caddy serves it on
     fn domain(&self) -> &str {
-        if self.external { config().external_domain.as_str() } else { config().local_domain.as_str() }
+        self.domain_in(&config().local_caddy)
+    }
+
+    fn domain_in<'a>(&self, caddy: &'a CaddyConfig) -> &'a str {
+        if self.external { caddy.external_domain.as_str() } else { caddy.local_domain.as_str() }
+    }
+
+    /// Where the "local" Caddy proxies the app to. If the Docker Caddy serves the app on another
+    /// domain, the Host header is rewritten to match.
+    fn format_local_upstream(&self, indent: &str) -> String {
+        let docker_domain = self.domain_in(&config().docker_caddy);
+        match docker_domain == self.domain() {
+            true => "http://localhost:880".to_string(),
+            false => format!("http://localhost:880 {{\n{indent}  header_up Host {}.{docker_domain}\n{indent}}}", self.app_name),
+        }
+    }
+
+    fn auth(&self) -> String {
+        match self.auth_type {
+            CaddyAuthType::TrustedHeaders if !self.auth_headers.is_empty() => self.format_auth_headers_guard(),
+            CaddyAuthType::TrustedHeaders => "import auth-headers".to_string(),
+            CaddyAuthType::Unknown(_) => match config().unknown_auth_policy {
+                UnknownAuthPolicy::Fallback(ref snippet) => format!("import {snippet}"),
+                UnknownAuthPolicy::Reject | UnknownAuthPolicy::None => String::new(),
+            },
+            _ => String::new(),
+        }
+    }
+
+    /// Why the app must be left out of the snippets, if it must
+    fn rejection(&self) -> Option<String> {
+        self.rejection_with(config().require_auth_for_external)
     }
 
-    fn auth(&self) -> &'static str {
-        match self.auth_type { CaddyAuthType::TrustedHeaders => "import auth-headers", _ => "" }
+    fn rejection_with(&self, require_auth_for_external: bool) -> Option<String> {
+        match (&self.auth_type, &config().unknown_auth_policy) {
+            (CaddyAuthType::Unknown(auth), UnknownAuthPolicy::Reject) => Some(format!("unknown auth type {auth} (--unknown-auth-policy is reject)")),
+            _ if require_auth_for_external && report::unauthenticated(self) => Some("external without authentication (--require-auth-for-external is set)".to_string()),
+            _ => None,
+        }
+    }
+
+    /// Rejects requests missing any of the identity headers. The route keeps the check after the
+    /// auth-headers snippet, which may be what sets them (e.g., with forward_auth).
+    fn format_auth_headers_guard(&self) -> String {
+        let headers = self.auth_headers.iter()
+            .map(|header| format!("    header {} *", header))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let guard = format!(indoc!("
+            @{matcher}_missing_auth_headers {{
+              not {{
+            {headers}
+              }}
+            }}
+            route {{
+              import auth-headers
+              respond @{matcher}_missing_auth_headers 401
+            }}"), matcher=self.matcher(), headers=headers);
+
+        // indent to match the handle block it's placed in
+        guard.lines().collect::<Vec<_>>().join("\n    ")
+    }
+
+    /// Blocks the app's internal-only paths for anyone outside the internal CIDRs. This has to
+    /// live in the local Caddy, as the docker Caddy never sees the real client address.
+    fn format_internal_paths(&self) -> String {
+        if !self.external || self.internal_paths.is_empty() {
+            return String::new();
+        }
+
+        format!(indoc!("
+            @{matcher}_internal_paths {{
+              host {app_name}.{domain}
+              path {paths}
+              not remote_ip {cidrs}{not_public}
+            }}
+            handle @{matcher}_internal_paths {{
+              {blocked}
+            }}
+        "),
+            matcher=self.matcher(),
+            app_name=self.app_name,
+            domain=self.domain(),
+            paths=self.internal_paths.join(" "),
+            cidrs=config().internal_cidrs.join(" "),
+            not_public=self.format_not_public_paths(),
+            blocked=self.blocked_path_action.directive(),
+        )
     }
 
-    fn format_local_caddy(&self) -> String {
+    /// Blocks clients outside the allowed (or inside the denied) countries. Like the internal
+    /// paths, this needs the real client address, so is only applied by the local Caddy.
+    fn format_geo_policy(&self) -> String {
+        if self.allow_countries.is_empty() && self.deny_countries.is_empty() {
+            return String::new();
+        }
+
+        let mut geo_rules = vec![format!("db_path \"{}\"", config().geoip_db_path.display())];
+        if !self.allow_countries.is_empty() {
+            geo_rules.push(format!("allow_countries {}", self.allow_countries.join(" ")));
+        }
+        if !self.deny_countries.is_empty() {
+            geo_rules.push(format!("deny_countries {}", self.deny_countries.join(" ")));
+        }
+
         format!(indoc!("
-            @{app_name} host {app_name}.{domain}
-              handle @{app_name} {{
-                handle /metrics {{
-                  abort
+            @{matcher}_geo_blocked {{
+              host {app_name}.{domain}
+              not remote_ip {cidrs}{not_public}
+              not maxmind_geolocation {{
+                {geo_rules}
+              }}
+            }}
+            handle @{matcher}_geo_blocked {{
+              {blocked}
+            }}
+        "),
+            matcher=self.matcher(),
+            app_name=self.app_name,
+            domain=self.domain(),
+            cidrs=config().internal_cidrs.join(" "),
+            not_public=self.format_not_public_paths(),
+            geo_rules=geo_rules.join("\n    "),
+            blocked=self.blocked_path_action.directive(),
+        )
+    }
+
+    /// Exempts the public paths from the local Caddy's access restrictions
+    fn format_not_public_paths(&self) -> String {
+        match self.public_paths.is_empty() {
+            true => String::new(),
+            false => format!("\n  not path {}", self.public_paths.join(" ")),
+        }
+    }
+
+    /// Handles the public paths before anything that could block them. That's ahead of the app's
+    /// own handle rather than in it, where Caddy would run any auth first.
+    fn format_public_paths(&self, domain: &str, upstream: &str) -> String {
+        if self.public_paths.is_empty() {
+            return String::new();
+        }
+
+        format!(
+            indoc!("
+                @{matcher}_public_paths {{
+                  host {app_name}.{domain}
+                  path {paths}
                 }}
-                handle /metrics/* {{
-                  abort
+                handle @{matcher}_public_paths {{
+                  reverse_proxy {upstream}
+                }}
+            "),
+            matcher=self.matcher(),
+            app_name=self.app_name,
+            domain=domain,
+            paths=self.public_paths.join(" "),
+            upstream=upstream,
+        )
+    }
+
+    fn format_local_caddy(&self) -> Result<String> {
+        template::render_local(&self.local_template_context())
+    }
+
+    /// What the local template renders the app's block from
+    fn local_template_context(&self) -> AppTemplateContext<'_> {
+        AppTemplateContext {
+            app: self,
+            domain: self.domain(),
+            generated: BTreeMap::from([
+                ("upstream", self.format_local_upstream("    ")),
+                ("internal_paths", self.format_internal_paths()),
+                ("geo_policy", self.format_geo_policy()),
+                ("public_paths", self.format_public_paths(self.domain(), &self.format_local_upstream("  "))),
+                ("matcher", self.matcher()),
+                ("blocked", self.blocked_path_action.directive().to_string()),
+                ("security_headers", self.format_security_headers()),
+            ]),
+        }
+    }
+
+    /// The caddy.extra directives, indented to match the handle block they're placed in
+    fn format_extra_directives(&self) -> String {
+        self.extra_directives.iter()
+            .map(|directive| directive.lines().collect::<Vec<_>>().join("\n    ") + "\n    ")
+            .collect()
+    }
+
+    /// The reverse_proxy options block for the retry policy and circuit breaker, if any options
+    /// are set
+    fn format_retry_policy(&self, upstreams: usize, indent: &str) -> String {
+        let policy = &self.retry_policy;
+        let circuit_breaker = self.circuit_breaker.options(upstreams);
+        if policy.is_empty() && circuit_breaker.is_empty() {
+            return String::new();
+        }
+
+        let options = [
+            ("lb_retries", policy.retries.map(|r| r.to_string())),
+            ("lb_try_duration", policy.try_duration.clone()),
+            ("lb_try_interval", policy.try_interval.clone()),
+        ];
+        let options = options.into_iter()
+            .filter_map(|(name, value)| Some((name, value?)))
+            .chain(circuit_breaker)
+            .map(|(name, value)| format!("\n{indent}  {name} {value}"))
+            .collect::<String>();
+        format!(" {{{options}\n{indent}}}")
+    }
+
+    /// Only external apps get security headers, as internal apps are often served over plain HTTP
+    /// or embedded by other internal tools
+    fn format_security_headers(&self) -> String {
+        let headers = self.security_headers.headers();
+        if !self.external || headers.is_empty() {
+            return String::new();
+        }
+
+        let headers = headers.iter()
+            .map(|(name, value)| format!("\n      {name} \"{value}\""))
+            .collect::<String>();
+        format!("header {{{headers}\n    }}\n    ")
+    }
+
+    /// Client auth is configured per site, so apps that need it get a dedicated site block rather
+    /// than being matched within the shared (wildcard) site.
+    fn format_local_client_auth_site(&self, client_auth: &ClientAuth) -> Result<String> {
+        Ok(format!(indoc!("
+            {app_name}.{domain} {{
+              {bind}tls {{
+                client_auth {{
+                  mode {mode}
+                  trust_pool file {ca_file}
                 }}
-                reverse_proxy http://localhost:880
               }}
-        "), app_name=self.app_name, domain=self.domain())
+              {local}
+            }}
+        "),
+            app_name=self.app_name,
+            domain=self.domain(),
+            mode=client_auth.mode.caddy_mode(),
+            ca_file=client_auth.ca_file,
+            bind=bind_directive(self.external),
+            local=self.format_local_caddy()?,
+        ))
     }
 
-    fn format_docker_caddy(&self) -> String {
-        let targets = self.containers
-            .iter()
-            .map(|adc|
-                format!(
-                    "http://{}:{}",
-                    match self.network_mode_host {
-                        true => "host.docker.internal",
-                        false => &adc.hostname
-                    },
-                    self.port
-                )
-            )
-            .collect::<Vec<String>>()
-            .join(" ");
-        format!(indoc!("
-            @{app_name} host {app_name}.{domain}
-              handle @{app_name} {{
-                handle /metrics {{
-                  abort
+    /// A plain HTTP site for the app, for LAN clients that can't do TLS. As an `http://` site is
+    /// defined for the host, Caddy won't redirect it to HTTPS.
+    fn format_local_insecure_http_site(&self) -> Result<String> {
+        Ok(format!(indoc!("
+            http://{app_name}.{domain} {{
+              {bind}{local}
+            }}
+        "),
+            app_name=self.app_name,
+            domain=self.domain(),
+            bind=bind_directive(self.external),
+            local=self.format_local_caddy()?,
+        ))
+    }
+
+    /// Everything rendered from the app's labels, with any secret:// references left in
+    fn format_blocks(&self) -> Result<AppBlocks> {
+        let mut local_sites = Vec::new();
+        if let Some(ref client_auth) = self.client_auth {
+            local_sites.push(self.format_local_client_auth_site(client_auth)?);
+        }
+        if self.insecure_http {
+            local_sites.push(self.format_local_insecure_http_site()?);
+        }
+
+        Ok(AppBlocks {
+            local_sites,
+            docker: self.format_docker_caddy()?,
+            // with client auth, the local block is only served in the app's own site
+            local: match self.client_auth {
+                Some(_) => None,
+                None => Some(self.format_local_caddy()?),
+            },
+        })
+    }
+
+    /// Site for the app's host on the external domain, if it's served only on the local domain
+    fn format_local_internal_host_site(&self) -> Option<String> {
+        self.format_local_internal_host_site_with(config().internal_host_response)
+    }
+
+    fn format_local_internal_host_site_with(&self, internal_host_response: InternalHostResponse) -> Option<String> {
+        let caddy = &config().local_caddy;
+        // with a shared domain, the app's host is the same either way
+        if caddy.external_domain == caddy.local_domain {
+            return None;
+        }
+        let response = match internal_host_response {
+            InternalHostResponse::None => return None,
+            InternalHostResponse::NotFound => "respond \"Not Found\" 404".to_string(),
+            InternalHostResponse::Redirect => format!("redir https://{}.{}{{uri}}", self.app_name, caddy.local_domain),
+        };
+        Some(format!(indoc!("
+            {app_name}.{domain} {{
+              {bind}{response}
+            }}
+        "),
+            app_name=self.app_name,
+            domain=caddy.external_domain,
+            bind=bind_directive(true),
+            response=response,
+        ))
+    }
+
+    fn format_docker_caddy(&self) -> Result<String> {
+        template::render_docker(&self.docker_template_context())
+    }
+
+    /// What the Docker template renders the app's block from
+    fn docker_template_context(&self) -> AppTemplateContext<'_> {
+        let upstreams = self.upstream_targets();
+        let targets = upstreams.join(" ");
+        let domain = self.domain_in(&config().docker_caddy);
+        AppTemplateContext {
+            app: self,
+            domain,
+            generated: BTreeMap::from([
+                ("extra", self.format_extra_directives()),
+                ("retry_policy", self.format_retry_policy(upstreams.len(), "    ")),
+                ("comments", self.format_comments()),
+                ("public_paths", self.format_public_paths(domain, &format!("{targets}{}", self.format_retry_policy(upstreams.len(), "  ")))),
+                ("matcher", self.matcher()),
+                ("blocked", self.blocked_path_action.directive().to_string()),
+                ("well_known", self.format_well_known(domain)),
+                ("auth", self.auth()),
+                ("targets", targets),
+            ]),
+        }
+    }
+
+    /// Where the Docker Caddy proxies the app to
+    fn upstream_targets(&self) -> Vec<String> {
+        // drained containers (see --oom-drain-threshold) get no traffic at all. Containers with a
+        // health check only get traffic once it passes, so they aren't sent requests while the
+        // app inside is still starting. Those failing it later are taken out too, unless no
+        // container is healthy - they may still serve some requests, where nothing else can. Of
+        // the rest, healthy containers go first, so `lb_policy first` only falls back to an
+        // unhealthy (or paused) one when there's nothing else. Then compose replicas go in replica
+        // order, and anything else by name, so the order doesn't depend on when each container was
+        // seen
+        let mut containers = self.containers.iter().filter(|adc| !adc.drained).collect::<Vec<_>>();
+        if config().compat_level > CompatLevel::V0_1 {
+            containers.retain(|adc| adc.health != Health::Starting);
+            if containers.iter().any(|adc| !adc.unhealthy()) {
+                containers.retain(|adc| adc.health != Health::Unhealthy);
+            }
+            containers.sort_by(|a, b| (a.unhealthy(), a.replica.is_none(), a.replica, &a.container_name)
+                .cmp(&(b.unhealthy(), b.replica.is_none(), b.replica, &b.container_name)));
+        }
+        // Caddy rejects a reverse_proxy without upstreams, which would fail the whole reload
+        if containers.is_empty() {
+            if let Some(last) = self.containers.last() {
+                warn!(app_name=self.app_name, container_name=last.container_name, "every container is drained or starting, keeping the last one as the upstream");
+                containers.push(last);
+            }
+        }
+        match self.upstream {
+            // the socket is mounted into the Caddy container, so there is only ever one target
+            AppUpstream::Socket(ref socket) => vec![format!("unix/{}", socket)],
+            AppUpstream::Port(port) => containers
+                .into_iter()
+                .filter_map(|adc| {
+                    let host = match self.network_mode_host {
+                        true => "host.docker.internal".to_string(),
+                        false => adc.upstream_host()?,
+                    };
+                    Some(adc.upstream_url(&self.app_name, &host, port))
+                })
+                .collect(),
+        }
+    }
+
+    /// Handles for the generated robots.txt and security.txt. They go ahead of the app's own handle
+    /// rather than in it, where Caddy would run any auth first, so crawlers and researchers can
+    /// read them. The file contents can't be indented, as that would end up in the response.
+    fn format_well_known(&self, domain: &str) -> String {
+        let text_file = |name: &str, path: &str, body: String| format!(
+            indoc!("
+                @{matcher}_{name} {{
+                  host {app_name}.{domain}
+                  path {path}
                 }}
-                handle /metrics/* {{
-                  abort
+                handle @{matcher}_{name} {{
+                  header Content-Type \"text/plain; charset=utf-8\"
+                  respond `{body}` 200
                 }}
-                {auth}
-                reverse_proxy {targets}
-              }}
-        "), app_name=self.app_name, domain=self.domain(), auth=self.auth(), targets=targets)
+            "),
+            matcher=self.matcher(),
+            name=name,
+            app_name=self.app_name,
+            domain=domain,
+            path=path,
+            body=body,
+        );
+
+        let mut handles = String::new();
+        if self.robots_deny {
+            handles.push_str(&text_file("robots", "/robots.txt", "User-agent: *\nDisallow: /\n".to_string()));
+        }
+        if let Some(ref contact) = self.security_contact {
+            // RFC 9116 requires an expiry - the end of next year only changes the snippets yearly
+            let expires = chrono::Utc::now().year() + 1;
+            handles.push_str(&text_file(
+                "security_txt",
+                "/.well-known/security.txt",
+                format!("Contact: {contact}\nExpires: {expires}-12-31T23:59:59Z\n"),
+            ));
+        }
+        handles
+    }
+
+    fn format_comments(&self) -> String {
+        if !config().snippet_comments {
+            return String::new();
+        }
+
+        self.containers
+            .iter()
+            .map(|adc| format!(
+                "# {}: image {}, started {}{}\n",
+                adc.container_name,
+                adc.image.as_deref().unwrap_or("unknown"),
+                adc.started_at.as_deref().unwrap_or("unknown"),
+                match (adc.health, adc.paused) {
+                    (Health::Starting, _) => ", health check starting (not an upstream)",
+                    (Health::Unhealthy, _) => ", unhealthy (an upstream only if nothing is healthy)",
+                    (_, true) => ", paused",
+                    _ => "",
+                },
+            ))
+            .collect()
     }
 }
 
-#[derive(Debug)]
+/// Label compose numbers the replicas of a scaled service with
+const COMPOSE_CONTAINER_NUMBER_LABEL: &str = "com.docker.compose.container-number";
+/// Labels compose names the project and service a container belongs to with
+const COMPOSE_PROJECT_LABEL: &str = "com.docker.compose.project";
+const COMPOSE_SERVICE_LABEL: &str = "com.docker.compose.service";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct AppContainerData {
     container_id: String,
     container_name: String,
     hostname: String,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    /// The network the container is reached on (see --proxy-network)
+    network: Option<String>,
+    /// Not attached to the network from --proxy-network or the network label, so the docker Caddy
+    /// can't reach it
+    #[serde(skip)]
+    off_network: bool,
+    image: Option<String>,
+    started_at: Option<String>,
+    #[serde(default)]
+    paused: bool,
+    #[serde(default)]
+    health: Health,
+    /// OOM-killed repeatedly, so left out of the upstreams for now (see --oom-drain-threshold)
+    #[serde(default)]
+    drained: bool,
+    /// The replica number given by compose when a service is scaled
+    replica: Option<u32>,
+    /// The compose project and service the container belongs to
+    project: Option<String>,
+    service: Option<String>,
 }
 
 impl AppContainerData {
+    /// Paused, or failing its health check
+    fn unhealthy(&self) -> bool {
+        self.paused || self.health == Health::Unhealthy
+    }
+
     fn new_from_summary(summary: &ContainerSummaryInternal) -> Option<Self> {
         if let Some(labels) = &summary.labels {
             if !labels.contains_key(&config().app_name_label) {
@@ -400,104 +2756,315 @@ impl AppContainerData {
                 let container_id = summary.id.clone();
                 let container_name = summary.container_name.clone();
 
+                let (network, ipv4, ipv6, off_network) = match labels.get(&config().network_label).or(config().upstream.network.as_ref()) {
+                    Some(wanted) => match summary.networks.iter().find(|network| &network.name == wanted) {
+                        Some(network) => (Some(network.name.clone()), network.ipv4, network.ipv6, false),
+                        None => {
+                            warn!(container_name, network=wanted, "container is not attached to the proxy network, leaving it out of the upstreams");
+                            (None, None, None, true)
+                        },
+                    },
+                    // the first address of each family, which may be on different networks
+                    None => (
+                        summary.networks.first().map(|network| network.name.clone()),
+                        summary.networks.iter().find_map(|network| network.ipv4),
+                        summary.networks.iter().find_map(|network| network.ipv6),
+                        false,
+                    ),
+                };
+
                 Some(Self {
                     container_id,
                     container_name,
                     hostname,
+                    ipv4,
+                    ipv6,
+                    network,
+                    off_network,
+                    image: summary.image.clone(),
+                    started_at: summary.started_at.clone(),
+                    paused: summary.paused,
+                    health: summary.health,
+                    drained: false,
+                    replica: labels.get(COMPOSE_CONTAINER_NUMBER_LABEL).and_then(|n| n.parse().ok()),
+                    project: labels.get(COMPOSE_PROJECT_LABEL).cloned(),
+                    service: labels.get(COMPOSE_SERVICE_LABEL).cloned(),
                 })
             }
         } else {
             None
         }
     }
+
+    /// The upstream URL for this container, from --upstream-url-template
+    fn upstream_url(&self, app_name: &str, host: &str, port: u16) -> String {
+        self.fill_url_template(&config().upstream.url_template, app_name, host, port)
+    }
+
+    fn fill_url_template(&self, template: &str, app_name: &str, host: &str, port: u16) -> String {
+        template
+            .replace("{host}", host)
+            .replace("{name}", &self.container_name)
+            .replace("{network}", self.network.as_deref().unwrap_or_default())
+            .replace("{app}", app_name)
+            .replace("{port}", &port.to_string())
+    }
+
+    /// The host portion of the upstream URL, based on the configured address mode - IPv6 addresses
+    /// are bracketed so they can be used directly in a URL.
+    fn upstream_host(&self) -> Option<String> {
+        if self.off_network {
+            return None;
+        }
+
+        let ip = match config().upstream.address_mode {
+            UpstreamAddressMode::Name => return Some(self.hostname.clone()),
+            UpstreamAddressMode::Ip => config().upstream.ip_family.pick(self.ipv4, self.ipv6),
+        };
+
+        match ip {
+            Some(IpAddr::V4(ip)) => Some(ip.to_string()),
+            Some(IpAddr::V6(ip)) => Some(format!("[{}]", ip)),
+            // if no container has one, the app is left out of the snippets
+            None => {
+                if let Some(r) = recurring::check("no_upstream_ip", &self.container_name) {
+                    warn!(container_name=self.container_name, ip_family=?config().upstream.ip_family, r.suppressed, persisting=?r.persisting, "no IP address of the requested family found for container, skipping upstream");
+                }
+                None
+            }
+        }
+    }
 }
 
 struct Listener {
     app_data: ApplicationData,
-    dns_client: PowerDnsClient,
+    /// Apps whose last container was destroyed, and when to remove them if no replacement appears
+    pending_removals: HashMap<String, Instant>,
+    /// Changes waiting out the reload debounce window
+    pending_write: Option<PendingWrite>,
+    /// When the next reconciliation sweep is due, if they're enabled
+    next_sweep: Option<Instant>,
+    /// When the exposure report is next logged, if it's periodic
+    next_report: Option<Instant>,
+    /// Tracked containers missing from the sweeps, and when they were first found to be missing
+    missing_since: HashMap<String, Instant>,
+    /// The snippets last written and successfully reloaded
+    last_rendered: Option<RenderedSnippets>,
+    /// Reload Caddy on the next write even if our snippets are unchanged (e.g., orphaned snippet
+    /// files were removed)
+    force_reload: bool,
+    /// Containers already inspected and found to have no app label, so their events don't cause
+    /// another inspect
+    unlabelled: HashSet<String>,
+    inspect_cache: InspectCache,
+    /// The apps as of the last transitions written for --emit-events
+    emitted: emit::EmittedApps,
+    /// The hosts left out of the snippets as of the last transitions written
+    rejected: emit::EmittedRejections,
+    /// The apps as of the last --verify-after-reload
+    verified: VerifiedApps,
+    dns_client: Option<PowerDnsClient>,
+    generations: Option<SnippetGenerations>,
+    /// The apps with containers as of the last write, for --max-deletions
+    served_apps: HashSet<String>,
+    /// Write the next removals even if they're over --max-deletions
+    deletions_confirmed: bool,
+    /// When each container was recently OOM-killed, for --oom-drain-threshold
+    oom_kills: HashMap<String, Vec<Instant>>,
+    /// Containers drained after repeated OOM kills, and when to restore them
+    drained: HashMap<String, Instant>,
 }
 
 impl Listener {
     fn new() -> Result<Self> {
-        let dns_conf = match &config().dns_provider {
-            DnsProvider::PowerDNS(conf) => conf,
-            _ => return Err("currently only support PowerDNS as a client".into()),
-        };
         Ok(Self {
             app_data: HashMap::new(),
-            dns_client: PowerDnsClient::new(
-                Url::parse(&dns_conf.url)?,
-                dns_conf.server.to_string(),
-                dns_conf.api_key.to_string(),
-            )?
+            pending_removals: HashMap::new(),
+            pending_write: None,
+            next_sweep: None,
+            next_report: None,
+            missing_since: HashMap::new(),
+            last_rendered: None,
+            force_reload: false,
+            unlabelled: HashSet::new(),
+            inspect_cache: InspectCache::default(),
+            emitted: emit::EmittedApps::new(),
+            rejected: emit::EmittedRejections::new(),
+            verified: VerifiedApps::new(),
+            dns_client: new_dns_client()?,
+            generations: match config().snippet_generations {
+                0 => None,
+                keep => Some(SnippetGenerations::load(keep)?),
+            },
+            served_apps: HashSet::new(),
+            deletions_confirmed: false,
+            oom_kills: HashMap::new(),
+            drained: HashMap::new(),
         })
     }
 
-    async fn write_caddy_snippets(&self) -> Result<()> {
-        let mut docker_hosts_file = File::options().create(true).write(true).truncate(true).open(config().docker_caddy.snippets_dir.join("docker-hosts"))?;
-        let mut local_docker_hosts_file = File::options().create(true).write(true).truncate(true).open(config().local_caddy.snippets_dir.join("docker-hosts"))?;
-        let mut external_hosts = Vec::new();
-        let mut local_external_hosts = Vec::new();
-        let mut internal_hosts = Vec::new();
-        let mut local_internal_hosts = Vec::new();
+    async fn write_caddy_snippets(&mut self, change: &SnippetChange<'_>) -> Result<()> {
+        // whatever was waiting to be written is covered by this write
+        self.pending_write = None;
+        if self.deletions_held(change) {
+            return Ok(());
+        }
+        // containers may have been rediscovered since they were drained
+        for adc in self.app_data.values_mut().flat_map(|ad| ad.containers.iter_mut()) {
+            adc.drained = self.drained.contains_key(&adc.container_id);
+        }
+        emit::emit_app_changes(&mut self.emitted, &self.app_data);
+        emit::emit_rejections(&mut self.rejected, &self.app_data);
+        if let Some(path) = config().state_file.as_ref().filter(|_| !config().dry_run) {
+            state::save(path, &self.app_data);
+        }
+        let started = std::time::Instant::now();
+        let rendered = render_snippets(&self.app_data)?;
+        metrics::observe(Stage::Render, started.elapsed());
+        let must_reload = std::mem::take(&mut self.force_reload);
+        if !must_reload && self.last_rendered.as_ref() == Some(&rendered) {
+            info!(?change, "snippets unchanged, skipping write, reload & DNS update");
+            return Ok(());
+        }
+
+        if config().dry_run {
+            info!(?change, "dry run - printing the snippets instead of writing them");
+            rendered.write_marked(&mut std::io::stdout().lock())?;
+            self.last_rendered = Some(rendered);
+            return Ok(());
+        }
 
-        for (key, ad) in self.app_data.iter() {
-            if ad.containers.is_empty() {
-                warn!(app_name=key, "app is in the map but has no running containers...");
-                continue;
+        // nothing has been written since starting, but the files may already hold these snippets
+        // from before a restart - unless they failed to reload, Caddy is already serving them
+        let reloaded_before = self.generations.as_ref().is_none_or(SnippetGenerations::latest_is_good);
+        if !must_reload && self.last_rendered.is_none() && reloaded_before && rendered.matches_files() {
+            info!(?change, "snippet files unchanged, skipping write & reload");
+            self.last_rendered = Some(rendered);
+            // DNS isn't recorded in the files, so may not have been updated before the restart
+            self.update_dns().await.map_err(|e| Failure::wrap(FailureKind::Dns, e))?;
+            return Ok(());
+        }
+
+        // held until Caddy has reloaded, so another writer can't swap the files out in between
+        let _locks = SnippetLocks::acquire().await?;
+        if let Some(ref last_written) = self.last_rendered {
+            snippet_lock::warn_if_modified(last_written)?;
+        }
+        let previous_files = match config().validate_before_reload {
+            true => Some(SnippetFileBackup::read()?),
+            false => None,
+        };
+        let started = std::time::Instant::now();
+        rendered.write_files()?;
+        metrics::observe(Stage::Write, started.elapsed());
+        if let Some(previous_files) = previous_files {
+            if let Err(e) = self.validate_caddy().await {
+                previous_files.restore()?;
+                error!(?change, error=%e, "new snippets failed validation, keeping the previous snippets");
+                emit::emit(emit::Transition::ReloadFailed { action: change.action, app: change.app_name, error: e.to_string() });
+                // the next change retries, with these snippets counted as unwritten
+                return Ok(());
             }
+        }
+        let generation = match self.generations {
+            Some(ref mut generations) => Some(generations.record(&rendered)?),
+            None => None,
+        };
 
-            if ad.external {
-                //println!("writing line [{line}] to external");
-                external_hosts.push(ad.format_docker_caddy());
-                local_external_hosts.push(ad.format_local_caddy());
-            } else {
-                //println!("writing line [{line}] to internal");
-                internal_hosts.push(ad.format_docker_caddy());
-                local_internal_hosts.push(ad.format_local_caddy());
-            };
+        if config().git_history {
+            history::commit_snippets(&config().local_caddy.snippets_dir, change);
+            if config().docker_caddy.snippets_dir != config().local_caddy.snippets_dir {
+                history::commit_snippets(&config().docker_caddy.snippets_dir, change);
+            }
         }
-        write!(&mut docker_hosts_file, indoc!("
-            (external_docker_hosts) {{
-              {}
-            }}
 
-            (internal_docker_hosts) {{
-              {}
-            }}
-            "), external_hosts.join("\n  "), internal_hosts.join("\n  "))?;
+        if let Some(ref hook) = config().reload_hooks.pre_reload {
+            run_reload_hook("pre-reload", hook, change, &[]);
+        }
 
-        write!(&mut local_docker_hosts_file, indoc!("
-            (external_docker_hosts) {{
-              {}
-            }}
+        let mut reload_result = self.reload_caddy().await.map_err(|e| Failure::wrap(FailureKind::Reload, e));
 
-            (internal_docker_hosts) {{
-              {}
-            }}
-            "), local_external_hosts.join("\n  "), local_internal_hosts.join("\n  "))?;
+        if let Some(ref hook) = config().reload_hooks.post_reload {
+            let status = if reload_result.is_ok() { "success" } else { "failure" };
+            run_reload_hook("post-reload", hook, change, &[("DOCKER_CADDY_RELOAD_STATUS", status)]);
+        }
+        if let (Ok(()), Some(ref verify)) = (&reload_result, &config().verify) {
+            if let Err(e) = verify::verify_changed(verify, &mut self.verified, &self.app_data).await {
+                if verify.on_failure == VerifyFailureAction::Rollback {
+                    reload_result = Err(Failure::wrap(FailureKind::Reload, e));
+                }
+            }
+        }
 
-        docker_hosts_file.sync_all()?;
-        local_docker_hosts_file.sync_all()?;
+        emit::emit(match reload_result {
+            Ok(()) => emit::Transition::Reloaded { action: change.action, app: change.app_name },
+            Err(ref e) => emit::Transition::ReloadFailed { action: change.action, app: change.app_name, error: e.to_string() },
+        });
+
+        match (reload_result, &mut self.generations) {
+            (Ok(()), Some(generations)) => generations.mark_good(generation.expect("generation is recorded if enabled"))?,
+            (Ok(()), None) => {},
+            (Err(e), Some(generations)) => {
+                let Some(last_good) = generations.last_good() else {
+                    return Err(e);
+                };
+
+                error!(error=%e, last_good, failed=generation, "unable to reload Caddy, rolling back to the last good snippets");
+                let restored = generations.restore(last_good)?;
+                restored.write_files()?;
+                self.reload_caddy().await
+                    .map_err(|rollback_error| Failure::wrap(
+                        FailureKind::Reload,
+                        format!("unable to reload Caddy after rolling back to generation {last_good}: {rollback_error} (original error: {e})").into(),
+                    ))?;
+                error!(last_good, failed=generation, "rolled back to the last good snippets - Caddy is not serving the latest container changes");
+
+                // DNS is left alone, as it would no longer match what Caddy is serving
+                self.last_rendered = Some(restored);
+                return Ok(());
+            },
+            (Err(e), None) => {
+                let Some(restored) = self.last_rendered.take() else {
+                    return Err(e);
+                };
+
+                error!(error=%e, "unable to reload Caddy, rolling back to the snippets it last reloaded");
+                restored.write_files()?;
+                self.reload_caddy().await
+                    .map_err(|rollback_error| Failure::wrap(
+                        FailureKind::Reload,
+                        format!("unable to reload Caddy after rolling back to the previous snippets: {rollback_error} (original error: {e})").into(),
+                    ))?;
+                error!("rolled back to the previous snippets - Caddy is not serving the latest container changes");
+
+                // DNS is left alone, as it would no longer match what Caddy is serving
+                self.last_rendered = Some(restored);
+                return Ok(());
+            },
+        }
 
-        self.reload_caddy().await?;
+        self.sync_remote_caddies().await;
+        self.last_rendered = Some(rendered);
 
-        self.update_dns().await?;
+        self.update_dns().await.map_err(|e| Failure::wrap(FailureKind::Dns, e))?;
 
         Ok(())
     }
 
     async fn reload_local_caddy(&self, config: &CaddyConfig) -> Result<()> {
         info!("reloading local-caddy...");
-        let exit_status = std::process::Command::new(&config.bin_path)
-            .current_dir(config.config_dir.to_str().ok_or("unable to get local caddy config dir as string")?)
-            .args(["reload"])
+        self.run_local_caddy(config, "reload")
+    }
+
+    /// Run a caddy subcommand (e.g., reload or validate) that takes the Caddyfile
+    fn run_local_caddy(&self, config: &CaddyConfig, subcommand: &str) -> Result<()> {
+        let exit_status = local_caddy_command(config, subcommand, crate::config().local_reload_dir.as_deref(), &crate::config().local_reload_env)?
             .spawn()?
             .wait()?;
 
         if !exit_status.success() {
-            error!(code=exit_status.code(), "unable to reload local Caddy");
-            return Err(format!("unable to reload local Caddy - exited with status {}", exit_status.code().unwrap_or(-1)).into());
+            error!(code=exit_status.code(), subcommand, "unable to run local Caddy");
+            return Err(format!("unable to {subcommand} local Caddy - exited with status {}", exit_status.code().unwrap_or(-1)).into());
         }
 
         Ok(())
@@ -505,6 +3072,11 @@ impl Listener {
 
     async fn reload_docker_caddy(&self, config: &CaddyConfig, container_name: &str) -> Result<()> {
         info!(container_name, "reloading docker-caddy...");
+        self.exec_docker_caddy(config, container_name, "reload").await
+    }
+
+    /// Run a caddy subcommand (e.g., reload or validate) in the Caddy container
+    async fn exec_docker_caddy(&self, config: &CaddyConfig, container_name: &str, subcommand: &str) -> Result<()> {
         let docker = new_docker()?;
         let opts = ContainerListOpts::builder()
             .filter(vec![ContainerFilter::Name(format!("^/{}$", container_name))])
@@ -514,246 +3086,2964 @@ impl Listener {
             return Err("expected only a single container with the caddy container name".into());
         }
 
-        let caddy_container = docker.containers().get(search_results[0].id.as_ref().expect("containers must always have an ID"));
+        let caddy_container_id = search_results[0].id.as_ref().expect("containers must always have an ID");
 
         let create_opts = ExecCreateOpts::builder()
             .working_dir(&config.config_dir)
             .attach_stdout(true)
             .attach_stderr(true)
-            .command(vec!["sh", "-c", format!("DO_API_KEY=\"$(cat \"$DO_API_KEY_FILE\")\" {} reload", config.bin_path.to_str().ok_or("could not turn caddy docker bin path into string")?).as_str()])
+            .command(vec!["sh", "-c", format!("{}{} {subcommand}", secret_env_assignments(&crate::config().docker_secret_env), config.bin_path.to_str().ok_or("could not turn caddy docker bin path into string")?).as_str()])
             .build();
         let start_opts = ExecStartOpts::builder().build();
 
-        let mut result = caddy_container.exec(&create_opts, &start_opts).await?;
+        let exec = Exec::create(docker, caddy_container_id, &create_opts).await?;
+        let mut result = exec.start(&start_opts).await?;
+        let mut output = String::new();
         while let Some(chunk) = result.next().await {
             match chunk? {
                 TtyChunk::StdIn(_) => unreachable!("never attached"),
-                TtyChunk::StdOut(bytes) => info!("{}", str::from_utf8(&bytes).unwrap_or_default()),
-                TtyChunk::StdErr(bytes) => warn!("{}", str::from_utf8(&bytes).unwrap_or_default()),
+                TtyChunk::StdOut(bytes) | TtyChunk::StdErr(bytes) => output.push_str(&String::from_utf8_lossy(&bytes)),
             }
         }
 
+        match exec.inspect().await?.exit_code {
+            Some(0) => {
+                debug!(container_name, subcommand, output, "ran caddy in docker-caddy");
+                Ok(())
+            },
+            exit_code => Err(format!(
+                "caddy {subcommand} in container {container_name} failed with exit code {}: {}",
+                exit_code.map(|c| c.to_string()).unwrap_or_else(|| "unknown".to_string()),
+                truncate_exec_output(&output),
+            ).into()),
+        }
+    }
+
+    /// Load the Caddyfile through the admin API. Caddy adapts it, so the Caddy binary isn't needed
+    /// here, and the running Caddy's own environment is used for any placeholders.
+    async fn reload_admin_api_caddy(&self, url: &Url, caddyfile: &PathBuf) -> Result<()> {
+        info!(%url, caddyfile=%caddyfile.display(), "reloading Caddy through its admin API...");
+        let content = std::fs::read_to_string(caddyfile)
+            .map_err(|e| format!("unable to read Caddyfile {}: {e}", caddyfile.display()))?;
+        let (status, body) = caddy_admin::post_caddyfile(url, "load", content).await?;
+        if !status.is_success() {
+            return Err(format!("reloading Caddy through {url} failed with status {status}: {}", truncate_exec_output(&body)).into());
+        }
+
+        debug!(%url, "reloaded Caddy through its admin API");
         Ok(())
     }
 
-    async fn reload_caddy(&self) -> Result<()> {
-        match config().docker_caddy.location {
-            CaddyLocation::Local => self.reload_local_caddy(&config().docker_caddy).await?,
-            CaddyLocation::Docker(ref container_name) => self.reload_docker_caddy(&config().docker_caddy, container_name).await?,
+    /// Adapt the Caddyfile through the admin API, which fails if it can't be parsed - without
+    /// loading it
+    async fn adapt_admin_api_caddy(&self, url: &Url, caddyfile: &PathBuf) -> Result<()> {
+        let content = std::fs::read_to_string(caddyfile)
+            .map_err(|e| format!("unable to read Caddyfile {}: {e}", caddyfile.display()))?;
+        let (status, body) = caddy_admin::post_caddyfile(url, "adapt", content).await?;
+        if !status.is_success() {
+            return Err(format!("adapting the Caddyfile through {url} failed with status {status}: {}", truncate_exec_output(&body)).into());
         }
 
-        match config().local_caddy.location {
-            CaddyLocation::Local => self.reload_local_caddy(&config().local_caddy).await?,
-            CaddyLocation::Docker(ref container_name) => self.reload_docker_caddy(&config().local_caddy, container_name).await?,
+        Ok(())
+    }
+
+    /// Check both Caddy instances accept their configs with the snippets as written
+    async fn validate_caddy(&self) -> Result<()> {
+        for caddy_config in [&config().docker_caddy, &config().local_caddy] {
+            info!(config_dir=%caddy_config.config_dir.display(), "validating Caddy config...");
+            match caddy_config.location {
+                CaddyLocation::Local => self.run_local_caddy(caddy_config, "validate")?,
+                CaddyLocation::Docker(ref container_name) => self.exec_docker_caddy(caddy_config, container_name, "validate").await?,
+                CaddyLocation::AdminApi { ref url, ref caddyfile } => self.adapt_admin_api_caddy(url, caddyfile).await?,
+            }
         }
 
         Ok(())
     }
 
-    async fn update_dns(&self) -> Result<()> {
-        // let mut hosts = config().static_hosts.clone();
+    async fn reload_caddy(&self) -> Result<()> {
+        for caddy_config in [&config().docker_caddy, &config().local_caddy] {
+            self.reload_instance(caddy_config).await?;
+        }
 
-        let local_ipv4 = match local_ip() {
-            Ok(v) => Some(match v {
-                IpAddr::V4(v) => v,
-                _ => return Err("updating DNS, expected IPv4, got IPv6".into())
-            }),
-            Err(LocalIpAddressNotFound) => None,
-            Err(e) => return Err(e.into()),
-        };
-        let local_ipv6 = match local_ipv6() {
-            Ok(v) => Some(match v {
-                IpAddr::V6(v) => v,
-                _ => return Err("updating DNS, expected IPv6, got IPv4".into())
-            }),
-            Err(LocalIpAddressNotFound) => None,
-            Err(e) => return Err(e.into()),
-        };
+        self.reload_local_caddy_peers().await
+    }
 
-        let mut internal_dns = Vec::new();
-        let mut external_dns = Vec::new();
+    /// Pass the reloaded local snippets on to the Caddy servers over SSH and the agents. Those are
+    /// on other hosts, so a failure is only logged - the local Caddy isn't rolled back for it.
+    async fn sync_remote_caddies(&self) {
+        if let Some(ref ssh_caddy) = config().ssh_caddy {
+            if let Err(e) = ssh_caddy.sync().await {
+                warn!(error=%e, "unable to sync the snippets to Caddy over SSH");
+            }
+        }
 
-        for (key, ad) in self.app_data.iter() {
-            if ad.containers.is_empty() {
-                warn!(app_name=key, "app is in the map but has no running containers - deleting from DNS");
-                if ad.external {
-                    internal_dns.push(PowerDnsApiRRSet::delete_ipv4(&ad.app_name, &config().external_domain));
-                    external_dns.push(PowerDnsApiRRSet::delete_ipv6(&ad.app_name, &config().external_domain));
-                } else {
-                    internal_dns.push(PowerDnsApiRRSet::delete_ipv4(&ad.app_name, &config().local_domain));
+        if let Err(e) = agent::push_to_agents().await {
+            warn!(error=%e, "unable to send the snippets to the agents");
+        }
+    }
+
+    /// Reload a single Caddy instance, wherever it is
+    async fn reload_instance(&self, caddy_config: &CaddyConfig) -> Result<()> {
+        let started = std::time::Instant::now();
+        let stage = match caddy_config.location {
+            CaddyLocation::Local => {
+                self.reload_local_caddy(caddy_config).await?;
+                Stage::LocalReload
+            },
+            CaddyLocation::Docker(ref container_name) => {
+                self.reload_docker_caddy(caddy_config, container_name).await?;
+                Stage::DockerExecReload
+            },
+            CaddyLocation::AdminApi { ref url, ref caddyfile } => {
+                self.reload_admin_api_caddy(url, caddyfile).await?;
+                Stage::AdminApiReload
+            },
+        };
+        metrics::observe(stage, started.elapsed());
+
+        Ok(())
+    }
+
+    /// Push the local Caddyfile to every other member of the local Caddy's reload group. All of
+    /// them are tried, so one unreachable member doesn't leave the rest on the old snippets.
+    async fn reload_local_caddy_peers(&self) -> Result<()> {
+        self.reload_peers(&config().local_caddy_peers, &config().local_caddy.config_dir.join("Caddyfile")).await
+    }
+
+    async fn reload_peers(&self, peers: &[Url], caddyfile: &PathBuf) -> Result<()> {
+        let mut failed = Vec::new();
+        for url in peers {
+            let started = std::time::Instant::now();
+            match self.reload_admin_api_caddy(url, caddyfile).await {
+                Ok(()) => metrics::observe(Stage::AdminApiReload, started.elapsed()),
+                Err(e) => {
+                    error!(%url, error=%e, "unable to reload local Caddy reload group member");
+                    failed.push(format!("{url}: {e}"));
+                },
+            }
+        }
+
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(format!("{} of {} local Caddy reload group member(s) failed to reload - {}", failed.len(), peers.len(), failed.join("; ")).into()),
+        }
+    }
+
+    async fn update_dns(&self) -> Result<()> {
+        let started = std::time::Instant::now();
+        let rrsets_by_zone = self.dns_changes().await?;
+        if let Some(ref dns_client) = self.dns_client {
+            for (zone, rrsets) in rrsets_by_zone {
+                backup_zone_before_changes(dns_client, &zone, &rrsets).await?;
+                dns_client.update_rrsets(&zone, PowerDnsApiRRSets { rrsets }).await?;
+            }
+        }
+        metrics::observe(Stage::Dns, started.elapsed());
+
+        Ok(())
+    }
+
+    /// The DNS records for the current apps, by PowerDNS zone
+    async fn dns_changes(&self) -> Result<HashMap<String, Vec<PowerDnsApiRRSet>>> {
+        // let mut hosts = config().static_hosts.clone();
+
+        let local_ipv4 = match local_ip() {
+            Ok(v) => Some(match v {
+                IpAddr::V4(v) => v,
+                _ => return Err("updating DNS, expected IPv4, got IPv6".into())
+            }),
+            Err(LocalIpAddressNotFound) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let local_ipv6 = match local_ipv6() {
+            Ok(v) => Some(match v {
+                IpAddr::V6(v) => v,
+                _ => return Err("updating DNS, expected IPv6, got IPv4".into())
+            }),
+            Err(LocalIpAddressNotFound) => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut internal_dns = Vec::new();
+        let mut external_dns = Vec::new();
+
+        // PowerDNS is currently the only provider, so only its apps have records to manage
+        let apps = self.app_data.values()
+            .filter(|ad| ad.dns_provider == DnsProviderCli::PowerDNS)
+            .flat_map(AppData::with_port_variants)
+            .collect::<Vec<_>>();
+        if apps.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let Some(ref dns_client) = self.dns_client else {
+            if let Some(r) = recurring::check("power_dns_unconfigured", "") {
+                warn!(r.suppressed, persisting=?r.persisting, "apps use PowerDNS, but the --power-dns-* options are not set - skipping DNS update");
+            }
+            return Ok(HashMap::new());
+        };
+
+        for ad in apps {
+            if ad.containers.is_empty() {
+                if let Some(r) = recurring::check("no_containers_dns", &ad.app_name) {
+                    warn!(app_name=ad.app_name, r.suppressed, persisting=?r.persisting, "app is in the map but has no running containers - deleting from DNS");
+                }
+                if ad.external {
+                    internal_dns.push(PowerDnsApiRRSet::delete_ipv4(&ad.app_name, &config().local_caddy.external_domain));
+                    external_dns.push(PowerDnsApiRRSet::delete_ipv6(&ad.app_name, &config().local_caddy.external_domain));
+                } else {
+                    internal_dns.push(PowerDnsApiRRSet::delete_ipv4(&ad.app_name, &config().local_caddy.local_domain));
                 }
             } else if ad.external {
                 if let Some(ref ipv4) = local_ipv4 {
-                    internal_dns.push(PowerDnsApiRRSet::new_ipv4(&ad.app_name, &config().external_domain, ipv4));
-                    external_dns.push(PowerDnsApiRRSet::new_ipv4(&ad.app_name, &config().external_domain, ipv4));
+                    internal_dns.push(PowerDnsApiRRSet::new_ipv4(&ad.app_name, &config().local_caddy.external_domain, ipv4));
+                    external_dns.push(PowerDnsApiRRSet::new_ipv4(&ad.app_name, &config().local_caddy.external_domain, ipv4));
                 }
                 if let Some(ref ipv6) = local_ipv6 {
-                    internal_dns.push(PowerDnsApiRRSet::new_ipv6(&ad.app_name, &config().external_domain, ipv6));
-                    external_dns.push(PowerDnsApiRRSet::new_ipv6(&ad.app_name, &config().external_domain, ipv6));
+                    internal_dns.push(PowerDnsApiRRSet::new_ipv6(&ad.app_name, &config().local_caddy.external_domain, ipv6));
+                    external_dns.push(PowerDnsApiRRSet::new_ipv6(&ad.app_name, &config().local_caddy.external_domain, ipv6));
                 }
             } else {
                 if let Some(ref ipv4) = local_ipv4 {
-                    internal_dns.push(PowerDnsApiRRSet::new_ipv4(&ad.app_name, &config().local_domain, ipv4));
+                    internal_dns.push(PowerDnsApiRRSet::new_ipv4(&ad.app_name, &config().local_caddy.local_domain, ipv4));
                 }
                 if let Some(ref ipv6) = local_ipv6 {
-                    internal_dns.push(PowerDnsApiRRSet::new_ipv6(&ad.app_name, &config().local_domain, ipv6));
+                    internal_dns.push(PowerDnsApiRRSet::new_ipv6(&ad.app_name, &config().local_caddy.local_domain, ipv6));
                 }
             };
+
+            if let Some(ref instance) = config().instance_name {
+                internal_dns.push(match ad.containers.is_empty() {
+                    true => PowerDnsApiRRSet::delete_owner_txt(&ad.app_name, ad.domain()),
+                    false => PowerDnsApiRRSet::new_owner_txt(&ad.app_name, ad.domain(), instance),
+                });
+            }
+        }
+
+        let zones = dns_zones(dns_client).await?;
+        let mut rrsets_by_zone: HashMap<String, Vec<PowerDnsApiRRSet>> = HashMap::new();
+        for rrset in internal_dns {
+            match zone_for_name(&rrset.name, &zones) {
+                Some(zone) => rrsets_by_zone.entry(zone.to_string()).or_default().push(rrset),
+                None => if let Some(r) = recurring::check("no_dns_zone", &rrset.name) {
+                    warn!(name=rrset.name, r.suppressed, persisting=?r.persisting, "no PowerDNS zone found for record, skipping");
+                },
+            }
+        }
+
+        // with an instance name, only names that are free or marked as this instance's are changed
+        if let Some(ref instance) = config().instance_name {
+            for (zone, rrsets) in rrsets_by_zone.iter_mut() {
+                let existing = dns_client.list_zone(zone).await?
+                    .and_then(|zone| zone.rrsets)
+                    .unwrap_or_default();
+                rrsets.retain(|rrset| match check_owner(&rrset.name, &existing, instance) {
+                    Ok(()) => true,
+                    Err(owner) => {
+                        if let Some(r) = recurring::check("dns_not_owned", &rrset.name) {
+                            warn!(name=rrset.name, ?owner, r.suppressed, persisting=?r.persisting, "DNS name isn't marked as this instance's, not changing it");
+                        }
+                        false
+                    },
+                });
+            }
+            rrsets_by_zone.retain(|_, rrsets| !rrsets.is_empty());
+        }
+
+        Ok(rrsets_by_zone)
+    }
+
+    /// Whether the apps removed since the last write are over --max-deletions and not yet
+    /// confirmed, in which case nothing is written. Otherwise, the apps being served are updated.
+    fn deletions_held(&mut self, change: &SnippetChange) -> bool {
+        let served = self.app_data.iter()
+            .filter(|(_, ad)| !ad.containers.is_empty())
+            .map(|(app_name, _)| app_name.clone())
+            .collect::<HashSet<_>>();
+        let mut deleted = self.served_apps.difference(&served).cloned().collect::<Vec<_>>();
+        let over_limit = config().max_deletions.is_some_and(|limit| limit.exceeded(deleted.len(), self.served_apps.len()));
+        if over_limit && !self.deletions_confirmed {
+            deleted.sort();
+            error!(?change, ?deleted, served=self.served_apps.len(), limit=?config().max_deletions, "too many apps would be removed at once, not writing until confirmed with SIGUSR1");
+            return true;
+        }
+        if over_limit {
+            warn!(?change, ?deleted, "removing more apps than --max-deletions, as confirmed");
+        }
+
+        self.deletions_confirmed = false;
+        self.served_apps = served;
+        false
+    }
+
+    /// Write the removals held back by --max-deletions
+    async fn confirm_deletions(&mut self) -> Result<()> {
+        info!("received SIGUSR1, confirming held removals");
+        self.deletions_confirmed = true;
+        self.write_caddy_snippets(&SnippetChange { action: "confirm-deletions", app_name: None })
+            .instrument(info_span!("app", action="confirm-deletions"))
+            .await
+    }
+
+    /// With --create-missing-zones, create the local domain's zone if PowerDNS has no zone for it
+    async fn create_missing_zone(&self) -> Result<()> {
+        let (Some(dns_client), Some(opts)) = (&self.dns_client, &config().dns.power_dns) else {
+            return Ok(());
+        };
+        if !opts.create_missing_zones || config().dry_run {
+            return Ok(());
         }
 
-        self.dns_client.update_rrsets(
-            &format!("{}.", config().external_domain),
-            PowerDnsApiRRSets { rrsets: internal_dns }
-        ).await?;
+        let local_domain = format!("{}.", config().local_caddy.local_domain);
+        let zones = dns_zones(dns_client).await?;
+        let zone = match zone_for_name(&local_domain, &zones) {
+            Some(zone) => zone.to_string(),
+            // records are only written to the configured zones, so a new one would go unused
+            None if !opts.zones.is_empty() => {
+                warn!(local_domain, "the local domain is not in --power-dns-zones, not creating its zone");
+                return Ok(());
+            },
+            None => local_domain,
+        };
+        // the configured zones may not exist yet, so check the server's
+        if dns_client.list_zones().await?.iter().any(|existing| existing.name == zone) {
+            debug!(zone, "local domain's zone exists");
+            return Ok(());
+        }
 
+        dns_client.create_zone(&PowerDnsApiNewZone::native(&zone, &opts.zone_nameservers, opts.soa_contact.as_deref())).await?;
+        info!(zone, "created PowerDNS zone for the local domain");
         Ok(())
     }
 
     async fn listen(&mut self) -> Result<()> {
         let docker = new_docker()?;
 
-        let container_opts = ContainerListOpts::builder().build();
+        // subscribe from just before the scan, so containers created or removed while it runs
+        // aren't missed - events for containers the scan already saw are then no-ops. Docker's
+        // "since" is in whole seconds, so go back one more.
+        let since = chrono::Utc::now() - chrono::Duration::seconds(1);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let mut events = spawn_event_reader(docker.clone(), overflowed.clone(), since);
+        let mut hangups = Hangups::new()?;
+        let mut confirmations = Confirmations::new()?;
+        // caught from the start, so a stop during startup waits for the first write too
+        let mut shutdowns = Shutdowns::new()?;
+
+        self.create_missing_zone().await.map_err(|e| Failure::wrap(FailureKind::Dns, e))?;
+
+        let saved = config().state_file.as_deref().and_then(|path| state::load(path, config().state_max_age));
+        // the saved apps were being served before the restart
+        if let Some(ref saved) = saved {
+            self.served_apps = saved.iter()
+                .filter(|(_, ad)| !ad.containers.is_empty())
+                .map(|(app_name, _)| app_name.clone())
+                .collect();
+        }
+        self.deletions_confirmed = config().force_deletions;
         info!("checking containers & building app data on startup");
-        for container in docker.containers().list(&container_opts).await? {
-            let container_id = container.id.as_ref().unwrap().to_string();
-            let container = docker.containers().get(&container_id).inspect().await?;
-            let container_summary = ContainerSummaryInternal::new_from_inspect(&container)?;
-
-            info!(container_name=container_summary.container_name, "checking container...");
-            if let Some(mut ad) = AppData::new_from_container(&container_summary)? {
-                if let Some(acd) = AppContainerData::new_from_summary(&container_summary) {
-                    info!(?ad, "adding app data");
-                    ad.containers.push(acd);
-                    self.app_data.insert(ad.app_name.clone(), ad);
-                } else {
-                    warn!(app_name=ad.app_name, "built AppData but not AppContainerData");
+        self.app_data = match (discover_apps(&docker, &mut self.inspect_cache).await, saved) {
+            (Ok(app_data), saved) => {
+                if let Some(ref saved) = saved {
+                    state::reconcile(saved, &app_data);
                 }
+                app_data
+            },
+            // the event stream fails too, so the apps are rebuilt once Docker can be reached
+            (Err(e), Some(saved)) if is_connection_error(e.as_ref()) => {
+                warn!(error=%e, "unable to reach Docker on startup, using the saved state until it can be");
+                saved
+            },
+            (Err(e), _) => return Err(e),
+        };
+        self.force_reload = remove_orphaned_snippet_files()?;
+
+        //write_caddy_snippets(&app_data)?;
+        self.write_caddy_snippets(&SnippetChange { action: "startup", app_name: None })
+            .instrument(info_span!("app", action="startup"))
+            .await?;
+        if !config().reconcile_interval.is_zero() {
+            self.next_sweep = Some(Instant::now() + config().reconcile_interval);
+        }
+        report::log(&self.app_data);
+        if !config().exposure_report_interval.is_zero() {
+            self.next_report = Some(Instant::now() + config().exposure_report_interval);
+        }
+        systemd::notify("READY=1");
+        // pinged from the event loop, so systemd restarts the daemon if it stops handling events
+        let mut watchdog = systemd::watchdog_interval().map(tokio::time::interval);
+
+        loop {
+            let next_removal = self.pending_removals.values().min().copied();
+            let next_write = self.pending_write.as_ref().map(|pending| pending.deadline);
+            let next_restore = self.drained.values().min().copied();
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(_) if overflowed.swap(false, Ordering::SeqCst) => {
+                        self.resync(&docker, &mut events).await?;
+                    },
+                    Some(Ok(event)) => self.handle_event(&docker, event).await?,
+                    Some(Err(e)) => {
+                        warn!(error=%e, "Docker event stream failed, reconnecting");
+                        events = self.reconnect(&docker, &overflowed).await?;
+                    },
+                    None => {
+                        warn!("Docker event stream ended, reconnecting");
+                        events = self.reconnect(&docker, &overflowed).await?;
+                    },
+                },
+                _ = tokio::time::sleep_until(next_removal.unwrap_or_else(Instant::now)), if next_removal.is_some() => {
+                    self.remove_expired_apps().await?;
+                },
+                _ = tokio::time::sleep_until(next_write.unwrap_or_else(Instant::now)), if next_write.is_some() => {
+                    self.write_pending().await?;
+                },
+                _ = hangups.recv() => {
+                    self.rebuild(&docker).await?;
+                },
+                _ = confirmations.recv() => {
+                    self.confirm_deletions().await?;
+                },
+                _ = tokio::time::sleep_until(next_restore.unwrap_or_else(Instant::now)), if next_restore.is_some() => {
+                    self.restore_drained().await?;
+                },
+                _ = tokio::time::sleep_until(self.next_report.unwrap_or_else(Instant::now)), if self.next_report.is_some() => {
+                    self.next_report = Some(Instant::now() + config().exposure_report_interval);
+                    report::log(&self.app_data);
+                },
+                _ = tokio::time::sleep_until(self.next_sweep.unwrap_or_else(Instant::now)), if self.next_sweep.is_some() => {
+                    self.next_sweep = Some(Instant::now() + config().reconcile_interval);
+                    self.expire_missing_containers(&docker).await?;
+                },
+                _ = async { watchdog.as_mut().expect("watchdog is enabled").tick().await }, if watchdog.is_some() => {
+                    systemd::notify("WATCHDOG=1");
+                },
+                signal = shutdowns.recv() => {
+                    return self.shut_down(signal).await;
+                },
             }
-            else {
-                debug!("container not exposed via Caddy annotations");
+        }
+    }
+
+    /// Stop, after writing any debounced changes - a write in progress has already finished, as
+    /// events are handled one at a time - and with --on-shutdown=clear, removing every app
+    async fn shut_down(&mut self, signal: &str) -> Result<()> {
+        info!(signal, on_shutdown=?config().on_shutdown, "shutting down");
+        systemd::notify("STOPPING=1");
+        self.write_pending().await?;
+        if config().on_shutdown == ShutdownAction::Clear {
+            self.app_data.clear();
+            self.pending_removals.clear();
+            // asked for, so not held back by --max-deletions
+            self.deletions_confirmed = true;
+            self.write_caddy_snippets(&SnippetChange { action: "shutdown", app_name: None })
+                .instrument(info_span!("app", action="shutdown"))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Subscribe to events again after the stream failed, retrying with exponential backoff until
+    /// Docker can be reached, then rebuild the app data from the running containers so nothing
+    /// that changed while disconnected is missed
+    async fn reconnect(&mut self, docker: &Docker, overflowed: &Arc<AtomicBool>) -> Result<Receiver<docker_api::Result<EventMessage>>> {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            // as on startup, the subscription starts from before the scan
+            let since = chrono::Utc::now() - chrono::Duration::seconds(1);
+            match discover_apps(docker, &mut self.inspect_cache).await {
+                Ok(app_data) => {
+                    info!("reconnected to Docker, rebuilding app data from running containers");
+                    overflowed.store(false, Ordering::SeqCst);
+                    let events = spawn_event_reader(docker.clone(), overflowed.clone(), since);
+                    self.app_data = app_data;
+                    self.pending_removals.clear();
+                    self.write_caddy_snippets(&SnippetChange { action: "reconnect", app_name: None })
+                        .instrument(info_span!("app", action="reconnect"))
+                        .await?;
+                    return Ok(events);
+                },
+                // anything else (e.g., a bug) won't go away by retrying
+                Err(e) if !e.is::<docker_api::Error>() => return Err(e),
+                Err(e) => {
+                    warn!(error=%e, retry_in=?backoff, "unable to reach Docker, retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff = next_backoff(backoff, config().reconnect_max_backoff);
+                },
             }
         }
+    }
 
-        //write_caddy_snippets(&app_data)?;
-        self.write_caddy_snippets().await?;
-
-        let opts = EventsOpts::builder().build();
-        let mut events = docker.events(&opts);
-        while let Some(event) = events.next().await {
-            let event = event?;
-            if let Some("container") = event.type_.as_ref().map(|s| s.as_str()) {
-                if let Some(action) = event.action.as_ref().map(|s| s.as_str()) {
-                    let event_summary = EventSummaryInternal::new_from_event(&event)?;
-                    match action {
-                        "create" => {
-                            //info!(?event, "received container event");
-                            info!(actor_id=event.actor.unwrap().id, "received container create event");
-                            let container = docker.containers().get(&event_summary.id).inspect().await?;
-                            let container_summary = ContainerSummaryInternal::new_from_inspect(&container)?;
-                            if let Some(app_name) = AppData::name_from_summary(&container_summary) {
-                                if let Some(ad) = self.app_data.get_mut(&app_name) { 
-                                    if let Some(adc) = AppContainerData::new_from_summary(&container_summary) {
-                                        ad.containers.push(adc);
-                                    } else {
-                                        warn!(app_name, "generated AppData but no AppContainerData!");
-                                        continue;
-                                    }
-                                } else {
-                                    if let Some(mut ad) = AppData::new_from_container(&container_summary)? {
-                                        if let Some(adc) = AppContainerData::new_from_summary(&container_summary) {
-                                            ad.containers.push(adc);
-                                            self.app_data.insert(app_name.clone(), ad);
-                                        } else {
-                                            warn!(app_name, "generated AppData but no AppContainerData!");
-                                            continue;
-                                        }
-                                    } else {
-                                        warn!(app_name, "app found in map, but generated no AppData");
-                                        continue;
-                                    }
-                                }
-                                self.write_caddy_snippets().await?;
-                            }
+    /// Throw away the queued events and rebuild the app data from the running containers, after
+    /// the event queue overflowed and events were dropped
+    async fn resync(&mut self, docker: &Docker, events: &mut Receiver<docker_api::Result<EventMessage>>) -> Result<()> {
+        let mut dropped = 0;
+        while events.try_recv().is_ok() {
+            dropped += 1;
+        }
+        warn!(dropped, queue_depth=config().event_queue_depth, "event queue overflowed, rebuilding app data from running containers");
+
+        self.app_data = discover_apps(docker, &mut self.inspect_cache).await?;
+        self.pending_removals.clear();
+        self.force_reload |= remove_orphaned_snippet_files()?;
+        self.write_caddy_snippets(&SnippetChange { action: "resync", app_name: None })
+            .instrument(info_span!("app", action="resync"))
+            .await
+    }
+
+    /// Re-read the options, forget everything known about the containers and rebuild it from
+    /// scratch, then write the snippets and reload Caddy even if they're unchanged. Done on SIGHUP,
+    /// so e.g. an edited --env-file or Caddyfile can be picked up.
+    async fn rebuild(&mut self, docker: &Docker) -> Result<()> {
+        info!("received SIGHUP, re-reading the options and rebuilding app data from running containers");
+        match reload_config() {
+            Ok(()) => {
+                prepare_snippets_dirs()?;
+                self.dns_client = new_dns_client()?;
+            },
+            Err(e) => error!(error=%e, "invalid options, keeping the running ones"),
+        }
+        self.inspect_cache = InspectCache::default();
+        self.unlabelled.clear();
+        self.pending_removals.clear();
+        self.missing_since.clear();
+        self.app_data = discover_apps(docker, &mut self.inspect_cache).await?;
+        remove_orphaned_snippet_files()?;
+        self.force_reload = true;
+        self.write_caddy_snippets(&SnippetChange { action: "rebuild", app_name: None })
+            .instrument(info_span!("app", action="rebuild"))
+            .await
+    }
+
+    async fn handle_event(&mut self, docker: &Docker, event: EventMessage) -> Result<()> {
+        if let Some("container") = event.type_.as_deref() {
+            if let Some(action) = event.action.as_deref() {
+                let event_summary = match EventSummaryInternal::new_from_event(&event) {
+                    Ok(event_summary) => event_summary,
+                    Err(e) => {
+                        warn!(action, error=%e, "ignoring malformed container event");
+                        return Ok(());
+                    },
+                };
+                let attributes = event.actor.as_ref().and_then(|actor| actor.attributes.as_ref());
+                let action = normalize_action(config().docker_config.runtime, action, attributes);
+                let action = action.as_ref();
+                if attributes.is_some_and(claimed_by_other_instance) {
+                    debug!(app_name=event_summary.app_name, action, "ignoring event for container claimed by another instance");
+                    return Ok(());
+                }
+                if !project_selected(attributes) {
+                    debug!(app_name=event_summary.app_name, action, "ignoring event for container outside the --project-filter projects");
+                    return Ok(());
+                }
+                if let Some(ref app_name) = event_summary.app_name {
+                    if !config().app_filter.selects(app_name) {
+                        debug!(app_name, action, "ignoring event for app excluded by --only-apps/--exclude-apps");
+                        return Ok(());
+                    }
+                }
+                let span = info_span!("app", app=event_summary.app_name, container_id=event_summary.id, action);
+                self.handle_container_event(docker, action, event_summary)
+                    .instrument(span)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_container_event(&mut self, docker: &Docker, action: &str, event_summary: EventSummaryInternal) -> Result<()> {
+        match action {
+            // the labels may have changed, or the id is gone for good
+            "destroy" | "update" => {
+                self.unlabelled.remove(&event_summary.id);
+                self.inspect_cache.remove(&event_summary.id);
+            },
+            "rename" => self.inspect_cache.remove(&event_summary.id),
+            _ if self.unlabelled.contains(&event_summary.id) => {
+                debug!(action, "container has no app label, ignoring event");
+                return Ok(());
+            },
+            _ => {},
+        }
+
+        match action {
+            "create" => {
+                //info!(?event, "received container event");
+                info!("received container create event");
+                let container = match self.inspect_cache.refresh(docker, &event_summary.id).await {
+                    Ok(container) => container,
+                    Err(e) if is_not_found(e.as_ref()) => {
+                        info!("container no longer exists, ignoring event");
+                        return Ok(());
+                    },
+                    Err(e) => return Err(e),
+                };
+                let container_summary = ContainerSummaryInternal::new_from_inspect(&container)?;
+                let apps = container_summary.app_views();
+                if apps.is_empty() {
+                    self.unlabelled.insert(event_summary.id);
+                    return Ok(());
+                }
+                for container_summary in apps {
+                    let Some(app_name) = AppData::name_from_summary(&container_summary) else {
+                        continue;
+                    };
+                    if !config().app_filter.selects(&app_name) {
+                        debug!(app_name, "ignoring app excluded by --only-apps/--exclude-apps");
+                        continue;
+                    }
+                    if self.pending_removals.remove(&app_name).is_some() {
+                        info!(app_name, "replacement container created for app");
+                    }
+                    let Some(mut candidate) = AppData::new_from_container_or_warn(&container_summary) else {
+                        continue;
+                    };
+                    if let Some(ad) = self.app_data.get_mut(&app_name) { 
+                        if !ad.same_config(&candidate) {
+                            info!(app_name, "labels changed, updating app data");
+                            candidate.containers = std::mem::take(&mut ad.containers);
+                            *ad = candidate;
+                        }
+                        if let Some(adc) = AppContainerData::new_from_summary(&container_summary) {
+                            // after a resync, the container may already have been discovered
+                            ad.containers.retain(|c| c.container_id != adc.container_id);
+                            ad.containers.push(adc);
+                        } else {
+                            warn!(app_name, "generated AppData but no AppContainerData!");
+                            continue;
                         }
-                        "destroy" => {
-                            //info!(?event, "received container event");
-                            info!(actor_id=event.actor.unwrap().id, "received container destroy event");
-                            if let Some(app_name) = event_summary.app_name {
-                                if let Some(ad) = self.app_data.get_mut(&app_name) {
-                                    ad.containers.retain(|ad| ad.container_id != event_summary.id);
-                                    self.write_caddy_snippets().await?;
-                                } else {
-                                    warn!(app_name, "no AppData found for event - app not registered?");
-                                }
-                            } else {
-                                debug!("no app name found for event");
-                            }
+                    } else {
+                        if let Some(adc) = AppContainerData::new_from_summary(&container_summary) {
+                            candidate.containers.push(adc);
+                            self.app_data.insert(app_name.clone(), candidate);
+                        } else {
+                            warn!(app_name, "generated AppData but no AppContainerData!");
+                            continue;
+                        }
+                    }
+                    self.snippets_changed(action, &app_name).await?;
+                }
+            }
+            "destroy" => {
+                //info!(?event, "received container event");
+                info!("received container destroy event");
+                let app_names = self.apps_with_container(&event_summary.id);
+                if app_names.is_empty() {
+                    debug!("container isn't part of any app");
+                }
+                for app_name in app_names {
+                    if let Some(ad) = self.app_data.get_mut(&app_name) {
+                        ad.containers.retain(|ad| ad.container_id != event_summary.id);
+                        if ad.containers.is_empty() && !config().redeploy_grace.is_zero() {
+                            info!(app_name, grace=?config().redeploy_grace, "last container destroyed, waiting for a replacement before removing app");
+                            self.pending_removals.insert(app_name, Instant::now() + config().redeploy_grace);
+                            continue;
                         }
-                        "rename" => {
-                            //println!("received container rename event:\n{:?}", event);
-                            info!(actor_id=event.actor.unwrap().id, "received container rename event");
-                            if let Some(app_name) = event_summary.app_name {
-                                if let Some(ad) = self.app_data.get_mut(&app_name) {
-                                    ad.containers.iter_mut().filter(|ad| &ad.container_name == event_summary.old_name.as_ref().unwrap()).for_each(|ad| {
-                                        ad.container_name = event_summary.container_name.clone();
-                                        ad.hostname = event_summary.container_name.clone();
-                                    });
-                                    self.write_caddy_snippets().await?;
-                                }
-                            }
+                        self.snippets_changed(action, &app_name).await?;
+                    }
+                }
+            }
+            "rename" => {
+                //println!("received container rename event:\n{:?}", event);
+                info!("received container rename event");
+                // e.g., Podman doesn't include the old name
+                let Some(old_name) = event_summary.old_name.as_ref() else {
+                    warn!("rename event has no old name, ignoring it");
+                    return Ok(());
+                };
+                for app_name in self.apps_with_container(&event_summary.id) {
+                    if let Some(ad) = self.app_data.get_mut(&app_name) {
+                        ad.containers.iter_mut().filter(|ad| &ad.container_name == old_name).for_each(|ad| {
+                            ad.container_name = event_summary.container_name.clone();
+                            ad.hostname = event_summary.container_name.clone();
+                        });
+                        self.snippets_changed(action, &app_name).await?;
+                    }
+                }
+            }
+            "pause" | "unpause" | "health_status: starting" | "health_status: healthy" | "health_status: unhealthy" => {
+                info!("received container health event");
+                let app_names = self.apps_with_container(&event_summary.id);
+                if app_names.is_empty() {
+                    return Ok(());
+                }
+                let container = match self.inspect_cache.refresh(docker, &event_summary.id).await {
+                    Ok(container) => container,
+                    Err(e) if is_not_found(e.as_ref()) => {
+                        info!("container no longer exists, ignoring event");
+                        return Ok(());
+                    },
+                    Err(e) => return Err(e),
+                };
+                let container_summary = ContainerSummaryInternal::new_from_inspect(&container)?;
+                for app_name in app_names {
+                    if let Some(ad) = self.app_data.get_mut(&app_name) {
+                        ad.containers.iter_mut().filter(|adc| adc.container_id == event_summary.id).for_each(|adc| {
+                            adc.paused = container_summary.paused;
+                            adc.health = container_summary.health;
+                        });
+                        self.snippets_changed(action, &app_name).await?;
+                    }
+                }
+            }
+            "update" => {
+                //println!("received container event:\n{:?}", event);
+                info!("received container update event");
+                let container = match self.inspect_cache.refresh(docker, &event_summary.id).await {
+                    Ok(container) => container,
+                    Err(e) if is_not_found(e.as_ref()) => {
+                        info!("container no longer exists, ignoring event");
+                        return Ok(());
+                    },
+                    Err(e) => return Err(e),
+                };
+                let container_summary = ContainerSummaryInternal::new_from_inspect(&container)?;
+                let old_app_names = self.apps_with_container(&event_summary.id);
+                let mut new_apps = Vec::new();
+                for container_summary in container_summary.app_views() {
+                    let Some(app_name) = AppData::name_from_summary(&container_summary)
+                        .filter(|app_name| config().app_filter.selects(app_name)) else {
+                        continue;
+                    };
+                    if let Some(app) = AppData::new_from_container_or_warn(&container_summary).zip(AppContainerData::new_from_summary(&container_summary)) {
+                        new_apps.push((app_name, app));
+                    }
+                }
+
+                for old_app_name in &old_app_names {
+                    if !new_apps.iter().any(|(app_name, _)| app_name == old_app_name) {
+                        info!(app_name=old_app_name, "container no longer belongs to app, removing it");
+                        if let Some(ad) = self.app_data.get_mut(old_app_name) {
+                            ad.containers.retain(|adc| adc.container_id != event_summary.id);
                         }
-                        "update" => {
-                            //println!("received container event:\n{:?}", event);
-                            info!(actor_id=event.actor.unwrap().id, "received container update event");
-                            //let container = docker.containers().get(&event_summary.id).inspect().await?;
-                            //let container_summary = ContainerSummaryInternal::new_from_inspect(&container)?;
-                            //let name = container_summary.container_name.clone();
-                            //if let Some(ad) = app_data.get_mut(&name) {
-                            //    if let Some(labels) = &container_summary.labels {
-                            //        if !labels.contains_key(&config().app_name_label) {
-                            //            ad.app_name = labels[&config().app_name_label].clone();
-                            //            ad.hostname = name.clone();
-                            //            ad.port = labels[&config().port_label].parse()?;
-                            //            ad.external = labels[&config().external_label].parse()?;
-                            //            ad.auth_type = labels.get(&config().auth_label).map(|s| match s.as_str() {
-                            //                "oidc" => CaddyAuthType::Oidc,
-                            //                "headers" => CaddyAuthType::TrustedHeaders, 
-                            //                v @ _ => CaddyAuthType::Unknown(v.to_string())
-                            //            }).unwrap_or(CaddyAuthType::None);
-
-                            //            write_caddy_snippets(&app_data)?;
-                            //        } else if let Some(_) = app_data.remove(&name) {
-                            //            write_caddy_snippets(&app_data)?;
-                            //        }
-                            //    } else if let Some(_) = app_data.remove(&name) {
-                            //        write_caddy_snippets(&app_data)?;
-                            //    }
-                            //} else if let Some(ad) = AppData::new_from_container(&container_summary)? {
-                            //    app_data.insert(name, ad);
-                            //    write_caddy_snippets(&app_data)?;
-                            //}
+                        self.snippets_changed(action, old_app_name).await?;
+                    }
+                }
+                if new_apps.is_empty() {
+                    self.unlabelled.insert(event_summary.id);
+                    return Ok(());
+                }
+
+                for (app_name, (mut ad, adc)) in new_apps {
+                    if let Some(existing) = self.app_data.remove(&app_name) {
+                        if !existing.same_config(&ad) {
+                            info!(app_name, "labels changed, updating app data");
                         }
-                        _ => {}
+                        ad.containers = existing.containers;
                     }
+                    ad.containers.retain(|c| c.container_id != adc.container_id);
+                    ad.containers.push(adc);
+                    self.app_data.insert(app_name.clone(), ad);
+                    self.snippets_changed(action, &app_name).await?;
                 }
             }
+            "oom" if config().oom_drain_threshold > 0 => self.container_oom_killed(&event_summary).await?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Count an OOM kill, draining the container if it's had too many recently
+    async fn container_oom_killed(&mut self, event_summary: &EventSummaryInternal) -> Result<()> {
+        let now = Instant::now();
+        let window = config().oom_drain_window;
+        let oom_kills = self.count_oom_kill(&event_summary.id, now, window);
+        warn!(oom_kills, window=?window, "container was OOM-killed");
+        if oom_kills < config().oom_drain_threshold || self.drained.contains_key(&event_summary.id) {
+            return Ok(());
+        }
+
+        let until = now + config().oom_drain_duration;
+        self.drained.insert(event_summary.id.clone(), until);
+        let restore_at = (chrono::Utc::now() + config().oom_drain_duration).to_rfc3339();
+        for app_name in self.apps_with_container(&event_summary.id) {
+            error!(app_name, oom_kills, restore_at, "container keeps being OOM-killed, draining it from the upstreams");
+            emit::emit(emit::Transition::ContainerDrained {
+                app: &app_name,
+                container: &event_summary.container_name,
+                oom_kills,
+                until: restore_at.clone(),
+            });
+            self.snippets_changed("oom", &app_name).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Record an OOM kill, returning how many the container has had within the window
+    fn count_oom_kill(&mut self, container_id: &str, now: Instant, window: Duration) -> usize {
+        self.oom_kills.retain(|_, kills| {
+            kills.retain(|killed| now.duration_since(*killed) < window);
+            !kills.is_empty()
+        });
+        let kills = self.oom_kills.entry(container_id.to_string()).or_default();
+        kills.push(now);
+        kills.len()
+    }
+
+    /// Put drained containers whose time is up back in the upstreams
+    async fn restore_drained(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let restored = self.drained.iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(container_id, _)| container_id.clone())
+            .collect::<Vec<_>>();
+        for container_id in &restored {
+            self.drained.remove(container_id);
+            // it starts counting again, so a single OOM kill doesn't drain it straight away
+            self.oom_kills.remove(container_id);
+            for app_name in self.apps_with_container(container_id) {
+                let container_name = self.app_data[&app_name].containers.iter()
+                    .find(|adc| adc.container_id == *container_id)
+                    .map(|adc| adc.container_name.clone())
+                    .unwrap_or_default();
+                info!(app_name, container_name, "restoring drained container to the upstreams");
+                emit::emit(emit::Transition::ContainerRestored { app: &app_name, container: &container_name });
+            }
+        }
+
+        self.write_caddy_snippets(&SnippetChange { action: "restore-drained", app_name: None })
+            .instrument(info_span!("app", action="restore-drained"))
+            .await
+    }
+
+    /// The apps the container is one of the containers of
+    fn apps_with_container(&self, container_id: &str) -> Vec<String> {
+        self.app_data.iter()
+            .filter(|(_, ad)| ad.containers.iter().any(|adc| adc.container_id == container_id))
+            .map(|(app_name, _)| app_name.clone())
+            .collect()
+    }
+
+    /// Write the snippets for an event, or if --reload-debounce-ms is set, wait for any further
+    /// events within the window so they can be written (and Caddy reloaded) together
+    async fn snippets_changed(&mut self, action: &str, app_name: &str) -> Result<()> {
+        if config().reload_debounce.is_zero() {
+            return self.write_caddy_snippets(&SnippetChange { action, app_name: Some(app_name) }).await;
         }
 
+        let pending = self.pending_write.get_or_insert_with(|| PendingWrite {
+            deadline: Instant::now() + config().reload_debounce,
+            actions: Vec::new(),
+            app_names: Vec::new(),
+        });
+        pending.add(action, app_name);
+        debug!(action, app_name, deadline=?pending.deadline, "debouncing snippet write");
         Ok(())
     }
+
+    async fn write_pending(&mut self) -> Result<()> {
+        let Some(pending) = self.pending_write.take() else {
+            return Ok(());
+        };
+        info!(actions=?pending.actions, app_names=?pending.app_names, "writing debounced snippet changes");
+        self.write_caddy_snippets(&pending.change()).await
+    }
+
+    /// Expire tracked containers that have been missing from the running containers for longer
+    /// than the grace period, as their destroy events must have been missed
+    async fn expire_missing_containers(&mut self, docker: &Docker) -> Result<()> {
+        let running = docker.containers().list(&ContainerListOpts::builder().build()).await?
+            .into_iter()
+            .filter_map(|c| c.id)
+            .collect::<HashSet<_>>();
+
+        let expired_apps = self.expire_missing(&running, Instant::now());
+        if expired_apps.is_empty() {
+            return Ok(());
+        }
+        let app_name = match expired_apps.as_slice() {
+            [app_name] => Some(app_name.as_str()),
+            _ => None,
+        };
+        self.write_caddy_snippets(&SnippetChange { action: "expire", app_name })
+            .instrument(info_span!("app", app=app_name, action="expire"))
+            .await
+    }
+
+    /// Expire the tracked containers that aren't running and have been missing for longer than
+    /// the grace period, removing apps left without containers. Returns the apps that changed.
+    fn expire_missing(&mut self, running: &HashSet<String>, now: Instant) -> Vec<String> {
+        let mut expired_apps = Vec::new();
+        let mut tracked = HashSet::new();
+        for (app_name, ad) in self.app_data.iter_mut() {
+            ad.containers.retain(|adc| {
+                if running.contains(&adc.container_id) {
+                    return true;
+                }
+                let since = *self.missing_since.entry(adc.container_id.clone()).or_insert(now);
+                if now.duration_since(since) < config().missing_container_grace {
+                    debug!(app_name, container_id=adc.container_id, "tracked container is missing from the running containers");
+                    tracked.insert(adc.container_id.clone());
+                    return true;
+                }
+                warn!(app_name, container_id=adc.container_id, "tracked container has been missing for longer than the grace period, expiring it");
+                self.inspect_cache.remove(&adc.container_id);
+                if !expired_apps.contains(app_name) {
+                    expired_apps.push(app_name.clone());
+                }
+                false
+            });
+        }
+        // forget containers that came back or were expired
+        self.missing_since.retain(|id, _| tracked.contains(id));
+
+        if !expired_apps.is_empty() {
+            self.app_data.retain(|app_name, ad| {
+                let keep = !ad.containers.is_empty();
+                if !keep {
+                    info!(app_name, "expired the last container of the app, removing it");
+                    self.pending_removals.remove(app_name);
+                }
+                keep
+            });
+        }
+        expired_apps
+    }
+
+    /// Remove the apps whose last container was destroyed longer ago than the redeploy grace
+    /// period, without a replacement being created
+    async fn remove_expired_apps(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let expired = self.pending_removals.iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(app_name, _)| app_name.clone())
+            .collect::<Vec<_>>();
+        self.pending_removals.retain(|_, deadline| *deadline > now);
+
+        info!(?expired, "no replacement containers created, removing apps");
+        let app_name = match expired.as_slice() {
+            [app_name] => Some(app_name.as_str()),
+            _ => None,
+        };
+        self.write_caddy_snippets(&SnippetChange { action: "destroy", app_name })
+            .instrument(info_span!("app", app=app_name, action="destroy"))
+            .await
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let _ = config(); // init immediately to validate args, print help, etc.
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .pretty()
-        .init();
+/// SIGHUP, which asks for a rebuild. It's never received on platforms without it.
+struct Hangups {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangups {
+    fn new() -> Result<Self> {
+        Ok(Hangups {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
 
-    let mut listener = Listener::new()?;
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
 
-    listener.listen().await?;
-    
-    Ok(())
+/// SIGUSR1, which confirms removals held back by --max-deletions. It's never received on
+/// platforms without it.
+struct Confirmations {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Confirmations {
+    fn new() -> Result<Self> {
+        Ok(Confirmations {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
+
+/// SIGTERM (e.g., from docker stop or systemd) or SIGINT, which ask for a graceful shutdown. Only
+/// SIGINT (Ctrl-C) is received on platforms without signals.
+struct Shutdowns {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+}
+
+impl Shutdowns {
+    fn new() -> Result<Self> {
+        Ok(Shutdowns {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?,
+            #[cfg(unix)]
+            interrupt: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())?,
+        })
+    }
+
+    /// The name of the signal received
+    async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+            "SIGINT"
+        }
+    }
+}
+
+/// Read Docker events into a bounded queue, so a burst of events (e.g., a system prune) can't grow
+/// memory without limit while they are processed. When the queue is full, events are dropped and
+/// `overflowed` is set, so the listener knows to resync.
+fn spawn_event_reader(docker: Docker, overflowed: Arc<AtomicBool>, since: chrono::DateTime<chrono::Utc>) -> Receiver<docker_api::Result<EventMessage>> {
+    let (tx, rx) = mpsc::channel(config().event_queue_depth);
+    tokio::spawn(async move {
+        let opts = EventsOpts::builder().since(&since).build();
+        queue_events(docker.events(&opts), tx, overflowed).await;
+    });
+    rx
+}
+
+/// Queue the events without waiting for room, flagging any that had to be dropped
+async fn queue_events<T>(events: impl tokio_stream::Stream<Item = T>, tx: mpsc::Sender<T>, overflowed: Arc<AtomicBool>) {
+    tokio::pin!(events);
+    while let Some(event) = events.next().await {
+        match tx.try_send(event) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => overflowed.store(true, Ordering::SeqCst),
+            Err(TrySendError::Closed(_)) => return,
+        }
+    }
+}
+
+/// The wait before the next attempt to reconnect to Docker, doubling up to --reconnect-max-backoff-secs
+fn next_backoff(backoff: Duration, max: Duration) -> Duration {
+    (backoff * 2).min(max)
+}
+
+/// Whether Docker reported that a container doesn't exist, e.g., because it was removed between
+/// the event and the inspect
+fn is_not_found(error: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(error.downcast_ref::<docker_api::Error>(), Some(docker_api::Error::Fault { code, .. }) if code.as_u16() == 404)
+}
+
+/// Whether Docker couldn't be reached at all, rather than answering with an error
+fn is_connection_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    match error.downcast_ref::<docker_api::Error>() {
+        Some(docker_api::Error::Hyper(e) | docker_api::Error::Error(docker_api::conn::Error::Hyper(e))) => e.is_connect(),
+        Some(docker_api::Error::IO(_) | docker_api::Error::Error(docker_api::conn::Error::IO(_))) => true,
+        _ => false,
+    }
+}
+
+/// Snippet changes collected during the reload debounce window
+struct PendingWrite {
+    deadline: Instant,
+    actions: Vec<String>,
+    app_names: Vec<String>,
+}
+
+impl PendingWrite {
+    fn add(&mut self, action: &str, app_name: &str) {
+        if !self.actions.iter().any(|a| a == action) {
+            self.actions.push(action.to_string());
+        }
+        if !self.app_names.iter().any(|a| a == app_name) {
+            self.app_names.push(app_name.to_string());
+        }
+    }
+
+    /// The change to write, as a batch of changes can't be described by a single action or app
+    fn change(&self) -> SnippetChange<'_> {
+        let action = match self.actions.as_slice() {
+            [action] => action.as_str(),
+            _ => "batch",
+        };
+        let app_name = match self.app_names.as_slice() {
+            [app_name] => Some(app_name.as_str()),
+            _ => None,
+        };
+        SnippetChange { action, app_name }
+    }
+}
+
+/// What caused the snippets to be regenerated - either "startup" or the Docker event action
+#[derive(Debug)]
+struct SnippetChange<'a> {
+    action: &'a str,
+    app_name: Option<&'a str>,
+}
+
+/// Run a reload hook through the shell. Failures are logged rather than returned, so a broken hook
+/// can't stop Caddy from being reloaded.
+fn run_reload_hook(hook_name: &str, hook: &str, change: &SnippetChange, extra_env: &[(&str, &str)]) {
+    info!(hook_name, hook, ?change, "running reload hook");
+    let result = std::process::Command::new("sh")
+        .args(["-c", hook])
+        .env("DOCKER_CADDY_ACTION", change.action)
+        .env("DOCKER_CADDY_APP", change.app_name.unwrap_or_default())
+        .env("DOCKER_CADDY_LOCAL_SNIPPETS_DIR", &config().local_caddy.snippets_dir)
+        .env("DOCKER_CADDY_DOCKER_SNIPPETS_DIR", &config().docker_caddy.snippets_dir)
+        .envs(extra_env.iter().copied())
+        .status();
+
+    match result {
+        Ok(status) if status.success() => {},
+        Ok(status) => error!(hook_name, code=status.code(), "reload hook failed"),
+        Err(e) => error!(hook_name, error=%e, "unable to run reload hook"),
+    }
+}
+
+/// The generated snippet file contents for each Caddy instance
+#[derive(PartialEq)]
+struct RenderedSnippets {
+    local: String,
+    docker: String,
+}
+
+impl RenderedSnippets {
+    fn write_files(&self) -> Result<()> {
+        let docker_hosts_path = config().docker_caddy.snippets_dir.join(&config().snippet_file_name);
+        let local_docker_hosts_path = config().local_caddy.snippets_dir.join(&config().snippet_file_name);
+        // read before either file is truncated
+        let docker_content = managed_region::file_content(&docker_hosts_path, &self.docker)?;
+        let local_content = managed_region::file_content(&local_docker_hosts_path, &self.local)?;
+        let mut docker_hosts_file = File::options().create(true).write(true).truncate(true).open(docker_hosts_path)?;
+        let mut local_docker_hosts_file = File::options().create(true).write(true).truncate(true).open(local_docker_hosts_path)?;
+
+        docker_hosts_file.write_all(docker_content.as_bytes())?;
+        local_docker_hosts_file.write_all(local_content.as_bytes())?;
+
+        docker_hosts_file.sync_all()?;
+        local_docker_hosts_file.sync_all()?;
+        record_written_files()?;
+
+        Ok(())
+    }
+
+    /// Whether both snippet files already hold exactly these snippets
+    fn matches_files(&self) -> bool {
+        let matches = |dir: &PathBuf, content: &str| std::fs::read_to_string(dir.join(&config().snippet_file_name))
+            .is_ok_and(|existing| managed_region::snippets(&existing) == Some(content));
+        matches(&config().local_caddy.snippets_dir, &self.local)
+            && matches(&config().docker_caddy.snippets_dir, &self.docker)
+    }
+
+    /// Write the snippets for both instances to a single writer, each preceded by a marker line
+    /// naming the instance it belongs to
+    fn write_marked(&self, writer: &mut impl Write) -> Result<()> {
+        write!(writer, "# ---- local caddy: {} ----\n{}", config().snippet_file_name, self.local)?;
+        write!(writer, "# ---- docker caddy: {} ----\n{}", config().snippet_file_name, self.docker)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// The snippet files as they were before a write, to put back if the new snippets fail
+/// validation. Files that didn't exist are removed again.
+struct SnippetFileBackup(Vec<(PathBuf, Option<Vec<u8>>)>);
+
+impl SnippetFileBackup {
+    fn read() -> Result<Self> {
+        let mut paths = vec![
+            config().local_caddy.snippets_dir.join(&config().snippet_file_name),
+            config().docker_caddy.snippets_dir.join(&config().snippet_file_name),
+        ];
+        paths.dedup();
+
+        let mut files = Vec::new();
+        for path in paths {
+            let content = match std::fs::read(&path) {
+                Ok(content) => Some(content),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            files.push((path, content));
+        }
+        Ok(SnippetFileBackup(files))
+    }
+
+    fn restore(&self) -> Result<()> {
+        for (path, content) in &self.0 {
+            match content {
+                Some(content) => std::fs::write(path, content)?,
+                None => std::fs::remove_file(path)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check both snippets dirs exist before anything is written to them, creating them if
+/// --create-dirs is set, so a typo fails at startup rather than on the first write
+fn prepare_snippets_dirs() -> Result<()> {
+    if config().dry_run {
+        return Ok(());
+    }
+
+    let dirs = [
+        (&config().local_caddy.snippets_dir, "--local-caddy-snippets-dir"),
+        (&config().docker_caddy.snippets_dir, "--docker-caddy-snippets-dir"),
+    ];
+    for (dir, option) in dirs {
+        prepare_snippets_dir(dir, option)?;
+    }
+
+    Ok(())
+}
+
+fn prepare_snippets_dir(dir: &Path, option: &str) -> Result<()> {
+    match std::fs::metadata(dir) {
+        Ok(metadata) if metadata.is_dir() => {},
+        Ok(_) => return Err(format!("snippets dir {} ({option}) is not a directory", dir.display()).into()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && config().create_dirs => {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("unable to create snippets dir {} ({option}): {e}", dir.display()))?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(dir, std::fs::Permissions::from_mode(config().dir_mode))?;
+            }
+            info!(dir=%dir.display(), mode=format!("{:o}", config().dir_mode), "created snippets dir");
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(format!(
+            "snippets dir {} ({option}) does not exist - create it (for the Docker Caddy, it must be mounted into the container), or pass --create-dirs",
+            dir.display(),
+        ).into()),
+        Err(e) => return Err(format!("unable to read snippets dir {} ({option}): {e}", dir.display()).into()),
+    }
+
+    Ok(())
+}
+
+/// The first line of snippet files written by this instance, if it has a name to mark them with
+fn managed_header() -> Option<String> {
+    config().instance_name.as_ref().map(|instance| format!("# managed by docker-caddy-rs instance {instance}\n"))
+}
+
+/// The file in a snippets dir listing the names of the snippet files this instance has written
+/// there, so only those are ever removed as orphans. It's a dotfile, so Caddy never imports it.
+fn written_files_path(dir: &Path, instance: &str) -> PathBuf {
+    dir.join(format!(".docker-caddy-rs-{instance}.written"))
+}
+
+/// The snippet file names listed in a snippets dir's manifest
+fn written_files(dir: &Path, instance: &str) -> Result<Vec<String>> {
+    match std::fs::read_to_string(written_files_path(dir, instance)) {
+        Ok(content) => Ok(content.lines().filter(|name| !name.is_empty()).map(str::to_string).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_written_files(dir: &Path, instance: &str, names: &[String]) -> Result<()> {
+    let content = names.iter().map(|name| format!("{name}\n")).collect::<String>();
+    std::fs::write(written_files_path(dir, instance), content)?;
+    Ok(())
+}
+
+/// Both snippets dirs, once each
+fn snippets_dirs() -> Vec<&'static PathBuf> {
+    let mut dirs = vec![&config().local_caddy.snippets_dir, &config().docker_caddy.snippets_dir];
+    dirs.dedup();
+    dirs
+}
+
+/// Add the snippet file name to the manifest in each snippets dir, with --instance-name
+fn record_written_files() -> Result<()> {
+    let Some(ref instance) = config().instance_name else {
+        return Ok(());
+    };
+    for dir in snippets_dirs() {
+        let mut names = written_files(dir, instance)?;
+        if !names.contains(&config().snippet_file_name) {
+            names.push(config().snippet_file_name.clone());
+            write_written_files(dir, instance, &names)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove snippet files this instance wrote under another name (e.g., before --snippet-file-name
+/// was changed), so a crash or restart doesn't leave their sites in Caddy's config. Only the files
+/// listed in the dir's manifest of files this instance wrote are candidates, and only if they
+/// still start with the header marking them as this instance's, so nothing is removed without
+/// --instance-name. Returns whether anything was removed.
+fn remove_orphaned_snippet_files() -> Result<bool> {
+    let (Some(instance), Some(header)) = (config().instance_name.as_ref(), managed_header()) else {
+        return Ok(false);
+    };
+    if config().dry_run {
+        return Ok(false);
+    }
+
+    let mut removed = false;
+    for dir in snippets_dirs() {
+        let mut kept = Vec::new();
+        for name in written_files(dir, instance)? {
+            if name == config().snippet_file_name {
+                kept.push(name);
+                continue;
+            }
+            // the manifest only ever lists plain file names
+            if name.starts_with('.') || name.contains(std::path::is_separator) {
+                continue;
+            }
+
+            let path = dir.join(&name);
+            let is_ours = path.is_file() && std::fs::read_to_string(&path).is_ok_and(|content| content.starts_with(&header));
+            if is_ours {
+                info!(path=%path.display(), "removing orphaned snippet file");
+                std::fs::remove_file(&path)?;
+                removed = true;
+            } else if path.exists() {
+                warn!(path=%path.display(), "orphaned snippet file is no longer marked as this instance's, leaving it");
+            }
+        }
+        write_written_files(dir, instance, &kept)?;
+    }
+
+    Ok(removed)
+}
+
+/// Shell assignments (e.g., `DO_API_KEY="$(cat "$DO_API_KEY_FILE")" `) exporting each secret to
+/// a command run in a Caddy container. The paths are double-quoted, so $ references still expand
+fn secret_env_assignments(secret_env: &[(String, String)]) -> String {
+    secret_env.iter()
+        .map(|(name, path)| {
+            let path = path.replace('\\', "\\\\").replace('"', "\\\"").replace('`', "\\`");
+            format!("{name}=\"$(cat \"{path}\")\" ")
+        })
+        .collect()
+}
+
+/// The `bind` directive for a local Caddy site serving external or internal apps, if addresses
+/// were configured for it
+fn bind_directive(external: bool) -> String {
+    let addresses = match external {
+        true => &config().external_bind_addresses,
+        false => &config().internal_bind_addresses,
+    };
+    match addresses.is_empty() {
+        true => String::new(),
+        false => format!("bind {}\n  ", addresses.join(" ")),
+    }
+}
+
+/// An app's blocks for the snippets, see [`AppData::format_blocks`]
+struct AppBlocks {
+    /// Sites of the app's own for the local Caddy
+    local_sites: Vec<String>,
+    docker: String,
+    /// For the local Caddy's shared site, unless the app is only served in a site of its own
+    local: Option<String>,
+}
+
+impl AppBlocks {
+    /// Read the secrets the blocks reference. This is the only place they're read, so they never
+    /// end up in the app data that's saved to the state file, logged or dumped.
+    fn resolve_secrets(self) -> Result<Self> {
+        let resolve = |block: String| secrets::resolve_text(&block).map(Cow::into_owned);
+        Ok(AppBlocks {
+            local_sites: self.local_sites.into_iter().map(resolve).collect::<Result<_>>()?,
+            docker: resolve(self.docker)?,
+            local: self.local.map(resolve).transpose()?,
+        })
+    }
+}
+
+fn render_snippets(app_data: &ApplicationData) -> Result<RenderedSnippets> {
+    let mut external_hosts = Vec::new();
+    let mut local_external_hosts = Vec::new();
+    let mut internal_hosts = Vec::new();
+    let mut local_internal_hosts = Vec::new();
+    let mut local_sites = Vec::new();
+    // sites for internal apps' hosts on the external domain, unless the host is also served
+    // there (e.g., by a split port)
+    let mut internal_only = BTreeMap::new();
+    let mut external_names = HashSet::new();
+    let mut group_hosts: HashMap<String, Vec<String>> = HashMap::new();
+    let mut local_group_hosts: HashMap<String, Vec<String>> = HashMap::new();
+
+    // sort by priority, then app name so the output doesn't change with map ordering
+    let mut apps = app_data.iter().collect::<Vec<_>>();
+    apps.sort_by_key(|(key, ad)| (std::cmp::Reverse(ad.priority), *key));
+
+    for (key, ad) in apps {
+        if ad.containers.is_empty() {
+            if let Some(r) = recurring::check("no_containers", key) {
+                warn!(app_name=key, r.suppressed, persisting=?r.persisting, "app is in the map but has no running containers...");
+            }
+            continue;
+        }
+
+        if !ad.dependencies_ready(app_data) {
+            continue;
+        }
+
+        // named & split ports are served in blocks of their own, but otherwise share the app's config
+        for ad in ad.with_port_variants() {
+            // checked per block, as split ports can put only some of an app on the external domain
+            if let Some(reason) = ad.rejection() {
+                if let Some(r) = recurring::check("rejected", &ad.app_name) {
+                    error!(app_name=ad.app_name, reason, r.suppressed, persisting=?r.persisting, "leaving app out of the snippets");
+                }
+                continue;
+            }
+
+            // Caddy rejects a reverse_proxy without upstreams, which would fail the whole reload
+            if ad.upstream_targets().is_empty() {
+                if let Some(r) = recurring::check("no_upstreams", &ad.app_name) {
+                    warn!(app_name=ad.app_name, r.suppressed, persisting=?r.persisting, "no container can be reached as an upstream, leaving app out of the snippets");
+                }
+                continue;
+            }
+
+            let blocks = match ad.format_blocks()?.resolve_secrets() {
+                Ok(blocks) => blocks,
+                Err(e) => {
+                    if let Some(r) = recurring::check("unresolved_secret", &ad.app_name) {
+                        error!(app_name=ad.app_name, error=%e, r.suppressed, persisting=?r.persisting, "leaving app out of the snippets");
+                    }
+                    continue;
+                },
+            };
+            local_sites.extend(blocks.local_sites);
+
+            match ad.external {
+                true => { external_names.insert(ad.app_name.clone()); },
+                false if ad.zone.is_none() => { internal_only.insert(ad.app_name.clone(), ad.format_local_internal_host_site()); },
+                false => {},
+            }
+
+            if let Some(ref zone) = ad.zone {
+                group_hosts.entry(zone.clone()).or_default().push(blocks.docker);
+                local_group_hosts.entry(zone.clone()).or_default().extend(blocks.local);
+            } else if ad.external {
+                //println!("writing line [{line}] to external");
+                external_hosts.push(blocks.docker);
+                local_external_hosts.extend(blocks.local);
+            } else {
+                //println!("writing line [{line}] to internal");
+                internal_hosts.push(blocks.docker);
+                local_internal_hosts.extend(blocks.local);
+            };
+        }
+    }
+
+    local_sites.extend(internal_only.into_iter()
+        .filter(|(app_name, _)| !external_names.contains(app_name))
+        .filter_map(|(_, site)| site));
+
+    // every configured group is written, even if empty, so Caddyfiles can always import them
+    let group_snippets = |hosts: &HashMap<String, Vec<String>>| config().snippet_groups
+        .iter()
+        .map(|group| format!(
+            indoc!("
+
+                ({}_docker_hosts) {{
+                  {}
+                }}
+            "),
+            group,
+            hosts.get(group).map(|h| h.join("\n  ")).unwrap_or_default(),
+        ))
+        .collect::<String>();
+
+    let docker = format!(indoc!("
+        (external_docker_hosts) {{
+          {}
+        }}
+
+        (internal_docker_hosts) {{
+          {}
+        }}
+        {}"), external_hosts.join("\n  "), internal_hosts.join("\n  "), group_snippets(&group_hosts));
+
+    // the snippets are imported into the Caddyfile's site blocks, so a bind in them applies to
+    // the whole site. Groups can hold both kinds of app, so they're left to the Caddyfile
+    let local = format!(indoc!("
+        (external_docker_hosts) {{
+          {}{}
+        }}
+
+        (internal_docker_hosts) {{
+          {}{}
+        }}
+
+        (docker_sites) {{
+          {}
+        }}
+        {}"), bind_directive(true), local_external_hosts.join("\n  "), bind_directive(false), local_internal_hosts.join("\n  "), local_sites.join("\n  "), group_snippets(&local_group_hosts));
+
+    let format = |content: String| -> Result<String> {
+        match config().snippet_format {
+            SnippetFormat::None => Ok(content),
+            SnippetFormat::Internal => Ok(caddyfile::format(&content)),
+            SnippetFormat::Caddy => caddyfile::caddy_fmt(&content),
+        }
+    };
+
+    let header = managed_header().unwrap_or_default();
+
+    Ok(RenderedSnippets {
+        local: header.clone() + &format(local)?,
+        docker: header + &format(docker)?,
+    })
+}
+
+/// With --power-dns-backup-threshold, save the zone to a zone file if the record sets would change
+/// more of it than the threshold. The update is abandoned if the backup can't be written.
+async fn backup_zone_before_changes(dns_client: &PowerDnsClient, zone_id: &str, rrsets: &[PowerDnsApiRRSet]) -> Result<()> {
+    let Some((threshold, backup_dir)) = config().dns.power_dns.as_ref()
+        .and_then(|opts| opts.backup_threshold.zip(opts.backup_dir.as_ref())) else {
+        return Ok(());
+    };
+    // a count can't exceed the threshold without at least that many record sets
+    if rrsets.len() <= threshold {
+        return Ok(());
+    }
+
+    let Some(zone) = dns_client.list_zone(zone_id).await
+        .map_err(|e| format!("unable to fetch zone {zone_id} to back it up: {e}"))? else {
+        return Ok(());
+    };
+    let existing = zone.rrsets.as_deref().unwrap_or_default();
+    let changes = rrsets.iter().filter(|rrset| rrset.changes(existing)).count();
+    if changes <= threshold {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(backup_dir)?;
+    let path = backup_dir.join(format!("{}{}.zone", zone_id, chrono::Utc::now().format("%Y%m%dT%H%M%SZ")));
+    std::fs::write(&path, zone.to_zone_file())
+        .map_err(|e| format!("unable to write backup of zone {zone_id} to {}: {e}", path.display()))?;
+    warn!(zone=zone_id, changes, threshold, path=%path.display(), "changing many records, backed up the zone first");
+
+    Ok(())
+}
+
+/// The configured PowerDNS zones, or every zone on the server if none were configured
+async fn dns_zones(dns_client: &PowerDnsClient) -> Result<Vec<String>> {
+    let configured = config().dns.power_dns.as_ref().map(|opts| opts.zones.clone()).unwrap_or_default();
+
+    let zones = if configured.is_empty() {
+        dns_client.list_zones().await?.into_iter().map(|zone| zone.name).collect()
+    } else {
+        configured
+    };
+
+    Ok(zones.into_iter()
+        .map(|zone| if zone.ends_with('.') { zone } else { format!("{}.", zone) })
+        .collect())
+}
+
+/// A PowerDNS client, if PowerDNS is configured
+fn new_dns_client() -> Result<Option<PowerDnsClient>> {
+    Ok(match config().dns.power_dns {
+        Some(ref dns_conf) => Some(PowerDnsClient::new(
+            Url::parse(&dns_conf.url)?,
+            dns_conf.server.to_string(),
+            dns_conf.api_key.to_string(),
+        )?),
+        None => None,
+    })
+}
+
+/// Build the app data for all currently running containers
+async fn discover_apps(docker: &Docker, inspect_cache: &mut InspectCache) -> Result<ApplicationData> {
+    let mut app_data = ApplicationData::new();
+
+    let container_opts = ContainerListOpts::builder().build();
+    let containers = docker.containers().list(&container_opts).await?;
+    inspect_cache.retain_ids(&containers.iter().filter_map(|c| c.id.as_deref()).collect());
+    for container in containers {
+        let container = match inspect_cache.inspect_listed(docker, &container).await {
+            Ok(container) => container,
+            Err(e) if is_not_found(e.as_ref()) => {
+                debug!(container_id=container.id, "container removed while scanning, skipping");
+                continue;
+            },
+            Err(e) => return Err(e),
+        };
+        let container_summary = ContainerSummaryInternal::new_from_inspect(&container)?;
+
+        info!(container_name=container_summary.container_name, "checking container...");
+        if container_summary.labels.as_ref().is_some_and(claimed_by_other_instance) {
+            info!(container_name=container_summary.container_name, "container claimed by another instance, ignoring");
+            continue;
+        }
+        if !project_selected(container_summary.labels.as_ref()) {
+            debug!(container_name=container_summary.container_name, "container outside the --project-filter projects, ignoring");
+            continue;
+        }
+        add_discovered_container(&mut app_data, &container_summary);
+    }
+
+    app_data.retain(|app_name, _| {
+        let selected = config().app_filter.selects(app_name);
+        if !selected {
+            info!(app_name, "app excluded by --only-apps/--exclude-apps, ignoring");
+        }
+        selected
+    });
+
+    Ok(app_data)
+}
+
+/// Add the apps a discovered container serves, or the container to them if they were already
+/// discovered
+fn add_discovered_container(app_data: &mut ApplicationData, container_summary: &ContainerSummaryInternal) {
+    let apps = container_summary.app_views();
+    if apps.is_empty() {
+        debug!("container not exposed via Caddy annotations");
+    }
+    for container_summary in apps {
+        if let Some(mut ad) = AppData::new_from_container_or_warn(&container_summary) {
+            if let Some(acd) = AppContainerData::new_from_summary(&container_summary) {
+                // replicas (e.g., of a scaled compose service) share an app
+                if let Some(existing) = app_data.get_mut(&ad.app_name) {
+                    info!(app_name=ad.app_name, project=acd.project, service=acd.service, container_name=acd.container_name, "adding container to app");
+                    existing.containers.push(acd);
+                    continue;
+                }
+                info!(project=acd.project, service=acd.service, ?ad, "adding app data");
+                ad.containers.push(acd);
+                app_data.insert(ad.app_name.clone(), ad);
+            } else {
+                warn!(app_name=ad.app_name, "built AppData but not AppContainerData");
+            }
+        }
+    }
+}
+
+fn init_logging() {
+    let writer = match logs_to_stdout(&config().command, emit::to_stdout(), config().dry_run) {
+        true => BoxMakeWriter::new(std::io::stdout),
+        false => BoxMakeWriter::new(std::io::stderr),
+    };
+    log_subscriber(config().log_format, writer).init();
+}
+
+/// Whether stdout is free for the logs. It's kept clean for a command's own output, the events
+/// from --emit-events - and the snippets printed by --dry-run
+fn logs_to_stdout(command: &Command, events_to_stdout: bool, dry_run: bool) -> bool {
+    matches!(command, Command::Run) && !events_to_stdout && !dry_run
+}
+
+fn log_subscriber(format: LogFormat, writer: BoxMakeWriter) -> Box<dyn tracing::Subscriber + Send + Sync> {
+    let subscriber = tracing_subscriber::fmt()
+        .with_target(false)
+        .with_writer(writer);
+    match format {
+        LogFormat::Pretty => Box::new(subscriber.pretty().finish()),
+        LogFormat::Compact => Box::new(subscriber.compact().finish()),
+        // span fields (e.g., the container being handled) go under "span", the event's at the top
+        LogFormat::Json => Box::new(subscriber.json().flatten_event(true).with_current_span(true).with_span_list(false).finish()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let _ = config(); // init immediately to validate args, print help, etc.
+    template::load();
+    init_logging();
+
+    match config().docker_config.remote {
+        Some(ref remote) => info!(host=remote.host, tls=remote.tls.is_some(), "using remote Docker"),
+        None => info!(
+            docker_socket_path=%config().docker_config.docker_socket_path.display(),
+            source=config().docker_config.docker_socket_source,
+            "using Docker socket",
+        ),
+    }
+    if let CaddyLocation::Local = config().local_caddy.location {
+        info!(
+            bin_path=%config().local_caddy.bin_path.display(),
+            config_dir=%config().local_caddy.config_dir.display(),
+            source=config().local_caddy_source,
+            "using local Caddy",
+        );
+    }
+
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!(error=%e, "exiting due to error");
+            failure::exit_code(e.as_ref())
+        },
+    }
+}
+
+async fn run() -> Result<()> {
+    match config().command {
+        Command::Run => {
+            prepare_snippets_dirs()?;
+            if config().check_for_updates {
+                tokio::spawn(update_check::check_for_update());
+            }
+            if let Some(address) = config().metrics_address {
+                metrics::spawn(address);
+            }
+            let mut listener = Listener::new()?;
+
+            listener.listen().await?;
+        }
+        Command::Generate { ref output, reload } => {
+            let rendered = render_snippets(&discover_apps(&new_docker()?, &mut InspectCache::default()).await?)?;
+            match output {
+                None if config().dry_run => rendered.write_marked(&mut std::io::stdout().lock())?,
+                None if reload => {
+                    prepare_snippets_dirs()?;
+                    // held until Caddy has reloaded, as when running
+                    let _locks = SnippetLocks::acquire().await?;
+                    rendered.write_files()?;
+                    let listener = Listener::new()?;
+                    listener.reload_caddy().await.map_err(|e| Failure::wrap(FailureKind::Reload, e))?;
+                    listener.sync_remote_caddies().await;
+                },
+                None => {
+                    prepare_snippets_dirs()?;
+                    rendered.write_files()?
+                },
+                Some(path) if path.as_os_str() == "-" => rendered.write_marked(&mut std::io::stdout().lock())?,
+                Some(path) => rendered.write_marked(&mut File::create(path)?)?,
+            }
+        }
+        Command::Plan { auto_approve } => plan::plan(auto_approve).await?,
+        Command::Report => {
+            report::print(&discover_apps(&new_docker()?, &mut InspectCache::default()).await?)?;
+        }
+        Command::Agent { listen } => agent::serve(listen).await?,
+        Command::ListApps { json } => list_apps::print(&discover_apps(&new_docker()?, &mut InspectCache::default()).await?, json)?,
+        Command::LintLabels => lint::lint(&new_docker()?).await?,
+        Command::Schema => {
+            println!("{}", serde_json::to_string_pretty(&schema::label_schema())?);
+        }
+        Command::TemplateContext { command: TemplateContextCommand::Defaults } => {
+            print!("{{# local #}}\n{}\n{{# docker #}}\n{}", template::DEFAULT_LOCAL_TEMPLATE, template::DEFAULT_DOCKER_TEMPLATE);
+        }
+        Command::TemplateContext { command: TemplateContextCommand::Dump } => {
+            let app_data = discover_apps(&new_docker()?, &mut InspectCache::default()).await?;
+            println!("{}", serde_json::to_string_pretty(&template::TemplateContext::new(&app_data))?);
+        }
+        Command::Service { ref label, command: ServiceCommand::Install } => service::install(label)?,
+        Command::Service { ref label, command: ServiceCommand::Uninstall } => service::uninstall(label)?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The command line the config is built from in tests, with the secrets dir in a temp dir of
+    /// this process's own
+    pub(crate) fn args() -> Vec<String> {
+        [
+            "docker-caddy-rs",
+            "--local-caddy-snippets-dir", "/tmp/local",
+            "--docker-caddy-snippets-dir", "/tmp/docker",
+            "--label-prefix", "test",
+            "--local-domain-prefix", "local",
+            "--domain-name", "example.com",
+            "--unknown-auth-policy", "reject",
+            "--external-bind-addresses", "0.0.0.0,[::]",
+            "--instance-name", "test",
+            "--snippet-groups", "dmz",
+            "--power-dns-url", "http://localhost:8081",
+            "--power-dns-server", "localhost",
+            "--power-dns-api-key", "key",
+        ].into_iter()
+            .map(str::to_string)
+            .chain(["--secrets-dir".to_string(), secrets_dir().display().to_string()])
+            .collect()
+    }
+
+    pub(crate) fn secrets_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("docker-caddy-rs-test-secrets-{}", std::process::id()))
+    }
+
+    /// A container with the given labels, which are prefixed with the label prefix
+    pub(crate) fn container(name: &str, labels: &[(&str, &str)]) -> ContainerSummaryInternal {
+        ContainerSummaryInternal {
+            id: format!("{name}-id"),
+            container_name: name.to_string(),
+            labels: Some(labels.iter().map(|(label, value)| (format!("test.{label}"), value.to_string())).collect()),
+            network_mode_host: false,
+            networks: Vec::new(),
+            image: None,
+            started_at: None,
+            paused: false,
+            health: Health::None,
+        }
+    }
+
+    /// The app a container with the given labels serves, with the container added to it
+    pub(crate) fn app(name: &str, labels: &[(&str, &str)]) -> AppData {
+        let container = container(&format!("{name}-1"), &[&[("app", name)], labels].concat());
+        let mut app = AppData::new_from_container(&container).unwrap().unwrap();
+        app.containers.push(AppContainerData::new_from_summary(&container).unwrap());
+        app
+    }
+
+    /// Held by tests writing to the snippets dirs, which all tests share
+    pub(crate) static SNIPPETS_DIRS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    pub(crate) fn app_data(apps: Vec<AppData>) -> ApplicationData {
+        apps.into_iter().map(|app| (app.app_name.clone(), app)).collect()
+    }
+
+    #[test]
+    fn render_snippets_orders_apps_by_priority_then_name() {
+        let rendered = render_snippets(&app_data(vec![
+            app("bravo", &[("port", "80"), ("external", "true")]),
+            app("alpha", &[("port", "80"), ("external", "true")]),
+            app("charlie", &[("port", "80"), ("external", "true"), ("priority", "10")]),
+        ])).unwrap();
+
+        let position = |host: &str| rendered.docker.find(&format!("host {host}.example.com")).unwrap();
+        assert!(position("charlie") < position("alpha"));
+        assert!(position("alpha") < position("bravo"));
+    }
+
+    #[test]
+    fn render_snippets_writes_upstreams_to_both_instances() {
+        let rendered = render_snippets(&app_data(vec![
+            app("web", &[("port", "8080"), ("external", "true")]),
+            app("admin", &[("port", "9000")]),
+        ])).unwrap();
+
+        assert!(rendered.docker.contains("host web.example.com"));
+        assert!(rendered.docker.contains("reverse_proxy http://web-1:8080"));
+        assert!(rendered.docker.contains("host admin.local.example.com"));
+        assert!(rendered.docker.contains("reverse_proxy http://admin-1:9000"));
+        assert!(rendered.local.contains("host web.example.com"));
+        assert!(rendered.local.contains("host admin.local.example.com"));
+    }
+
+    #[test]
+    fn render_snippets_skips_apps_without_containers() {
+        let mut idle = app("idle", &[("port", "80")]);
+        idle.containers.clear();
+        let rendered = render_snippets(&app_data(vec![idle])).unwrap();
+
+        assert!(!rendered.docker.contains("idle"));
+        assert!(!rendered.local.contains("idle"));
+    }
+
+    #[test]
+    fn circuit_breaker_labels() {
+        let app = app("web", &[("port", "80"), ("fail_duration", "10s"), ("max_fails", "5"), ("unhealthy_status", "503, 5xx")]);
+
+        assert_eq!(app.circuit_breaker.options(1), vec![
+            ("fail_duration", "10s".to_string()),
+            ("max_fails", "5".to_string()),
+            ("unhealthy_status", "503 5xx".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn circuit_breaker_defaults_for_several_upstreams() {
+        let app = app("web", &[("port", "80")]);
+
+        assert!(app.circuit_breaker.options(1).is_empty());
+        assert_eq!(app.circuit_breaker.options(2), vec![
+            ("fail_duration", "30s".to_string()),
+            ("max_fails", "3".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn circuit_breaker_zero_fail_duration_disables() {
+        let app = app("web", &[("port", "80"), ("fail_duration", "0"), ("max_fails", "5")]);
+
+        assert!(app.circuit_breaker.options(2).is_empty());
+    }
+
+    #[test]
+    fn circuit_breaker_rejects_invalid_labels() {
+        let invalid = |labels: &[(&str, &str)]| {
+            let container = container("web-1", &[&[("app", "web"), ("port", "80")], labels].concat());
+            AppData::new_from_container(&container).is_err()
+        };
+
+        assert!(invalid(&[("fail_duration", "ten")]));
+        assert!(invalid(&[("max_fails", "-1")]));
+        assert!(invalid(&[("unhealthy_status", "600")]));
+        assert!(invalid(&[("unhealthy_status", "50x")]));
+    }
+
+    #[test]
+    fn secrets_are_only_resolved_in_the_snippets() {
+        let path = secrets_dir().join("render-token");
+        std::fs::create_dir_all(secrets_dir()).unwrap();
+        std::fs::write(path, "s3cr3t\n").unwrap();
+        let app = app("web", &[("port", "80"), ("caddy.extra", "header_up X-Token secret://render-token")]);
+
+        assert_eq!(app.extra_directives, vec!["header_up X-Token secret://render-token"]);
+        assert!(!serde_json::to_string(&app).unwrap().contains("s3cr3t"));
+        let rendered = render_snippets(&app_data(vec![app])).unwrap();
+        assert!(rendered.docker.contains("header_up X-Token s3cr3t"));
+    }
+
+    #[test]
+    fn apps_with_missing_secrets_are_left_out() {
+        let rendered = render_snippets(&app_data(vec![
+            app("web", &[("port", "80"), ("caddy.extra", "header_up X-Token secret://missing")]),
+            app("admin", &[("port", "80")]),
+        ])).unwrap();
+
+        assert!(!rendered.docker.contains("web.local.example.com"));
+        assert!(rendered.docker.contains("admin.local.example.com"));
+    }
+
+    #[test]
+    fn apps_without_reachable_containers_are_left_out() {
+        let rendered = render_snippets(&app_data(vec![
+            app("web", &[("port", "80"), ("network", "proxy")]),
+            app("admin", &[("port", "80")]),
+        ])).unwrap();
+
+        assert!(!rendered.docker.contains("web.local.example.com"));
+        assert!(rendered.docker.contains("admin.local.example.com"));
+    }
+
+    #[test]
+    fn ip_family_preference_picks_an_allowed_address() {
+        let ipv4 = Some(Ipv4Addr::new(172, 18, 0, 2));
+        let ipv6 = Some("fd00::2".parse::<Ipv6Addr>().unwrap());
+
+        assert_eq!(IpFamilyPreference::PreferV4.pick(ipv4, ipv6), ipv4.map(IpAddr::V4));
+        assert_eq!(IpFamilyPreference::PreferV6.pick(ipv4, ipv6), ipv6.map(IpAddr::V6));
+        assert_eq!(IpFamilyPreference::PreferV6.pick(ipv4, None), ipv4.map(IpAddr::V4));
+        assert_eq!(IpFamilyPreference::V6Only.pick(ipv4, None), None);
+        assert_eq!(IpFamilyPreference::V4Only.pick(None, ipv6), None);
+    }
+
+    #[test]
+    fn containers_with_invalid_labels_are_skipped() {
+        let invalid = container("web-1", &[("app", "web"), ("port", "eighty")]);
+        let valid = container("admin-1", &[("app", "admin"), ("port", "80")]);
+
+        assert!(AppData::new_from_container_or_warn(&invalid).is_none());
+        assert!(AppData::new_from_container_or_warn(&valid).is_some());
+    }
+
+    #[test]
+    fn only_unreachable_docker_is_a_connection_error() {
+        let refused = docker_api::Error::IO(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        let fault = docker_api::Error::Fault { code: reqwest::StatusCode::INTERNAL_SERVER_ERROR, message: "oops".to_string() };
+        let labels: Box<dyn std::error::Error> = "invalid port".into();
+
+        assert!(is_connection_error(&refused));
+        assert!(!is_connection_error(&fault));
+        assert!(!is_connection_error(labels.as_ref()));
+    }
+
+    #[test]
+    fn well_known_files_are_served_ahead_of_auth() {
+        let rendered = render_snippets(&app_data(vec![app("web", &[
+            ("port", "80"), ("auth", "headers"), ("robots", "deny"), ("security_contact", "mailto:security@example.com"),
+        ])])).unwrap();
+
+        let app_handle = rendered.docker.find("handle @web {").unwrap();
+        assert!(rendered.docker.find("handle @web_robots {").unwrap() < app_handle);
+        assert!(rendered.docker.find("handle @web_security_txt {").unwrap() < app_handle);
+        assert!(rendered.docker.contains("path /.well-known/security.txt"));
+        assert!(rendered.docker[app_handle..].contains("import auth-headers"));
+    }
+
+    #[test]
+    fn security_contact_rejects_backticks() {
+        let container = container("web-1", &[("app", "web"), ("port", "80"), ("security_contact", "mailto:a@example.com` 200\n}")]);
+
+        assert!(AppData::new_from_container(&container).is_err());
+    }
+
+    #[test]
+    fn public_paths_are_served_ahead_of_auth_with_the_retry_policy() {
+        let rendered = render_snippets(&app_data(vec![app("web", &[
+            ("port", "80"), ("auth", "headers"), ("public_paths", "/health"), ("lb_retries", "2"),
+        ])])).unwrap();
+
+        let public = rendered.docker.find("handle @web_public_paths {").unwrap();
+        let app_handle = rendered.docker.find("handle @web {").unwrap();
+        assert!(public < app_handle);
+        assert!(rendered.docker[public..app_handle].contains("reverse_proxy http://web-1:80 {\n    lb_retries 2\n  }"));
+        assert!(rendered.local.find("handle @web_public_paths {").unwrap() < rendered.local.find("handle @web {").unwrap());
+    }
+
+    #[test]
+    fn auth_headers_must_be_header_names() {
+        let parse = |headers: &str| AppData::new_from_container(&container("web-1", &[("app", "web"), ("port", "80"), ("auth", "headers"), ("auth_headers", headers)]));
+
+        assert_eq!(parse("Remote-User, X-Email").unwrap().unwrap().auth_headers, vec!["Remote-User", "X-Email"]);
+        assert!(parse("Remote-User *\n}").is_err());
+        assert!(parse("Remote User").is_err());
+        assert!(parse("X-Email{").is_err());
+    }
+
+    #[test]
+    fn apps_wait_for_a_healthy_dependency() {
+        let mut api = app("api", &[("port", "80")]);
+        let ui = app("ui", &[("port", "80"), ("depends_on", "api")]);
+        api.containers[0].health = Health::Starting;
+        let mut apps = app_data(vec![api, ui]);
+
+        assert_eq!(apps["ui"].waiting_for(&apps).map(|wait| wait.to_string()).as_deref(), Some("waiting for api"));
+        assert!(!render_snippets(&apps).unwrap().docker.contains("host ui."));
+
+        let api = apps.get_mut("api").unwrap();
+        api.containers[0].health = Health::Unhealthy;
+        assert!(apps["ui"].waiting_for(&apps).is_some());
+
+        apps.get_mut("api").unwrap().containers[0].health = Health::Healthy;
+        assert!(apps["ui"].waiting_for(&apps).is_none());
+        assert!(render_snippets(&apps).unwrap().docker.contains("host ui."));
+    }
+
+    #[test]
+    fn parse_env_file_skips_comments_and_unquotes_values() {
+        let content = "# options\nDOMAIN_NAME=example.org\n\nexport LABEL_PREFIX = \"web\"\nSECRETS_DIR='/run/secrets'\nnot a variable\n";
+        assert_eq!(parse_env_file(content), vec![
+            ("DOMAIN_NAME", "example.org"),
+            ("LABEL_PREFIX", "web"),
+            ("SECRETS_DIR", "/run/secrets"),
+        ]);
+    }
+
+    #[test]
+    fn parse_cli_takes_env_file_options_below_the_command_line() {
+        let env_file = std::env::temp_dir().join(format!("docker-caddy-rs-env-{}", std::process::id()));
+        std::fs::write(&env_file, "DOMAIN_NAME=example.org\nLOCAL_DOMAIN_PREFIX=home\n").unwrap();
+        let mut command_line = args().into_iter()
+            .filter(|arg| arg != "--local-domain-prefix" && arg != "local")
+            .map(std::ffi::OsString::from)
+            .collect::<Vec<_>>();
+        command_line.extend(["--env-file".into(), env_file.clone().into()]);
+
+        let cli = parse_cli(&command_line);
+        std::fs::remove_file(&env_file).unwrap();
+        let cli = cli.unwrap();
+        // the command line's --domain-name wins
+        assert_eq!(cli.domain_name, "example.com");
+        assert_eq!(cli.local_domain_prefix, "home");
+    }
+
+    #[test]
+    fn starting_containers_get_no_traffic_and_unhealthy_ones_only_as_a_last_resort() {
+        let mut web = app("web", &[("port", "80")]);
+        for (name, health) in [("web-2", Health::Starting), ("web-3", Health::Unhealthy)] {
+            let mut adc = web.containers[0].clone();
+            adc.container_name = name.to_string();
+            adc.hostname = name.to_string();
+            adc.health = health;
+            web.containers.push(adc);
+        }
+        assert_eq!(web.upstream_targets(), vec!["http://web-1:80"]);
+
+        web.containers[0].health = Health::Unhealthy;
+        assert_eq!(web.upstream_targets(), vec!["http://web-1:80", "http://web-3:80"]);
+    }
+
+    #[test]
+    fn security_headers_are_only_added_to_external_apps() {
+        let rendered = render_snippets(&app_data(vec![
+            app("public", &[("port", "80"), ("external", "true"), ("auth", "headers"), ("security_headers", "strict")]),
+            app("private", &[("port", "80"), ("security_headers", "strict")]),
+        ])).unwrap();
+        let docker = rendered.docker + &rendered.local;
+        assert_eq!(docker.matches("Strict-Transport-Security \"max-age=63072000; includeSubDomains; preload\"").count(), 1);
+        assert!(docker.contains("Content-Security-Policy \"frame-ancestors 'none'\""));
+
+        assert!(AppData::new_from_container(&container("web-1", &[("app", "web"), ("port", "80"), ("security_headers", "loose")])).is_err());
+    }
+
+    #[test]
+    fn named_ports_are_served_on_hosts_of_their_own() {
+        let rendered = render_snippets(&app_data(vec![
+            app("web", &[("port", "80"), ("ports", "admin:9000, metrics:9100")]),
+        ])).unwrap();
+        assert!(rendered.docker.contains("host web.local.example.com"));
+        assert!(rendered.docker.contains("reverse_proxy http://web-1:80"));
+        assert!(rendered.docker.contains("host web-admin.local.example.com"));
+        assert!(rendered.docker.contains("reverse_proxy http://web-1:9000"));
+        assert!(rendered.docker.contains("host web-metrics.local.example.com"));
+
+        let parse = |ports: &str| AppData::new_from_container(&container("web-1", &[("app", "web"), ("ports", ports)]));
+        assert!(matches!(parse("admin:9000").unwrap().unwrap().upstream, AppUpstream::Port(9000)));
+        assert!(parse("9000").is_err());
+        assert!(parse("admin:http").is_err());
+    }
+
+    #[test]
+    fn same_config_ignores_containers_but_not_labels() {
+        let web = app("web", &[("port", "80")]);
+        let mut redeployed = app("web", &[("port", "80")]);
+        redeployed.containers[0].container_id = "web-2-id".to_string();
+        assert!(web.same_config(&redeployed));
+
+        let relabelled = app("web", &[("port", "8080")]);
+        assert!(!web.same_config(&relabelled));
+    }
+
+    #[test]
+    fn upstream_url_template_fills_in_the_container_and_app() {
+        let mut web = app("web", &[("port", "80")]);
+        web.containers[0].network = Some("proxy".to_string());
+        let adc = &web.containers[0];
+        assert_eq!(adc.upstream_url("web", "web-1", 80), "http://web-1:80");
+        assert_eq!(adc.fill_url_template("h2c://{name}.{network}.local:{port}/{app}", "web", "10.0.0.2", 8080), "h2c://web-1.proxy.local:8080/web");
+    }
+
+    #[test]
+    fn debounced_changes_are_written_as_one_batch() {
+        let mut pending = PendingWrite { deadline: Instant::now(), actions: Vec::new(), app_names: Vec::new() };
+        pending.add("start", "web");
+        pending.add("start", "web");
+        let change = pending.change();
+        assert_eq!((change.action, change.app_name), ("start", Some("web")));
+
+        pending.add("die", "api");
+        let change = pending.change();
+        assert_eq!((change.action, change.app_name), ("batch", None));
+        assert_eq!(pending.app_names, ["web", "api"]);
+    }
+
+    #[test]
+    fn external_sites_are_bound_to_the_external_bind_addresses() {
+        let rendered = render_snippets(&app_data(vec![
+            app("public", &[("port", "80"), ("external", "true"), ("auth", "headers")]),
+            app("private", &[("port", "80")]),
+        ])).unwrap();
+        assert_eq!(rendered.local.matches("bind 0.0.0.0 [::]\n").count(), 1, "{}", rendered.local);
+        assert!(!rendered.docker.contains("bind "));
+    }
+
+    #[test]
+    fn written_snippets_match_the_files_until_they_change() {
+        let _dirs = SNIPPETS_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+        for dir in snippets_dirs() {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        let rendered = render_snippets(&app_data(vec![app("web", &[("port", "80")])])).unwrap();
+        rendered.write_files().unwrap();
+        assert!(rendered.matches_files());
+
+        let changed = render_snippets(&app_data(vec![app("web", &[("port", "8080")])])).unwrap();
+        assert!(!changed.matches_files());
+    }
+
+    #[test]
+    fn only_orphaned_snippet_files_marked_as_ours_are_removed() {
+        let _dirs = SNIPPETS_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = &config().local_caddy.snippets_dir;
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::create_dir_all(&config().docker_caddy.snippets_dir).unwrap();
+        std::fs::write(dir.join("old.caddy"), managed_header().unwrap() + "old.example.com {\n}\n").unwrap();
+        std::fs::write(dir.join("edited.caddy"), "edited.example.com {\n}\n").unwrap();
+        let names = ["old.caddy", "edited.caddy", "../escape.caddy"].map(str::to_string);
+        write_written_files(dir, "test", &names).unwrap();
+
+        assert!(remove_orphaned_snippet_files().unwrap());
+        assert!(!dir.join("old.caddy").exists());
+        assert!(dir.join("edited.caddy").exists());
+        assert!(written_files(dir, "test").unwrap().is_empty());
+        std::fs::remove_file(dir.join("edited.caddy")).unwrap();
+    }
+
+    #[test]
+    fn containers_missing_for_longer_than_the_grace_period_are_expired() {
+        let mut listener = Listener::new().unwrap();
+        listener.app_data = app_data(vec![app("web", &[("port", "80")]), app("api", &[("port", "80")])]);
+        let running = HashSet::from(["api-1-id".to_string()]);
+        let missing = Instant::now();
+
+        assert!(listener.expire_missing(&running, missing).is_empty());
+        assert!(listener.missing_since.contains_key("web-1-id"));
+
+        let expired = listener.expire_missing(&running, missing + config().missing_container_grace);
+        assert_eq!(expired, ["web"]);
+        assert_eq!(listener.app_data.keys().collect::<Vec<_>>(), ["api"]);
+        assert!(listener.missing_since.is_empty());
+    }
+
+    #[test]
+    fn snippets_dirs_must_exist_unless_they_may_be_created() {
+        let dir = std::env::temp_dir().join(format!("docker-caddy-rs-test-snippets-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let error = prepare_snippets_dir(&dir, "--local-caddy-snippets-dir").unwrap_err().to_string();
+        assert!(error.contains("does not exist") && error.contains("--create-dirs"), "{error}");
+
+        std::fs::write(&dir, "").unwrap();
+        let error = prepare_snippets_dir(&dir, "--local-caddy-snippets-dir").unwrap_err().to_string();
+        assert!(error.contains("is not a directory"), "{error}");
+
+        std::fs::remove_file(&dir).unwrap();
+        std::fs::create_dir(&dir).unwrap();
+        assert!(prepare_snippets_dir(&dir, "--local-caddy-snippets-dir").is_ok());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn further_comma_separated_ports_get_hosts_of_their_own() {
+        let rendered = render_snippets(&app_data(vec![
+            app("web", &[("port", "80, metrics:9100,9200")]),
+        ])).unwrap();
+        assert!(rendered.docker.contains("host web.local.example.com"));
+        assert!(rendered.docker.contains("reverse_proxy http://web-1:80"));
+        assert!(rendered.docker.contains("host web-metrics.local.example.com"));
+        assert!(rendered.docker.contains("reverse_proxy http://web-1:9100"));
+        assert!(rendered.docker.contains("host web-9200.local.example.com"));
+
+        assert!(AppData::new_from_container(&container("web-1", &[("app", "web"), ("port", "80,metrics:http")])).is_err());
+    }
+
+    #[test]
+    fn indexed_labels_define_several_apps_on_one_container() {
+        let multi = container("multi-1", &[
+            ("external", "true"),
+            ("0.app", "web"), ("0.port", "80"),
+            ("1.app", "admin"), ("1.port", "9000"), ("1.external", "false"),
+        ]);
+        let apps = multi.app_views().iter()
+            .map(|view| AppData::new_from_container(view).unwrap().unwrap())
+            .map(|ad| (ad.app_name.clone(), ad.external, ad.upstream))
+            .collect::<Vec<_>>();
+        assert!(matches!(apps.as_slice(), [
+            (web, true, AppUpstream::Port(80)),
+            (admin, false, AppUpstream::Port(9000)),
+        ] if web == "web" && admin == "admin"), "{apps:?}");
+
+        assert_eq!(container("web-1", &[("app", "web")]).app_views().len(), 1);
+        assert!(container("db-1", &[("port", "5432")]).app_views().is_empty());
+    }
+
+    #[test]
+    fn split_ports_serve_each_domain_from_its_own_port() {
+        let web = app("web", &[("port", "80"), ("external", "true"), ("auth", "headers"), ("port.external", "8443"), ("port.internal", "9000")]);
+        let variants = web.with_port_variants().iter()
+            .map(|ad| (ad.matcher(), ad.external, ad.upstream_targets()))
+            .collect::<Vec<_>>();
+        assert_eq!(variants, [
+            ("web_external".to_string(), true, vec!["http://web-1:8443".to_string()]),
+            ("web_internal".to_string(), false, vec!["http://web-1:9000".to_string()]),
+        ]);
+
+        // an internal app keeps its own block, as only port.external replaces it
+        let api = app("api", &[("port", "80"), ("port.external", "8443")]);
+        let matchers = api.with_port_variants().iter().map(|ad| ad.matcher()).collect::<Vec<_>>();
+        assert_eq!(matchers, ["api", "api_external"]);
+    }
+
+    #[test]
+    fn compose_replicas_are_ordered_by_number_ahead_of_other_containers() {
+        let mut web = app("web", &[("port", "80")]);
+        web.containers[0].container_name = "zz-standalone".to_string();
+        web.containers[0].hostname = "zz-standalone".to_string();
+        for replica in [10, 2] {
+            let mut adc = web.containers[0].clone();
+            adc.container_name = format!("web-{replica}");
+            adc.hostname = adc.container_name.clone();
+            adc.replica = Some(replica);
+            web.containers.push(adc);
+        }
+        assert_eq!(web.upstream_targets(), ["http://web-2:80", "http://web-10:80", "http://zz-standalone:80"]);
+    }
+
+    #[test]
+    fn extra_directives_go_in_order_ahead_of_the_reverse_proxy() {
+        let web = app("web", &[
+            ("port", "80"),
+            ("caddy.extra.10", "encode gzip"),
+            ("caddy.extra.2", "request_body {\n  max_size 10MB\n}"),
+            ("caddy.extra", "header -Server"),
+            ("caddy.extra.x", "ignored"),
+        ]);
+        assert_eq!(web.extra_directives, ["header -Server", "request_body {\n  max_size 10MB\n}", "encode gzip"]);
+
+        let docker = render_snippets(&app_data(vec![web])).unwrap().docker;
+        let extra = docker.find("header -Server\n    request_body {\n      max_size 10MB\n    }\n    encode gzip\n").expect(&docker);
+        assert!(extra < docker.find("reverse_proxy").unwrap());
+    }
+
+    #[test]
+    fn caddy_instances_can_override_the_domains() {
+        let command_line = args().into_iter()
+            .chain(["--docker-caddy-internal-domain", "internal.lan", "--local-caddy-external-domain", "example.net"].map(str::to_string))
+            .map(std::ffi::OsString::from)
+            .collect::<Vec<_>>();
+        let config = Config::new(parse_cli(&command_line).unwrap()).unwrap();
+        assert_eq!(config.docker_caddy.external_domain, "example.com");
+        assert_eq!(config.local_caddy.local_domain, "local.example.com");
+
+        let internal = app("web", &[("port", "80")]);
+        assert_eq!(internal.domain_in(&config.docker_caddy), "internal.lan");
+        assert_eq!(internal.domain_in(&config.local_caddy), "local.example.com");
+        let external = app("web", &[("port", "80"), ("external", "true")]);
+        assert_eq!(external.domain_in(&config.local_caddy), "example.net");
+    }
+
+    #[test]
+    fn compose_replicas_are_grouped_into_one_app() {
+        let mut app_data = ApplicationData::new();
+        for replica in ["1", "2"] {
+            let mut replica_container = container(&format!("shop-web-{replica}"), &[("app", "shop"), ("port", "80")]);
+            replica_container.labels.as_mut().unwrap().extend([
+                (COMPOSE_PROJECT_LABEL.to_string(), "shop".to_string()),
+                (COMPOSE_SERVICE_LABEL.to_string(), "web".to_string()),
+                (COMPOSE_CONTAINER_NUMBER_LABEL.to_string(), replica.to_string()),
+            ]);
+            add_discovered_container(&mut app_data, &replica_container);
+        }
+        add_discovered_container(&mut app_data, &container("db-1", &[]));
+
+        assert_eq!(app_data.keys().collect::<Vec<_>>(), ["shop"]);
+        let containers = app_data["shop"].containers.iter()
+            .map(|adc| (adc.container_name.as_str(), adc.service.as_deref(), adc.replica))
+            .collect::<Vec<_>>();
+        assert_eq!(containers, [("shop-web-1", Some("web"), Some(1)), ("shop-web-2", Some("web"), Some(2))]);
+        // without --project-filter, containers outside compose are selected too
+        assert!(project_selected(None));
+    }
+
+    #[test]
+    fn unknown_auth_types_follow_the_unknown_auth_policy() {
+        let rendered = render_snippets(&app_data(vec![
+            app("typo", &[("port", "80"), ("auth", "oicd")]),
+            app("fine", &[("port", "80")]),
+        ])).unwrap();
+        assert!(!rendered.docker.contains("typo.local.example.com"));
+        assert!(rendered.docker.contains("fine.local.example.com"));
+
+        assert!(matches!(parse_unknown_auth_policy("none"), Ok(UnknownAuthPolicy::None)));
+        assert!(matches!(parse_unknown_auth_policy("fallback:sso"), Ok(UnknownAuthPolicy::Fallback(snippet)) if snippet == "sso"));
+        assert!(parse_unknown_auth_policy("fallback:").is_err());
+        assert!(parse_unknown_auth_policy("allow").is_err());
+    }
+
+    #[test]
+    fn require_auth_for_external_rejects_only_unauthenticated_external_apps() {
+        let open = app("open", &[("port", "80"), ("external", "true")]);
+        assert!(open.rejection().is_none());
+        assert!(open.rejection_with(true).is_some_and(|reason| reason.contains("--require-auth-for-external")));
+        assert!(app("headers", &[("port", "80"), ("external", "true"), ("auth", "headers")]).rejection_with(true).is_none());
+        assert!(app("internal", &[("port", "80")]).rejection_with(true).is_none());
+    }
+
+    #[test]
+    fn reload_config_replaces_the_running_options() {
+        let before = config();
+        reload_config().unwrap();
+        // the running options stay valid for whoever still holds them
+        assert!(!std::ptr::eq(before, config()));
+        assert_eq!(before.local_caddy.external_domain, config().local_caddy.external_domain);
+    }
+
+    #[test]
+    fn socket_upstreams_replace_the_port() {
+        let web = app("web", &[("socket", "/run/web/web.sock"), ("port", "80")]);
+        assert_eq!(web.upstream_targets(), ["unix//run/web/web.sock"]);
+        let rendered = render_snippets(&app_data(vec![web])).unwrap();
+        assert!(rendered.docker.contains("reverse_proxy unix//run/web/web.sock"), "{}", rendered.docker);
+
+        assert!(AppData::new_from_container(&container("web-1", &[("app", "web")])).is_err());
+    }
+
+    #[test]
+    fn blocked_paths_get_each_apps_blocked_path_action() {
+        let rendered = render_snippets(&app_data(vec![
+            app("web", &[("port", "80"), ("blocked_path_action", "not-found")]),
+            app("api", &[("port", "80")]),
+        ])).unwrap();
+        // /metrics and /metrics/* in both Caddy instances
+        assert_eq!(rendered.docker.matches("respond \"Not Found\" 404").count(), 2);
+        assert_eq!(rendered.docker.matches("abort").count(), 2);
+        assert_eq!(rendered.local.matches("respond \"Not Found\" 404").count(), 2);
+
+        assert!(AppData::new_from_container(&container("web-1", &[("app", "web"), ("port", "80"), ("blocked_path_action", "drop")])).is_err());
+    }
+
+    #[test]
+    fn internal_paths_are_blocked_outside_the_internal_cidrs_on_external_apps() {
+        let web = app("web", &[("port", "80"), ("external", "true"), ("auth", "headers"), ("internal_paths", "/admin/*, /debug/*")]);
+        assert_eq!(web.internal_paths, ["/admin/*", "/debug/*"]);
+        let internal_paths = web.format_internal_paths();
+        assert!(internal_paths.contains("@web_internal_paths {\n  host web.example.com\n  path /admin/* /debug/*\n  not remote_ip private_ranges\n"), "{internal_paths}");
+        assert!(internal_paths.contains("handle @web_internal_paths {\n  abort\n}"), "{internal_paths}");
+
+        // internal apps are only reachable from inside anyway
+        assert!(app("api", &[("port", "80"), ("internal_paths", "/admin/*")]).format_internal_paths().is_empty());
+    }
+
+    #[test]
+    fn geo_policy_blocks_outside_the_allowed_countries() {
+        let web = app("web", &[("port", "80"), ("allow_countries", "de, at"), ("deny_countries", "ru")]);
+        assert_eq!(web.allow_countries, ["DE", "AT"]);
+        let geo_policy = web.format_geo_policy();
+        assert!(geo_policy.contains("@web_geo_blocked {\n  host web.local.example.com\n  not remote_ip private_ranges\n"), "{geo_policy}");
+        assert!(geo_policy.contains("    allow_countries DE AT\n    deny_countries RU\n"), "{geo_policy}");
+
+        assert!(app("api", &[("port", "80")]).format_geo_policy().is_empty());
+    }
+
+    #[test]
+    fn client_auth_apps_get_a_local_site_of_their_own() {
+        let rendered = render_snippets(&app_data(vec![
+            app("web", &[("port", "80"), ("client_auth", "verify-if-given"), ("client_auth_ca", "/certs/ca.pem")]),
+        ])).unwrap();
+        assert!(rendered.local.contains("web.local.example.com {\n  tls {\n    client_auth {\n      mode verify_if_given\n      trust_pool file /certs/ca.pem\n"), "{}", rendered.local);
+
+        let missing_ca = container("web-1", &[("app", "web"), ("port", "80"), ("client_auth", "require")]);
+        assert!(AppData::new_from_container(&missing_ca).is_err());
+    }
+
+    #[test]
+    fn generate_writes_both_snippets_marked_with_their_instance() {
+        let rendered = render_snippets(&app_data(vec![app("web", &[("port", "80")])])).unwrap();
+        let mut output = Vec::new();
+        rendered.write_marked(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let file_name = &config().snippet_file_name;
+        assert_eq!(output, format!("# ---- local caddy: {file_name} ----\n{}# ---- docker caddy: {file_name} ----\n{}", rendered.local, rendered.docker));
+
+        let command_line = |extra: &[&str]| args().into_iter()
+            .chain(["generate"].iter().chain(extra).map(|arg| arg.to_string()))
+            .map(std::ffi::OsString::from)
+            .collect::<Vec<_>>();
+        assert!(matches!(parse_cli(&command_line(&["--output", "-"])).unwrap().command, Some(Command::Generate { output: Some(_), reload: false })));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reload_hooks_get_the_change_in_their_environment() {
+        let out = std::env::temp_dir().join(format!("docker-caddy-rs-hook-{}", std::process::id()));
+        let hook = format!("echo \"$DOCKER_CADDY_ACTION $DOCKER_CADDY_APP $DOCKER_CADDY_RELOAD_STATUS\" > {}", out.display());
+        run_reload_hook("post-reload", &hook, &SnippetChange { action: "start", app_name: Some("web") }, &[("DOCKER_CADDY_RELOAD_STATUS", "success")]);
+        let written = std::fs::read_to_string(&out).unwrap();
+        std::fs::remove_file(&out).unwrap();
+        assert_eq!(written, "start web success\n");
+
+        // a failing hook is only logged
+        run_reload_hook("pre-reload", "exit 1", &SnippetChange { action: "startup", app_name: None }, &[]);
+    }
+
+    #[test]
+    fn insecure_http_is_only_served_for_internal_apps() {
+        let rendered = render_snippets(&app_data(vec![
+            app("printer", &[("port", "80"), ("insecure_http", "true")]),
+            app("public", &[("port", "80"), ("external", "true"), ("auth", "headers"), ("insecure_http", "true")]),
+        ])).unwrap();
+        assert!(rendered.local.contains("http://printer.local.example.com {"), "{}", rendered.local);
+        assert!(!rendered.local.contains("http://public."));
+
+        let mtls = app("mtls", &[("port", "80"), ("insecure_http", "true"), ("client_auth", "require"), ("client_auth_ca", "/ca.pem")]);
+        assert!(!mtls.insecure_http);
+    }
+
+    #[test]
+    fn inspect_responses_give_the_image_and_start_time() {
+        let inspect = serde_json::from_value::<ContainerInspect200Response>(serde_json::json!({
+            "Id": "web-1-id",
+            "Name": "/web-1",
+            "Config": {"Image": "nginx:1.27", "Labels": {"test.app": "web", "test.port": "80"}},
+            "State": {"StartedAt": "2026-10-16T09:00:00Z"},
+        })).unwrap();
+        let summary = ContainerSummaryInternal::new_from_inspect(&inspect).unwrap();
+        assert_eq!(summary.container_name, "web-1");
+        assert_eq!(summary.image.as_deref(), Some("nginx:1.27"));
+        assert_eq!(summary.started_at.as_deref(), Some("2026-10-16T09:00:00Z"));
+
+        let adc = AppContainerData::new_from_summary(&summary).unwrap();
+        assert_eq!((adc.image, adc.started_at), (summary.image, summary.started_at));
+    }
+
+    #[test]
+    fn the_dns_provider_label_overrides_the_default_provider() {
+        assert_eq!(app("web", &[("port", "80")]).dns_provider, DnsProviderCli::None);
+        assert_eq!(app("web", &[("port", "80"), ("external", "true"), ("dns_provider", "power-dns")]).dns_provider, DnsProviderCli::PowerDNS);
+        assert!(AppData::new_from_container(&container("web-1", &[("app", "web"), ("port", "80"), ("dns_provider", "route53")])).is_err());
+
+        // --external-dns-provider defaults to --local-dns-provider
+        let command_line = args().into_iter()
+            .chain(["--local-dns-provider", "power-dns"].map(str::to_string))
+            .map(std::ffi::OsString::from)
+            .collect::<Vec<_>>();
+        let config = Config::new(parse_cli(&command_line).unwrap()).unwrap();
+        assert_eq!(config.dns.external_provider, DnsProviderCli::PowerDNS);
+    }
+
+    #[test]
+    fn the_rootless_socket_is_only_used_when_the_system_socket_is_absent() {
+        let existing = std::env::temp_dir();
+        let missing = existing.join("docker-caddy-rs-missing.sock");
+        let rootless = || Some(PathBuf::from("/run/user/1000/docker.sock"));
+
+        assert_eq!(pick_socket(ContainerRuntime::Docker, missing.clone(), rootless()), (rootless().unwrap(), "rootless"));
+        assert_eq!(pick_socket(ContainerRuntime::Docker, existing.clone(), rootless()), (existing.clone(), "system"));
+        assert_eq!(pick_socket(ContainerRuntime::Docker, missing.clone(), None), (missing.clone(), "system"));
+        // Podman is usually rootless, so its socket wins even when the system one exists
+        assert_eq!(pick_socket(ContainerRuntime::Podman, existing, rootless()), (rootless().unwrap(), "rootless"));
+        assert_eq!(pick_socket(ContainerRuntime::Podman, missing.clone(), None), (missing, "system"));
+    }
+
+    #[test]
+    fn a_redeploy_renders_the_same_snippets() {
+        let before = render_snippets(&app_data(vec![app("web", &[("port", "80")])])).unwrap();
+        let mut redeployed = app("web", &[("port", "80")]);
+        redeployed.containers[0].container_id = "web-1-new-id".to_string();
+        redeployed.containers[0].started_at = Some("2026-10-16T10:00:00Z".to_string());
+        assert!(render_snippets(&app_data(vec![redeployed])).unwrap() == before);
+
+        let moved = render_snippets(&app_data(vec![app("web", &[("port", "8080")])])).unwrap();
+        assert!(moved != before);
+    }
+
+    #[tokio::test]
+    async fn events_beyond_the_queue_depth_are_dropped_and_flagged() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        queue_events(tokio_stream::iter(1..=5), tx, overflowed.clone()).await;
+
+        assert!(overflowed.load(Ordering::SeqCst));
+        assert_eq!((rx.recv().await, rx.recv().await, rx.recv().await), (Some(1), Some(2), None));
+    }
+
+    #[test]
+    fn exec_output_keeps_its_end_on_a_char_boundary() {
+        assert_eq!(truncate_exec_output("  Error: adapting config\n"), "Error: adapting config");
+
+        // the cut falls inside the two bytes of the é
+        let output = format!("é{}Error: at the end", "x".repeat(MAX_EXEC_OUTPUT_LEN - 1 - "Error: at the end".len()));
+        let truncated = truncate_exec_output(&output);
+        assert!(truncated.starts_with("[truncated] ...x"), "{truncated}");
+        assert!(truncated.ends_with("Error: at the end"));
+        assert_eq!(truncated.len(), MAX_EXEC_OUTPUT_LEN - 1 + "[truncated] ...".len());
+    }
+
+    #[test]
+    fn paused_containers_go_last_in_the_upstreams() {
+        let mut web = app("web", &[("port", "80")]);
+        let mut second = web.containers[0].clone();
+        second.container_name = "web-2".to_string();
+        second.hostname = "web-2".to_string();
+        web.containers.push(second);
+        web.containers[0].paused = true;
+        assert_eq!(web.upstream_targets(), ["http://web-2:80", "http://web-1:80"]);
+    }
+
+    #[test]
+    fn local_caddy_paths_keep_what_is_configured() {
+        let bin = PathBuf::from("/srv/caddy/caddy");
+        let etc = PathBuf::from("/srv/caddy/etc");
+        assert_eq!(local_caddy_paths(Some(bin.clone()), Some(etc.clone())), (bin.clone(), etc, "configured"));
+
+        // the config dir is detected (or defaulted) alongside the configured binary
+        let (bin_path, config_dir, source) = local_caddy_paths(Some(bin.clone()), None);
+        assert_eq!(bin_path, bin);
+        assert!(LOCAL_CADDY_PREFIXES.iter().any(|prefix| config_dir == PathBuf::from(prefix).join("etc")));
+        assert_ne!(source, "configured");
+    }
+
+    #[test]
+    fn app_filters_match_globs() {
+        assert!(glob_matches("web-*", "web-api"));
+        assert!(glob_matches("*-api", "web-api"));
+        assert!(glob_matches("w?b*i", "web-api"));
+        assert!(glob_matches("*a*a*", "banana"));
+        assert!(!glob_matches("web-?", "web-api"));
+        assert!(!glob_matches("api", "web-api"));
+
+        let filter = AppFilter { only: vec!["web-*".to_string(), "api".to_string()], exclude: vec!["*-staging".to_string()] };
+        assert!(filter.selects("web-shop"));
+        assert!(filter.selects("api"));
+        assert!(!filter.selects("web-staging"));
+        assert!(!filter.selects("admin"));
+        assert!(AppFilter { only: Vec::new(), exclude: Vec::new() }.selects("admin"));
+    }
+
+    #[test]
+    fn instances_only_manage_their_own_and_unclaimed_apps() {
+        let labels = |extra: &[(&str, &str)]| container("web-1", &[&[("app", "web")], extra].concat()).labels.unwrap();
+        assert!(!claimed_by_other_instance(&labels(&[])));
+        assert!(!claimed_by_other_instance(&labels(&[("instance", "test")])));
+        assert!(claimed_by_other_instance(&labels(&[("instance", "other")])));
+
+        let rendered = render_snippets(&app_data(vec![app("web", &[("port", "80")])])).unwrap();
+        assert!(rendered.docker.starts_with("# managed by docker-caddy-rs instance test\n"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn events_of_containers_without_an_app_label_are_skipped_until_destroyed() {
+        // never connected to, so any inspect fails
+        let docker = Docker::unix("/nonexistent/docker.sock");
+        let event = || EventSummaryInternal { id: "db-1-id".to_string(), app_name: None, container_name: "db-1".to_string(), old_name: None };
+        let mut listener = Listener::new().unwrap();
+        listener.unlabelled.insert("db-1-id".to_string());
+
+        listener.handle_container_event(&docker, "start", event()).await.unwrap();
+        listener.handle_container_event(&docker, "destroy", event()).await.unwrap();
+        assert!(listener.unlabelled.is_empty());
+        assert!(listener.handle_container_event(&docker, "create", event()).await.is_err());
+    }
+
+    #[test]
+    fn compat_level_defaults_to_this_build() {
+        let command_line = |extra: &[&str]| args().into_iter()
+            .chain(extra.iter().map(|arg| arg.to_string()))
+            .map(std::ffi::OsString::from)
+            .collect::<Vec<_>>();
+        assert_eq!(Config::new(parse_cli(&command_line(&[])).unwrap()).unwrap().compat_level, CompatLevel::Latest);
+        let pinned = Config::new(parse_cli(&command_line(&["--compat-level", "0.1"])).unwrap()).unwrap();
+        assert_eq!(pinned.compat_level, CompatLevel::V0_1);
+        assert!(pinned.compat_level < CompatLevel::Latest);
+    }
+
+    #[test]
+    fn only_docker_404s_count_as_not_found() {
+        let fault = |code| -> Box<dyn std::error::Error> {
+            Box::new(docker_api::Error::Fault { code, message: "No such container: web-1".to_string() })
+        };
+        assert!(is_not_found(fault(reqwest::StatusCode::NOT_FOUND).as_ref()));
+        assert!(!is_not_found(fault(reqwest::StatusCode::INTERNAL_SERVER_ERROR).as_ref()));
+        assert!(!is_not_found(Box::<dyn std::error::Error>::from("not found").as_ref()));
+    }
+
+    #[test]
+    fn zoned_apps_go_in_their_snippet_group() {
+        let rendered = render_snippets(&app_data(vec![
+            app("web", &[("port", "80"), ("external", "true"), ("zone", "dmz")]),
+            app("api", &[("port", "80"), ("external", "true")]),
+        ])).unwrap();
+        let (plain, group) = rendered.docker.split_once("(dmz_docker_hosts)").unwrap();
+        assert!(group.contains("host web.example.com") && !plain.contains("host web.example.com"), "{}", rendered.docker);
+        assert!(plain.contains("host api.example.com"));
+        // configured groups are written even without apps
+        assert!(render_snippets(&app_data(vec![])).unwrap().local.contains("(dmz_docker_hosts)"));
+
+        let unknown = container("web-1", &[("app", "web"), ("port", "80"), ("zone", "lan")]);
+        assert!(AppData::new_from_container(&unknown).is_err());
+    }
+
+    #[test]
+    fn the_network_label_picks_the_upstream_network() {
+        let attached = |labels: &[(&str, &str)]| {
+            let mut multi = container("web-1", &[&[("app", "web"), ("port", "80")], labels].concat());
+            multi.networks = ["backend", "frontend"].map(|name| ContainerNetwork { name: name.to_string(), ipv4: None, ipv6: None }).to_vec();
+            let mut app = AppData::new_from_container(&multi).unwrap().unwrap();
+            app.containers.push(AppContainerData::new_from_summary(&multi).unwrap());
+            app
+        };
+
+        let first = attached(&[]);
+        assert_eq!(first.containers[0].network.as_deref(), Some("backend"));
+        let frontend = attached(&[("network", "frontend")]);
+        assert_eq!(frontend.containers[0].fill_url_template("http://{name}.{network}:{port}", "web", "web-1", 80), "http://web-1.frontend:80");
+        // not attached to the wanted network, so the docker Caddy can't reach it
+        assert!(attached(&[("network", "proxy")]).upstream_targets().is_empty());
+    }
+
+    #[test]
+    fn docker_host_picks_the_socket_or_a_remote_daemon() {
+        let docker_config = |extra: &[&str]| {
+            let command_line = args().into_iter()
+                .chain(extra.iter().map(|arg| arg.to_string()))
+                .map(std::ffi::OsString::from)
+                .collect::<Vec<_>>();
+            DockerConfig::new(&parse_cli(&command_line).unwrap())
+        };
+
+        let local = docker_config(&["--docker-host", "unix:///run/user/1000/docker.sock"]).unwrap();
+        assert_eq!(local.docker_socket_path, PathBuf::from("/run/user/1000/docker.sock"));
+        assert!(local.remote.is_none());
+        let remote = docker_config(&["--docker-host", "tcp://docker.lan:2375"]).unwrap().remote.unwrap();
+        assert_eq!(remote.host, "docker.lan:2375");
+        assert!(remote.tls.is_none());
+        let tls = docker_config(&["--docker-host", "tcp://docker.lan:2376", "--docker-tls-cert", "/certs/cert.pem", "--docker-tls-key", "/certs/key.pem"]).unwrap();
+        assert_eq!(tls.remote.unwrap().tls.unwrap().key, PathBuf::from("/certs/key.pem"));
+
+        assert!(docker_config(&["--docker-host", "https://docker.lan:2376"]).is_err());
+        assert!(docker_config(&["--docker-host", "ssh://docker.lan"]).is_err());
+        assert!(docker_config(&["--docker-host", "unix:///var/run/docker.sock", "--docker-socket-path", "/var/run/docker.sock"]).is_err());
+    }
+
+    #[test]
+    fn local_caddy_runs_from_the_reload_dir_with_the_reload_env() {
+        let command_line = args().into_iter()
+            .chain(["--local-caddy-reload-dir", "/srv/caddy", "--local-caddy-reload-env", "HOME=/var/lib/caddy,XDG_DATA_HOME="].map(str::to_string))
+            .map(std::ffi::OsString::from)
+            .collect::<Vec<_>>();
+        let config = Config::new(parse_cli(&command_line).unwrap()).unwrap();
+        let command_args = |command: &std::process::Command| command.get_args().map(|arg| arg.to_string_lossy().to_string()).collect::<Vec<_>>();
+
+        let command = local_caddy_command(&config.local_caddy, "reload", config.local_reload_dir.as_deref(), &config.local_reload_env).unwrap();
+        assert_eq!(command.get_current_dir(), Some(Path::new("/srv/caddy")));
+        assert_eq!(command_args(&command), ["reload", "--config", &config.local_caddy.config_dir.join("Caddyfile").display().to_string()]);
+        assert_eq!(command.get_envs().collect::<Vec<_>>(), [
+            (std::ffi::OsStr::new("HOME"), Some(std::ffi::OsStr::new("/var/lib/caddy"))),
+            (std::ffi::OsStr::new("XDG_DATA_HOME"), Some(std::ffi::OsStr::new(""))),
+        ]);
+
+        let command = local_caddy_command(&config.local_caddy, "validate", None, &[]).unwrap();
+        assert_eq!(command.get_current_dir(), Some(config.local_caddy.config_dir.as_path()));
+        assert_eq!(command_args(&command), ["validate"]);
+
+        assert!(parse_env_var("=value").is_err());
+    }
+
+    #[test]
+    fn podman_events_are_named_as_docker_names_them() {
+        let health = HashMap::from([("health_status".to_string(), "healthy".to_string())]);
+        assert_eq!(normalize_action(ContainerRuntime::Podman, "remove", None), "destroy");
+        assert_eq!(normalize_action(ContainerRuntime::Podman, "died", None), "die");
+        assert_eq!(normalize_action(ContainerRuntime::Podman, "health_status", Some(&health)), "health_status: healthy");
+        assert_eq!(normalize_action(ContainerRuntime::Podman, "start", None), "start");
+        assert_eq!(normalize_action(ContainerRuntime::Docker, "remove", None), "remove");
+
+        // Podman leaves the name out of some events
+        let event: EventMessage = serde_json::from_value(serde_json::json!({
+            "Type": "container",
+            "Action": "remove",
+            "Actor": { "ID": "web-1-id", "Attributes": { "test.app": "web" } },
+        })).unwrap();
+        let summary = EventSummaryInternal::new_from_event(&event).unwrap();
+        assert_eq!((summary.app_name.as_deref(), summary.container_name.as_str()), (Some("web"), "web-1-id"));
+    }
+
+    #[test]
+    fn reconnect_backoff_doubles_up_to_the_maximum() {
+        let max = Duration::from_secs(60);
+        let waits = std::iter::successors(Some(Duration::from_secs(1)), |&backoff| Some(next_backoff(backoff, max)))
+            .take(8)
+            .map(|backoff| backoff.as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(waits, [1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn every_reload_group_member_is_tried() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = std::env::temp_dir().join(format!("docker-caddy-rs-peers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let caddyfile = dir.join("Caddyfile");
+        std::fs::write(&caddyfile, ":80").unwrap();
+        let socket = dir.join("admin.sock");
+        let server = tokio::net::UnixListener::bind(&socket).unwrap();
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n:80") {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+        });
+
+        // the unreachable member is first, so the other is only reloaded if it's tried anyway
+        let peers = [format!("unix://{}", dir.join("missing.sock").display()), format!("unix://{}", socket.display())]
+            .map(|url| Url::parse(&url).unwrap());
+        let result = Listener::new().unwrap().reload_peers(&peers, &caddyfile).await;
+        peer.await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let error = result.unwrap_err().to_string();
+        assert!(error.starts_with("1 of 2 local Caddy reload group member(s) failed to reload"), "{error}");
+        assert!(error.contains("missing.sock"), "{error}");
+    }
+
+    #[test]
+    fn internal_hosts_on_the_external_domain_get_the_internal_host_response() {
+        let web = app("web", &[("port", "80")]);
+        assert_eq!(web.format_local_internal_host_site_with(InternalHostResponse::None), None);
+        let not_found = web.format_local_internal_host_site_with(InternalHostResponse::NotFound).unwrap();
+        assert!(not_found.starts_with("web.example.com {\n"), "{not_found}");
+        assert!(not_found.contains("respond \"Not Found\" 404"), "{not_found}");
+        let redirect = web.format_local_internal_host_site_with(InternalHostResponse::Redirect).unwrap();
+        assert!(redirect.contains("redir https://web.local.example.com{uri}"), "{redirect}");
+    }
+
+    #[test]
+    fn json_logs_put_the_event_fields_at_the_top_level() {
+        let buffer = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let writer = buffer.clone();
+        let subscriber = log_subscriber(LogFormat::Json, BoxMakeWriter::new(move || SharedBuffer(writer.clone())));
+        tracing::subscriber::with_default(subscriber, || {
+            info_span!("app", action="start").in_scope(|| info!(app_name="web", "reloaded"));
+        });
+
+        let line = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(line["app_name"], "web");
+        assert_eq!(line["message"], "reloaded");
+        assert_eq!(line["span"]["action"], "start");
+    }
+
+    struct SharedBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn secret_env_is_read_into_the_docker_caddy_reload() {
+        let secret_env = |extra: &[&str]| {
+            let command_line = args().into_iter()
+                .chain(extra.iter().map(|arg| arg.to_string()))
+                .map(std::ffi::OsString::from)
+                .collect::<Vec<_>>();
+            Config::new(parse_cli(&command_line).unwrap()).unwrap().docker_secret_env
+        };
+
+        assert_eq!(secret_env_assignments(&secret_env(&[])), "DO_API_KEY=\"$(cat \"$DO_API_KEY_FILE\")\" ");
+        // an empty path turns the default off
+        let custom = secret_env(&["--docker-caddy-secret-env", "DO_API_KEY=,CF_API_TOKEN=/run/secrets/cf\"token"]);
+        assert_eq!(secret_env_assignments(&custom), "CF_API_TOKEN=\"$(cat \"/run/secrets/cf\\\"token\")\" ");
+
+        assert!(parse_secret_env("1PASSWORD=/run/secrets/op").is_err());
+        assert!(parse_secret_env("CF-TOKEN=/run/secrets/cf").is_err());
+    }
+
+    #[test]
+    fn generate_only_reloads_when_writing_the_snippet_files() {
+        let command_line = |extra: &[&str]| args().into_iter()
+            .chain(["generate"].iter().chain(extra).map(|arg| arg.to_string()))
+            .map(std::ffi::OsString::from)
+            .collect::<Vec<_>>();
+        assert!(matches!(parse_cli(&command_line(&["--reload"])).unwrap().command, Some(Command::Generate { output: None, reload: true })));
+        assert!(matches!(parse_cli(&command_line(&[])).unwrap().command, Some(Command::Generate { output: None, reload: false })));
+        assert!(parse_cli(&command_line(&["--output", "-", "--reload"])).is_err());
+    }
+
+    #[test]
+    fn dry_runs_keep_the_logs_off_stdout() {
+        let command_line = args().into_iter()
+            .chain(["--dry-run".to_string()])
+            .map(std::ffi::OsString::from)
+            .collect::<Vec<_>>();
+        let config = Config::new(parse_cli(&command_line).unwrap()).unwrap();
+        assert!(config.dry_run);
+
+        assert!(logs_to_stdout(&Command::Run, false, false));
+        assert!(!logs_to_stdout(&config.command, false, config.dry_run));
+        assert!(!logs_to_stdout(&Command::Run, true, false));
+        assert!(!logs_to_stdout(&Command::Schema, false, false));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sigterm_and_sigint_ask_for_a_shutdown() {
+        // caught from here on, so neither stops the tests
+        let mut shutdowns = Shutdowns::new().unwrap();
+        for (signal, name) in [("-TERM", "SIGTERM"), ("-INT", "SIGINT")] {
+            std::process::Command::new("kill").args([signal, &std::process::id().to_string()]).status().unwrap();
+            assert_eq!(tokio::time::timeout(Duration::from_secs(5), shutdowns.recv()).await.unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn deletion_limits_take_a_count_or_a_share_of_the_served_apps() {
+        let count = parse_deletion_limit("2").unwrap();
+        assert!(!count.exceeded(2, 100));
+        assert!(count.exceeded(3, 100));
+        let percent = parse_deletion_limit("25%").unwrap();
+        assert!(!percent.exceeded(1, 4));
+        assert!(percent.exceeded(2, 4));
+
+        assert!(parse_deletion_limit("150%").is_err());
+        assert!(parse_deletion_limit("some").is_err());
+    }
+
+    #[test]
+    fn snippets_failing_validation_are_replaced_by_the_previous_files() {
+        let _dirs = SNIPPETS_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+        for dir in snippets_dirs() {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        let local = config().local_caddy.snippets_dir.join(&config().snippet_file_name);
+        let docker = config().docker_caddy.snippets_dir.join(&config().snippet_file_name);
+        std::fs::write(&local, "(docker_sites) {\n}\n").unwrap();
+        let _ = std::fs::remove_file(&docker);
+
+        let previous = SnippetFileBackup::read().unwrap();
+        render_snippets(&app_data(vec![app("web", &[("port", "80")])])).unwrap().write_files().unwrap();
+        previous.restore().unwrap();
+
+        assert_eq!(std::fs::read_to_string(&local).unwrap(), "(docker_sites) {\n}\n");
+        // it didn't exist before
+        assert!(!docker.exists());
+    }
+
+    #[test]
+    fn failed_reloads_roll_back_to_the_snippets_last_reloaded() {
+        let _dirs = SNIPPETS_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+        for dir in snippets_dirs() {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let change = SnippetChange { action: "start", app_name: Some("web") };
+        // neither Caddy can be reloaded here, so every reload fails
+        let mut listener = Listener::new().unwrap();
+        listener.app_data = app_data(vec![app("web", &[("port", "8080")])]);
+
+        let error = runtime.block_on(listener.write_caddy_snippets(&change)).unwrap_err().to_string();
+        assert!(!error.contains("rolling back"), "{error}");
+
+        listener.last_rendered = Some(render_snippets(&app_data(vec![app("web", &[("port", "80")])])).unwrap());
+        let error = runtime.block_on(listener.write_caddy_snippets(&change)).unwrap_err().to_string();
+        assert!(error.starts_with("unable to reload Caddy after rolling back to the previous snippets"), "{error}");
+        assert!(render_snippets(&app_data(vec![app("web", &[("port", "80")])])).unwrap().matches_files());
+    }
+
+    #[test]
+    fn oom_kills_are_counted_within_the_window() {
+        let mut listener = Listener::new().unwrap();
+        let window = Duration::from_secs(300);
+        let start = Instant::now();
+        assert_eq!(listener.count_oom_kill("a", start, window), 1);
+        assert_eq!(listener.count_oom_kill("b", start, window), 1);
+        assert_eq!(listener.count_oom_kill("a", start + Duration::from_secs(60), window), 2);
+        // the first kill has dropped out of the window by now
+        assert_eq!(listener.count_oom_kill("a", start + Duration::from_secs(330), window), 2);
+        assert!(!listener.oom_kills.contains_key("b"));
+    }
+
+    #[test]
+    fn drained_containers_get_no_traffic_unless_nothing_else_is_left() {
+        let mut web = app("web", &[("port", "80")]);
+        let mut second = web.containers[0].clone();
+        second.container_name = "web-2".to_string();
+        second.hostname = "web-2".to_string();
+        web.containers.push(second);
+        web.containers[0].drained = true;
+        assert_eq!(web.upstream_targets(), ["http://web-2:80"]);
+
+        web.containers[1].drained = true;
+        assert_eq!(web.upstream_targets(), ["http://web-2:80"]);
+    }
 }
@@ -0,0 +1,75 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use super::{config, Result};
+
+const BEGIN_MARKER: &str = "# BEGIN docker-caddy-rs\n";
+const END_MARKER: &str = "# END docker-caddy-rs\n";
+
+/// What a snippet file should hold for the given snippets. With --preserve-unmanaged, that's the
+/// file as it is, with the snippets between the markers - added at the end if the file has none.
+pub(crate) fn file_content(path: &Path, snippets: &str) -> Result<String> {
+    if !config().preserve_unmanaged {
+        return Ok(snippets.to_string());
+    }
+
+    let existing = match std::fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(replace_region(&existing, snippets))
+}
+
+/// The content with the snippets between the markers, replacing what was there
+fn replace_region(existing: &str, snippets: &str) -> String {
+    let region = format!("{BEGIN_MARKER}{snippets}{END_MARKER}");
+    match bounds(existing) {
+        Some((start, end)) => format!("{}{region}{}", &existing[..start], &existing[end..]),
+        None if existing.is_empty() || existing.ends_with('\n') => format!("{existing}{region}"),
+        None => format!("{existing}\n{region}"),
+    }
+}
+
+/// The snippets in a snippet file - everything in it, unless --preserve-unmanaged is set, when
+/// it's what's between the markers (if they're there)
+pub(crate) fn snippets(content: &str) -> Option<&str> {
+    if !config().preserve_unmanaged {
+        return Some(content);
+    }
+    region(content)
+}
+
+/// What's between the markers
+fn region(content: &str) -> Option<&str> {
+    let (start, end) = bounds(content)?;
+    Some(&content[start + BEGIN_MARKER.len()..end - END_MARKER.len()])
+}
+
+/// The start of the begin marker and the end of the end marker, if both are on lines of their own
+fn bounds(content: &str) -> Option<(usize, usize)> {
+    let at_line_start = |i: usize| i == 0 || content.as_bytes()[i - 1] == b'\n';
+    let start = content.match_indices(BEGIN_MARKER).map(|(i, _)| i).find(|i| at_line_start(*i))?;
+    let after_begin = start + BEGIN_MARKER.len();
+    let end = content[after_begin..].match_indices(END_MARKER)
+        .map(|(i, _)| after_begin + i)
+        .find(|i| at_line_start(*i))?;
+    Some((start, end + END_MARKER.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{region, replace_region};
+
+    #[test]
+    fn only_the_marked_region_is_rewritten() {
+        let added = replace_region("(manual) {\n  respond 200\n}", "(docker_sites) {\n}\n");
+        assert_eq!(added, "(manual) {\n  respond 200\n}\n# BEGIN docker-caddy-rs\n(docker_sites) {\n}\n# END docker-caddy-rs\n");
+        assert_eq!(region(&added), Some("(docker_sites) {\n}\n"));
+
+        let replaced = replace_region(&format!("{added}# kept\n"), "(docker_sites) {\n  web\n}\n");
+        assert_eq!(replaced, "(manual) {\n  respond 200\n}\n# BEGIN docker-caddy-rs\n(docker_sites) {\n  web\n}\n# END docker-caddy-rs\n# kept\n");
+
+        // markers only count on lines of their own
+        assert_eq!(region("  # BEGIN docker-caddy-rs\n# END docker-caddy-rs\n"), None);
+    }
+}
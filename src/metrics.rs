@@ -0,0 +1,195 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+use super::Result;
+
+/// Upper bounds of the histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// The stages a container change goes through on its way to Caddy & DNS
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Stage {
+    /// Inspecting a container through the Docker API
+    Inspect,
+    /// Rendering the snippets from the app data
+    Render,
+    /// Writing the snippet files
+    Write,
+    /// Running `caddy reload` for a local Caddy
+    LocalReload,
+    /// Running `caddy reload` in the Caddy container
+    DockerExecReload,
+    /// Loading a Caddyfile through Caddy's admin API
+    AdminApiReload,
+    /// Uploading the snippets to a Caddy over SSH and reloading it
+    SshReload,
+    /// Sending the snippets to an agent, which reloads its Caddy
+    AgentReload,
+    /// Updating DNS records, including looking up the zones
+    Dns,
+}
+
+impl Stage {
+    const ALL: [Stage; 9] = [
+        Stage::Inspect,
+        Stage::Render,
+        Stage::Write,
+        Stage::LocalReload,
+        Stage::DockerExecReload,
+        Stage::AdminApiReload,
+        Stage::SshReload,
+        Stage::AgentReload,
+        Stage::Dns,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Inspect => "inspect",
+            Stage::Render => "render",
+            Stage::Write => "write",
+            Stage::LocalReload => "local_reload",
+            Stage::DockerExecReload => "docker_exec_reload",
+            Stage::AdminApiReload => "admin_api_reload",
+            Stage::SshReload => "ssh_reload",
+            Stage::AgentReload => "agent_reload",
+            Stage::Dns => "dns",
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+struct Histogram {
+    /// Observations in each bucket (not cumulative - summed when exported)
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+fn histograms() -> &'static Mutex<[Histogram; Stage::ALL.len()]> {
+    static HISTOGRAMS: OnceLock<Mutex<[Histogram; Stage::ALL.len()]>> = OnceLock::new();
+    HISTOGRAMS.get_or_init(Default::default)
+}
+
+/// Record how long a stage took
+pub(crate) fn observe(stage: Stage, duration: Duration) {
+    let seconds = duration.as_secs_f64();
+    let mut histograms = histograms().lock().unwrap_or_else(|e| e.into_inner());
+    let histogram = &mut histograms[stage as usize];
+    if let Some(bucket) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+        histogram.buckets[bucket] += 1;
+    }
+    histogram.count += 1;
+    histogram.sum += seconds;
+}
+
+/// The histograms in the Prometheus text format
+fn render() -> String {
+    let histograms = histograms().lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP docker_caddy_stage_duration_seconds Time taken by each stage of getting container changes to Caddy & DNS");
+    let _ = writeln!(out, "# TYPE docker_caddy_stage_duration_seconds histogram");
+    for stage in Stage::ALL {
+        let histogram = &histograms[stage as usize];
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "docker_caddy_stage_duration_seconds_bucket{{stage=\"{}\",le=\"{bound}\"}} {cumulative}", stage.name());
+        }
+        let _ = writeln!(out, "docker_caddy_stage_duration_seconds_bucket{{stage=\"{}\",le=\"+Inf\"}} {}", stage.name(), histogram.count);
+        let _ = writeln!(out, "docker_caddy_stage_duration_seconds_sum{{stage=\"{}\"}} {}", stage.name(), histogram.sum);
+        let _ = writeln!(out, "docker_caddy_stage_duration_seconds_count{{stage=\"{}\"}} {}", stage.name(), histogram.count);
+    }
+    out
+}
+
+/// Serve the metrics at /metrics until the process exits. This is a minimal HTTP/1 responder for
+/// scrapers, not a general purpose server - each connection gets a single response.
+async fn serve(address: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(address).await
+        .map_err(|e| format!("unable to listen for metrics on {address}: {e}"))?;
+    info!(%address, "serving metrics at /metrics");
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream).await {
+                debug!(%peer, error=%e, "unable to respond to metrics request");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> Result<()> {
+    // only the request line matters, which fits in the first read from any real client
+    let mut request = [0; 1024];
+    let read = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Serve the metrics in the background, logging if the server stops
+pub(crate) fn spawn(address: SocketAddr) {
+    tokio::spawn(async move {
+        if let Err(e) = serve(address).await {
+            warn!(error=%e, "metrics server stopped");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use super::{observe, render, respond, Stage};
+
+    #[test]
+    fn buckets_are_exported_cumulatively() {
+        // no other test records SSH reloads, so the counts are this test's own
+        observe(Stage::SshReload, Duration::from_millis(20));
+        observe(Stage::SshReload, Duration::from_secs(3));
+
+        let metrics = render();
+        let line = |suffix: &str| metrics.lines().find(|line| line.starts_with(&format!("docker_caddy_stage_duration_seconds_{suffix}"))).unwrap().to_string();
+        assert!(line("bucket{stage=\"ssh_reload\",le=\"0.01\"}").ends_with(" 0"));
+        assert!(line("bucket{stage=\"ssh_reload\",le=\"0.025\"}").ends_with(" 1"));
+        assert!(line("bucket{stage=\"ssh_reload\",le=\"5\"}").ends_with(" 2"));
+        assert!(line("bucket{stage=\"ssh_reload\",le=\"+Inf\"}").ends_with(" 2"));
+        assert_eq!(line("count{stage=\"ssh_reload\"}"), "docker_caddy_stage_duration_seconds_count{stage=\"ssh_reload\"} 2");
+    }
+
+    #[tokio::test]
+    async fn only_the_metrics_path_is_served() {
+        let get = |path: &'static str| async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move { respond(listener.accept().await.unwrap().0).await.unwrap() });
+            let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+            client.write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            server.await.unwrap();
+            response
+        };
+
+        let metrics = get("/metrics").await;
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"), "{metrics}");
+        assert!(metrics.contains("# TYPE docker_caddy_stage_duration_seconds histogram"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
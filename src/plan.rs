@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use super::{config, discover_apps, managed_region, new_docker, prepare_snippets_dirs, render_snippets, CaddyConfig, CaddyLocation, Listener, Result, SnippetChange};
+use crate::inspect_cache::InspectCache;
+use crate::powerdns::{PowerDnsApiRecord, RRSetChangeType};
+
+/// Lines of unchanged context shown around each change in a snippet diff
+const DIFF_CONTEXT: usize = 2;
+
+/// Print everything a run would change for the currently running containers, then apply it if
+/// auto-approved
+pub(crate) async fn plan(auto_approve: bool) -> Result<()> {
+    let mut listener = Listener::new()?;
+    listener.app_data = discover_apps(&new_docker()?, &mut InspectCache::default()).await?;
+    let rendered = render_snippets(&listener.app_data)?;
+
+    println!("Snippets:");
+    let snippets = [
+        ("local caddy", &config().local_caddy, &rendered.local),
+        ("docker caddy", &config().docker_caddy, &rendered.docker),
+    ];
+    let mut snippets_changed = 0;
+    for (name, caddy, content) in snippets {
+        let path = caddy.snippets_dir.join(&config().snippet_file_name);
+        // a missing file is shown as all additions
+        let existing = std::fs::read_to_string(&path).unwrap_or_default();
+        // with --preserve-unmanaged, the hand-written content is shown as context
+        let content = &managed_region::file_content(&path, content)?;
+        if existing == *content {
+            println!("  {name} ({}): unchanged", path.display());
+            continue;
+        }
+
+        snippets_changed += 1;
+        println!("  {name} ({}):", path.display());
+        for line in diff_lines(&existing, content) {
+            println!("    {line}");
+        }
+    }
+
+    // both instances are reloaded whenever the snippets are written
+    println!("\nCaddy reloads:");
+    if snippets_changed == 0 {
+        println!("  none");
+    } else {
+        println!("  docker caddy ({})", describe_location(&config().docker_caddy));
+        println!("  local caddy ({})", describe_location(&config().local_caddy));
+        for url in &config().local_caddy_peers {
+            println!("  local caddy reload group member (load {} through {url})", config().local_caddy.config_dir.join("Caddyfile").display());
+        }
+        for destination in config().ssh_caddy.iter().flat_map(|ssh_caddy| &ssh_caddy.destinations) {
+            println!("  caddy over ssh (upload the local snippets to {destination}, then caddy reload)");
+        }
+        for url in &config().agents.urls {
+            println!("  agent (send the local snippets to {url})");
+        }
+    }
+
+    println!("\nDNS:");
+    let dns_changes = planned_dns_changes(&listener).await?;
+    if dns_changes.is_empty() {
+        println!("  no changes");
+    }
+    for change in &dns_changes {
+        println!("  {change}");
+    }
+
+    println!(
+        "\nPlan: {snippets_changed} snippet file(s) to change, {} DNS record set(s) to change",
+        dns_changes.len(),
+    );
+
+    if snippets_changed == 0 && dns_changes.is_empty() {
+        return Ok(());
+    }
+    if !auto_approve {
+        println!("Run with --auto-approve to apply these changes");
+        return Ok(());
+    }
+
+    println!("\nApplying...");
+    prepare_snippets_dirs()?;
+    listener.write_caddy_snippets(&SnippetChange { action: "plan", app_name: None }).await?;
+    println!("Applied");
+
+    Ok(())
+}
+
+fn describe_location(caddy: &CaddyConfig) -> String {
+    match caddy.location {
+        CaddyLocation::Local => format!("caddy reload in {}", caddy.config_dir.display()),
+        CaddyLocation::Docker(ref container_name) => format!("caddy reload in container {container_name}"),
+        CaddyLocation::AdminApi { ref url, ref caddyfile } => format!("load {} through {url}", caddyfile.display()),
+    }
+}
+
+/// The record sets that would be created, replaced or deleted, compared with what PowerDNS holds
+/// now. Record sets that already match are left out.
+async fn planned_dns_changes(listener: &Listener) -> Result<Vec<String>> {
+    let Some(ref dns_client) = listener.dns_client else {
+        return Ok(Vec::new());
+    };
+
+    let mut changes = Vec::new();
+    let mut rrsets_by_zone = listener.dns_changes().await?.into_iter().collect::<Vec<_>>();
+    rrsets_by_zone.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (zone, rrsets) in rrsets_by_zone {
+        let existing = dns_client.list_zone(&zone).await?
+            .and_then(|zone| zone.rrsets)
+            .unwrap_or_default();
+        let mut existing_records = HashMap::new();
+        for rrset in &existing {
+            existing_records.insert((rrset.name.as_str(), rrset.record_type.to_string()), rrset.records.as_deref().unwrap_or_default());
+        }
+
+        for rrset in rrsets {
+            let current = existing_records.get(&(rrset.name.as_str(), rrset.record_type.to_string()))
+                .copied()
+                .filter(|records| !records.is_empty());
+            let wanted = rrset.records.as_deref().unwrap_or_default();
+            let change = match (&rrset.change_type, current) {
+                (Some(RRSetChangeType::DELETE), Some(current)) => format!("- {} {} {}", rrset.name, rrset.record_type, format_records(current)),
+                (Some(RRSetChangeType::DELETE), None) => continue,
+                (_, Some(current)) if current == wanted => continue,
+                (_, Some(current)) => format!("~ {} {} {} -> {}", rrset.name, rrset.record_type, format_records(current), format_records(wanted)),
+                (_, None) => format!("+ {} {} {}", rrset.name, rrset.record_type, format_records(wanted)),
+            };
+            changes.push(change);
+        }
+    }
+
+    Ok(changes)
+}
+
+fn format_records(records: &[PowerDnsApiRecord]) -> String {
+    records.iter()
+        .map(|record| match record.disabled {
+            true => format!("{} (disabled)", record.content),
+            false => record.content.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A line diff of the snippets, each line prefixed with "-" (removed), "+" (added) or " "
+/// (unchanged context), with "..." between changes too far apart to share context
+fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    // lengths of the longest common subsequences of the remaining lines
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = match old[i] == new[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', new[j]));
+            j += 1;
+        }
+    }
+
+    // only keep the unchanged lines near a change
+    let changed = lines.iter().enumerate()
+        .filter(|(_, (kind, _))| *kind != ' ')
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    let near_change = |index: usize| changed.iter().any(|&c| c.abs_diff(index) <= DIFF_CONTEXT);
+
+    let mut output = Vec::new();
+    let mut skipped = false;
+    for (index, (kind, line)) in lines.into_iter().enumerate() {
+        if !near_change(index) {
+            skipped = true;
+            continue;
+        }
+        if skipped && !output.is_empty() {
+            output.push("...".to_string());
+        }
+        skipped = false;
+        output.push(format!("{kind} {line}"));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diff_lines;
+
+    #[test]
+    fn diff_lines_marks_changes_with_nearby_context() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+        assert_eq!(diff_lines(old, new), vec![
+            "  a", "- b", "+ B", "  c", "  d",
+            "...",
+            "  i", "  j", "+ k",
+        ]);
+    }
+
+    #[test]
+    fn diff_lines_keeps_the_longest_common_lines() {
+        // moving a line shows as one removal and one addition, not a rewrite of everything between
+        assert_eq!(diff_lines("x\na\nb\n", "a\nb\nx\n"), vec!["- x", "  a", "  b", "+ x"]);
+        assert!(diff_lines("same\n", "same\n").is_empty());
+        assert_eq!(diff_lines("", "new\n"), vec!["+ new"]);
+    }
+}
@@ -1,13 +1,16 @@
+// Enum variants mirror the PowerDNS API's wire format
+#![allow(clippy::upper_case_acronyms)]
+
 use std::fmt::{Display, Formatter};
-use super::{config, Result};
+use super::Result;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
 use clap::Args;
 use reqwest::{Client, ClientBuilder, StatusCode, Url};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info};
-use tracing::field::debug;
+use tracing::info;
 
-const BASE_PATH: &'static str = "api/v1/";
+const BASE_PATH: &str = "api/v1/";
 
 #[derive(Debug, Clone, Args)]
 pub(crate) struct PowerDnsCliOpts {
@@ -20,6 +23,30 @@ pub(crate) struct PowerDnsCliOpts {
     /// API Key for PowerDNS. Set as the `api-key` property in the PowerDNS config.
     #[arg(long="power-dns-api-key", visible_alias="pdnsak", env)]
     pub(crate) api_key: String,
+    /// Comma-separated zones managed in PowerDNS (e.g., "example.com.,lan.example.com."). Each record
+    /// is written to the longest matching zone. If not set, the zones are fetched from PowerDNS
+    #[arg(long="power-dns-zones", visible_alias="pdnsz", env, value_delimiter=',')]
+    pub(crate) zones: Vec<String>,
+    /// On startup, create the local domain's zone (as a Native zone) if PowerDNS has no zone for
+    /// it, so a fresh PowerDNS needs no setup. With --power-dns-zones, the local domain must be
+    /// one of them
+    #[arg(long="create-missing-zones", visible_alias="cmz", env, requires="zone_nameservers")]
+    pub(crate) create_missing_zones: bool,
+    /// Comma-separated nameservers (e.g., "ns1.example.com.,ns2.example.com.") for zones created
+    /// by --create-missing-zones. The first is the SOA's primary
+    #[arg(long="power-dns-zone-nameservers", visible_alias="pdnszn", env, value_delimiter=',')]
+    pub(crate) zone_nameservers: Vec<String>,
+    /// SOA contact (e.g., "hostmaster.example.com.") for zones created by --create-missing-zones,
+    /// instead of hostmaster in the zone itself
+    #[arg(long="power-dns-soa-contact", visible_alias="pdnssc", env)]
+    pub(crate) soa_contact: Option<String>,
+    /// Before changing more than this many record sets in a zone at once, save the zone to a
+    /// timestamped zone file in --power-dns-backup-dir, so a run that overwrites the wrong names can
+    /// be undone (e.g., with pdnsutil load-zone)
+    #[arg(long="power-dns-backup-threshold", visible_alias="pdnsbt", env, requires="backup_dir")]
+    pub(crate) backup_threshold: Option<usize>,
+    #[arg(long="power-dns-backup-dir", visible_alias="pdnsbd", env)]
+    pub(crate) backup_dir: Option<PathBuf>,
 }
 
 pub(crate) struct PowerDnsClient {
@@ -41,6 +68,46 @@ impl PowerDnsClient {
         })
     }
 
+    pub(crate) async fn list_zones(&self) -> Result<Vec<PowerDnsApiZoneSummary>> {
+        let request = self.client.get(self.url
+            .join(BASE_PATH)?
+            .join("servers/")?
+            .join(&format!("{}/", self.server))?
+            .join("zones")?
+        ).header("X-API-Key", &self.api_key).build()?;
+
+        let response = self.client.execute(request).await?;
+
+        match response.status() {
+            StatusCode::OK => {
+                Ok(response.json().await?)
+            },
+            StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+                let api_error: PowerDnsApiError = response.json().await?;
+                Err(format!(
+                    "malformed request passed to PowerDNS, Error Message [{}], Error Codes [{}]",
+                    api_error.error,
+                    api_error.errors.unwrap_or_default().join(","),
+                ).into())
+            },
+            StatusCode::INTERNAL_SERVER_ERROR => {
+                let api_error: PowerDnsApiError = response.json().await?;
+                Err(format!(
+                    "PowerDNS return an internal error, Error Message [{}], Error Codes [{}]",
+                    api_error.error,
+                    api_error.errors.unwrap_or_default().join(","),
+                ).into())
+            },
+            s => {
+                Err(format!(
+                    "unexpected {} error calling API: {}",
+                    s.as_str(),
+                    response.text().await.unwrap_or("unexpected error fetching error response content".to_string()),
+                ).into())
+            }
+        }
+    }
+
     pub(crate) async fn list_zone(&self, zone_id: &str) -> Result<Option<PowerDnsApiZone>> {
         if !zone_id.ends_with(".") {
             return Err(format!("zone_id {zone_id} must end with a dot - e.g., [{zone_id}.]").into())
@@ -80,7 +147,53 @@ impl PowerDnsClient {
             StatusCode::NOT_FOUND => {
                 Ok(None)
             },
-            s @ _ => {
+            s => {
+                Err(format!(
+                    "unexpected {} error calling API: {}",
+                    s.as_str(),
+                    response.text().await.unwrap_or("unexpected error fetching error response content".to_string()),
+                ).into())
+            }
+        }
+    }
+
+    pub(crate) async fn create_zone(&self, zone: &PowerDnsApiNewZone) -> Result<()> {
+        info!(zone_id=zone.name, url=self.url.as_str(), BASE_PATH, server=self.server, "creating zone");
+
+        let request = self.client.post(
+            self.url
+                .join(BASE_PATH)?
+                .join("servers/")?
+                .join(&format!("{}/", self.server))?
+                .join("zones")?
+        ).header("X-API-Key", &self.api_key)
+            .json(zone)
+            .build()?;
+
+        let response = self.client.execute(request).await?;
+
+        match response.status() {
+            StatusCode::CREATED => {
+                Ok(())
+            },
+            StatusCode::BAD_REQUEST | StatusCode::CONFLICT | StatusCode::UNPROCESSABLE_ENTITY => {
+                let api_error: PowerDnsApiError = response.json().await?;
+                Err(format!(
+                    "PowerDNS refused to create zone {}, Error Message [{}], Error Codes [{}]",
+                    zone.name,
+                    api_error.error,
+                    api_error.errors.unwrap_or_default().join(","),
+                ).into())
+            },
+            StatusCode::INTERNAL_SERVER_ERROR => {
+                let api_error: PowerDnsApiError = response.json().await?;
+                Err(format!(
+                    "PowerDNS return an internal error, Error Message [{}], Error Codes [{}]",
+                    api_error.error,
+                    api_error.errors.unwrap_or_default().join(","),
+                ).into())
+            },
+            s => {
                 Err(format!(
                     "unexpected {} error calling API: {}",
                     s.as_str(),
@@ -130,7 +243,7 @@ impl PowerDnsClient {
                     api_error.errors.unwrap_or_default().join(","),
                 ).into())
             },
-            s @ _ => {
+            s => {
                 Err(format!(
                     "unexpected {} error calling API: {}",
                     s.as_str(),
@@ -140,6 +253,7 @@ impl PowerDnsClient {
         }
     }
 
+    #[allow(dead_code)]
     pub(crate) async fn create_rrset_record(&self, zone_id: &str, rrset_id: &str, rrset_type: RRSetType, record: PowerDnsApiRecord) -> Result<()> {
         // if rrset.change_type.is_none() || matches!(rrset.change_type, Some(RRSetChangeType::DELETE)) {
         //     return Err("change_type must be set to REPLACE when creating an RRset".into());
@@ -179,6 +293,7 @@ impl PowerDnsClient {
         self.update_rrsets(zone_id, PowerDnsApiRRSets { rrsets: vec![rrset] }).await
     }
 
+    #[allow(dead_code)]
     pub(crate) async fn delete_rrset_record(&self, zone_id: &str, rrset_id: &str, rrset_type: RRSetType, record: PowerDnsApiRecord) -> Result<()> {
         // if rrset.change_type.is_none() || matches!(rrset.change_type, Some(RRSetChangeType::DELETE)) {
         //     return Err("change_type must be set to REPLACE when creating an RRset".into());
@@ -236,11 +351,18 @@ pub(crate) enum RRSetType {
     AAAA,
     PTR,
     MX,
+    TXT,
+    NS,
+    SOA,
+    CNAME,
+    SRV,
+    CAA,
+    DS,
 }
 
 impl Display for RRSetType {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
+        write!(f, "{:?}", self)
     }
 }
 
@@ -275,7 +397,7 @@ pub(crate) struct PowerDnsApiZone {
     url: String,
     kind: ZoneKind,
     #[serde(skip_serializing_if = "Option::is_none")]
-    rrsets: Option<Vec<PowerDnsApiRRSet>>,
+    pub(crate) rrsets: Option<Vec<PowerDnsApiRRSet>>,
     serial: f64,
     #[serde(skip_serializing_if = "Option::is_none")]
     masters: Option<Vec<IpAddr>>,
@@ -299,6 +421,101 @@ pub(crate) struct PowerDnsApiZone {
     slave_tsig_key_ids: Option<Vec<String>>,
 }
 
+/// A zone to create - PowerDNS adds the NS records from the nameservers
+#[derive(Debug, Serialize)]
+pub(crate) struct PowerDnsApiNewZone {
+    pub(crate) name: String,
+    kind: ZoneKind,
+    nameservers: Vec<String>,
+    rrsets: Vec<PowerDnsApiRRSet>,
+}
+
+impl PowerDnsApiNewZone {
+    /// A Native zone (replicated by the database rather than by zone transfers) with an SOA whose
+    /// timers are PowerDNS's defaults. The serial starts at 1, and is bumped by PowerDNS from then on
+    pub(crate) fn native(name: &str, nameservers: &[String], soa_contact: Option<&str>) -> Self {
+        let contact = soa_contact.map(str::to_string).unwrap_or_else(|| format!("hostmaster.{name}"));
+        let primary = nameservers.first().map(String::as_str).unwrap_or_default();
+        PowerDnsApiNewZone {
+            name: name.to_string(),
+            kind: ZoneKind::NATIVE,
+            nameservers: nameservers.to_vec(),
+            rrsets: vec![PowerDnsApiRRSet {
+                name: name.to_string(),
+                record_type: RRSetType::SOA,
+                ttl: Some(3600.0),
+                change_type: Some(RRSetChangeType::REPLACE),
+                records: Some(vec![PowerDnsApiRecord {
+                    content: format!("{primary} {contact} 1 10800 3600 604800 3600"),
+                    disabled: false,
+                }]),
+                comments: None,
+            }],
+        }
+    }
+}
+
+impl PowerDnsApiZone {
+    /// The zone in zone file (AXFR) format, one record per line, with disabled records commented
+    /// out
+    pub(crate) fn to_zone_file(&self) -> String {
+        let mut lines = vec![format!("; {} (serial {}), exported {}", self.name, self.serial, chrono::Utc::now().to_rfc3339())];
+        for rrset in self.rrsets.iter().flatten() {
+            let ttl = rrset.ttl.unwrap_or_default();
+            for record in rrset.records.iter().flatten() {
+                let comment = if record.disabled { "; " } else { "" };
+                lines.push(format!("{comment}{}\t{ttl}\tIN\t{}\t{}", rrset.name, rrset.record_type, record.content));
+            }
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+}
+
+/// A zone as returned when listing all zones - only the fields needed to pick a zone are kept
+#[derive(Debug, Deserialize, Serialize, PartialOrd, PartialEq)]
+pub(crate) struct PowerDnsApiZoneSummary {
+    pub(crate) id: String,
+    pub(crate) name: String,
+}
+
+/// The longest of the zones that the record name falls under, e.g., "app.lan.example.com." is in
+/// "lan.example.com." rather than "example.com." if both exist. Names and zones must end with a dot.
+pub(crate) fn zone_for_name<'a>(name: &str, zones: &'a [String]) -> Option<&'a str> {
+    zones.iter()
+        .filter(|zone| name == zone.as_str() || name.ends_with(&format!(".{}", zone)))
+        .max_by_key(|zone| zone.len())
+        .map(|zone| zone.as_str())
+}
+
+/// Whether the instance may change the records at a name, given the zone's record sets: it may if
+/// the name has no records yet, or its TXT record marks it as the instance's. Otherwise, the
+/// instance the name is marked with is returned (None if its records aren't marked at all).
+pub(crate) fn check_owner(name: &str, existing: &[PowerDnsApiRRSet], instance: &str) -> std::result::Result<(), Option<String>> {
+    let records = existing.iter()
+        .filter(|rrset| rrset.name == name)
+        .filter(|rrset| rrset.records.as_ref().is_some_and(|records| !records.is_empty()))
+        .collect::<Vec<_>>();
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let owner = records.iter()
+        .filter(|rrset| rrset.record_type == RRSetType::TXT)
+        .flat_map(|rrset| rrset.records.iter().flatten())
+        .find_map(|record| {
+            let fields = record.content.trim_matches('"').split(',').collect::<Vec<_>>();
+            match fields.contains(&"heritage=docker-caddy-rs") {
+                true => fields.iter().find_map(|field| field.strip_prefix("instance=")),
+                false => None,
+            }
+        });
+    match owner {
+        Some(owner) if owner == instance => Ok(()),
+        owner => Err(owner.map(str::to_string)),
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialOrd, PartialEq)]
 pub(crate) struct PowerDnsApiRRSets {
     pub(crate) rrsets: Vec<PowerDnsApiRRSet>,
@@ -320,6 +537,19 @@ pub(crate) struct PowerDnsApiRRSet {
 }
 
 impl PowerDnsApiRRSet {
+    /// Whether applying this record set would change what the zone holds now
+    pub(crate) fn changes(&self, existing: &[PowerDnsApiRRSet]) -> bool {
+        let current = existing.iter()
+            .find(|rrset| rrset.name == self.name && rrset.record_type == self.record_type)
+            .and_then(|rrset| rrset.records.as_deref())
+            .filter(|records| !records.is_empty());
+        match (&self.change_type, current) {
+            (Some(RRSetChangeType::DELETE), current) => current.is_some(),
+            (_, Some(current)) => current != self.records.as_deref().unwrap_or_default(),
+            (_, None) => true,
+        }
+    }
+
     pub(crate) fn new_ipv4(host: &str, domain: &str, ipv4addr: &Ipv4Addr) -> Self {
         PowerDnsApiRRSet {
             name: format!("{}.{}.", host, domain),
@@ -367,6 +597,37 @@ impl PowerDnsApiRRSet {
         }
     }
 
+    /// A TXT record marking the name as managed by the given instance, so several instances can
+    /// share a zone
+    pub(crate) fn new_owner_txt(host: &str, domain: &str, instance: &str) -> Self {
+        PowerDnsApiRRSet {
+            name: format!("{}.{}.", host, domain),
+            record_type: RRSetType::TXT,
+            ttl: Some(300.0),
+            change_type: Some(RRSetChangeType::REPLACE),
+            records: Some(
+                vec![
+                    PowerDnsApiRecord {
+                        content: format!("\"heritage=docker-caddy-rs,instance={}\"", instance),
+                        disabled: false,
+                    }
+                ]
+            ),
+            comments: None,
+        }
+    }
+
+    pub(crate) fn delete_owner_txt(host: &str, domain: &str) -> Self {
+        PowerDnsApiRRSet {
+            name: format!("{}.{}.", host, domain),
+            record_type: RRSetType::TXT,
+            ttl: Some(300.0),
+            change_type: Some(RRSetChangeType::DELETE),
+            records: None,
+            comments: None,
+        }
+    }
+
     pub(crate) fn delete_ipv6(host: &str, domain: &str) -> Self {
         PowerDnsApiRRSet {
             name: format!("{}.{}.", host, domain),
@@ -396,4 +657,70 @@ pub(crate) struct PowerDnsApiComment {
     content: String,
     account: String,
     modified_at: f64,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use serde_json::json;
+    use super::{check_owner, zone_for_name, PowerDnsApiNewZone, PowerDnsApiRRSet, PowerDnsApiZone};
+
+    #[test]
+    fn check_owner_only_allows_free_names_and_the_instances_own() {
+        let existing = vec![
+            PowerDnsApiRRSet::new_ipv4("mine", "example.com", &Ipv4Addr::LOCALHOST),
+            PowerDnsApiRRSet::new_owner_txt("mine", "example.com", "a"),
+            PowerDnsApiRRSet::new_ipv4("theirs", "example.com", &Ipv4Addr::LOCALHOST),
+            PowerDnsApiRRSet::new_owner_txt("theirs", "example.com", "b"),
+            PowerDnsApiRRSet::new_ipv4("unmarked", "example.com", &Ipv4Addr::LOCALHOST),
+        ];
+
+        assert_eq!(check_owner("new.example.com.", &existing, "a"), Ok(()));
+        assert_eq!(check_owner("mine.example.com.", &existing, "a"), Ok(()));
+        assert_eq!(check_owner("theirs.example.com.", &existing, "a"), Err(Some("b".to_string())));
+        assert_eq!(check_owner("unmarked.example.com.", &existing, "a"), Err(None));
+    }
+
+    #[test]
+    fn zone_for_name_picks_the_longest_matching_zone() {
+        let zones = ["example.com.", "lan.example.com.", "ample.com."].map(str::to_string);
+        assert_eq!(zone_for_name("app.lan.example.com.", &zones), Some("lan.example.com."));
+        assert_eq!(zone_for_name("app.example.com.", &zones), Some("example.com."));
+        assert_eq!(zone_for_name("lan.example.com.", &zones), Some("lan.example.com."));
+        assert_eq!(zone_for_name("app.example.org.", &zones), None);
+    }
+
+    #[test]
+    fn new_zones_get_an_soa_from_the_first_nameserver() {
+        let nameservers = ["ns1.example.com.", "ns2.example.com."].map(str::to_string);
+        let zone = serde_json::to_value(PowerDnsApiNewZone::native("local.example.com.", &nameservers, None)).unwrap();
+        assert_eq!(zone["nameservers"], json!(nameservers));
+        assert_eq!(zone["rrsets"][0]["type"], json!("SOA"));
+        assert_eq!(zone["rrsets"][0]["records"][0]["content"], json!("ns1.example.com. hostmaster.local.example.com. 1 10800 3600 604800 3600"));
+
+        let zone = PowerDnsApiNewZone::native("local.example.com.", &nameservers, Some("dns.example.com."));
+        assert!(serde_json::to_string(&zone).unwrap().contains("ns1.example.com. dns.example.com. 1 "));
+    }
+
+    #[test]
+    fn backups_hold_every_record_and_changes_are_counted_against_them() {
+        let zone: PowerDnsApiZone = serde_json::from_value(json!({
+            "id": "example.com.", "name": "example.com.", "type": "Zone", "url": "/api/v1/servers/localhost/zones/example.com.",
+            "kind": "NATIVE", "serial": 2026101601.0, "dnssec": false, "nsec3param": "", "nsec3narrow": false,
+            "presigned": false, "soa_edit": "", "soa_edit_api": "", "api_rectify": false, "catalog": "",
+            "rrsets": [
+                { "name": "web.example.com.", "type": "A", "ttl": 300.0, "records": [{ "content": "10.0.0.2", "disabled": false }] },
+                { "name": "old.example.com.", "type": "A", "ttl": 300.0, "records": [{ "content": "10.0.0.3", "disabled": true }] },
+            ],
+        })).unwrap();
+
+        let zone_file = zone.to_zone_file();
+        let lines = zone_file.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("; example.com. (serial 2026101601), exported "), "{zone_file}");
+        assert_eq!(lines[1..], ["web.example.com.\t300\tIN\tA\t10.0.0.2", "; old.example.com.\t300\tIN\tA\t10.0.0.3"]);
+
+        let existing = zone.rrsets.as_deref().unwrap();
+        assert!(!PowerDnsApiRRSet::new_ipv4("web", "example.com", &Ipv4Addr::new(10, 0, 0, 2)).changes(existing));
+        assert!(PowerDnsApiRRSet::new_ipv4("web", "example.com", &Ipv4Addr::new(10, 0, 0, 4)).changes(existing));
+        assert!(PowerDnsApiRRSet::new_ipv4("new", "example.com", &Ipv4Addr::new(10, 0, 0, 5)).changes(existing));
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use super::config;
+
+/// A condition that was logged, and how often it has recurred since
+struct Seen {
+    first_seen: Instant,
+    last_seen: Instant,
+    last_logged: Instant,
+    suppressed: u64,
+}
+
+/// A recurring condition that is due to be logged again
+pub(crate) struct Recurrence {
+    /// How many times it occurred without being logged since it was last logged
+    pub(crate) suppressed: u64,
+    /// How long it has been recurring for (zero the first time)
+    pub(crate) persisting: Duration,
+}
+
+fn seen() -> &'static Mutex<HashMap<(&'static str, String), Seen>> {
+    static SEEN: OnceLock<Mutex<HashMap<(&'static str, String), Seen>>> = OnceLock::new();
+    SEEN.get_or_init(Default::default)
+}
+
+/// How long a condition is remembered after it was last found: two intervals, or two minutes if
+/// every occurrence is logged, so it can still be listed as active
+fn memory(interval: Duration) -> Duration {
+    match interval.is_zero() {
+        true => Duration::from_secs(120),
+        false => interval * 2,
+    }
+}
+
+/// Forget the conditions that stopped recurring, e.g., because their app is gone
+fn prune(seen: &mut HashMap<(&'static str, String), Seen>, now: Instant, memory: Duration) {
+    seen.retain(|_, entry| now.duration_since(entry.last_seen) <= memory);
+}
+
+/// Whether a condition (e.g., an app having no running containers) that is found again on every
+/// snippet write should be logged this time. It's logged the first time, then at most once per
+/// --warning-repeat-interval-secs, with the number of occurrences in between. A condition that
+/// stops recurring for two intervals is forgotten, and treated as new when it's next found.
+pub(crate) fn check(condition: &'static str, subject: &str) -> Option<Recurrence> {
+    let interval = config().warning_repeat_interval;
+    let now = Instant::now();
+    let mut seen = seen().lock().unwrap_or_else(|e| e.into_inner());
+    prune(&mut seen, now, memory(interval));
+
+    let key = (condition, subject.to_string());
+    let Some(entry) = seen.get_mut(&key) else {
+        seen.insert(key, Seen { first_seen: now, last_seen: now, last_logged: now, suppressed: 0 });
+        return Some(Recurrence { suppressed: 0, persisting: Duration::ZERO });
+    };
+
+    entry.last_seen = now;
+    if now.duration_since(entry.last_logged) < interval {
+        entry.suppressed += 1;
+        return None;
+    }
+
+    entry.last_logged = now;
+    Some(Recurrence {
+        suppressed: std::mem::take(&mut entry.suppressed),
+        persisting: now.duration_since(entry.first_seen),
+    })
+}
+
+/// A condition that is still recurring
+#[derive(Debug)]
+pub(crate) struct Active {
+    pub(crate) condition: &'static str,
+    pub(crate) subject: String,
+    /// How long it has been recurring for
+    pub(crate) persisting: Duration,
+}
+
+impl std::fmt::Display for Active {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // whole seconds are precise enough, and read better
+        write!(f, "{} for {:?}", self.condition, Duration::from_secs(self.persisting.as_secs()))
+    }
+}
+
+/// The conditions that are still recurring, by subject then condition
+pub(crate) fn active() -> Vec<Active> {
+    let now = Instant::now();
+    let mut seen = seen().lock().unwrap_or_else(|e| e.into_inner());
+    prune(&mut seen, now, memory(config().warning_repeat_interval));
+
+    let mut active = seen.iter()
+        .map(|((condition, subject), entry)| Active {
+            condition,
+            subject: subject.clone(),
+            persisting: now.duration_since(entry.first_seen),
+        })
+        .collect::<Vec<_>>();
+    active.sort_by(|a, b| (&a.subject, a.condition).cmp(&(&b.subject, b.condition)));
+    active
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use super::{active, check, prune, Seen};
+
+    #[test]
+    fn check_logs_the_first_occurrence_then_counts_repeats() {
+        assert!(check("checked", "recurring-app").is_some());
+        assert!(check("checked", "recurring-app").is_none());
+        assert!(active().iter().any(|a| a.condition == "checked" && a.subject == "recurring-app"));
+    }
+
+    #[test]
+    fn prune_forgets_conditions_that_stopped_recurring() {
+        let now = Instant::now();
+        let seen_at = |ago| {
+            let at = now - Duration::from_secs(ago);
+            Seen { first_seen: at, last_seen: at, last_logged: at, suppressed: 0 }
+        };
+        let mut seen = HashMap::from([
+            (("gone", "old-app".to_string()), seen_at(601)),
+            (("recurring", "app".to_string()), seen_at(10)),
+        ]);
+        prune(&mut seen, now, Duration::from_secs(600));
+        assert_eq!(seen.keys().collect::<Vec<_>>(), vec![&("recurring", "app".to_string())]);
+    }
+}
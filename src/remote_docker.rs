@@ -0,0 +1,91 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use docker_api::Docker;
+use super::Result;
+
+/// A Docker daemon on another machine, from --docker-host
+pub(crate) struct RemoteDocker {
+    /// The host & port, without the scheme
+    pub(crate) host: String,
+    pub(crate) tls: Option<DockerTls>,
+}
+
+/// Client certificate for a daemon started with --tlsverify
+pub(crate) struct DockerTls {
+    /// CA to verify the daemon with - the system CAs are used if not set
+    pub(crate) ca: Option<PathBuf>,
+    pub(crate) cert: PathBuf,
+    pub(crate) key: PathBuf,
+}
+
+impl RemoteDocker {
+    pub(crate) fn connect(&self) -> Result<Docker> {
+        match self.tls {
+            None => Ok(Docker::tcp(&self.host)?),
+            Some(ref tls) => Ok(Docker::tls(&self.host, tls.cert_dir()?, tls.ca.is_some())?),
+        }
+    }
+}
+
+impl DockerTls {
+    /// The Docker client reads the files as ca.pem, cert.pem and key.pem from a single dir, as
+    /// the Docker CLI lays them out in ~/.docker. Files named otherwise are linked into a private
+    /// dir with those names, once per process.
+    fn cert_dir(&self) -> Result<&'static Path> {
+        static CERT_DIR: OnceLock<PathBuf> = OnceLock::new();
+        if let Some(dir) = CERT_DIR.get() {
+            return Ok(dir);
+        }
+
+        let files = [(self.ca.as_deref(), "ca.pem"), (Some(self.cert.as_path()), "cert.pem"), (Some(self.key.as_path()), "key.pem")];
+        let shared_dir = self.cert.parent().filter(|dir| files.iter().all(|(path, name)| match path {
+            Some(path) => path.parent() == Some(*dir) && path.file_name().is_some_and(|file| file == *name),
+            None => true,
+        }));
+        let dir = match shared_dir {
+            Some(dir) => dir.to_path_buf(),
+            None => {
+                let dir = std::env::temp_dir().join(format!("docker-caddy-rs-{}-docker-tls", std::process::id()));
+                create_private_dir(&dir)?;
+                for (path, name) in files {
+                    let Some(path) = path else { continue };
+                    let link = dir.join(name);
+                    let _ = fs::remove_file(&link);
+                    link_file(&fs::canonicalize(path)
+                        .map_err(|e| format!("unable to read Docker TLS file {}: {e}", path.display()))?, &link)?;
+                }
+                dir
+            },
+        };
+
+        Ok(CERT_DIR.get_or_init(|| dir))
+    }
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    if !dir.exists() {
+        fs::DirBuilder::new().mode(0o700).create(dir)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn link_file(target: &Path, link: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, link)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn link_file(target: &Path, link: &Path) -> Result<()> {
+    fs::copy(target, link)?;
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use std::borrow::Cow;
+use tracing::{info, warn};
+use super::{config, recurring, AppData, ApplicationData, CaddyAuthType, ClientAuthMode, Result, UnknownAuthPolicy};
+
+/// An app served on the external domain, and what stands between it and the internet
+pub(crate) struct Exposure {
+    host: String,
+    auth: Cow<'static, str>,
+    /// Nothing in the generated snippets requires authentication, so the app is only protected
+    /// if it does so itself
+    unauthenticated: bool,
+    /// Why the app isn't served yet, if its depends_on is holding it back
+    waiting: Option<String>,
+}
+
+impl Exposure {
+    fn new(ad: &AppData) -> Self {
+        let required_client_cert = ad.client_auth.as_ref().is_some_and(|client_auth| matches!(client_auth.mode, ClientAuthMode::Require));
+        let (auth, unauthenticated): (Cow<str>, bool) = match ad.auth_type {
+            CaddyAuthType::TrustedHeaders => ("headers".into(), false),
+            _ if required_client_cert => ("client certificate".into(), false),
+            // OIDC is left to the app, so it counts as authenticated
+            CaddyAuthType::Oidc => ("oidc (by the app)".into(), false),
+            CaddyAuthType::Unknown(ref auth) => match config().unknown_auth_policy {
+                UnknownAuthPolicy::Fallback(ref snippet) => (format!("unknown ({auth}), falling back to {snippet}").into(), false),
+                _ => (format!("unknown ({auth})").into(), true),
+            },
+            CaddyAuthType::None => ("none".into(), true),
+        };
+
+        Exposure {
+            host: format!("{}.{}", ad.app_name, ad.domain()),
+            auth,
+            unauthenticated,
+            waiting: None,
+        }
+    }
+}
+
+/// Whether the app is served on the external domain with nothing requiring authentication
+pub(crate) fn unauthenticated(ad: &AppData) -> bool {
+    ad.external && Exposure::new(ad).unauthenticated
+}
+
+/// Every host served on the external domain, sorted by host
+pub(crate) fn exposures(app_data: &ApplicationData) -> Vec<Exposure> {
+    let mut exposures = app_data.values()
+        .flat_map(AppData::with_port_variants)
+        .filter(|ad| ad.external)
+        .map(|ad| Exposure { waiting: ad.waiting_for(app_data).map(|wait| wait.to_string()), ..Exposure::new(&ad) })
+        .collect::<Vec<_>>();
+    exposures.sort_by(|a, b| a.host.cmp(&b.host));
+    exposures
+}
+
+/// Print the external hosts as a table, unauthenticated ones marked, then the recurring conditions
+pub(crate) fn print(app_data: &ApplicationData) -> Result<()> {
+    // rendering finds the conditions that leave apps out of the snippets
+    super::render_snippets(app_data)?;
+    let exposures = exposures(app_data);
+    let width = exposures.iter().map(|e| e.host.len()).chain([4]).max().unwrap_or_default();
+    println!("{:width$}  AUTH", "HOST");
+    for exposure in &exposures {
+        let warning = if exposure.unauthenticated { "  <- UNAUTHENTICATED" } else { "" };
+        let waiting = exposure.waiting.as_ref().map(|waiting| format!("  (not served yet, {waiting})")).unwrap_or_default();
+        println!("{:width$}  {}{warning}{waiting}", exposure.host, exposure.auth);
+    }
+    println!(
+        "\n{} external host(s), {} unauthenticated",
+        exposures.len(),
+        exposures.iter().filter(|e| e.unauthenticated).count(),
+    );
+
+    let active = recurring::active();
+    if !active.is_empty() {
+        println!("\nRecurring conditions:");
+        for a in &active {
+            println!("  {}: {a}", if a.subject.is_empty() { "-" } else { &a.subject });
+        }
+    }
+    Ok(())
+}
+
+/// Log a summary of the external hosts, with a warning for each unauthenticated one
+pub(crate) fn log(app_data: &ApplicationData) {
+    let exposures = exposures(app_data);
+    let mut unauthenticated = 0;
+    for exposure in exposures.iter().filter(|e| e.unauthenticated) {
+        warn!(host=exposure.host, auth=%exposure.auth, "external app has no authentication in the generated snippets");
+        unauthenticated += 1;
+    }
+    for exposure in &exposures {
+        if let Some(ref waiting) = exposure.waiting {
+            info!(host=exposure.host, waiting, "external app isn't served yet");
+        }
+    }
+    let active = recurring::active().iter()
+        .map(|a| format!("{} ({a})", a.subject))
+        .collect::<Vec<_>>();
+    info!(external=exposures.len(), unauthenticated, conditions=?active, "exposure report");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exposures, unauthenticated};
+    use crate::tests::{app, app_data};
+
+    #[test]
+    fn only_external_hosts_without_auth_are_unauthenticated() {
+        let app_data = app_data(vec![
+            app("open", &[("port", "80"), ("external", "true")]),
+            app("headers", &[("port", "80"), ("external", "true"), ("auth", "headers")]),
+            app("mtls", &[("port", "80"), ("external", "true"), ("client_auth", "require"), ("client_auth_ca", "/ca.pem")]),
+            app("typo", &[("port", "80"), ("external", "true"), ("auth", "odic")]),
+            app("internal", &[("port", "80")]),
+        ]);
+        let exposures = exposures(&app_data).into_iter()
+            .map(|e| (e.host, e.auth.into_owned(), e.unauthenticated))
+            .collect::<Vec<_>>();
+        assert_eq!(exposures, [
+            ("headers.example.com".to_string(), "headers".to_string(), false),
+            ("mtls.example.com".to_string(), "client certificate".to_string(), false),
+            ("open.example.com".to_string(), "none".to_string(), true),
+            ("typo.example.com".to_string(), "unknown (odic)".to_string(), true),
+        ]);
+        assert!(!unauthenticated(&app_data["internal"]));
+    }
+}
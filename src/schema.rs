@@ -0,0 +1,101 @@
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+use super::{config, BlockedPathAction, ClientAuthMode, DnsProviderCli, SecurityHeaders};
+
+/// A JSON Schema describing the labels this build supports, using the configured label prefix, so
+/// compose files can be validated by editors and CI.
+pub(crate) fn label_schema() -> Value {
+    let config = config();
+    let boolean = || json!({ "type": "string", "enum": ["true", "false"] });
+    let string = || json!({ "type": "string" });
+
+    let properties = [
+        (&config.app_name_label, string(), "Name of the application, prepended to the domain or local domain"),
+        (&config.port_label, json!({ "type": "string", "pattern": "^[0-9]+(,([A-Za-z0-9_-]+:)?[0-9]+)*$" }), "Port the app runs on (mandatory unless socket is set). Further comma-separated ports (e.g., 8080,api:9090) are each served on a host of their own"),
+        (&config.socket_label, string(), "Path (inside the docker Caddy container) to a unix socket the app listens on, used instead of the port"),
+        (&config.ports_label, json!({ "type": "string", "pattern": "^[A-Za-z0-9_-]+:[0-9]+(,[A-Za-z0-9_-]+:[0-9]+)*$" }), "Comma-separated named ports (e.g., web:8080,api:9090), each served on its own host from --named-port-host-template"),
+        (&config.external_label, boolean(), "Expose the app via the domain name (true), or the local domain (false, the default)"),
+        (&config.auth_label, json!({ "type": "string", "enum": ["oidc", "headers", "none"] }), "Authentication for the app - headers imports the auth-headers snippet"),
+        (&config.auth_headers_label, json!({ "type": "string", "pattern": "^ *[A-Za-z0-9!#$%&'*+.^_`|~-]+ *(, *[A-Za-z0-9!#$%&'*+.^_`|~-]+ *)*$" }), "Comma-separated identity headers (e.g., Remote-User) requests must have with auth=headers - requests missing any are rejected with a 401"),
+        (&config.blocked_path_action_label, value_enum::<BlockedPathAction>(), "What Caddy should do with requests for blocked paths, overriding --blocked-path-action"),
+        (&config.security_headers_label, value_enum::<SecurityHeaders>(), "Security headers the local Caddy adds for an external app, overriding --security-headers"),
+        (&config.internal_paths_label, string(), "Comma-separated paths of an external app that are only reachable from the internal CIDRs"),
+        (&config.public_paths_label, string(), "Comma-separated paths (e.g., /healthz) reachable by anyone, ahead of the blocked paths, internal paths, country restrictions and auth"),
+        (&config.allow_countries_label, string(), "Space or comma-separated ISO country codes allowed to access the app"),
+        (&config.deny_countries_label, string(), "Space or comma-separated ISO country codes denied access to the app"),
+        (&config.client_auth_label, value_enum::<ClientAuthMode>(), "Require TLS client certificates, verified against client_auth_ca"),
+        (&config.client_auth_ca_label, string(), "Path (as seen by the local Caddy) of the CA used to verify client certificates"),
+        (&config.insecure_http_label, boolean(), "Also serve an internal app over plain HTTP"),
+        (&config.robots_label, json!({ "type": "string", "enum": ["deny", "allow"] }), "Serve a robots.txt disallowing all crawlers (deny), instead of the app's own"),
+        (&config.security_contact_label, json!({ "type": "string", "pattern": "^[^`]*$" }), "Contact (e.g., mailto:security@example.com) for a generated /.well-known/security.txt"),
+        (&config.depends_on_label, string(), "Name of an app that must have a running, healthy container before this app is included"),
+        (&config.dns_provider_label, value_enum::<DnsProviderCli>(), "DNS provider for the app's records, overriding the local/external default"),
+        (&config.zone_label, json!({ "type": "string", "enum": config.snippet_groups }), "Snippet group (one of --snippet-groups) to put the app in, instead of the external/internal split"),
+        (&config.external_port_label, json!({ "type": "string", "pattern": "^[0-9]+$" }), "Port to serve on the external domain, replacing the app's own block there"),
+        (&config.internal_port_label, json!({ "type": "string", "pattern": "^[0-9]+$" }), "Port to serve on the internal domain, replacing the app's own block there"),
+        (&config.extra_label, string(), "Caddy directives inserted verbatim into the app's handle block, before reverse_proxy. Further directives can be given in numbered labels (e.g., caddy.extra.0)"),
+        (&config.lb_retries_label, json!({ "type": "string", "pattern": "^[0-9]+$" }), "How many times Caddy retries a request on another container"),
+        (&config.lb_try_duration_label, string(), "How long Caddy keeps retrying a request on other containers (e.g., 5s)"),
+        (&config.lb_try_interval_label, string(), "How long Caddy waits between retries (e.g., 250ms)"),
+        (&config.fail_duration_label, string(), "How long Caddy leaves out a container after a failed request (e.g., 30s), or 0 for never. Defaults to 30s for apps with several containers"),
+        (&config.max_fails_label, json!({ "type": "string", "pattern": "^[0-9]+$" }), "How many failed requests within the fail duration leave out a container. Defaults to 3 for apps with several containers"),
+        (&config.unhealthy_status_label, string(), "Comma-separated statuses (e.g., 5xx,429) counted as failed requests"),
+        (&config.network_label, string(), "Docker network to reach the app's containers on, overriding --proxy-network"),
+        (&config.priority_label, json!({ "type": "string", "pattern": "^-?[0-9]+$" }), "Apps with a higher priority (default 0) are written first, so their handles win when matchers overlap"),
+        (&config.instance_label, string(), "Name of the instance (--instance-name) that manages the app - other instances ignore it"),
+    ];
+
+    let properties = properties.into_iter()
+        .map(|(label, mut schema, description)| {
+            schema["description"] = description.into();
+            (label.clone(), schema)
+        })
+        .collect::<Map<String, Value>>();
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "docker-caddy-rs container labels",
+        "type": "object",
+        "properties": properties,
+        "dependentSchemas": {
+            config.app_name_label.as_str(): {
+                "anyOf": [
+                    { "required": [config.port_label] },
+                    { "required": [config.socket_label] },
+                    { "required": [config.ports_label] },
+                    { "required": [config.external_port_label] },
+                    { "required": [config.internal_port_label] },
+                ],
+            },
+            config.client_auth_label.as_str(): {
+                "required": [config.client_auth_ca_label],
+            },
+        },
+    })
+}
+
+fn value_enum<T: ValueEnum>() -> Value {
+    let values = T::value_variants()
+        .iter()
+        .filter_map(|v| v.to_possible_value())
+        .map(|v| v.get_name().to_string())
+        .collect::<Vec<_>>();
+
+    json!({ "type": "string", "enum": values })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::label_schema;
+
+    #[test]
+    fn label_schema_uses_the_label_prefix_and_value_enums() {
+        let schema = label_schema();
+        let properties = schema["properties"].as_object().unwrap();
+        assert!(properties.keys().all(|label| label.starts_with("test.")));
+        assert_eq!(schema["properties"]["test.blocked_path_action"]["enum"], json!(["abort", "forbidden", "not-found"]));
+        assert_eq!(schema["properties"]["test.client_auth"]["enum"], json!(["require", "verify-if-given"]));
+        assert_eq!(schema["dependentSchemas"]["test.client_auth"], json!({ "required": ["test.client_auth_ca"] }));
+    }
+}
@@ -0,0 +1,97 @@
+use std::borrow::Cow;
+use std::path::{Component, Path};
+use tracing::debug;
+use super::{config, Result};
+
+const SECRET_SCHEME: &str = "secret://";
+
+/// The text (a rendered snippet block) with each `secret://<path>` reference replaced by the
+/// contents of that file in --secrets-dir (e.g., `secret://htpasswd/app1` reads
+/// `/run/secrets/htpasswd/app1`), so credentials can be given to labels without appearing in
+/// `docker inspect`. A reference runs up to whitespace or a quote. A single trailing newline is
+/// dropped. Fails if a referenced secret can't be read.
+pub(crate) fn resolve_text(text: &str) -> Result<Cow<'_, str>> {
+    if !text.contains(SECRET_SCHEME) {
+        return Ok(Cow::Borrowed(text));
+    }
+
+    let mut resolved = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(SECRET_SCHEME) {
+        resolved.push_str(&rest[..start]);
+        let reference = &rest[start..];
+        let end = reference.find(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '`')).unwrap_or(reference.len());
+        let (reference, after) = reference.split_at(end);
+        let secret = read_secret(&reference[SECRET_SCHEME.len()..])
+            .map_err(|e| format!("unable to resolve {reference}: {e}"))?;
+        debug!(reference, "resolved secret");
+        resolved.push_str(&secret);
+        rest = after;
+    }
+    resolved.push_str(rest);
+    Ok(Cow::Owned(resolved))
+}
+
+fn read_secret(name: &str) -> Result<String> {
+    let relative = Path::new(name);
+    // only files inside the secrets dir can be referenced
+    if name.is_empty() || !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err("secret references must be a relative path without . or .. components".into());
+    }
+
+    let path = config().secrets_dir.join(relative);
+    let mut secret = std::fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+    if secret.ends_with('\n') {
+        secret.pop();
+        if secret.ends_with('\r') {
+            secret.pop();
+        }
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use super::resolve_text;
+    use crate::tests::secrets_dir;
+
+    fn write_secret(name: &str, content: &str) {
+        let path = secrets_dir().join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn resolves_secret_references() {
+        write_secret("tokens/web", "s3cr3t\n");
+        let resolved = resolve_text("header_up Authorization \"Bearer secret://tokens/web\"\n  reverse_proxy web:80").unwrap();
+
+        assert_eq!(resolved, "header_up Authorization \"Bearer s3cr3t\"\n  reverse_proxy web:80");
+    }
+
+    #[test]
+    fn only_drops_one_trailing_newline() {
+        write_secret("crlf", "value\r\n");
+        write_secret("blank-lines", "value\n\n");
+
+        assert_eq!(resolve_text("a secret://crlf b secret://blank-lines").unwrap(), "a value b value\n");
+    }
+
+    #[test]
+    fn leaves_text_without_secrets_borrowed() {
+        assert!(matches!(resolve_text("reverse_proxy web:80").unwrap(), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_secrets_dir() {
+        for reference in ["secret://../passwd", "secret:///etc/passwd", "secret://./web", "secret://"] {
+            assert!(resolve_text(reference).is_err(), "{reference}");
+        }
+    }
+
+    #[test]
+    fn fails_on_missing_secrets() {
+        assert!(resolve_text("basic_auth secret://missing").is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use clap::CommandFactory;
+use clap::parser::ValueSource;
+use tracing::info;
+use super::{Cli, Result};
+
+/// The options given on the command line before the `service` subcommand, to pass on to the service
+/// unchanged. Taken from what clap parsed rather than the raw arguments, so an option value that
+/// happens to be "service" isn't mistaken for the subcommand.
+fn service_args() -> Result<Vec<String>> {
+    let command = Cli::command();
+    let matches = command.clone().get_matches();
+    let mut args = Vec::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        if !arg.get_action().takes_values() {
+            args.push(format!("--{long}"));
+            continue;
+        }
+        for occurrence in matches.get_raw_occurrences(id).into_iter().flatten() {
+            let values = occurrence
+                .map(|value| value.to_str().ok_or_else(|| format!("could not turn the value of --{long} into a string")))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            // one option per value, rejoined if clap split them on a delimiter
+            match arg.get_value_delimiter() {
+                Some(delimiter) => args.push(format!("--{long}={}", values.join(&delimiter.to_string()))),
+                None => args.extend(values.iter().map(|value| format!("--{long}={value}"))),
+            }
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(target_os = "macos")]
+fn plist_path(label: &str) -> PathBuf {
+    PathBuf::from(format!("/Library/LaunchDaemons/{label}.plist"))
+}
+
+#[cfg(not(target_os = "macos"))]
+fn plist_path(_label: &str) -> PathBuf {
+    unreachable!("launchd is only available on macOS")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// A launchd daemon running this binary with the given arguments, restarted if it exits
+fn launchd_plist(label: &str, program: &str, args: &[String]) -> String {
+    let program_arguments = std::iter::once(program)
+        .chain(args.iter().map(String::as_str))
+        .map(|arg| format!("        <string>{}</string>\n", escape_xml(arg)))
+        .collect::<String>();
+    format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+{program_arguments}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>/var/log/{label}.log</string>
+    <key>StandardErrorPath</key>
+    <string>/var/log/{label}.log</string>
+</dict>
+</plist>
+"#, label=escape_xml(label))
+}
+
+/// Where the WinSW service definition is written
+fn winsw_config_path(label: &str) -> PathBuf {
+    let program_data = std::env::var_os("ProgramData").unwrap_or_else(|| "C:\\ProgramData".into());
+    PathBuf::from(program_data).join("docker-caddy-rs").join(format!("{label}.xml"))
+}
+
+/// A Windows command line argument, quoted if it needs to be
+fn quote_windows_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            // backslashes before a quote escape each other, then one more escapes the quote
+            '"' => {
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+            },
+            c => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                quoted.push(c);
+                backslashes = 0;
+            },
+        }
+    }
+    // as do those before the closing quote
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+/// A WinSW service running this binary with the given arguments, restarted if it exits. WinSW
+/// answers the service control manager for it, which the binary itself doesn't do.
+fn winsw_config(label: &str, program: &str, args: &[String]) -> String {
+    let arguments = args.iter().map(|arg| quote_windows_arg(arg)).collect::<Vec<_>>().join(" ");
+    format!(r#"<service>
+  <id>{label}</id>
+  <name>{label}</name>
+  <description>Writes Caddy snippets for the running Docker containers</description>
+  <executable>{program}</executable>
+  <arguments>{arguments}</arguments>
+  <onfailure action="restart" delay="10 sec"/>
+  <log mode="roll"/>
+</service>
+"#, label=escape_xml(label), program=escape_xml(program), arguments=escape_xml(&arguments))
+}
+
+/// Register a service running this binary with the options it was given before `service install`,
+/// with launchd on macOS or WinSW (which must be on the PATH) on Windows. Options set through
+/// environment variables are not carried over.
+pub(crate) fn install(label: &str) -> Result<()> {
+    if cfg!(windows) {
+        return install_winsw(label);
+    }
+    if !cfg!(target_os = "macos") {
+        return Err(unsupported());
+    }
+
+    let program = std::env::current_exe()?;
+    let program = program.to_str().ok_or("could not turn the binary path into a string")?;
+    let path = plist_path(label);
+    fs::write(&path, launchd_plist(label, program, &service_args()?))?;
+    info!(path=%path.display(), label, "wrote launchd plist");
+
+    launchctl(&["bootstrap", "system", path.to_str().ok_or("could not turn the plist path into a string")?])?;
+    info!(label, "service installed & started");
+    Ok(())
+}
+
+fn install_winsw(label: &str) -> Result<()> {
+    let program = std::env::current_exe()?;
+    let program = program.to_str().ok_or("could not turn the binary path into a string")?;
+    let path = winsw_config_path(label);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, winsw_config(label, program, &service_args()?))?;
+    info!(path=%path.display(), label, "wrote WinSW service definition");
+
+    let path = path.to_str().ok_or("could not turn the service definition path into a string")?;
+    run("winsw", &["install", path])?;
+    run("winsw", &["start", path])?;
+    info!(label, "service installed & started");
+    Ok(())
+}
+
+/// Stop and remove a service registered by `install`
+pub(crate) fn uninstall(label: &str) -> Result<()> {
+    if cfg!(windows) {
+        return uninstall_winsw(label);
+    }
+    if !cfg!(target_os = "macos") {
+        return Err(unsupported());
+    }
+
+    launchctl(&["bootout", &format!("system/{label}")])?;
+    let path = plist_path(label);
+    fs::remove_file(&path)?;
+    info!(path=%path.display(), label, "service stopped & plist removed");
+    Ok(())
+}
+
+fn uninstall_winsw(label: &str) -> Result<()> {
+    let path = winsw_config_path(label);
+    let path_str = path.to_str().ok_or("could not turn the service definition path into a string")?;
+    run("winsw", &["stop", path_str])?;
+    run("winsw", &["uninstall", path_str])?;
+    fs::remove_file(&path)?;
+    info!(path=%path.display(), label, "service stopped & definition removed");
+    Ok(())
+}
+
+fn unsupported() -> Box<dyn std::error::Error> {
+    "service install is only supported on macOS and Windows - use the init system (e.g., a systemd unit) directly".into()
+}
+
+fn launchctl(args: &[&str]) -> Result<()> {
+    run("launchctl", args)
+}
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| format!("unable to run {program}: {e}"))?;
+
+    if !status.success() {
+        return Err(format!("{program} {} exited with status {}", args.join(" "), status.code().unwrap_or(-1)).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{launchd_plist, quote_windows_arg, winsw_config};
+
+    #[test]
+    fn launchd_plist_lists_each_argument_escaped() {
+        let plist = launchd_plist("com.example.caddy-updater", "/usr/local/bin/docker-caddy-rs", &[
+            "--domain-name=example.com".to_string(),
+            "--local-caddy-reload-env=A=<b>&c".to_string(),
+        ]);
+        assert!(plist.contains("    <string>com.example.caddy-updater</string>\n"));
+        assert!(plist.contains(concat!(
+            "        <string>/usr/local/bin/docker-caddy-rs</string>\n",
+            "        <string>--domain-name=example.com</string>\n",
+            "        <string>--local-caddy-reload-env=A=&lt;b&gt;&amp;c</string>\n",
+            "    </array>",
+        )), "{plist}");
+    }
+
+    #[test]
+    fn winsw_config_quotes_and_escapes_the_arguments() {
+        let config = winsw_config("caddy-updater", "C:\\Tools\\docker-caddy-rs.exe", &[
+            "--domain-name=example.com".to_string(),
+            "--local-caddy-snippets-dir=C:\\Caddy Snippets".to_string(),
+            "--local-caddy-reload-env=A=<b>".to_string(),
+        ]);
+        assert!(config.contains("<id>caddy-updater</id>"));
+        assert!(config.contains("<executable>C:\\Tools\\docker-caddy-rs.exe</executable>"));
+        assert!(config.contains(r#"<arguments>--domain-name=example.com &quot;--local-caddy-snippets-dir=C:\Caddy Snippets&quot; --local-caddy-reload-env=A=&lt;b&gt;</arguments>"#));
+    }
+
+    #[test]
+    fn quote_windows_arg_escapes_quotes_and_trailing_backslashes() {
+        assert_eq!(quote_windows_arg("plain"), "plain");
+        assert_eq!(quote_windows_arg(""), r#""""#);
+        assert_eq!(quote_windows_arg(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quote_windows_arg(r"C:\Caddy Snippets\"), r#""C:\Caddy Snippets\\""#);
+    }
+}
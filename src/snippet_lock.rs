@@ -0,0 +1,101 @@
+use std::fs::{self, File, TryLockError};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, warn};
+use super::{config, managed_region, RenderedSnippets, Result};
+
+/// The lock file for the snippets in a dir. It's a dotfile, so Caddy never imports it.
+fn lock_path(dir: &Path) -> PathBuf {
+    dir.join(format!(".{}.lock", config().snippet_file_name))
+}
+
+/// How often a lock held by another process is tried again
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Advisory locks on the snippets dirs, held from writing the snippets until Caddy has reloaded
+/// them, so two instances misconfigured to share a dir can't interleave their writes. The locks
+/// are released when dropped.
+pub(crate) struct SnippetLocks {
+    _files: Vec<File>,
+}
+
+impl SnippetLocks {
+    /// Lock each snippets dir, waiting for any held by another process. The locks are only ever
+    /// tried, so waiting doesn't block the runtime.
+    pub(crate) async fn acquire() -> Result<Self> {
+        let mut dirs = vec![&config().local_caddy.snippets_dir, &config().docker_caddy.snippets_dir];
+        // locking the same file twice would wait on ourselves
+        dirs.dedup();
+
+        let mut files = Vec::with_capacity(dirs.len());
+        for dir in dirs {
+            let path = lock_path(dir);
+            let file = File::options().create(true).truncate(false).write(true).open(&path)?;
+            let mut warned = false;
+            loop {
+                match file.try_lock() {
+                    Ok(()) => break,
+                    Err(TryLockError::WouldBlock) => {
+                        if !warned {
+                            warn!(path=%path.display(), "snippets are locked by another process - is another instance using the same snippets dir? Waiting for the lock...");
+                            warned = true;
+                        }
+                        tokio::time::sleep(RETRY_INTERVAL).await;
+                    },
+                    Err(TryLockError::Error(e)) => return Err(e.into()),
+                }
+            }
+            debug!(path=%path.display(), "locked snippets");
+            files.push(file);
+        }
+
+        Ok(SnippetLocks { _files: files })
+    }
+}
+
+/// Warn if the snippet files no longer hold what we last wrote to them, as that means something
+/// else is writing them too, and one of us is about to clobber the other's changes
+pub(crate) fn warn_if_modified(last_written: &RenderedSnippets) -> Result<()> {
+    let files = [
+        (&config().local_caddy.snippets_dir, &last_written.local),
+        (&config().docker_caddy.snippets_dir, &last_written.docker),
+    ];
+    for (dir, expected) in files {
+        let path = dir.join(&config().snippet_file_name);
+        match fs::read_to_string(&path) {
+            Ok(content) if managed_region::snippets(&content) == Some(expected.as_str()) => {},
+            Ok(_) => warn!(path=%path.display(), "snippets were modified by another process since they were last written - overwriting them"),
+            Err(e) if e.kind() == ErrorKind::NotFound => warn!(path=%path.display(), "snippets were removed by another process since they were last written"),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::Duration;
+    use super::{lock_path, SnippetLocks};
+    use crate::config;
+
+    #[tokio::test]
+    async fn acquire_waits_for_another_lock_without_blocking_the_runtime() {
+        let dir = &config().local_caddy.snippets_dir;
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::create_dir_all(&config().docker_caddy.snippets_dir).unwrap();
+        let held = File::options().create(true).truncate(false).write(true).open(lock_path(dir)).unwrap();
+        held.lock().unwrap();
+
+        // on this single-threaded runtime, the release only runs if acquire yields while waiting
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(held);
+        });
+        let locks = tokio::time::timeout(Duration::from_secs(5), SnippetLocks::acquire()).await;
+        release.await.unwrap();
+        assert!(locks.expect("the lock was never acquired").is_ok());
+    }
+}
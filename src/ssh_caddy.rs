@@ -0,0 +1,241 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{debug, error, info};
+use super::metrics::{self, Stage};
+use super::{config, Result};
+
+/// Caddy servers on other hosts (e.g., an off-site edge proxy), sent the local snippets over SSH
+pub(crate) struct SshCaddy {
+    /// Each server as an ssh destination, e.g., "caddy@edge.example.com" or a ~/.ssh/config alias
+    pub(crate) destinations: Vec<String>,
+    pub(crate) identity_file: Option<PathBuf>,
+    pub(crate) transfer: SnippetTransfer,
+    pub(crate) snippets_dir: String,
+    pub(crate) config_dir: String,
+    pub(crate) bin_path: String,
+}
+
+/// How the snippet file is copied to the servers
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub(crate) enum SnippetTransfer {
+    Sftp,
+    Scp,
+    /// Needs rsync on both ends
+    Rsync,
+}
+
+/// Seconds ssh waits to connect, so an unreachable server doesn't hold up the others for minutes
+const CONNECT_TIMEOUT_SECS: u32 = 10;
+
+impl SshCaddy {
+    /// Upload the local snippet file to every server, then reload Caddy on it once the uploaded
+    /// file's checksum matches. All of them are tried, so one unreachable server doesn't leave the
+    /// rest on the old snippets.
+    pub(crate) async fn sync(&self) -> Result<()> {
+        let snippet = config().local_caddy.snippets_dir.join(&config().snippet_file_name);
+        let checksum = format!("{:x}", Sha256::digest(tokio::fs::read(&snippet).await?));
+        let mut failed = Vec::new();
+        for destination in &self.destinations {
+            let started = std::time::Instant::now();
+            let result = match self.upload(destination, &snippet).await {
+                Ok(()) => match self.verify_checksum(destination, &checksum).await {
+                    Ok(()) => self.reload(destination).await,
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => metrics::observe(Stage::SshReload, started.elapsed()),
+                Err(e) => {
+                    error!(destination, error=%e, "unable to reload Caddy over SSH");
+                    failed.push(format!("{destination}: {e}"));
+                },
+            }
+        }
+
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(format!("{} of {} Caddy server(s) over SSH failed to reload - {}", failed.len(), self.destinations.len(), failed.join("; ")).into()),
+        }
+    }
+
+    /// Common options - key auth only, as there's nobody to type a password
+    fn options(&self) -> Vec<String> {
+        let mut options = vec![
+            "-o".to_string(), "BatchMode=yes".to_string(),
+            "-o".to_string(), format!("ConnectTimeout={CONNECT_TIMEOUT_SECS}"),
+        ];
+        if let Some(ref identity_file) = self.identity_file {
+            options.extend(["-i".to_string(), identity_file.to_string_lossy().into_owned()]);
+        }
+        options
+    }
+
+    fn remote_snippet(&self) -> String {
+        format!("{}/{}", self.snippets_dir.trim_end_matches('/'), config().snippet_file_name)
+    }
+
+    async fn upload(&self, destination: &str, snippet: &Path) -> Result<()> {
+        info!(destination, transfer=?self.transfer, "uploading snippets...");
+        let (mut command, batch) = self.upload_command(destination, snippet);
+        let program = command.as_std().get_program().to_string_lossy().into_owned();
+        let mut child = command.stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("unable to run {program}: {e}"))?;
+        if let Some(batch) = batch {
+            // dropped once written, so sftp sees the end of the batch
+            child.stdin.take().expect("stdin is piped").write_all(batch.as_bytes()).await?;
+        }
+
+        let exit_status = child.wait().await?;
+        match exit_status.success() {
+            true => Ok(()),
+            false => Err(format!("{program} exited with status {}", exit_status.code().unwrap_or(-1)).into()),
+        }
+    }
+
+    /// The command copying the snippet file to the server, with the batch to give sftp on stdin
+    fn upload_command(&self, destination: &str, snippet: &Path) -> (Command, Option<String>) {
+        let remote = self.remote_snippet();
+        let program = match self.transfer {
+            SnippetTransfer::Sftp => "sftp",
+            SnippetTransfer::Scp => "scp",
+            SnippetTransfer::Rsync => "rsync",
+        };
+        let mut command = Command::new(program);
+        let mut batch = None;
+        match self.transfer {
+            SnippetTransfer::Sftp => {
+                command.args(self.options()).args(["-b", "-", destination]).stdin(Stdio::piped());
+                batch = Some(format!("put {} {}\n", sftp_quote(&snippet.to_string_lossy()), sftp_quote(&remote)));
+            },
+            SnippetTransfer::Scp => {
+                // scp's SFTP mode (the default since OpenSSH 9) takes the remote path as it is
+                command.args(self.options()).arg(snippet).arg(format!("{destination}:{remote}"));
+            },
+            SnippetTransfer::Rsync => {
+                // --protect-args passes the remote path without the remote shell splitting it
+                let ssh = std::iter::once("ssh".to_string()).chain(self.options().iter().map(|option| shell_quote(option))).collect::<Vec<_>>();
+                command.args(["--checksum", "--protect-args", "-e"]).arg(ssh.join(" ")).arg(snippet).arg(format!("{destination}:{remote}"));
+            },
+        }
+        (command, batch)
+    }
+
+    /// Check the uploaded file is the one written locally, so a truncated upload is never loaded
+    async fn verify_checksum(&self, destination: &str, checksum: &str) -> Result<()> {
+        let output = Command::new("ssh")
+            .args(self.options())
+            .arg(destination)
+            .arg(format!("sha256sum {}", shell_quote(&self.remote_snippet())))
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| format!("unable to run ssh: {e}"))?;
+        if !output.status.success() {
+            return Err(format!("sha256sum over ssh exited with status {}: {}", output.status.code().unwrap_or(-1), String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match stdout.split_whitespace().next() {
+            Some(remote) if remote == checksum => {
+                debug!(destination, checksum, "uploaded snippets match");
+                Ok(())
+            },
+            remote => Err(format!("uploaded snippets have checksum {}, expected {checksum}", remote.unwrap_or("(none)")).into()),
+        }
+    }
+
+    async fn reload(&self, destination: &str) -> Result<()> {
+        info!(destination, "reloading Caddy over SSH...");
+        // the remote command goes through the remote user's shell
+        let exit_status = Command::new("ssh")
+            .args(self.options())
+            .arg(destination)
+            .arg(format!("cd {} && {} reload", shell_quote(&self.config_dir), shell_quote(&self.bin_path)))
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("unable to run ssh: {e}"))?
+            .wait()
+            .await?;
+        match exit_status.success() {
+            true => Ok(()),
+            false => Err(format!("caddy reload over ssh exited with status {}", exit_status.code().unwrap_or(-1)).into()),
+        }
+    }
+}
+
+fn sftp_quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use super::{shell_quote, SnippetTransfer, SshCaddy};
+
+    fn edge() -> SshCaddy {
+        SshCaddy {
+            destinations: vec!["caddy@edge.example.com".to_string()],
+            identity_file: Some(PathBuf::from("/etc/docker-caddy-rs/id_ed25519")),
+            transfer: SnippetTransfer::Sftp,
+            snippets_dir: "/etc/caddy/snippets/".to_string(),
+            config_dir: "/etc/caddy".to_string(),
+            bin_path: "caddy".to_string(),
+        }
+    }
+
+    #[test]
+    fn ssh_never_prompts_and_uses_the_identity_file() {
+        let options = edge().options();
+        assert!(options.windows(2).any(|option| option == ["-o", "BatchMode=yes"]));
+        assert!(options.ends_with(&["-i".to_string(), "/etc/docker-caddy-rs/id_ed25519".to_string()]));
+        assert!(edge().remote_snippet().starts_with("/etc/caddy/snippets/"));
+        assert!(!edge().remote_snippet().contains("//"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn remote_commands_get_each_argument_as_it_is() {
+        for arg in ["/etc/caddy", "/srv/it's here", "$HOME; rm -rf /", "\"quoted\""] {
+            let output = std::process::Command::new("sh")
+                .args(["-c", &format!("printf %s {}", shell_quote(arg))])
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), arg);
+        }
+    }
+
+    #[test]
+    fn each_transfer_copies_the_snippet_to_the_snippets_dir() {
+        let snippet = Path::new("/tmp/local/docker-hosts.caddy");
+        let upload = |transfer| {
+            let (command, batch) = SshCaddy { transfer, ..edge() }.upload_command("caddy@edge.example.com", snippet);
+            let args = command.as_std().get_args().map(|arg| arg.to_string_lossy().into_owned()).collect::<Vec<_>>();
+            (command.as_std().get_program().to_string_lossy().into_owned(), args, batch)
+        };
+        let remote = edge().remote_snippet();
+
+        let (program, args, batch) = upload(SnippetTransfer::Sftp);
+        assert_eq!(program, "sftp");
+        assert!(args.ends_with(&["-b".to_string(), "-".to_string(), "caddy@edge.example.com".to_string()]));
+        assert_eq!(batch, Some(format!("put \"/tmp/local/docker-hosts.caddy\" \"{remote}\"\n")));
+
+        let (program, args, batch) = upload(SnippetTransfer::Scp);
+        assert_eq!(program, "scp");
+        assert_eq!(args.last(), Some(&format!("caddy@edge.example.com:{remote}")));
+        assert_eq!(batch, None);
+
+        let (program, args, _) = upload(SnippetTransfer::Rsync);
+        assert_eq!(program, "rsync");
+        assert!(args.contains(&"--checksum".to_string()));
+        assert!(args.contains(&"ssh '-o' 'BatchMode=yes' '-o' 'ConnectTimeout=10' '-i' '/etc/docker-caddy-rs/id_ed25519'".to_string()), "{args:?}");
+    }
+}
@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+use super::{ApplicationData, Result};
+
+/// The apps as last discovered, kept in --state-file so a restart can start from them
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    saved_at: DateTime<Utc>,
+    apps: ApplicationData,
+}
+
+/// The apps saved before the last restart, unless there are none or they're older than the max age
+/// (0 for no limit)
+pub(crate) fn load(path: &Path, max_age: Duration) -> Option<ApplicationData> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!(path=%path.display(), error=%e, "unable to read the saved state, ignoring it");
+            return None;
+        },
+    };
+    let state: SavedState = match serde_json::from_slice(&contents) {
+        Ok(state) => state,
+        Err(e) => {
+            // e.g., saved by a version with different app data
+            warn!(path=%path.display(), error=%e, "unable to parse the saved state, ignoring it");
+            return None;
+        },
+    };
+
+    let age = (Utc::now() - state.saved_at).to_std().unwrap_or_default();
+    if !max_age.is_zero() && age > max_age {
+        info!(path=%path.display(), saved_at=%state.saved_at, "saved state is too old, ignoring it");
+        return None;
+    }
+    info!(path=%path.display(), saved_at=%state.saved_at, apps=state.apps.len(), "loaded saved state");
+    Some(state.apps)
+}
+
+/// Save the apps, replacing the file in one step so a crash mid-write can't leave it truncated.
+/// Failures are logged rather than returned, as the state is only a head start for the next run.
+pub(crate) fn save(path: &Path, app_data: &ApplicationData) {
+    if let Err(e) = try_save(path, app_data) {
+        error!(path=%path.display(), error=%e, "unable to save state");
+    }
+}
+
+fn try_save(path: &Path, app_data: &ApplicationData) -> Result<()> {
+    let state = SavedState { saved_at: Utc::now(), apps: app_data.clone() };
+    let temp = path.with_extension("tmp");
+    // one left behind by a crash would keep its permissions
+    let _ = std::fs::remove_file(&temp);
+    create_private_file(&temp)?.write_all(&serde_json::to_vec(&state)?)?;
+    std::fs::rename(&temp, path)?;
+    debug!(path=%path.display(), "saved state");
+
+    Ok(())
+}
+
+/// Readable by this user only, as the app data comes from labels that may hold credentials
+#[cfg(unix)]
+fn create_private_file(path: &Path) -> Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    Ok(std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?)
+}
+
+#[cfg(not(unix))]
+fn create_private_file(path: &Path) -> Result<File> {
+    Ok(File::create(path)?)
+}
+
+/// Log how the running containers differ from the saved state, which they replace
+pub(crate) fn reconcile(saved: &ApplicationData, app_data: &ApplicationData) {
+    let mut gone = saved.keys().filter(|app_name| !app_data.contains_key(*app_name)).collect::<Vec<_>>();
+    let mut new = app_data.keys().filter(|app_name| !saved.contains_key(*app_name)).collect::<Vec<_>>();
+    gone.sort();
+    new.sort();
+    match gone.is_empty() && new.is_empty() {
+        true => debug!(apps=app_data.len(), "saved state matches the running containers"),
+        false => info!(?gone, ?new, "saved state was stale, using the running containers"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{load, save};
+    use crate::tests::{app, app_data};
+
+    #[test]
+    fn saved_apps_are_loaded_until_they_are_too_old() {
+        let dir = std::env::temp_dir().join(format!("docker-caddy-rs-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+
+        assert!(load(&path, Duration::ZERO).is_none());
+        save(&path, &app_data(vec![app("web", &[("port", "80")])]));
+        let loaded = load(&path, Duration::from_secs(3600)).unwrap();
+        assert_eq!(loaded["web"].containers[0].container_name, "web-1");
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        let saved_at = serde_json::from_str::<serde_json::Value>(&saved).unwrap()["saved_at"].as_str().unwrap().to_string();
+        std::fs::write(&path, saved.replace(&saved_at, "2026-01-01T00:00:00Z")).unwrap();
+        assert!(load(&path, Duration::from_secs(3600)).is_none());
+        assert!(load(&path, Duration::ZERO).is_some());
+
+        std::fs::write(&path, "{\"apps\": 1}").unwrap();
+        assert!(load(&path, Duration::ZERO).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Tell systemd about the daemon's state over $NOTIFY_SOCKET (as sd_notify does), for a unit with
+/// Type=notify. Does nothing when not started by systemd, or on platforms without it.
+pub(crate) fn notify(state: &str) {
+    #[cfg(target_os = "linux")]
+    {
+        let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+        if let Err(e) = send(&socket, state) {
+            warn!(state, error=%e, "unable to notify systemd");
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = state;
+}
+
+#[cfg(target_os = "linux")]
+fn send(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    // a leading @ is a socket in the abstract namespace
+    let address = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// How often to ping the watchdog - half of WatchdogSec, as systemd recommends - if it's enabled
+/// for this process
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    // set for the main process only, so a child started with the environment doesn't ping
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    debug!(usec, "systemd watchdog enabled");
+    Some(Duration::from_micros(usec) / 2)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+    use super::send;
+
+    #[test]
+    fn states_are_sent_to_path_and_abstract_sockets() {
+        let path = std::env::temp_dir().join(format!("docker-caddy-rs-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let read = socket.recv(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&buf[..read], b"READY=1");
+
+        let name = format!("docker-caddy-rs-notify-{}", std::process::id());
+        let address = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&address).unwrap();
+        send(format!("@{name}").as_ref(), "WATCHDOG=1").unwrap();
+        let read = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"WATCHDOG=1");
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use indoc::indoc;
+use minijinja::{Environment, UndefinedBehavior, Value};
+use serde::Serialize;
+use super::{config, config_error, AppData, ApplicationData, Result};
+
+/// What the snippet templates render each block of the apps from, as printed by
+/// `template-context dump`. The contexts are converted as for rendering, so a generated piece named
+/// like a field of the app data (e.g., upstream) shadows it here too.
+#[derive(Debug, Serialize)]
+pub(crate) struct TemplateContext {
+    blocks: Vec<BlockContext>,
+}
+
+#[derive(Debug, Serialize)]
+struct BlockContext {
+    local: Value,
+    docker: Value,
+}
+
+impl TemplateContext {
+    pub(crate) fn new(app_data: &ApplicationData) -> Self {
+        let mut apps = app_data.values().collect::<Vec<_>>();
+        // keep the output stable regardless of map ordering
+        apps.sort_by(|a, b| a.app_name.cmp(&b.app_name));
+
+        TemplateContext {
+            blocks: apps.into_iter()
+                .flat_map(AppData::with_port_variants)
+                .map(|app| BlockContext {
+                    local: Value::from_serialize(app.local_template_context()),
+                    docker: Value::from_serialize(app.docker_template_context()),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The built-in template for an app's block in the "local" snippets
+pub(crate) const DEFAULT_LOCAL_TEMPLATE: &str = indoc! {"
+    {{ public_paths }}{{ internal_paths }}{{ geo_policy }}@{{ matcher }} host {{ app_name }}.{{ domain }}
+      handle @{{ matcher }} {
+        handle /metrics {
+          {{ blocked }}
+        }
+        handle /metrics/* {
+          {{ blocked }}
+        }
+        {{ security_headers }}reverse_proxy {{ upstream }}
+      }
+"};
+
+/// The built-in template for an app's block in the Docker snippets
+pub(crate) const DEFAULT_DOCKER_TEMPLATE: &str = indoc! {"
+    {{ comments }}{{ well_known }}{{ public_paths }}@{{ matcher }} host {{ app_name }}.{{ domain }}
+      handle @{{ matcher }} {
+        handle /metrics {
+          {{ blocked }}
+        }
+        handle /metrics/* {
+          {{ blocked }}
+        }
+        {{ auth }}
+        {{ extra }}reverse_proxy {{ targets }}{{ retry_policy }}
+      }
+"};
+
+const LOCAL_TEMPLATE: &str = "local";
+const DOCKER_TEMPLATE: &str = "docker";
+const NOTIFY_TEMPLATE: &str = "notify";
+
+/// What a snippet template renders for one app: the app's own data, the domain it's served on by
+/// the Caddy instance, and the pieces of Caddyfile already generated for it (e.g., public_paths)
+#[derive(Serialize)]
+pub(crate) struct AppTemplateContext<'a> {
+    #[serde(flatten)]
+    pub(crate) app: &'a AppData,
+    pub(crate) domain: &'a str,
+    #[serde(flatten)]
+    pub(crate) generated: BTreeMap<&'static str, String>,
+}
+
+/// The snippet templates, from --local-template and --docker-template or the built-in ones, plus
+/// any --notify-template. They're loaded on first use, exiting with a usage error if they can't be
+/// read or parsed.
+fn environment() -> &'static Environment<'static> {
+    static ENVIRONMENT: OnceLock<Environment<'static>> = OnceLock::new();
+    ENVIRONMENT.get_or_init(|| {
+        let mut environment = Environment::new();
+        // the blocks are joined on newlines, so must keep theirs
+        environment.set_keep_trailing_newline(true);
+        environment.set_undefined_behavior(UndefinedBehavior::Strict);
+        add_helpers(&mut environment);
+
+        let templates = [
+            (LOCAL_TEMPLATE, &config().local_template, DEFAULT_LOCAL_TEMPLATE, "--local-template"),
+            (DOCKER_TEMPLATE, &config().docker_template, DEFAULT_DOCKER_TEMPLATE, "--docker-template"),
+        ];
+        let notify = config().notify.template.as_ref().map(|_| (NOTIFY_TEMPLATE, &config().notify.template, "", "--notify-template"));
+        for (name, path, default, option) in templates.into_iter().chain(notify) {
+            let source = match path {
+                Some(path) => std::fs::read_to_string(path)
+                    .unwrap_or_else(|e| config_error(&format!("unable to read {option} {}: {e}", path.display()))),
+                None => default.to_string(),
+            };
+            environment.add_template_owned(name, source)
+                .unwrap_or_else(|e| config_error(&format!("invalid {option}: {e}")));
+        }
+
+        environment
+    })
+}
+
+/// Helpers for custom templates, on top of minijinja's built-in filters (e.g., default)
+fn add_helpers(environment: &mut Environment) {
+    environment.add_filter("slugify", slugify);
+    environment.add_filter("to_upper", |value: &str| value.to_uppercase());
+    environment.add_filter("b64", |value: &str| b64(value.as_bytes()));
+    environment.add_function("cidr_contains", cidr_contains);
+}
+
+/// Lowercase, with each run of anything but letters and digits replaced by a single dash, e.g.,
+/// for a name usable in a matcher
+fn slugify(value: &str) -> String {
+    value.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Standard, padded base64, e.g., for a basic auth header
+fn b64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, byte)| n | (u32::from(*byte) << (16 - 8 * i)));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+/// Whether the IP address is in the CIDR range (e.g., "10.0.0.0/8"). Addresses of the other
+/// family are never in it.
+fn cidr_contains(cidr: &str, ip: &str) -> std::result::Result<bool, minijinja::Error> {
+    let invalid = |message: String| minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, message);
+    let (network, prefix) = cidr.split_once('/').ok_or_else(|| invalid(format!("{cidr} is not a CIDR range")))?;
+    let network = network.parse::<IpAddr>().map_err(|e| invalid(format!("invalid network in {cidr}: {e}")))?;
+    let prefix = prefix.parse::<u32>().map_err(|e| invalid(format!("invalid prefix length in {cidr}: {e}")))?;
+    let ip = ip.parse::<IpAddr>().map_err(|e| invalid(format!("invalid IP address {ip}: {e}")))?;
+
+    let (network, ip, bits) = match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => (u128::from(u32::from(network)) << 96, u128::from(u32::from(ip)) << 96, 32),
+        (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+        _ => return Ok(false),
+    };
+    if prefix > bits {
+        return Err(invalid(format!("prefix length in {cidr} is longer than the address")));
+    }
+    let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+    Ok(network & mask == ip & mask)
+}
+
+/// Parse the snippet templates now, so a broken template fails on startup
+pub(crate) fn load() {
+    environment();
+}
+
+pub(crate) fn render_local(context: &AppTemplateContext) -> Result<String> {
+    render(LOCAL_TEMPLATE, context)
+}
+
+pub(crate) fn render_docker(context: &AppTemplateContext) -> Result<String> {
+    render(DOCKER_TEMPLATE, context)
+}
+
+/// The body for a state change notification, if there's a --notify-template
+pub(crate) fn render_notification(context: &impl Serialize) -> Option<Result<String>> {
+    config().notify.template.as_ref()?;
+    Some(environment().get_template(NOTIFY_TEMPLATE)
+        .and_then(|template| template.render(context))
+        .map_err(|e| format!("unable to render the --notify-template: {e}").into()))
+}
+
+fn render(name: &str, context: &AppTemplateContext) -> Result<String> {
+    environment().get_template(name)?
+        .render(context)
+        .map_err(|e| format!("unable to render the {name} snippet template for app {}: {e}", context.app.app_name).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use minijinja::{context, Environment};
+    use serde_json::json;
+    use super::{add_helpers, TemplateContext};
+    use crate::tests::{app, app_data};
+
+    fn render(template: &str) -> String {
+        let mut environment = Environment::new();
+        environment.set_undefined_behavior(minijinja::UndefinedBehavior::Strict);
+        add_helpers(&mut environment);
+        environment.render_str(template, context! {}).unwrap()
+    }
+
+    #[test]
+    fn template_context_holds_each_block_as_rendered_in_app_order() {
+        let app_data = app_data(vec![
+            app("web", &[("port", "8080"), ("external", "true")]),
+            app("admin", &[("port", "9000")]),
+        ]);
+        let context = serde_json::to_value(TemplateContext::new(&app_data)).unwrap();
+
+        let blocks = context["blocks"].as_array().unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0]["local"]["app_name"], json!("admin"));
+        assert_eq!(blocks[0]["local"]["domain"], json!("local.example.com"));
+        assert_eq!(blocks[0]["docker"]["targets"], json!("http://admin-1:9000"));
+        assert_eq!(blocks[1]["local"]["app_name"], json!("web"));
+        assert_eq!(blocks[1]["local"]["external"], json!(true));
+        assert_eq!(blocks[1]["local"]["containers"][0]["container_name"], json!("web-1"));
+        assert_eq!(blocks[1]["docker"]["matcher"], json!("web"));
+    }
+
+    #[test]
+    fn generated_pieces_shadow_app_data() {
+        let app_data = app_data(vec![app("web", &[("port", "8080")])]);
+        let context = serde_json::to_value(TemplateContext::new(&app_data)).unwrap();
+
+        // the local template's upstream is the generated reverse_proxy target, not the app's port
+        assert!(context["blocks"][0]["local"]["upstream"].is_string());
+        assert_eq!(context["blocks"][0]["docker"]["upstream"], json!({"port": 8080}));
+    }
+
+    #[test]
+    fn replacement_templates_render_the_app_and_its_generated_pieces() {
+        let web = app("web", &[("port", "8080"), ("external", "true")]);
+        let mut environment = Environment::new();
+        environment.set_undefined_behavior(minijinja::UndefinedBehavior::Strict);
+        add_helpers(&mut environment);
+
+        let rendered = environment.render_str("{{ app_name | to_upper }}.{{ domain }} -> {{ targets }}", web.docker_template_context());
+        assert_eq!(rendered.unwrap(), "WEB.example.com -> http://web-1:8080");
+        // a typo in a template fails rather than leaving a hole in the snippets
+        assert!(environment.render_str("{{ target }}", web.docker_template_context()).is_err());
+    }
+
+    #[test]
+    fn helpers() {
+        assert_eq!(render("{{ 'My App_2 ' | slugify }}"), "my-app-2");
+        assert_eq!(render("{{ 'web' | to_upper }}"), "WEB");
+        assert_eq!(render("{{ missing | default('none') }}"), "none");
+        assert_eq!(render("{{ 'user:pass' | b64 }} {{ 'a' | b64 }} {{ 'ab' | b64 }}"), "dXNlcjpwYXNz YQ== YWI=");
+        assert_eq!(render("{{ cidr_contains('10.0.0.0/8', '10.1.2.3') }}"), "True");
+        assert_eq!(render("{{ cidr_contains('10.0.0.0/8', '192.168.0.1') }}"), "False");
+        assert_eq!(render("{{ cidr_contains('fd00::/8', 'fd12::1') }}"), "True");
+        assert_eq!(render("{{ cidr_contains('fd00::/8', '10.1.2.3') }}"), "False");
+        assert_eq!(render("{{ cidr_contains('0.0.0.0/0', '1.2.3.4') }}"), "True");
+    }
+}
@@ -0,0 +1,65 @@
+use reqwest::ClientBuilder;
+use serde::Deserialize;
+use tracing::{debug, info};
+use super::Result;
+
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/ipsi/docker-caddy-rs/releases/latest";
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+}
+
+/// Log if there is a newer release than this build on GitHub. Failures are only logged at debug,
+/// as being offline shouldn't be noisy.
+pub(crate) async fn check_for_update() {
+    if let Err(e) = try_check_for_update().await {
+        debug!(error=%e, "unable to check for a newer release");
+    }
+}
+
+async fn try_check_for_update() -> Result<()> {
+    let client = ClientBuilder::new()
+        .user_agent(concat!("docker-caddy-rs/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+    let release = client.get(LATEST_RELEASE_URL)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Release>()
+        .await?;
+
+    let current = env!("CARGO_PKG_VERSION");
+    match (parse_version(&release.tag_name), parse_version(current)) {
+        (Some(latest), Some(running)) if latest > running => {
+            info!(current, latest=release.tag_name, url=release.html_url, "a newer release is available");
+        },
+        _ => debug!(current, latest=release.tag_name, "running the latest release"),
+    }
+
+    Ok(())
+}
+
+/// Parse "v1.2.3" or "1.2.3" into its numeric parts, ignoring any pre-release suffix
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version.trim_start_matches('v')
+        .split(['-', '+'])
+        .next()?
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_version;
+
+    #[test]
+    fn versions_compare_by_their_numeric_parts() {
+        assert_eq!(parse_version("v1.2.3"), Some(vec![1, 2, 3]));
+        assert_eq!(parse_version("1.10.0-rc.1"), Some(vec![1, 10, 0]));
+        assert_eq!(parse_version("nightly"), None);
+        assert!(parse_version("v1.10.0") > parse_version("1.9.3"));
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use reqwest::ClientBuilder;
+use reqwest::redirect::Policy;
+use tracing::{debug, error, info};
+use super::{emit, ApplicationData, Result};
+
+/// How the apps changed by a reload are checked afterwards, from --verify-after-reload
+#[derive(Debug)]
+pub(crate) struct VerifyConfig {
+    pub(crate) timeout: Duration,
+    /// Address to send the requests to, instead of resolving each host
+    pub(crate) address: Option<IpAddr>,
+    pub(crate) on_failure: VerifyFailureAction,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, clap::ValueEnum)]
+pub(crate) enum VerifyFailureAction {
+    /// Log the failure (and emit it, with --emit-events), keeping the new snippets
+    Log,
+    /// Roll back to the last good snippets, as if the reload had failed
+    Rollback,
+}
+
+/// The config of each app as of the last verification, to find the apps a reload changed
+pub(crate) type VerifiedApps = HashMap<String, serde_json::Value>;
+
+/// Request the host of each app that was added or changed since the last verification through
+/// the local Caddy, failing if any don't respond, or respond with a 5xx, within the timeout.
+/// Apps are only verified once per change, whether or not they pass.
+pub(crate) async fn verify_changed(verify: &VerifyConfig, verified: &mut VerifiedApps, app_data: &ApplicationData) -> Result<()> {
+    let current = app_data.iter()
+        .filter(|(_, ad)| !ad.containers.is_empty() && ad.dependencies_ready(app_data))
+        .filter_map(|(app_name, ad)| Some((app_name.clone(), serde_json::to_value(ad).ok()?)))
+        .collect::<VerifiedApps>();
+    let changed = current.iter()
+        .filter(|(app_name, value)| verified.get(*app_name) != Some(value))
+        .map(|(app_name, _)| app_name.as_str())
+        .collect::<Vec<_>>();
+
+    let mut failures = Vec::new();
+    for app_name in changed {
+        for ad in app_data[app_name].with_port_variants() {
+            if ad.rejection().is_some() {
+                continue;
+            }
+            let host = format!("{}.{}", ad.app_name, ad.domain());
+            // the request has no client certificate to offer
+            if ad.client_auth.is_some() {
+                debug!(app_name, host, "app requires client certificates, not verifying");
+                continue;
+            }
+
+            match request(verify, &host).await {
+                Ok(status) => info!(app_name, host, status, "verified app after reload"),
+                Err(e) => {
+                    error!(app_name, host, error=%e, "app failed verification after reload");
+                    emit::emit(emit::Transition::VerificationFailed { app: app_name, host: &host, error: e.to_string() });
+                    failures.push(format!("{host} ({e})"));
+                },
+            }
+        }
+    }
+    *verified = current;
+
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(format!("apps failed verification after reload: {}", failures.join(", ")).into()),
+    }
+}
+
+/// The response status for the host's root, if it isn't a server error. Certificates aren't
+/// checked, as a new site's may not have been issued yet - it's the routing being verified.
+async fn request(verify: &VerifyConfig, host: &str) -> Result<u16> {
+    let mut builder = ClientBuilder::new()
+        .timeout(verify.timeout)
+        .redirect(Policy::none())
+        .danger_accept_invalid_certs(true);
+    if let Some(address) = verify.address {
+        // the port is taken from the URL
+        builder = builder.resolve(host, SocketAddr::new(address, 443));
+    }
+    let response = builder.build()?
+        .get(format!("https://{host}/"))
+        .send()
+        .await?;
+
+    let status = response.status();
+    match status.is_server_error() {
+        true => Err(format!("responded with {status}").into()),
+        false => Ok(status.as_u16()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{verify_changed, VerifiedApps, VerifyConfig, VerifyFailureAction};
+    use crate::tests::{app, app_data};
+
+    #[tokio::test]
+    async fn apps_are_verified_once_per_change() {
+        // nothing listens on localhost's 443 here, so every request fails
+        let verify = VerifyConfig { timeout: Duration::from_secs(1), address: Some([127, 0, 0, 1].into()), on_failure: VerifyFailureAction::Log };
+        let mut verified = VerifiedApps::new();
+
+        let apps = app_data(vec![app("web", &[("port", "80")])]);
+        let error = verify_changed(&verify, &mut verified, &apps).await.unwrap_err().to_string();
+        assert!(error.contains("web.local.example.com"), "{error}");
+        assert!(verify_changed(&verify, &mut verified, &apps).await.is_ok());
+        assert!(verify_changed(&verify, &mut verified, &app_data(vec![app("web", &[("port", "8080")])])).await.is_err());
+
+        // there's no client certificate to offer
+        let mtls = app_data(vec![app("vault", &[("port", "80"), ("client_auth", "require"), ("client_auth_ca", "/etc/caddy/ca.pem")])]);
+        assert!(verify_changed(&verify, &mut verified, &mtls).await.is_ok());
+    }
+}